@@ -67,13 +67,10 @@ async fn send_query(
     tracing::info!("PONG: {pong:?}");
 
     let answer = shard
-        .adnl_query(&adnl, &peer_id, RpcGetCapabilities, None)
+        .query::<_, Capabilities>(&adnl, &peer_id, RpcGetCapabilities)
         .await?
         .context("no answer")?;
-    tracing::info!("response: {}", hex::encode(&answer));
-
-    let parsed = tl_proto::deserialize::<Capabilities>(&answer)?;
-    tracing::info!("answer: {parsed:?}");
+    tracing::info!("answer: {answer:?}");
 
     Ok(())
 }
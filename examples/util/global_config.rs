@@ -68,6 +68,7 @@ impl<'de> Deserialize<'de> for DhtNode {
                 .map(|addr| proto::adnl::Address::from(&addr)),
             version: entry.addr_list.version,
             reinit_date: entry.addr_list.reinit_date,
+            priority: 0,
             expire_at: entry.addr_list.expire_at,
         };
 
@@ -0,0 +1,153 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// UDP address of the remote ADNL node.
+///
+/// Parsed either from `ip:port` or from `host:port`, in which case the host
+/// is resolved with [`AdnlAddressUdp::resolve`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AdnlAddressUdp {
+    /// Known IPv4 socket address
+    Resolved(SocketAddrV4),
+    /// Host name which must be resolved before use
+    Host { host: String, port: u16 },
+}
+
+impl AdnlAddressUdp {
+    /// Returns the first IPv4 address of the host.
+    ///
+    /// Resolved addresses are returned as is, host names are looked up with the system resolver
+    pub async fn resolve(&self) -> Result<SocketAddrV4, AdnlAddressUdpError> {
+        let (host, port) = match self {
+            Self::Resolved(addr) => return Ok(*addr),
+            Self::Host { host, port } => (host.as_str(), *port),
+        };
+
+        tokio::net::lookup_host((host, port))
+            .await
+            .map_err(AdnlAddressUdpError::ResolutionFailed)?
+            .find_map(|addr| match addr {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| AdnlAddressUdpError::NoIpv4Address(host.to_owned()))
+    }
+}
+
+impl From<SocketAddrV4> for AdnlAddressUdp {
+    fn from(addr: SocketAddrV4) -> Self {
+        Self::Resolved(addr)
+    }
+}
+
+impl std::fmt::Display for AdnlAddressUdp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Resolved(addr) => addr.fmt(f),
+            Self::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+impl FromStr for AdnlAddressUdp {
+    type Err = AdnlAddressUdpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or(AdnlAddressUdpError::InvalidFormat)?;
+        let port = port.parse().map_err(|_| AdnlAddressUdpError::InvalidPort)?;
+
+        if host.is_empty() {
+            return Err(AdnlAddressUdpError::InvalidFormat);
+        }
+
+        Ok(match Ipv4Addr::from_str(host) {
+            Ok(ip) => Self::Resolved(SocketAddrV4::new(ip, port)),
+            Err(_) => Self::Host {
+                host: host.to_owned(),
+                port,
+            },
+        })
+    }
+}
+
+impl Serialize for AdnlAddressUdp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for AdnlAddressUdp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(Error::custom)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AdnlAddressUdpError {
+    #[error("Expected `host:port`")]
+    InvalidFormat,
+    #[error("Invalid port")]
+    InvalidPort,
+    #[error("Failed to resolve host")]
+    ResolutionFailed(#[source] std::io::Error),
+    #[error("No IPv4 address found for `{0}`")]
+    NoIpv4Address(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        let addr = AdnlAddressUdp::from_str("1.2.3.4:30303").unwrap();
+        assert_eq!(
+            addr,
+            AdnlAddressUdp::Resolved(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 30303))
+        );
+        assert_eq!(addr.to_string(), "1.2.3.4:30303");
+
+        let addr = AdnlAddressUdp::from_str("localhost:123").unwrap();
+        assert_eq!(
+            addr,
+            AdnlAddressUdp::Host {
+                host: "localhost".to_owned(),
+                port: 123
+            }
+        );
+        assert_eq!(addr.to_string(), "localhost:123");
+
+        for invalid in ["localhost", ":123", "localhost:", "localhost:65536"] {
+            assert!(AdnlAddressUdp::from_str(invalid).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_ip() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 30303);
+        assert_eq!(AdnlAddressUdp::from(addr).resolve().await.unwrap(), addr);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires the system resolver"]
+    async fn resolve_host() {
+        let addr = AdnlAddressUdp::from_str("localhost:123").unwrap();
+        assert_eq!(
+            addr.resolve().await.unwrap(),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 123)
+        );
+    }
+}
@@ -1,12 +1,37 @@
 use std::convert::TryInto;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use aes::cipher::{StreamCipher, StreamCipherSeek};
+use chacha20poly1305::AeadInPlace;
 use everscale_crypto::ed25519;
+use serde::{Deserialize, Serialize};
 
 use super::encryption::*;
 use super::node_id::NodeIdShort;
 use super::packet_view::*;
+use crate::util::now;
+
+/// ADNL version of the channel packets encrypted with ChaCha20-Poly1305.
+///
+/// Channel packets with this version advertise that the sender prefers this cipher
+pub const ADNL_CHACHA20_POLY1305_VERSION: u16 = 1;
+
+/// ADNL version of the channel packets encrypted with AES-256-CTR
+pub const ADNL_INITIAL_VERSION: u16 = 0;
+
+/// Negotiates ADNL version of the AES channel packets from the versions
+/// advertised by both sides (packets without version are used if any side
+/// doesn't advertise it).
+///
+/// NOTE: ChaCha20-Poly1305 is negotiated separately over the channel
+pub fn negotiate_version(local: Option<u16>, remote: Option<u16>) -> Option<u16> {
+    match (local, remote) {
+        (Some(_), Some(_)) => Some(ADNL_INITIAL_VERSION),
+        _ => None,
+    }
+}
+
+const NO_VERSION: u32 = u32::MAX;
 
 /// ADNL channel state
 pub struct Channel {
@@ -26,6 +51,26 @@ pub struct Channel {
     peer_channel_date: u32,
     /// Channel drop timestamp
     drop: AtomicU32,
+    /// Local channel creation timestamp
+    created_at: u32,
+    /// Traffic counters for the ordinary subchannel
+    ordinary_counters: ChannelCounters,
+    /// Traffic counters for the priority subchannel
+    priority_counters: ChannelCounters,
+    /// Number of packets which failed to decrypt
+    decrypt_failures: AtomicU64,
+    /// Timestamp of the last received packet
+    last_activity: AtomicU32,
+    /// Number of consecutive failed keepalive pings
+    keepalive_failures: AtomicU32,
+    /// Whether channel was created from the pre-shared keys
+    is_static: bool,
+    /// Whether outgoing packets are encrypted with ChaCha20-Poly1305
+    use_chacha20: AtomicBool,
+    /// Whether ChaCha20-Poly1305 support was advertised to the peer
+    cipher_probe_sent: AtomicBool,
+    /// ADNL version negotiated with the peer ([`NO_VERSION`] if none)
+    version: AtomicU32,
 }
 
 impl Channel {
@@ -58,9 +103,40 @@ impl Channel {
             peer_channel_public_key,
             peer_channel_date,
             drop: Default::default(),
+            created_at: now(),
+            ordinary_counters: Default::default(),
+            priority_counters: Default::default(),
+            decrypt_failures: Default::default(),
+            last_activity: AtomicU32::new(now()),
+            keepalive_failures: Default::default(),
+            is_static: false,
+            use_chacha20: Default::default(),
+            cipher_probe_sent: Default::default(),
+            version: AtomicU32::new(NO_VERSION),
         }
     }
 
+    /// Creates ready channel state from the pre-shared channel keys.
+    ///
+    /// Such channels don't require `CreateChannel`/`ConfirmChannel` roundtrip
+    pub fn new_static(
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+        channel_key: &ed25519::KeyPair,
+        peer_channel_public_key: ed25519::PublicKey,
+    ) -> Self {
+        let mut channel = Self::new(
+            local_id,
+            peer_id,
+            channel_key,
+            peer_channel_public_key,
+            0,
+            ChannelCreationContext::ConfirmChannel,
+        );
+        channel.is_static = true;
+        channel
+    }
+
     /// Checks whether channel it initialized by the given key and date
     pub fn is_still_valid(
         &self,
@@ -77,10 +153,67 @@ impl Channel {
         self.ready.load(Ordering::Acquire)
     }
 
-    /// Sets channel ready
+    /// Whether channel was created from the pre-shared keys
+    #[inline(always)]
+    pub fn is_static(&self) -> bool {
+        self.is_static
+    }
+
+    /// Cipher used to encrypt outgoing packets
+    #[inline(always)]
+    pub fn cipher(&self) -> ChannelCipher {
+        if self.use_chacha20.load(Ordering::Acquire) {
+            ChannelCipher::ChaCha20Poly1305
+        } else {
+            ChannelCipher::AesCtr
+        }
+    }
+
+    /// Changes cipher used to encrypt outgoing packets
+    #[inline(always)]
+    pub fn set_cipher(&self, cipher: ChannelCipher) {
+        self.use_chacha20
+            .store(cipher == ChannelCipher::ChaCha20Poly1305, Ordering::Release);
+    }
+
+    /// Marks that ChaCha20-Poly1305 support was advertised to the peer.
+    /// Returns `false` if it was already advertised
+    #[inline(always)]
+    pub fn start_cipher_probe(&self) -> bool {
+        !self.cipher_probe_sent.swap(true, Ordering::AcqRel)
+    }
+
+    /// ADNL version negotiated with the peer
+    #[inline(always)]
+    pub fn version(&self) -> Option<u16> {
+        match self.version.load(Ordering::Acquire) {
+            NO_VERSION => None,
+            version => Some(version as u16),
+        }
+    }
+
+    /// Records ADNL version negotiated with the peer
     #[inline(always)]
-    pub fn set_ready(&self) {
-        self.ready.store(true, Ordering::Release)
+    pub fn set_version(&self, version: Option<u16>) {
+        self.version.store(
+            version.map(u32::from).unwrap_or(NO_VERSION),
+            Ordering::Release,
+        );
+    }
+
+    /// ADNL version of the outgoing packets
+    #[inline(always)]
+    pub fn packet_version(&self) -> Option<u16> {
+        match self.cipher() {
+            ChannelCipher::AesCtr => self.version(),
+            ChannelCipher::ChaCha20Poly1305 => Some(ADNL_CHACHA20_POLY1305_VERSION),
+        }
+    }
+
+    /// Sets channel ready. Returns whether the channel was not ready before
+    #[inline(always)]
+    pub fn set_ready(&self) -> bool {
+        !self.ready.swap(true, Ordering::AcqRel)
     }
 
     /// Public key of the keypair from the peer's side
@@ -136,11 +269,82 @@ impl Channel {
         self.drop.store(0, Ordering::Release);
     }
 
+    /// Local channel creation timestamp
+    #[inline(always)]
+    pub fn created_at(&self) -> u32 {
+        self.created_at
+    }
+
+    /// Total size of the encrypted packets
+    #[inline(always)]
+    pub fn encrypted_bytes(&self) -> u64 {
+        self.ordinary_counters.bytes_sent.load(Ordering::Acquire)
+            + self.priority_counters.bytes_sent.load(Ordering::Acquire)
+    }
+
+    /// Returns channel statistics snapshot
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            ready: self.ready(),
+            is_static: self.is_static,
+            cipher: self.cipher(),
+            version: self.version(),
+            created_at: self.created_at,
+            peer_channel_date: self.peer_channel_date,
+            last_activity: self.last_activity(),
+            ordinary: self.ordinary_counters.stats(),
+            priority: self.priority_counters.stats(),
+            decrypt_failures: self.decrypt_failures.load(Ordering::Acquire),
+        }
+    }
+
+    /// Timestamp of the last received packet
+    #[inline(always)]
+    pub fn last_activity(&self) -> u32 {
+        self.last_activity.load(Ordering::Acquire)
+    }
+
+    /// Updates the timestamp of the last received packet
+    #[inline(always)]
+    pub fn refresh_last_activity(&self, now: u32) {
+        self.last_activity.store(now, Ordering::Release);
+    }
+
+    /// Increments the number of consecutive failed keepalive pings.
+    /// Returns the updated value
+    pub fn add_keepalive_failure(&self) -> u32 {
+        self.keepalive_failures.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Resets the number of consecutive failed keepalive pings
+    #[inline(always)]
+    pub fn reset_keepalive_failures(&self) {
+        self.keepalive_failures.store(0, Ordering::Release);
+    }
+
     /// Decrypts data from the channel. Returns the version of the ADNL
     pub fn decrypt(
         &self,
         buffer: &mut PacketView,
         priority: bool,
+    ) -> Result<Option<u16>, AdnlChannelError> {
+        let len = buffer.len() as u64;
+        match self.decrypt_impl(buffer, priority) {
+            Ok(version) => {
+                self.counters(priority).on_received(len);
+                Ok(version)
+            }
+            Err(e) => {
+                self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    fn decrypt_impl(
+        &self,
+        buffer: &mut PacketView,
+        priority: bool,
     ) -> Result<Option<u16>, AdnlChannelError> {
         // Ordinary data ranges
         const DATA_START: usize = 64;
@@ -166,6 +370,26 @@ impl Channel {
             if let Some(version) =
                 decode_version::<EXT_DATA_START>((&buffer[..EXT_DATA_START]).try_into().unwrap())
             {
+                if version == ADNL_CHACHA20_POLY1305_VERSION {
+                    let id: [u8; 32] = buffer[..32].try_into().unwrap();
+                    let nonce: [u8; 12] = buffer[36..48].try_into().unwrap();
+                    let tag: [u8; 16] = buffer[48..64].try_into().unwrap();
+
+                    // NOTE: data is left untouched if the tag is invalid
+                    if build_packet_aead(shared_secret)
+                        .decrypt_in_place_detached(
+                            &nonce.into(),
+                            &id,
+                            &mut buffer[EXT_DATA_RANGE],
+                            &tag.into(),
+                        )
+                        .is_ok()
+                    {
+                        buffer.remove_prefix(EXT_DATA_START);
+                        return Ok(Some(version));
+                    }
+                }
+
                 // Build cipher
                 let mut cipher = build_packet_cipher(
                     shared_secret,
@@ -206,9 +430,10 @@ impl Channel {
         Ok(None)
     }
 
-    /// Modifies `buffer` in-place to contain the channel packet
+    /// Modifies `buffer` in-place to contain the channel packet.
+    ///
+    /// Packets with [`ADNL_CHACHA20_POLY1305_VERSION`] are encrypted with ChaCha20-Poly1305
     pub fn encrypt(&self, buffer: &mut Vec<u8>, priority: bool, version: Option<u16>) {
-        let checksum: [u8; 32] = compute_packet_data_hash(version, buffer.as_slice());
         let channel_out = if priority {
             &self.channel_out.priority
         } else {
@@ -223,7 +448,24 @@ impl Channel {
         buffer[..32].copy_from_slice(&channel_out.id);
 
         match version {
+            Some(ADNL_CHACHA20_POLY1305_VERSION) => {
+                // NOTE: nonce must never repeat for the same key, so it is generated
+                // by the cryptographically secure RNG
+                let mut nonce = [0u8; 12];
+                rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut nonce);
+
+                let (prefix, data) = buffer.split_at_mut(68);
+                let tag = build_packet_aead(&channel_out.secret)
+                    .encrypt_in_place_detached(&nonce.into(), &prefix[..32], data)
+                    .expect("Shouldn't fail for the packet size");
+
+                prefix[36..48].copy_from_slice(&nonce);
+                prefix[48..64].copy_from_slice(&tag);
+                encode_version::<68>(prefix.try_into().unwrap(), ADNL_CHACHA20_POLY1305_VERSION);
+            }
             Some(version) => {
+                let checksum: [u8; 32] = compute_packet_data_hash(Some(version), &buffer[68..]);
+
                 let mut xor = [
                     (version >> 8) as u8,
                     version as u8,
@@ -242,11 +484,24 @@ impl Channel {
                     .apply_keystream(&mut buffer[68..]);
             }
             None => {
+                let checksum: [u8; 32] = compute_packet_data_hash(None, &buffer[64..]);
+
                 buffer[32..64].copy_from_slice(&checksum);
                 build_packet_cipher(&channel_out.secret, &checksum)
                     .apply_keystream(&mut buffer[64..]);
             }
         }
+
+        self.counters(priority).on_sent(buffer.len() as u64);
+    }
+
+    #[inline(always)]
+    fn counters(&self, priority: bool) -> &ChannelCounters {
+        if priority {
+            &self.priority_counters
+        } else {
+            &self.ordinary_counters
+        }
     }
 
     #[inline(always)]
@@ -255,6 +510,84 @@ impl Channel {
     }
 }
 
+/// Channel statistics snapshot
+///
+/// See [`Node::channel_stats`]
+///
+/// [`Node::channel_stats`]: crate::adnl::Node::channel_stats
+#[derive(Debug, Copy, Clone)]
+pub struct ChannelStats {
+    /// Whether channel was confirmed by both sides
+    pub ready: bool,
+    /// Whether channel was created from the pre-shared keys
+    pub is_static: bool,
+    /// Cipher used to encrypt outgoing packets
+    pub cipher: ChannelCipher,
+    /// ADNL version negotiated with the peer
+    pub version: Option<u16>,
+    /// Local channel creation timestamp
+    pub created_at: u32,
+    /// Channel creation timestamp from the peer's side
+    pub peer_channel_date: u32,
+    /// Timestamp of the last received packet
+    pub last_activity: u32,
+    /// Ordinary subchannel traffic
+    pub ordinary: SubChannelStats,
+    /// Priority subchannel traffic
+    pub priority: SubChannelStats,
+    /// Number of packets which failed to decrypt
+    pub decrypt_failures: u64,
+}
+
+/// Channel packets encryption algorithm
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelCipher {
+    /// AES-256-CTR with SHA256 checksum
+    #[default]
+    AesCtr,
+    /// ChaCha20-Poly1305. Faster on platforms without AES hardware
+    ChaCha20Poly1305,
+}
+
+/// Subchannel traffic statistics
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SubChannelStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+struct ChannelCounters {
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ChannelCounters {
+    fn on_sent(&self, len: u64) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len, Ordering::Release);
+    }
+
+    fn on_received(&self, len: u64) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> SubChannelStats {
+        SubChannelStats {
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Acquire),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ChannelCreationContext {
     CreateChannel,
@@ -352,7 +685,6 @@ pub enum AdnlChannelError {
 mod tests {
     use super::*;
     use crate::adnl::ComputeNodeIds;
-    use crate::util::now;
 
     #[test]
     fn test_encrypt_decrypt() {
@@ -384,7 +716,7 @@ mod tests {
 
         let message = b"Hello world!";
 
-        for version in [None, Some(0)] {
+        for version in [None, Some(0), Some(ADNL_CHACHA20_POLY1305_VERSION)] {
             // Send 1 to 2
             {
                 let mut packet = message.to_vec();
@@ -409,5 +741,72 @@ mod tests {
                 assert_eq!(received_packet.as_slice(), message);
             }
         }
+
+        let stats = channel12.stats();
+        assert_eq!(stats.ordinary.packets_sent, 3);
+        assert_eq!(stats.priority.packets_received, 3);
+        assert_eq!(stats.priority.packets_sent, 0);
+        assert_eq!(stats.decrypt_failures, 0);
+
+        let mut packet = message.to_vec();
+        channel21.encrypt(&mut packet, false, None);
+        let mut received_packet = PacketView::from(packet.as_mut_slice());
+        assert!(channel12.decrypt(&mut received_packet, true).is_err());
+        assert_eq!(channel12.stats().decrypt_failures, 1);
+
+        let mut packet = message.to_vec();
+        channel21.encrypt(&mut packet, false, Some(ADNL_CHACHA20_POLY1305_VERSION));
+        packet[70] ^= 1;
+        let mut received_packet = PacketView::from(packet.as_mut_slice());
+        assert!(channel12.decrypt(&mut received_packet, false).is_err());
+    }
+
+    #[test]
+    fn test_static_channel() {
+        let peer1_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let (_, peer1_id) = peer1_key.compute_node_ids();
+        let peer1_channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+
+        let peer2_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let (_, peer2_id) = peer2_key.compute_node_ids();
+        let peer2_channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+
+        let channel12 = Channel::new_static(
+            peer1_id,
+            peer2_id,
+            &peer1_channel_key,
+            peer2_channel_key.public_key,
+        );
+        let channel21 = Channel::new_static(
+            peer2_id,
+            peer1_id,
+            &peer2_channel_key,
+            peer1_channel_key.public_key,
+        );
+        assert!(channel12.ready() && channel21.ready());
+        assert!(channel12.is_static());
+        assert_eq!(
+            channel12.ordinary_channel_in_id(),
+            &channel21.channel_out.ordinary.id
+        );
+
+        let message = b"Hello world!";
+        let mut packet = message.to_vec();
+        channel12.encrypt(&mut packet, false, Some(0));
+
+        let mut received_packet = PacketView::from(packet.as_mut_slice());
+        assert_eq!(
+            channel21.decrypt(&mut received_packet, false).unwrap(),
+            Some(0)
+        );
+        assert_eq!(received_packet.as_slice(), message);
+    }
+
+    #[test]
+    fn version_negotiation() {
+        assert_eq!(negotiate_version(None, Some(1)), None);
+        assert_eq!(negotiate_version(Some(0), None), None);
+        assert_eq!(negotiate_version(Some(0), Some(1)), Some(0));
+        assert_eq!(negotiate_version(Some(2), Some(1)), Some(0));
     }
 }
@@ -0,0 +1,38 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use super::node_id::NodeIdShort;
+
+pub(crate) type CustomMessagesTx = mpsc::Sender<(NodeIdShort, Bytes)>;
+
+/// Stream of incoming custom messages for the local id.
+///
+/// Yields `(peer_id, data)` pairs. Custom messages are only passed to the stream
+/// when no message subscriber has consumed them. The receiver waits while the
+/// stream buffer is full, so a slow consumer slows down incoming messages processing.
+///
+/// See [`Node::custom_messages`]
+///
+/// [`Node::custom_messages`]: crate::adnl::Node::custom_messages
+pub struct CustomMessages {
+    rx: mpsc::Receiver<(NodeIdShort, Bytes)>,
+}
+
+impl CustomMessages {
+    pub(crate) fn new(capacity: usize) -> (CustomMessagesTx, Self) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        (tx, Self { rx })
+    }
+}
+
+impl futures_util::Stream for CustomMessages {
+    type Item = (NodeIdShort, Bytes);
+
+    #[inline]
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
@@ -14,6 +14,12 @@ pub fn build_packet_cipher(shared_secret: &[u8; 32], checksum: &[u8; 32]) -> Aes
     )
 }
 
+pub fn build_packet_aead(shared_secret: &[u8; 32]) -> ChaCha20Poly1305 {
+    use chacha20poly1305::KeyInit;
+
+    ChaCha20Poly1305::new(&generic_array::GenericArray::from(*shared_secret))
+}
+
 pub fn compute_packet_data_hash(version: Option<u16>, data: &[u8]) -> [u8; 32] {
     match version {
         Some(version) => {
@@ -27,6 +33,25 @@ pub fn compute_packet_data_hash(version: Option<u16>, data: &[u8]) -> [u8; 32] {
     .into()
 }
 
+pub fn encode_version<const LEN: usize>(prefix: &mut [u8; LEN], version: u16) {
+    let end: usize = LEN - 32;
+    let start: usize = end - 4;
+
+    let mut xor = [
+        (version >> 8) as u8,
+        version as u8,
+        (version >> 8) as u8,
+        version as u8,
+    ];
+    for (i, byte) in prefix[..start].iter().enumerate() {
+        xor[i % 4] ^= *byte;
+    }
+    for (i, byte) in prefix[end..].iter().enumerate() {
+        xor[i % 4] ^= *byte;
+    }
+    prefix[start..end].copy_from_slice(&xor);
+}
+
 pub fn decode_version<const LEN: usize>(prefix: &[u8; LEN]) -> Option<u16> {
     let end: usize = LEN - 32;
     let start: usize = end - 4;
@@ -47,6 +72,8 @@ pub fn decode_version<const LEN: usize>(prefix: &[u8; LEN]) -> Option<u16> {
 
 pub type Aes256Ctr = ctr::Ctr64BE<aes::Aes256>;
 
+pub use chacha20poly1305::ChaCha20Poly1305;
+
 #[cfg(test)]
 mod tests {
     use aes::cipher::{StreamCipher, StreamCipherSeek};
@@ -0,0 +1,127 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::sync::broadcast;
+use tokio_util::sync::ReusableBoxFuture;
+
+use super::node_id::NodeIdShort;
+use super::packet_drops::DropReason;
+
+/// ADNL node state change
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum NodeEvent {
+    /// New remote peer was added
+    PeerAdded {
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+    },
+    /// Remote peer was removed
+    PeerRemoved {
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+    },
+    /// Channel with the remote peer became ready
+    ChannelEstablished {
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+    },
+    /// Channel with the remote peer was dropped
+    ChannelReset {
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+    },
+    /// Query to the remote peer got no answer
+    QueryTimedOut {
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+    },
+    /// Received packet was dropped
+    PacketRejected { reason: DropReason },
+    /// Stream was not polled fast enough, so some events were skipped
+    Lagged { skipped: u64 },
+}
+
+pub(crate) type NodeEventsTx = broadcast::Sender<NodeEvent>;
+
+/// Stream of the ADNL node events.
+///
+/// Each stream receives all events which were sent after its creation.
+/// Events are never waited for, so a slow consumer skips the oldest events
+/// and receives [`NodeEvent::Lagged`] instead.
+///
+/// See [`Node::events`]
+///
+/// [`Node::events`]: crate::adnl::Node::events
+pub struct NodeEvents {
+    inner: ReusableBoxFuture<'static, RecvResult>,
+}
+
+type RecvResult = (
+    Result<NodeEvent, broadcast::error::RecvError>,
+    broadcast::Receiver<NodeEvent>,
+);
+
+impl NodeEvents {
+    pub(crate) fn new(rx: broadcast::Receiver<NodeEvent>) -> Self {
+        Self {
+            inner: ReusableBoxFuture::new(recv(rx)),
+        }
+    }
+}
+
+async fn recv(mut rx: broadcast::Receiver<NodeEvent>) -> RecvResult {
+    let result = rx.recv().await;
+    (result, rx)
+}
+
+impl futures_util::Stream for NodeEvents {
+    type Item = NodeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (result, rx) = futures_util::ready!(self.inner.poll(cx));
+        self.inner.set(recv(rx));
+        Poll::Ready(match result {
+            Ok(event) => Some(event),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                Some(NodeEvent::Lagged { skipped })
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lagged_events() {
+        let (tx, rx) = broadcast::channel(2);
+        let mut events = NodeEvents::new(rx);
+
+        let peer_id = NodeIdShort::new([1; 32]);
+        for _ in 0..3 {
+            tx.send(NodeEvent::PeerAdded {
+                local_id: peer_id,
+                peer_id,
+            })
+            .unwrap();
+        }
+
+        assert_eq!(events.next().await, Some(NodeEvent::Lagged { skipped: 1 }));
+        assert!(matches!(
+            events.next().await,
+            Some(NodeEvent::PeerAdded { .. })
+        ));
+        assert!(matches!(
+            events.next().await,
+            Some(NodeEvent::PeerAdded { .. })
+        ));
+
+        drop(tx);
+        assert_eq!(events.next().await, None);
+    }
+}
@@ -1,8 +1,10 @@
+use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::sync::Arc;
 
 use aes::cipher::{StreamCipher, StreamCipherSeek};
 use everscale_crypto::ed25519;
+use parking_lot::Mutex;
 
 use super::encryption::*;
 use super::keystore::Key;
@@ -15,19 +17,98 @@ pub fn compute_handshake_prefix_len(version: Option<u16>) -> usize {
     96 + if version.is_some() { 4 } else { 0 }
 }
 
+/// Temporary key of the outgoing handshake packets with its shared secret
+#[derive(Copy, Clone)]
+pub struct HandshakeKey {
+    public_key: [u8; 32],
+    shared_secret: [u8; 32],
+}
+
+impl HandshakeKey {
+    /// Generates new temporary key for the handshake packets to the peer
+    pub fn generate(peer_id_full: &NodeIdFull) -> Self {
+        let temp_private_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let temp_private_key = ed25519::ExpandedSecretKey::from(&temp_private_key);
+        let temp_public_key = ed25519::PublicKey::from(&temp_private_key);
+
+        Self {
+            public_key: *temp_public_key.as_bytes(),
+            shared_secret: temp_private_key.compute_shared_secret(peer_id_full.public_key()),
+        }
+    }
+}
+
+/// Shared secrets of the local keys with the temporary keys of the incoming handshake packets
+pub struct HandshakeSecrets {
+    secrets: Mutex<SecretsCache>,
+    capacity: usize,
+}
+
+type SecretsCacheKey = (NodeIdShort, [u8; 32]);
+
+#[derive(Default)]
+struct SecretsCache {
+    secrets: FastHashMap<SecretsCacheKey, [u8; 32]>,
+    /// Keys in the insertion order
+    order: VecDeque<SecretsCacheKey>,
+}
+
+impl HandshakeSecrets {
+    /// Creates an empty cache. Secrets are not cached if `capacity` is zero
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            secrets: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Returns the cached shared secret
+    fn get(&self, local_id: &NodeIdShort, other_public_key: &[u8; 32]) -> Option<[u8; 32]> {
+        self.secrets
+            .lock()
+            .secrets
+            .get(&(*local_id, *other_public_key))
+            .copied()
+    }
+
+    /// Caches the shared secret of the packet with a valid checksum
+    fn insert(&self, local_id: &NodeIdShort, other_public_key: &[u8; 32], secret: [u8; 32]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = (*local_id, *other_public_key);
+        let cache = &mut *self.secrets.lock();
+        if cache.secrets.insert(key, secret).is_some() {
+            return;
+        }
+        cache.order.push_back(key);
+
+        // NOTE: only the oldest secrets are evicted, so that a flood of valid
+        // handshakes with fresh temporary keys can't flush the whole cache
+        while cache.order.len() > self.capacity {
+            if let Some(oldest) = cache.order.pop_front() {
+                cache.secrets.remove(&oldest);
+            }
+        }
+    }
+
+    /// Removes all secrets of the local key
+    pub fn remove_local_key(&self, local_id: &NodeIdShort) {
+        let cache = &mut *self.secrets.lock();
+        cache.secrets.retain(|(id, _), _| id != local_id);
+        cache.order.retain(|(id, _)| id != local_id);
+    }
+}
+
 /// Modifies `buffer` in-place to contain the handshake packet
 pub fn build_handshake_packet(
     peer_id: &NodeIdShort,
-    peer_id_full: &NodeIdFull,
+    handshake_key: &HandshakeKey,
     buffer: &mut Vec<u8>,
     version: Option<u16>,
 ) {
-    // Create temp local key
-    let temp_private_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
-    let temp_private_key = ed25519::ExpandedSecretKey::from(&temp_private_key);
-    let temp_public_key = ed25519::PublicKey::from(&temp_private_key);
-
-    let shared_secret = temp_private_key.compute_shared_secret(peer_id_full.public_key());
+    let shared_secret = &handshake_key.shared_secret;
 
     // Prepare packet
     let checksum: [u8; 32] = compute_packet_data_hash(version, buffer.as_slice());
@@ -38,7 +119,7 @@ pub fn build_handshake_packet(
     buffer.copy_within(..buffer_len, header_len);
 
     buffer[..32].copy_from_slice(peer_id.as_slice());
-    buffer[32..64].copy_from_slice(temp_public_key.as_bytes());
+    buffer[32..64].copy_from_slice(&handshake_key.public_key);
 
     match version {
         Some(version) => {
@@ -56,11 +137,11 @@ pub fn build_handshake_packet(
             }
             buffer[64..68].copy_from_slice(&xor);
             buffer[68..100].copy_from_slice(&checksum);
-            build_packet_cipher(&shared_secret, &checksum).apply_keystream(&mut buffer[100..]);
+            build_packet_cipher(shared_secret, &checksum).apply_keystream(&mut buffer[100..]);
         }
         None => {
             buffer[64..96].copy_from_slice(&checksum);
-            build_packet_cipher(&shared_secret, &checksum).apply_keystream(&mut buffer[96..]);
+            build_packet_cipher(shared_secret, &checksum).apply_keystream(&mut buffer[96..]);
         }
     }
 }
@@ -68,6 +149,9 @@ pub fn build_handshake_packet(
 /// Attempts to decode the buffer as an ADNL handshake packet. On a successful nonempty result,
 /// this buffer remains as decrypted packet data.
 ///
+/// The local key is found by the short id prefix of the packet with a single
+/// hash map lookup, so the number of local keys doesn't affect the parsing cost.
+///
 /// Expected packet structure (without version):
 ///  - 0..=31 - short local node id
 ///  - 32..=63 - sender pubkey
@@ -84,21 +168,10 @@ pub fn build_handshake_packet(
 /// **NOTE: even on failure buffer can be modified**
 pub fn parse_handshake_packet(
     keys: &FastHashMap<NodeIdShort, Arc<Key>>,
+    secrets: &HandshakeSecrets,
     buffer: &mut PacketView<'_>,
 ) -> Result<Option<(NodeIdShort, Option<u16>)>, HandshakeError> {
-    const PUBLIC_KEY_RANGE: std::ops::Range<usize> = 32..64;
-
-    // Ordinary data ranges
-    const DATA_START: usize = 96;
-    const CHECKSUM_RANGE: std::ops::Range<usize> = 64..DATA_START;
-    const DATA_RANGE: std::ops::RangeFrom<usize> = DATA_START..;
-
-    // Data ranges for packets with ADNL version
-    const EXT_DATA_START: usize = 100;
-    const EXT_CHECKSUM_RANGE: std::ops::Range<usize> = 68..EXT_DATA_START;
-    const EXT_DATA_RANGE: std::ops::RangeFrom<usize> = EXT_DATA_START..;
-
-    if buffer.len() < DATA_START {
+    if buffer.len() < HANDSHAKE_DATA_START {
         return Err(HandshakeError::BadHandshakePacketLength);
     }
 
@@ -113,14 +186,79 @@ pub fn parse_handshake_packet(
         None => return Ok(None),
     };
 
-    // Compute shared secret
-    let shared_secret =
-        match ed25519::PublicKey::from_bytes(buffer[PUBLIC_KEY_RANGE].try_into().unwrap()) {
-            Some(other_public_key) => local_key
-                .secret_key()
-                .compute_shared_secret(&other_public_key),
-            None => return Err(HandshakeError::InvalidPublicKey),
-        };
+    // Keys with an external signer can't decrypt handshakes
+    let local_secret_key = match local_key.try_secret_key() {
+        Some(secret_key) => secret_key,
+        None => return Err(HandshakeError::ExternalSigner),
+    };
+
+    let local_id = *local_id;
+    let other_public_key: [u8; 32] = buffer[PUBLIC_KEY_RANGE].try_into().unwrap();
+    let (shared_secret, cached) = match secrets.get(&local_id, &other_public_key) {
+        Some(secret) => (secret, true),
+        None => (
+            compute_handshake_secret(local_secret_key, &other_public_key)?,
+            false,
+        ),
+    };
+
+    let version = decrypt_handshake_packet_data(&shared_secret, buffer)?;
+
+    // NOTE: secret is cached only after the checksum is validated, so that packets
+    // with random data don't occupy the cache. Anyone can still build valid
+    // handshakes with fresh temporary keys, so the cache size is bounded
+    // by evicting the oldest secrets (see `HandshakeSecrets::insert`)
+    if !cached {
+        secrets.insert(&local_id, &other_public_key, shared_secret);
+    }
+
+    Ok(Some((local_id, version)))
+}
+
+/// Decrypts the handshake packet with the local secret key. Returns the ADNL version.
+///
+/// See [`parse_handshake_packet`]
+///
+/// **NOTE: even on failure buffer can be modified**
+pub fn decrypt_handshake_packet(
+    local_secret_key: &ed25519::ExpandedSecretKey,
+    buffer: &mut PacketView<'_>,
+) -> Result<Option<u16>, HandshakeError> {
+    if buffer.len() < HANDSHAKE_DATA_START {
+        return Err(HandshakeError::BadHandshakePacketLength);
+    }
+
+    let shared_secret = compute_handshake_secret(
+        local_secret_key,
+        buffer[PUBLIC_KEY_RANGE].try_into().unwrap(),
+    )?;
+    decrypt_handshake_packet_data(&shared_secret, buffer)
+}
+
+fn compute_handshake_secret(
+    local_secret_key: &ed25519::ExpandedSecretKey,
+    other_public_key: &[u8; 32],
+) -> Result<[u8; 32], HandshakeError> {
+    match ed25519::PublicKey::from_bytes(*other_public_key) {
+        Some(other_public_key) => Ok(local_secret_key.compute_shared_secret(&other_public_key)),
+        None => Err(HandshakeError::InvalidPublicKey),
+    }
+}
+
+/// Decrypts the handshake packet with the computed shared secret
+fn decrypt_handshake_packet_data(
+    shared_secret: &[u8; 32],
+    buffer: &mut PacketView<'_>,
+) -> Result<Option<u16>, HandshakeError> {
+    // Ordinary data ranges
+    const DATA_START: usize = HANDSHAKE_DATA_START;
+    const CHECKSUM_RANGE: std::ops::Range<usize> = 64..DATA_START;
+    const DATA_RANGE: std::ops::RangeFrom<usize> = DATA_START..;
+
+    // Data ranges for packets with ADNL version
+    const EXT_DATA_START: usize = 100;
+    const EXT_CHECKSUM_RANGE: std::ops::Range<usize> = 68..EXT_DATA_START;
+    const EXT_DATA_RANGE: std::ops::RangeFrom<usize> = EXT_DATA_START..;
 
     if buffer.len() > EXT_DATA_START {
         if let Some(version) =
@@ -128,7 +266,7 @@ pub fn parse_handshake_packet(
         {
             // Build cipher
             let mut cipher = build_packet_cipher(
-                &shared_secret,
+                shared_secret,
                 &buffer[EXT_CHECKSUM_RANGE].try_into().unwrap(),
             );
 
@@ -141,7 +279,7 @@ pub fn parse_handshake_packet(
             {
                 // Leave only data in the buffer and return version
                 buffer.remove_prefix(EXT_DATA_START);
-                return Ok(Some((*local_id, Some(version))));
+                return Ok(Some(version));
             }
 
             // Otherwise restore data
@@ -151,7 +289,7 @@ pub fn parse_handshake_packet(
     }
 
     // Decode data
-    build_packet_cipher(&shared_secret, &buffer[CHECKSUM_RANGE].try_into().unwrap())
+    build_packet_cipher(shared_secret, &buffer[CHECKSUM_RANGE].try_into().unwrap())
         .apply_keystream(&mut buffer[DATA_RANGE]);
 
     // Check checksum
@@ -162,9 +300,15 @@ pub fn parse_handshake_packet(
     // Leave only data in the buffer
     buffer.remove_prefix(DATA_START);
 
-    Ok(Some((*local_id, None)))
+    Ok(None)
 }
 
+/// Header length of the handshake packet without version
+const HANDSHAKE_DATA_START: usize = 96;
+
+/// Sender temporary public key
+const PUBLIC_KEY_RANGE: std::ops::Range<usize> = 32..64;
+
 #[derive(thiserror::Error, Debug)]
 pub enum HandshakeError {
     #[error("Bad handshake packet length")]
@@ -173,4 +317,72 @@ pub enum HandshakeError {
     BadHandshakePacketChecksum,
     #[error("Invalid public key")]
     InvalidPublicKey,
+    #[error("Handshake to the key with an external signer")]
+    ExternalSigner,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_handshake_secrets() {
+        let local_key = Arc::new(Key::from_bytes(rand::random()));
+        let local_id = *local_key.id();
+        let local_id_full = *local_key.full_id();
+
+        let mut keys = FastHashMap::default();
+        keys.insert(local_id, local_key);
+
+        let secrets = HandshakeSecrets::new(2);
+        let handshake_key = HandshakeKey::generate(&local_id_full);
+
+        for _ in 0..2 {
+            let mut packet = vec![1, 2, 3, 4];
+            build_handshake_packet(&local_id, &handshake_key, &mut packet, None);
+
+            let mut buffer = PacketView::from(packet.as_mut_slice());
+            let (id, _) = parse_handshake_packet(&keys, &secrets, &mut buffer)
+                .unwrap()
+                .unwrap();
+            assert_eq!(id, local_id);
+            assert_eq!(buffer.as_slice(), [1, 2, 3, 4]);
+        }
+        assert_eq!(
+            secrets.get(&local_id, &handshake_key.public_key),
+            Some(handshake_key.shared_secret)
+        );
+        assert_eq!(secrets.secrets.lock().secrets.len(), 1);
+
+        // Secrets of the packets with an invalid checksum are not cached
+        let other_key = HandshakeKey::generate(&local_id_full);
+        let mut packet = vec![1, 2, 3, 4];
+        build_handshake_packet(&local_id, &other_key, &mut packet, None);
+        *packet.last_mut().unwrap() ^= 1;
+
+        let mut buffer = PacketView::from(packet.as_mut_slice());
+        assert!(parse_handshake_packet(&keys, &secrets, &mut buffer).is_err());
+        assert!(secrets.get(&local_id, &other_key.public_key).is_none());
+        assert_eq!(secrets.secrets.lock().secrets.len(), 1);
+
+        // Only the oldest secret is evicted when the cache is full
+        let third_key = HandshakeKey::generate(&local_id_full);
+        for key in [&other_key, &third_key] {
+            let mut packet = vec![1, 2, 3, 4];
+            build_handshake_packet(&local_id, key, &mut packet, None);
+
+            let mut buffer = PacketView::from(packet.as_mut_slice());
+            parse_handshake_packet(&keys, &secrets, &mut buffer)
+                .unwrap()
+                .unwrap();
+        }
+        assert!(secrets.get(&local_id, &handshake_key.public_key).is_none());
+        assert!(secrets.get(&local_id, &other_key.public_key).is_some());
+        assert!(secrets.get(&local_id, &third_key.public_key).is_some());
+        assert_eq!(secrets.secrets.lock().secrets.len(), 2);
+
+        secrets.remove_local_key(&local_id);
+        let cache = secrets.secrets.lock();
+        assert!(cache.secrets.is_empty() && cache.order.is_empty());
+    }
 }
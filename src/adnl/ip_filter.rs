@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use super::node_id::NodeIdShort;
+use super::peer::{NewPeerContext, PeerFilter};
+
+/// Ready-made peer filter based on IPv4 subnets
+///
+/// Rules can be replaced at runtime with [`IpFilter::set_config`]
+#[derive(Default)]
+pub struct IpFilter {
+    config: RwLock<IpFilterConfig>,
+}
+
+impl IpFilter {
+    /// Creates new filter with the specified rules
+    pub fn new(config: IpFilterConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+        }
+    }
+
+    /// Returns a copy of the current rules
+    pub fn config(&self) -> IpFilterConfig {
+        self.config.read().clone()
+    }
+
+    /// Replaces all rules
+    pub fn set_config(&self, config: IpFilterConfig) {
+        *self.config.write() = config;
+    }
+
+    /// Updates rules in-place
+    pub fn update_config<F>(&self, f: F)
+    where
+        F: FnOnce(&mut IpFilterConfig),
+    {
+        f(&mut self.config.write())
+    }
+}
+
+impl PeerFilter for IpFilter {
+    fn check(&self, ctx: NewPeerContext, addr: SocketAddrV4, _: &NodeIdShort) -> bool {
+        self.config.read().rules_for(ctx).check(addr.ip())
+    }
+}
+
+/// IP filter configuration
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpFilterConfig {
+    /// Rules used for all contexts without explicit rules
+    #[serde(flatten)]
+    pub rules: IpFilterRules,
+
+    /// Rules which override the default rules for the specified context
+    pub contexts: HashMap<NewPeerContext, IpFilterRules>,
+}
+
+impl IpFilterConfig {
+    /// Returns rules which are used for the specified context
+    pub fn rules_for(&self, ctx: NewPeerContext) -> &IpFilterRules {
+        self.contexts.get(&ctx).unwrap_or(&self.rules)
+    }
+}
+
+/// Set of allow/deny rules
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IpFilterRules {
+    /// If not empty, only addresses from these subnets are accepted
+    ///
+    /// Default: empty
+    pub allow: Vec<Ipv4Subnet>,
+
+    /// Addresses from these subnets are always rejected
+    ///
+    /// Default: empty
+    pub deny: Vec<Ipv4Subnet>,
+
+    /// Whether to reject private, loopback, link-local and unspecified addresses
+    ///
+    /// Default: `false`
+    pub deny_private_ranges: bool,
+}
+
+impl IpFilterRules {
+    /// Checks whether the address is accepted by these rules
+    pub fn check(&self, ip: &Ipv4Addr) -> bool {
+        if self.deny_private_ranges && is_private_range(ip) {
+            return false;
+        }
+
+        if self.deny.iter().any(|subnet| subnet.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|subnet| subnet.contains(ip))
+    }
+}
+
+/// IPv4 subnet in CIDR notation (e.g. `10.0.0.0/8`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Ipv4Subnet {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Subnet {
+    /// Creates new subnet. Host bits of the address are cleared
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self, Ipv4SubnetError> {
+        if prefix_len > 32 {
+            return Err(Ipv4SubnetError::InvalidPrefixLen);
+        }
+        Ok(Self {
+            addr: Ipv4Addr::from(u32::from(addr) & make_mask(prefix_len)),
+            prefix_len,
+        })
+    }
+
+    /// Network address
+    #[inline(always)]
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    /// Number of the network bits
+    #[inline(always)]
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Checks whether the address belongs to this subnet
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        u32::from(*ip) & make_mask(self.prefix_len) == u32::from(self.addr)
+    }
+}
+
+impl From<Ipv4Addr> for Ipv4Subnet {
+    fn from(addr: Ipv4Addr) -> Self {
+        Self {
+            addr,
+            prefix_len: 32,
+        }
+    }
+}
+
+impl std::fmt::Display for Ipv4Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Ipv4Subnet {
+    type Err = Ipv4SubnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Self::new(
+                addr.parse().map_err(|_| Ipv4SubnetError::InvalidAddress)?,
+                prefix_len
+                    .parse()
+                    .map_err(|_| Ipv4SubnetError::InvalidPrefixLen)?,
+            ),
+            None => Ok(Self::from(
+                Ipv4Addr::from_str(s).map_err(|_| Ipv4SubnetError::InvalidAddress)?,
+            )),
+        }
+    }
+}
+
+impl Serialize for Ipv4Subnet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Subnet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        Self::from_str(&s).map_err(Error::custom)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Ipv4SubnetError {
+    #[error("Invalid subnet address")]
+    InvalidAddress,
+    #[error("Invalid subnet prefix length")]
+    InvalidPrefixLen,
+}
+
+fn is_private_range(ip: &Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+}
+
+#[inline(always)]
+fn make_mask(prefix_len: u8) -> u32 {
+    u32::MAX
+        .checked_shl(32 - prefix_len as u32)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet_parsing() {
+        let subnet: Ipv4Subnet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(subnet.addr(), Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(subnet.prefix_len(), 8);
+        assert_eq!(subnet.to_string(), "10.0.0.0/8");
+
+        let single: Ipv4Subnet = "1.2.3.4".parse().unwrap();
+        assert_eq!(single.prefix_len(), 32);
+        assert!(single.contains(&Ipv4Addr::new(1, 2, 3, 4)));
+        assert!(!single.contains(&Ipv4Addr::new(1, 2, 3, 5)));
+
+        let any: Ipv4Subnet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(&Ipv4Addr::new(255, 255, 255, 255)));
+
+        assert!("1.2.3.4/33".parse::<Ipv4Subnet>().is_err());
+        assert!("1.2.3/24".parse::<Ipv4Subnet>().is_err());
+    }
+
+    #[test]
+    fn filter_rules() {
+        let peer_id = NodeIdShort::random();
+        let addr = |ip: [u8; 4]| SocketAddrV4::new(Ipv4Addr::from(ip), 30303);
+
+        let filter = IpFilter::new(IpFilterConfig {
+            rules: IpFilterRules {
+                allow: Vec::new(),
+                deny: vec!["1.2.3.0/24".parse().unwrap()],
+                deny_private_ranges: true,
+            },
+            contexts: HashMap::from([(
+                NewPeerContext::Dht,
+                IpFilterRules {
+                    allow: vec!["5.6.0.0/16".parse().unwrap()],
+                    ..Default::default()
+                },
+            )]),
+        });
+
+        let ctx = NewPeerContext::AdnlPacket;
+        assert!(filter.check(ctx, addr([8, 8, 8, 8]), &peer_id));
+        assert!(!filter.check(ctx, addr([1, 2, 3, 4]), &peer_id));
+        assert!(!filter.check(ctx, addr([192, 168, 0, 1]), &peer_id));
+        assert!(!filter.check(ctx, addr([127, 0, 0, 1]), &peer_id));
+
+        let ctx = NewPeerContext::Dht;
+        assert!(filter.check(ctx, addr([5, 6, 7, 8]), &peer_id));
+        assert!(!filter.check(ctx, addr([8, 8, 8, 8]), &peer_id));
+
+        filter.update_config(|config| config.contexts.clear());
+        assert!(filter.check(ctx, addr([8, 8, 8, 8]), &peer_id));
+    }
+}
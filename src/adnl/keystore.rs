@@ -1,9 +1,13 @@
-use std::collections::hash_map;
+use std::collections::{hash_map, BTreeMap};
+use std::ops::RangeBounds;
 use std::sync::Arc;
 
 use anyhow::Result;
 use everscale_crypto::ed25519;
+use serde::{Deserialize, Serialize};
 
+use super::key_formats::{parse_pem_secret_key, KeyFormatError};
+use super::mnemonic::{derive_secret_key, MnemonicDerivation, MnemonicError};
 use super::node_id::{ComputeNodeIds, NodeIdFull, NodeIdShort};
 use crate::util::FastHashMap;
 
@@ -11,7 +15,7 @@ use crate::util::FastHashMap;
 #[derive(Default)]
 pub struct Keystore {
     keys: FastHashMap<NodeIdShort, Arc<Key>>,
-    tags: FastHashMap<usize, NodeIdShort>,
+    tags: BTreeMap<usize, NodeIdShort>,
 }
 
 impl Keystore {
@@ -19,6 +23,11 @@ impl Keystore {
         KeystoreBuilder::default()
     }
 
+    /// Creates a new keystore from the config
+    pub fn from_config(config: &KeystoreConfig) -> Result<Self, KeystoreError> {
+        Ok(Self::builder().with_config(config)?.build())
+    }
+
     /// Searches key by its short id
     pub fn key_by_id(&self, id: &NodeIdShort) -> Result<&Arc<Key>, KeystoreError> {
         if let Some(key) = self.keys.get(id) {
@@ -43,32 +52,156 @@ impl Keystore {
         &self.keys
     }
 
+    /// Returns an iterator over `(tag, short id)` pairs, ordered by tag
+    pub fn tags(&self) -> impl Iterator<Item = (usize, &NodeIdShort)> + '_ {
+        self.tags.iter().map(|(tag, id)| (*tag, id))
+    }
+
+    /// Returns all tags of the key, ordered by tag
+    pub fn tags_by_id<'a>(&'a self, id: &'a NodeIdShort) -> impl Iterator<Item = usize> + 'a {
+        self.tags
+            .iter()
+            .filter(move |(_, key_id)| *key_id == id)
+            .map(|(tag, _)| *tag)
+    }
+
+    /// Returns an iterator over keys with tags from the range, ordered by tag
+    pub fn keys_by_tag_range<R>(&self, range: R) -> impl Iterator<Item = (usize, &Arc<Key>)> + '_
+    where
+        R: RangeBounds<usize>,
+    {
+        self.tags
+            .range(range)
+            .filter_map(|(tag, id)| Some((*tag, self.keys.get(id)?)))
+    }
+
+    /// Binds an additional tag to the existing key
+    ///
+    /// NOTE: existing tags will cause this method to fail
+    pub fn add_tag(&mut self, id: &NodeIdShort, tag: usize) -> Result<(), KeystoreError> {
+        if !self.keys.contains_key(id) {
+            return Err(KeystoreError::KeyIdNotFound(*id));
+        }
+        match self.tags.get(&tag) {
+            Some(key_id) if key_id == id => Ok(()),
+            Some(_) => Err(KeystoreError::DuplicatedKeyTag(tag)),
+            None => {
+                self.tags.insert(tag, *id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes the tag without removing the key. Returns the short id of the tagged key
+    pub fn remove_tag(&mut self, tag: usize) -> Option<NodeIdShort> {
+        self.tags.remove(&tag)
+    }
+
     /// Adds a new key with the specified tag
     ///
-    /// NOTE: duplicate keys or tags will cause this method to fail
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
     pub fn add_key(&mut self, key: [u8; 32], tag: usize) -> Result<NodeIdShort, KeystoreError> {
-        let secret_key = ed25519::SecretKey::from_bytes(key);
-        let (_, short_id) = secret_key.compute_node_ids();
+        self.insert_key(ed25519::SecretKey::from_bytes(key).into(), tag)
+    }
+
+    /// Generates a new random key with the specified tag.
+    /// Returns its short id and the secret key bytes (to persist it somewhere)
+    ///
+    /// NOTE: existing tags will cause this method to fail
+    pub fn generate_key(&mut self, tag: usize) -> Result<(NodeIdShort, [u8; 32]), KeystoreError> {
+        let secret_key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let short_id = self.add_key(secret_key.to_bytes(), tag)?;
+        Ok((short_id, secret_key.to_bytes()))
+    }
 
-        match self.tags.entry(tag) {
+    /// Adds a new key from the PEM-encoded PKCS#8 (`PRIVATE KEY`)
+    /// or OpenSSH (`OPENSSH PRIVATE KEY`) ed25519 private key
+    ///
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
+    pub fn add_pem_key(&mut self, pem: &str, tag: usize) -> Result<NodeIdShort, KeystoreError> {
+        self.add_key(parse_pem_secret_key(pem)?, tag)
+    }
+
+    /// Adds a new key derived from the seed phrase
+    ///
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
+    pub fn add_mnemonic_key(
+        &mut self,
+        phrase: &str,
+        derivation: MnemonicDerivation<'_>,
+        tag: usize,
+    ) -> Result<NodeIdShort, KeystoreError> {
+        self.add_key(derive_secret_key(phrase, derivation)?, tag)
+    }
+
+    /// Replaces the key under the tag with a new one. The replaced key is kept
+    /// in the keystore without tag until it is removed (see [`Keystore::remove_key`]).
+    ///
+    /// Returns the short id of the new key and the short id of the replaced key.
+    pub fn rotate_key(
+        &mut self,
+        key: [u8; 32],
+        tag: usize,
+    ) -> Result<(NodeIdShort, Option<NodeIdShort>), KeystoreError> {
+        let key = Key::from_bytes(key);
+        let short_id = *key.id();
+
+        let prev_id = self.tags.get(&tag).copied();
+        if prev_id == Some(short_id) {
+            return Ok((short_id, None));
+        }
+
+        match self.keys.entry(short_id) {
             hash_map::Entry::Vacant(entry) => {
-                entry.insert(short_id);
-                match self.keys.entry(short_id) {
-                    hash_map::Entry::Vacant(entry) => {
-                        entry.insert(Arc::new(secret_key.into()));
-                        Ok(short_id)
-                    }
-                    hash_map::Entry::Occupied(_) => Err(KeystoreError::DuplicatedKey(tag)),
-                }
-            }
-            hash_map::Entry::Occupied(entry) => {
-                if entry.get() == &short_id {
-                    Ok(short_id)
-                } else {
-                    Err(KeystoreError::DuplicatedKeyTag(tag))
-                }
+                entry.insert(Arc::new(key));
             }
+            hash_map::Entry::Occupied(_) => return Err(KeystoreError::DuplicatedKey(tag)),
         }
+        self.tags.insert(tag, short_id);
+
+        Ok((short_id, prev_id))
+    }
+
+    /// Removes the key and its tag (if any). Returns the removed key
+    pub fn remove_key(&mut self, id: &NodeIdShort) -> Option<Arc<Key>> {
+        let key = self.keys.remove(id)?;
+        self.tags.retain(|_, key_id| key_id != id);
+        Some(key)
+    }
+
+    /// Returns whether the key has a tag
+    pub fn is_tagged(&self, id: &NodeIdShort) -> bool {
+        self.tags.values().any(|key_id| key_id == id)
+    }
+
+    /// Adds a new key with an external signer and the specified tag
+    ///
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
+    pub fn add_external_key(
+        &mut self,
+        signer: Arc<dyn Signer>,
+        tag: usize,
+    ) -> Result<NodeIdShort, KeystoreError> {
+        self.insert_key(Key::from_signer(signer), tag)
+    }
+
+    fn insert_key(&mut self, key: Key, tag: usize) -> Result<NodeIdShort, KeystoreError> {
+        let short_id = *key.id();
+
+        match self.tags.get(&tag) {
+            Some(id) if id == &short_id => return Ok(short_id),
+            Some(_) => return Err(KeystoreError::DuplicatedKeyTag(tag)),
+            None => {}
+        }
+
+        // NOTE: existing key with a new tag is an alias
+        self.keys.entry(short_id).or_insert_with(|| Arc::new(key));
+        self.tags.insert(tag, short_id);
+        Ok(short_id)
     }
 }
 
@@ -84,12 +217,49 @@ impl KeystoreBuilder {
 
     /// Adds a new key with the specified tag
     ///
-    /// NOTE: duplicate keys or tags will cause this method to fail
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
     pub fn with_tagged_key(mut self, key: [u8; 32], tag: usize) -> Result<Self, KeystoreError> {
         self.keystore.add_key(key, tag)?;
         Ok(self)
     }
 
+    /// Adds a new key from the PEM-encoded PKCS#8 or OpenSSH ed25519 private key
+    ///
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
+    pub fn with_pem_key(mut self, pem: &str, tag: usize) -> Result<Self, KeystoreError> {
+        self.keystore.add_pem_key(pem, tag)?;
+        Ok(self)
+    }
+
+    /// Adds a new key derived from the seed phrase
+    ///
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
+    pub fn with_mnemonic_key(
+        mut self,
+        phrase: &str,
+        derivation: MnemonicDerivation<'_>,
+        tag: usize,
+    ) -> Result<Self, KeystoreError> {
+        self.keystore.add_mnemonic_key(phrase, derivation, tag)?;
+        Ok(self)
+    }
+
+    /// Adds a new key with an external signer and the specified tag
+    ///
+    /// NOTE: existing tags will cause this method to fail.
+    /// Adding an existing key with a new tag creates an alias
+    pub fn with_external_key(
+        mut self,
+        signer: Arc<dyn Signer>,
+        tag: usize,
+    ) -> Result<Self, KeystoreError> {
+        self.keystore.add_external_key(signer, tag)?;
+        Ok(self)
+    }
+
     /// Creates a new keystore from tagged secret keys
     pub fn with_tagged_keys<I>(mut self, keys: I) -> Result<Self, KeystoreError>
     where
@@ -100,13 +270,94 @@ impl KeystoreBuilder {
         }
         Ok(self)
     }
+
+    /// Adds all keys from the config
+    pub fn with_config(self, config: &KeystoreConfig) -> Result<Self, KeystoreError> {
+        self.with_tagged_keys(config.keys.iter().map(|key| (key.secret_key, key.tag)))
+    }
+}
+
+/// Serializable list of tagged keys
+///
+/// ```json
+/// [
+///   { "tag": 0, "secret_key": "<hex or base64>" },
+///   { "tag": 1, "secret_key": "<hex or base64>" }
+/// ]
+/// ```
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct KeystoreConfig {
+    pub keys: Vec<TaggedKeyConfig>,
+}
+
+/// Secret key with tag
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TaggedKeyConfig {
+    pub tag: usize,
+    /// Secret key bytes. Both hex and base64 strings are accepted, hex is used for serialization
+    #[serde(with = "serde_secret_key")]
+    pub secret_key: [u8; 32],
+}
+
+mod serde_secret_key {
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(data: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&hex::encode(data))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<[u8; 32], D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let data = String::deserialize(deserializer)?;
+        let data = data.trim();
+
+        let bytes = if data.len() == 64 {
+            hex::decode(data).map_err(Error::custom)?
+        } else {
+            base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .map_err(Error::custom)?
+        };
+
+        bytes
+            .try_into()
+            .map_err(|_| Error::custom("invalid secret key length"))
+    }
+}
+
+/// External signer for the ADNL key (e.g. remote KMS or HSM)
+///
+/// NOTE: ADNL handshake packets can only be decrypted with the raw secret key,
+/// so the node will not accept handshakes addressed to the key with an external signer.
+/// Outgoing handshake packets from such key are signed in the background and are dropped
+/// if the signer fails.
+#[async_trait::async_trait]
+pub trait Signer: Send + Sync {
+    /// Public key of the signer
+    fn public_key(&self) -> &ed25519::PublicKey;
+
+    /// Signs raw data
+    async fn sign_raw(&self, data: &[u8]) -> Result<[u8; 64]>;
 }
 
 /// ADNL key with precomputed node IDs
 pub struct Key {
     short_id: NodeIdShort,
     full_id: NodeIdFull,
-    secret_key: ed25519::ExpandedSecretKey,
+    secret: KeySecret,
+}
+
+enum KeySecret {
+    Local(ed25519::ExpandedSecretKey),
+    External(Arc<dyn Signer>),
 }
 
 impl Key {
@@ -115,6 +366,16 @@ impl Key {
         ed25519::SecretKey::from_bytes(secret_key).into()
     }
 
+    /// Constructs new key which uses an external signer
+    pub fn from_signer(signer: Arc<dyn Signer>) -> Self {
+        let full_id = NodeIdFull::new(*signer.public_key());
+        Self {
+            short_id: full_id.compute_short_id(),
+            full_id,
+            secret: KeySecret::External(signer),
+        }
+    }
+
     /// Returns short key id
     #[inline(always)]
     pub fn id(&self) -> &NodeIdShort {
@@ -128,15 +389,66 @@ impl Key {
     }
 
     /// Returns inner secret key (as expanded)
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key uses an external signer, see [`Key::try_secret_key`]
     #[inline(always)]
     pub fn secret_key(&self) -> &ed25519::ExpandedSecretKey {
-        &self.secret_key
+        match self.try_secret_key() {
+            Some(secret_key) => secret_key,
+            None => panic!("key {} uses an external signer", self.short_id),
+        }
+    }
+
+    /// Returns inner secret key (as expanded). `None` for the key with an external signer
+    #[inline(always)]
+    pub fn try_secret_key(&self) -> Option<&ed25519::ExpandedSecretKey> {
+        match &self.secret {
+            KeySecret::Local(secret_key) => Some(secret_key),
+            KeySecret::External(_) => None,
+        }
+    }
+
+    /// Whether the key uses an external signer
+    #[inline(always)]
+    pub fn is_external(&self) -> bool {
+        matches!(&self.secret, KeySecret::External(_))
     }
 
     /// Signs serializable boxed data
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key uses an external signer, see [`Key::try_sign`] and [`Key::sign_async`]
     #[inline(always)]
     pub fn sign<T: tl_proto::TlWrite<Repr = tl_proto::Boxed>>(&self, data: T) -> [u8; 64] {
-        self.secret_key.sign(data, self.full_id.public_key())
+        self.secret_key().sign(data, self.full_id.public_key())
+    }
+
+    /// Signs serializable boxed data.
+    ///
+    /// NOTE: fails for the key with an external signer, use [`Key::sign_async`] instead
+    #[inline(always)]
+    pub fn try_sign<T: tl_proto::TlWrite<Repr = tl_proto::Boxed>>(
+        &self,
+        data: T,
+    ) -> Result<[u8; 64], KeystoreError> {
+        match &self.secret {
+            KeySecret::Local(secret_key) => Ok(secret_key.sign(data, self.full_id.public_key())),
+            KeySecret::External(_) => Err(KeystoreError::ExternalSigner),
+        }
+    }
+
+    /// Signs serializable boxed data with either a local secret key or an external signer
+    pub async fn sign_async<T: tl_proto::TlWrite<Repr = tl_proto::Boxed>>(
+        &self,
+        data: T,
+    ) -> Result<[u8; 64]> {
+        match &self.secret {
+            KeySecret::Local(secret_key) => Ok(secret_key.sign(data, self.full_id.public_key())),
+            KeySecret::External(signer) => signer.sign_raw(&tl_proto::serialize(data)).await,
+        }
     }
 }
 
@@ -146,7 +458,7 @@ impl From<ed25519::SecretKey> for Key {
         Self {
             short_id,
             full_id,
-            secret_key: ed25519::ExpandedSecretKey::from(&secret_key),
+            secret: KeySecret::Local(ed25519::ExpandedSecretKey::from(&secret_key)),
         }
     }
 }
@@ -163,4 +475,126 @@ pub enum KeystoreError {
     KeyTagNotFound(usize),
     #[error("Unexpected key")]
     UnexpectedKey,
+    #[error("Key uses an external signer")]
+    ExternalSigner,
+    #[error("Invalid key format")]
+    InvalidKeyFormat(#[from] KeyFormatError),
+    #[error("Invalid seed phrase")]
+    InvalidMnemonic(#[from] MnemonicError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto;
+
+    #[test]
+    fn keystore_from_config() {
+        let config: KeystoreConfig = serde_json::from_str(
+            r#"[
+                { "tag": 0, "secret_key": "0101010101010101010101010101010101010101010101010101010101010101" },
+                { "tag": 1, "secret_key": "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI=" }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(config.keys[0].secret_key, [1; 32]);
+        assert_eq!(config.keys[1].secret_key, [2; 32]);
+
+        let keystore = Keystore::from_config(&config).unwrap();
+        assert_eq!(
+            keystore.key_by_tag(1).unwrap().id(),
+            Key::from_bytes([2; 32]).id()
+        );
+
+        let serialized = serde_json::to_string(&config).unwrap();
+        let config: KeystoreConfig = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(config.keys[1].secret_key, [2; 32]);
+    }
+
+    #[test]
+    fn tag_aliases() {
+        let mut keystore = Keystore::builder()
+            .with_tagged_keys([([1; 32], 0), ([2; 32], 10), ([1; 32], 5)])
+            .unwrap()
+            .build();
+        assert_eq!(keystore.keys().len(), 2);
+
+        let id = *keystore.key_by_tag(0).unwrap().id();
+        assert_eq!(keystore.key_by_tag(5).unwrap().id(), &id);
+        assert_eq!(keystore.tags_by_id(&id).collect::<Vec<_>>(), [0, 5]);
+        assert!(keystore.add_key([2; 32], 5).is_err());
+
+        keystore.add_tag(&id, 7).unwrap();
+        let tags = keystore
+            .keys_by_tag_range(1..=10)
+            .map(|(tag, _)| tag)
+            .collect::<Vec<_>>();
+        assert_eq!(tags, [5, 7, 10]);
+
+        assert_eq!(keystore.remove_tag(0), Some(id));
+        assert!(keystore.is_tagged(&id));
+        assert_eq!(keystore.tags().count(), 3);
+    }
+
+    #[test]
+    fn generate_key() {
+        let mut keystore = Keystore::default();
+        let (short_id, secret_key) = keystore.generate_key(0).unwrap();
+        assert_eq!(Key::from_bytes(secret_key).id(), &short_id);
+        assert_eq!(keystore.key_by_tag(0).unwrap().id(), &short_id);
+        assert!(keystore.generate_key(0).is_err());
+    }
+
+    #[test]
+    fn rotate_key() {
+        let mut keystore = Keystore::builder()
+            .with_tagged_key([1; 32], 0)
+            .unwrap()
+            .build();
+        let old_id = *keystore.key_by_tag(0).unwrap().id();
+
+        let (new_id, prev_id) = keystore.rotate_key([2; 32], 0).unwrap();
+        assert_eq!(prev_id, Some(old_id));
+        assert_eq!(keystore.key_by_tag(0).unwrap().id(), &new_id);
+
+        // Old key is still available by id but not by tag
+        assert!(keystore.key_by_id(&old_id).is_ok());
+        assert!(!keystore.is_tagged(&old_id));
+
+        assert!(keystore.remove_key(&old_id).is_some());
+        assert!(keystore.key_by_id(&old_id).is_err());
+        assert_eq!(keystore.key_by_tag(0).unwrap().id(), &new_id);
+    }
+
+    #[tokio::test]
+    async fn external_signer() {
+        struct LocalSigner(ed25519::KeyPair);
+
+        #[async_trait::async_trait]
+        impl Signer for LocalSigner {
+            fn public_key(&self) -> &ed25519::PublicKey {
+                &self.0.public_key
+            }
+
+            async fn sign_raw(&self, data: &[u8]) -> Result<[u8; 64]> {
+                Ok(self.0.sign_raw(data))
+            }
+        }
+
+        let local = Key::from_bytes([3; 32]);
+        let external = Key::from_signer(Arc::new(LocalSigner(ed25519::KeyPair::from(
+            &ed25519::SecretKey::from_bytes([3; 32]),
+        ))));
+        assert_eq!(local.id(), external.id());
+        assert!(external.is_external());
+        assert!(external.try_secret_key().is_none());
+
+        let data = proto::rpc::AdnlPing { value: 123 };
+        assert!(matches!(
+            external.try_sign(data),
+            Err(KeystoreError::ExternalSigner)
+        ));
+        assert_eq!(external.sign_async(data).await.unwrap(), local.sign(data));
+        assert_eq!(local.try_sign(data).unwrap(), local.sign(data));
+    }
 }
@@ -0,0 +1,164 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+
+/// Secret key derivation scheme for the seed phrase
+#[derive(Debug, Copy, Clone)]
+pub enum MnemonicDerivation<'a> {
+    /// TON wallet mnemonic (24 words, as in `tonweb-mnemonic`)
+    Ton {
+        /// Optional mnemonic password (empty by default)
+        password: &'a str,
+    },
+    /// BIP39 mnemonic with SLIP-0010 ed25519 derivation
+    Bip39 {
+        /// Optional BIP39 passphrase (empty by default)
+        passphrase: &'a str,
+        /// Derivation path with only hardened indices, e.g. `m/44'/607'/0'`
+        path: &'a str,
+    },
+}
+
+impl MnemonicDerivation<'static> {
+    /// TON wallet mnemonic without password
+    pub const TON: Self = Self::Ton { password: "" };
+
+    /// BIP39 mnemonic without passphrase with the default TON path (`m/44'/607'/0'`)
+    pub const BIP39: Self = Self::Bip39 {
+        passphrase: "",
+        path: "m/44'/607'/0'",
+    };
+}
+
+/// Derives ed25519 secret key from the seed phrase.
+///
+/// NOTE: words are not checked against the wordlist, only ASCII phrases are supported
+pub fn derive_secret_key(
+    phrase: &str,
+    derivation: MnemonicDerivation<'_>,
+) -> Result<[u8; 32], MnemonicError> {
+    if !phrase.is_ascii() {
+        return Err(MnemonicError::NonAsciiPhrase);
+    }
+    let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+    if phrase.is_empty() {
+        return Err(MnemonicError::EmptyPhrase);
+    }
+
+    match derivation {
+        MnemonicDerivation::Ton { password } => {
+            let entropy = hmac_sha512(phrase.as_bytes(), password.as_bytes());
+            let seed = pbkdf2_sha512(&entropy, TON_SEED_SALT, TON_SEED_ITERATIONS);
+            Ok(seed[..32].try_into().unwrap())
+        }
+        MnemonicDerivation::Bip39 { passphrase, path } => {
+            if !passphrase.is_ascii() {
+                return Err(MnemonicError::NonAsciiPhrase);
+            }
+            let salt = format!("mnemonic{passphrase}");
+            let seed = pbkdf2_sha512(phrase.as_bytes(), salt.as_bytes(), BIP39_SEED_ITERATIONS);
+            slip10_derive(&seed, path)
+        }
+    }
+}
+
+/// SLIP-0010 ed25519 derivation (only hardened indices are supported)
+fn slip10_derive(seed: &[u8], path: &str) -> Result<[u8; 32], MnemonicError> {
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(MnemonicError::InvalidPath);
+    }
+
+    let mut node = hmac_sha512(b"ed25519 seed", seed);
+    for part in parts {
+        let index = part
+            .strip_suffix('\'')
+            .or_else(|| part.strip_suffix('H'))
+            .and_then(|index| index.parse::<u32>().ok())
+            .filter(|index| *index < HARDENED_OFFSET)
+            .ok_or(MnemonicError::InvalidPath)?;
+
+        let mut data = [0; 37];
+        data[1..33].copy_from_slice(&node[..32]);
+        data[33..].copy_from_slice(&(index | HARDENED_OFFSET).to_be_bytes());
+        node = hmac_sha512(&node[32..], &data);
+    }
+
+    Ok(node[..32].try_into().unwrap())
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("Shouldn't fail for any key size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn pbkdf2_sha512(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 64] {
+    let mut result = [0; 64];
+    pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, &mut result);
+    result
+}
+
+const TON_SEED_SALT: &[u8] = b"TON default seed";
+const TON_SEED_ITERATIONS: u32 = 100_000;
+const BIP39_SEED_ITERATIONS: u32 = 2048;
+const HARDENED_OFFSET: u32 = 0x8000_0000;
+
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MnemonicError {
+    #[error("Empty seed phrase")]
+    EmptyPhrase,
+    #[error("Only ASCII seed phrases are supported")]
+    NonAsciiPhrase,
+    #[error("Invalid derivation path")]
+    InvalidPath,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slip10_vectors() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        for (path, expected) in [
+            (
+                "m",
+                "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+            ),
+            (
+                "m/0'",
+                "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+            ),
+            (
+                "m/0H/1H",
+                "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+            ),
+        ] {
+            assert_eq!(hex::encode(slip10_derive(&seed, path).unwrap()), expected);
+        }
+
+        assert_eq!(slip10_derive(&seed, "m/0"), Err(MnemonicError::InvalidPath));
+        assert_eq!(slip10_derive(&seed, "0'"), Err(MnemonicError::InvalidPath));
+    }
+
+    #[test]
+    fn derive_from_phrase() {
+        let bip39 = "abandon abandon abandon abandon abandon abandon \
+            abandon abandon abandon abandon abandon about";
+        assert_eq!(
+            hex::encode(derive_secret_key(bip39, MnemonicDerivation::BIP39).unwrap()),
+            "b477ef5ed17fb8a2b8faddd7a9835a227243a82c70b190c7af4896155aa7df9f"
+        );
+
+        let ton = format!("{} about", ["abandon"; 23].join(" "));
+        assert_eq!(
+            hex::encode(derive_secret_key(&ton, MnemonicDerivation::TON).unwrap()),
+            "0e5d1af976fc42954764144d47f6a4d38e1b753a22fda8173be659edddfd00ed"
+        );
+
+        assert_eq!(
+            derive_secret_key(" ", MnemonicDerivation::TON),
+            Err(MnemonicError::EmptyPhrase)
+        );
+    }
+}
@@ -3,7 +3,8 @@
 //! ADNL is a UDP-based data transfer protocol. It is a base layer for other protocols, used
 //! in Everscale. It provides no guarantees of reliability, so it should only be used for
 //! small data transfers. There is a support for multipart transfers, but the user must be
-//! prepared, that some parts of them may be lost and full transfer will be lost.
+//! prepared, that some parts of them may be lost and full transfer will be lost
+//! (unless parts retransmission is enabled, see [`NodeOptions::transfer_resend_attempts`]).
 //!
 //! #### Brief overview
 //!
@@ -82,28 +83,61 @@ use anyhow::{Context, Result};
 use frunk_core::hlist::{HCons, HList, HNil, Selector};
 use frunk_core::indices::Here;
 
-pub use self::keystore::{Key, Keystore};
-pub use self::node::{Node, NodeMetrics, NodeOptions};
-pub use self::node_id::{ComputeNodeIds, NodeIdFull, NodeIdShort};
-pub use self::peer::{NewPeerContext, PeerFilter};
+pub use self::address_udp::{AdnlAddressUdp, AdnlAddressUdpError};
+pub use self::channel::{ChannelCipher, ChannelStats, SubChannelStats};
+pub use self::custom_messages::CustomMessages;
+pub use self::events::{NodeEvent, NodeEvents};
+pub use self::ip_filter::{IpFilter, IpFilterConfig, IpFilterRules, Ipv4Subnet, Ipv4SubnetError};
+pub use self::key_formats::KeyFormatError;
+pub use self::keystore::{Key, Keystore, KeystoreConfig, KeystoreError, Signer, TaggedKeyConfig};
+pub use self::mnemonic::{derive_secret_key, MnemonicDerivation, MnemonicError};
+pub use self::node::{
+    Node, NodeMetrics, NodeOptions, PeerChannelState, PeerInfo, PeerMetrics, QueryOptions,
+};
+pub use self::node_id::{
+    ComputeNodeIds, KeyIdFull, NodeIdFull, NodeIdFullError, NodeIdShort, NodeIdShortError,
+};
+pub use self::packet_drops::{DropReason, PacketDropMetrics};
+pub use self::packet_tap::{PacketDirection, PacketTap, PcapNgTap, PcapNgWriter, TappedPacket};
+pub use self::padding::{PacketPaddingConfig, MAX_PADDED_PACKET_SIZE};
+pub use self::peer::{DeliveryConfirmation, NewPeerContext, PeerFilter};
 pub use self::peers_set::PeersSet;
+pub use self::rtt::PeerRtt;
+#[cfg(feature = "test-utils")]
+pub(crate) use self::socket::NodeSocket;
+pub use self::transfer::{
+    TransferCompletion, TransferDirection, TransferId, TransferObserver, TransferProgress,
+};
+#[cfg(feature = "rldp")]
+pub(crate) use self::transfer::{TransferPermit, TransfersBudget};
 
 use crate::subscriber::{MessageSubscriber, QuerySubscriber};
 use crate::util::{DeferredInitialization, NetworkBuilder};
 
+mod address_udp;
 mod channel;
+mod custom_messages;
 mod encryption;
+mod events;
 mod handshake;
+mod ip_filter;
+mod key_formats;
 mod keystore;
+mod mnemonic;
 mod node;
 mod node_id;
+mod packet_drops;
+mod packet_tap;
 mod packet_view;
+mod padding;
 mod peer;
 mod peers_set;
 mod ping_subscriber;
 mod queries_cache;
+mod rtt;
 mod socket;
 mod transfer;
+pub mod wire;
 
 pub(crate) type Deferred = Result<Arc<Node>>;
 
@@ -121,6 +155,7 @@ impl NetworkBuilder<HNil, (Here, Here)> {
     /// Creates a basic network layer that is an ADNL node
     ///
     /// See [`with_adnl_ext`] if you need a node with a peer filter
+    /// (e.g. the built-in [`IpFilter`])
     ///
     /// [`with_adnl_ext`]: fn@crate::util::NetworkBuilder::with_adnl_ext
     ///
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::adnl::Node;
+use crate::subscriber::BackgroundTask;
+use crate::util::now;
+
+impl Node {
+    /// Starts a process that periodically polls registered background tasks
+    pub(super) fn start_background_tasks(self: &Arc<Self>, tasks: Vec<Arc<dyn BackgroundTask>>) {
+        use futures_util::future::{join_all, select, Either};
+
+        let complete_signal = self.cancellation_token.clone();
+        let node = Arc::downgrade(self);
+        let interval = Duration::from_millis(self.options.background_tasks_interval_ms);
+
+        tokio::spawn(async move {
+            tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+            loop {
+                tokio::pin!(let sleep = tokio::time::sleep(interval););
+                if let Either::Right(_) = select(sleep, &mut cancelled).await {
+                    break;
+                }
+
+                // Stop polling when the node is dropped
+                if node.strong_count() == 0 {
+                    break;
+                }
+
+                let now = now();
+                for result in join_all(tasks.iter().map(|task| task.poll(now))).await {
+                    if let Err(e) = result {
+                        tracing::warn!("background task failed: {e:?}");
+                    }
+                }
+            }
+
+            tracing::debug!("background tasks loop finished");
+        });
+    }
+}
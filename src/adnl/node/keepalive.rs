@@ -0,0 +1,73 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::adnl::channel::Channel;
+use crate::adnl::Node;
+use crate::util::*;
+
+impl Node {
+    /// Starts a process that periodically pings remote peers over idle channels
+    pub(super) fn start_keepalive(self: &Arc<Self>, interval_sec: u32) {
+        use futures_util::future::{join_all, select, Either};
+
+        let complete_signal = self.cancellation_token.clone();
+        let node = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let interval = Duration::from_secs(interval_sec as u64);
+
+            tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+            loop {
+                tokio::pin!(let sleep = tokio::time::sleep(interval););
+                if let Either::Right(_) = select(sleep, &mut cancelled).await {
+                    break;
+                }
+
+                let node = match node.upgrade() {
+                    Some(node) => node,
+                    None => break,
+                };
+
+                let now = now();
+                let idle_channels = node
+                    .channels_by_peers
+                    .iter()
+                    .filter(|channel| {
+                        channel.ready() && channel.last_activity() + interval_sec <= now
+                    })
+                    .map(|channel| channel.value().clone())
+                    .collect::<Vec<_>>();
+
+                join_all(
+                    idle_channels
+                        .iter()
+                        .map(|channel| node.keepalive_ping(channel)),
+                )
+                .await;
+            }
+
+            tracing::debug!("keepalive loop finished");
+        });
+    }
+
+    async fn keepalive_ping(&self, channel: &Channel) {
+        let local_id = channel.local_id();
+        let peer_id = channel.peer_id();
+
+        match self.ping(local_id, peer_id, None).await {
+            Ok(true) => {
+                channel.reset_keepalive_failures();
+                return;
+            }
+            Ok(false) => {}
+            Err(error) => tracing::trace!(%local_id, %peer_id, ?error, "keepalive ping failed"),
+        }
+
+        let failures = channel.add_keepalive_failure();
+        if failures >= self.options.keepalive_max_failures {
+            tracing::debug!(%local_id, %peer_id, failures, "peer is not responding to pings");
+            self.reset_peer(local_id, peer_id).ok();
+        }
+    }
+}
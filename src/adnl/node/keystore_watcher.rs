@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+
+use crate::adnl::{Key, Keystore, KeystoreConfig, Node};
+
+impl Node {
+    /// Starts a process that periodically checks the keystore file and applies changes
+    /// to the running node. The file must contain [`KeystoreConfig`] in JSON.
+    ///
+    /// - keys with new tags are added;
+    /// - keys with changed secret are replaced;
+    /// - tags which are missing in the file are removed (with keys without other tags).
+    ///
+    /// Untagged keys (e.g. replaced by [`Node::rotate_key`]) are not affected.
+    /// Files with invalid JSON, duplicated tags or duplicated keys are ignored as a whole.
+    pub fn start_keystore_watcher(self: &Arc<Self>, path: PathBuf, interval: Duration) {
+        use futures_util::future::{select, Either};
+
+        let complete_signal = self.cancellation_token.clone();
+        let node = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+            let mut last_modified = modified_at(&path).ok();
+
+            loop {
+                tokio::pin!(let sleep = tokio::time::sleep(interval););
+                if let Either::Right(_) = select(sleep, &mut cancelled).await {
+                    break;
+                }
+
+                let node = match node.upgrade() {
+                    Some(node) => node,
+                    None => break,
+                };
+
+                let modified = match modified_at(&path) {
+                    Ok(modified) => modified,
+                    Err(e) => {
+                        tracing::warn!(path = %path.display(), "failed to check keystore file: {e:?}");
+                        continue;
+                    }
+                };
+                if last_modified == Some(modified) {
+                    continue;
+                }
+                last_modified = Some(modified);
+
+                if let Err(e) = node.reload_keystore(&path) {
+                    tracing::error!(path = %path.display(), "failed to reload keystore: {e:?}");
+                }
+            }
+
+            tracing::debug!("keystore watcher loop finished");
+        });
+    }
+
+    fn reload_keystore(&self, path: &Path) -> Result<()> {
+        let data = std::fs::read(path).context("Failed to read keystore file")?;
+        let config: KeystoreConfig =
+            serde_json::from_slice(&data).context("Failed to parse keystore file")?;
+
+        // Check the whole file before applying anything to keep the node consistent
+        Keystore::from_config(&config).context("Invalid keystore file")?;
+
+        let current = self
+            .keystore
+            .read()
+            .tags()
+            .map(|(tag, local_id)| (tag, *local_id))
+            .collect::<Vec<_>>();
+
+        // Remove tags which are missing or point to the changed secrets
+        for (tag, local_id) in current {
+            let unchanged = config
+                .keys
+                .iter()
+                .any(|key| key.tag == tag && Key::from_bytes(key.secret_key).id() == &local_id);
+            if unchanged {
+                continue;
+            }
+
+            let orphaned = {
+                let mut keystore = self.keystore.write();
+                keystore.remove_tag(tag);
+                !keystore.is_tagged(&local_id)
+            };
+            if orphaned {
+                self.delete_key(&local_id);
+            }
+        }
+
+        // Add new keys
+        for key in &config.keys {
+            if self.keystore.read().key_by_tag(key.tag).is_ok() {
+                continue;
+            }
+            let local_id = self.add_key(key.secret_key, key.tag)?;
+            tracing::info!(%local_id, tag = key.tag, "added ADNL key");
+        }
+
+        Ok(())
+    }
+}
+
+fn modified_at(path: &Path) -> std::io::Result<SystemTime> {
+    std::fs::metadata(path)?.modified()
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::adnl::NodeIdShort;
+    use crate::test_utils::MemoryNetwork;
+
+    fn write_keystore(path: &Path, keys: &[([u8; 32], usize)]) {
+        let keys = keys
+            .iter()
+            .map(|(key, tag)| {
+                format!(
+                    r#"{{ "tag": {tag}, "secret_key": "{}" }}"#,
+                    hex::encode(key)
+                )
+            })
+            .collect::<Vec<_>>();
+        std::fs::write(path, format!("[{}]", keys.join(","))).unwrap();
+    }
+
+    fn local_ids(node: &Node) -> Vec<(usize, NodeIdShort)> {
+        node.keystore
+            .read()
+            .tags()
+            .map(|(tag, id)| (tag, *id))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reload_keystore() {
+        let path = std::env::temp_dir().join(format!("keystore-{}.json", rand::random::<u64>()));
+
+        let network = MemoryNetwork::default();
+        let keystore = Keystore::builder()
+            .with_tagged_keys([([1; 32], 0), ([2; 32], 1)])
+            .unwrap()
+            .build();
+        let node = network
+            .create_node(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                keystore,
+                Default::default(),
+                None,
+            )
+            .unwrap();
+
+        let id = |key: [u8; 32]| *Key::from_bytes(key).id();
+
+        // Changed, removed and new tags
+        write_keystore(&path, &[([1; 32], 0), ([3; 32], 2), ([4; 32], 1)]);
+        node.reload_keystore(&path).unwrap();
+        assert_eq!(
+            local_ids(&node),
+            [(0, id([1; 32])), (1, id([4; 32])), (2, id([3; 32]))]
+        );
+        assert!(node.key_by_id(&id([2; 32])).is_err());
+
+        // Duplicated tag in the middle of the file
+        write_keystore(&path, &[([5; 32], 3), ([6; 32], 3), ([1; 32], 0)]);
+        assert!(node.reload_keystore(&path).is_err());
+        assert_eq!(
+            local_ids(&node),
+            [(0, id([1; 32])), (1, id([4; 32])), (2, id([3; 32]))]
+        );
+
+        // Malformed file
+        std::fs::write(&path, r#"[{ "tag": 0 }"#).unwrap();
+        assert!(node.reload_keystore(&path).is_err());
+        assert_eq!(local_ids(&node).len(), 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
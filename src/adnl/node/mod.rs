@@ -1,31 +1,49 @@
 use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use bytes::Bytes;
-use parking_lot::Mutex;
+use everscale_crypto::ed25519;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use tl_proto::{TlRead, TlWrite};
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use tokio_util::sync::CancellationToken;
 
 use self::receiver::*;
 use self::sender::*;
-use super::channel::{AdnlChannelId, Channel};
+use super::channel::{
+    AdnlChannelId, Channel, ChannelCipher, ChannelCreationContext, ChannelStats,
+    ADNL_CHACHA20_POLY1305_VERSION,
+};
+use super::custom_messages::{CustomMessages, CustomMessagesTx};
+use super::events::{NodeEvent, NodeEvents, NodeEventsTx};
+use super::handshake::HandshakeSecrets;
 use super::keystore::{Key, Keystore, KeystoreError};
 use super::node_id::{NodeIdFull, NodeIdShort};
-use super::peer::{NewPeerContext, Peer, PeerFilter, Peers};
+use super::packet_drops::{DropReason, PacketDropMetrics, PacketDrops};
+use super::packet_tap::{PacketDirection, PacketTap, TappedPacket};
+use super::padding::PacketPaddingConfig;
+use super::peer::{DeliveryConfirmation, NewPeerContext, Peer, PeerFilter, Peers};
 use super::ping_subscriber::PingSubscriber;
-use super::queries_cache::{QueriesCache, QueryId};
-use super::socket::make_udp_socket;
+use super::queries_cache::{CoalescedQuery, QueriesCache, QueryId};
+use super::rtt::{PeerRtt, RttTracker};
+use super::socket::{make_udp_socket, NodeSocket};
 use super::transfer::*;
 use crate::proto;
 use crate::subscriber::*;
 use crate::util::*;
 
+mod background_tasks;
+mod keepalive;
+#[cfg(feature = "keystore-watcher")]
+mod keystore_watcher;
+mod queries_gc;
 mod receiver;
 mod sender;
+mod transfers;
 
 /// ADNL node configuration
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -45,12 +63,120 @@ pub struct NodeOptions {
     /// See [`Node::query`], [`Node::query_with_prefix`], [`Node::query_raw`]
     pub query_default_timeout_ms: u64,
 
+    /// Whether to use the measured roundtrip time of the peer as a default query timeout.
+    /// Will fall back to [`query_default_timeout_ms`] if there were no answers from the peer yet.
+    ///
+    /// Default: `true`
+    ///
+    /// [`query_default_timeout_ms`]: NodeOptions::query_default_timeout_ms
+    pub query_adaptive_timeout: bool,
+
+    /// Whether to share a single wire query between identical concurrent queries
+    /// to the same peer. Coalesced queries use the options of the first query.
+    ///
+    /// Default: `false`
+    pub query_coalescing_enabled: bool,
+
+    /// Max number of pending queries. New queries are rejected if the limit is reached.
+    /// The number of pending queries is not limited if not specified.
+    ///
+    /// Default: None
+    pub query_cache_capacity: Option<usize>,
+
+    /// Pending queries which are older than this amount of time are removed
+    /// by the background sweep. It must be greater than any query timeout.
+    ///
+    /// Default: `300` seconds
+    pub query_stale_timeout_sec: u32,
+
+    /// Max size of the incoming query. Bigger queries are dropped before
+    /// they reach subscribers. The size is not limited if not specified.
+    ///
+    /// Default: None
+    pub query_max_size: Option<usize>,
+
+    /// Max number of incoming queries per second from each peer (bursts up to
+    /// this amount are allowed). Excess queries are dropped without an answer.
+    /// The rate is not limited if not specified.
+    ///
+    /// Default: None
+    pub query_rate_limit: Option<u32>,
+
+    /// Max size of the answer to the incoming ADNL query. Bigger answers are dropped,
+    /// so such queries must be sent via RLDP.
+    ///
+    /// NOTE: answers to RLDP queries are sent via RLDP and are only limited
+    /// by the max answer size from the query.
+    ///
+    /// Default: `16` MiB
+    pub max_answer_size: usize,
+
     /// ADNL multipart transfer timeout. It will drop the transfer if it is not completed
     /// within this timeout.
     ///
     /// Default: `3` seconds
     pub transfer_timeout_sec: u64,
 
+    /// Max total size of the incoming multipart transfer.
+    ///
+    /// Default: `16` MiB
+    pub transfer_max_size: usize,
+
+    /// Max number of concurrent incoming multipart transfers from the same peer.
+    ///
+    /// Default: `16`
+    pub transfer_max_per_peer: usize,
+
+    /// Max total size of all concurrent incoming multipart transfers.
+    ///
+    /// Default: `256` MiB
+    pub transfers_memory_limit: usize,
+
+    /// Max number of retransmissions of the unconfirmed parts of split messages.
+    /// Parts are not resent if zero.
+    ///
+    /// NOTE: Part is considered confirmed when the remote peer confirms any packet
+    /// with the same or greater seqno, so it only helps with the lost tail of the transfer.
+    /// Completed incoming transfers are kept until `transfer_timeout_sec` if enabled,
+    /// so that the resent parts are ignored.
+    ///
+    /// Default: `0`
+    pub transfer_resend_attempts: u32,
+
+    /// Interval between retransmissions of the unconfirmed parts.
+    ///
+    /// Default: `500` ms
+    pub transfer_resend_interval_ms: u64,
+
+    /// Interval between polls of the registered background tasks.
+    ///
+    /// See [`Node::add_background_task`]
+    ///
+    /// Default: `1000` ms
+    pub background_tasks_interval_ms: u64,
+
+    /// Max number of buffered messages in each custom messages stream.
+    ///
+    /// See [`Node::custom_messages`]
+    ///
+    /// Default: `1024`
+    pub custom_messages_queue_capacity: usize,
+
+    /// Log subscribers which process a query or a custom message longer than this
+    /// amount of time. Slow subscribers are not logged if not specified.
+    ///
+    /// See [`QueryStats::slow_count`]
+    ///
+    /// Default: `None`
+    pub slow_subscriber_threshold_ms: Option<u64>,
+
+    /// Max number of buffered events in each events stream.
+    ///
+    /// See [`Node::events`]
+    ///
+    /// Default: `1024`
+    pub events_queue_capacity: usize,
+
     /// Permissible time difference between remote and local clocks.
     ///
     /// Default: `60` seconds
@@ -61,6 +187,35 @@ pub struct NodeOptions {
     /// Default: `30` seconds
     pub channel_reset_timeout_sec: u32,
 
+    /// Whether to create channels with remote peers. All packets are sent
+    /// as handshake packets if channels are disabled.
+    ///
+    /// Default: `true`
+    pub channels_enabled: bool,
+
+    /// Recreate channels which are older than this amount of time.
+    /// Channels are not recreated by age if not specified.
+    ///
+    /// Default: None
+    pub channel_rekey_interval_sec: Option<u32>,
+
+    /// Recreate channels after encrypting this amount of bytes.
+    /// Channels are not recreated by traffic if not specified.
+    ///
+    /// Default: None
+    pub channel_rekey_bytes: Option<u64>,
+
+    /// Interval of keepalive pings for channels without incoming packets.
+    /// Keepalive pings are disabled if not specified.
+    ///
+    /// Default: None
+    pub keepalive_interval_sec: Option<u32>,
+
+    /// Number of consecutive failed keepalive pings after which the peer state is reset.
+    ///
+    /// Default: `3`
+    pub keepalive_max_failures: u32,
+
     /// How much time address lists from packets should be valid.
     ///
     /// Default: `1000` seconds
@@ -68,15 +223,27 @@ pub struct NodeOptions {
 
     /// Whether to add additional duplicated packets check.
     ///
+    /// NOTE: the highest received seqno is tracked and sent back in
+    /// `confirm_seqno` even when this check is disabled, because delivery
+    /// confirmations and transfer resends rely on it.
+    ///
     /// Default: `false`
     pub packet_history_enabled: bool,
 
+    /// Duplicated packets check settings.
+    ///
+    /// See [`packet_history_enabled`]
+    ///
+    /// [`packet_history_enabled`]: NodeOptions::packet_history_enabled
+    pub packet_history: PacketsHistoryConfig,
+
     /// Whether handshake packets signature is mandatory.
     ///
     /// Default: `true`
     pub packet_signature_required: bool,
 
-    /// Whether to use priority channels for queries.
+    /// Whether to use priority channels for queries and custom messages
+    /// if priority is not specified explicitly.
     ///
     /// Default: `true`
     pub force_use_priority_channels: bool,
@@ -86,10 +253,47 @@ pub struct NodeOptions {
     /// Default: `false`
     pub use_loopback_for_neighbours: bool,
 
-    /// ADNL protocol version.
+    /// Random padding of the outgoing packets
+    pub packet_padding: PacketPaddingConfig,
+
+    /// How long the temporary key of the outgoing handshake packets is reused
+    /// for the same peer. New key is generated for each packet if it is zero.
+    ///
+    /// Default: `0` seconds
+    pub handshake_key_ttl_sec: u32,
+
+    /// Max number of cached shared secrets of the incoming handshake packets.
+    /// Shared secrets are not cached if it is zero. The oldest secrets are evicted
+    /// when the cache is full.
+    ///
+    /// Default: `4096`
+    pub handshake_secrets_cache_capacity: usize,
+
+    /// ADNL protocol version advertised in handshake packets. Channel packets
+    /// use the lowest of the versions advertised by both sides.
     ///
     /// Default: None
     pub version: Option<u16>,
+
+    /// The lowest accepted ADNL version of the incoming packets.
+    /// Packets without version are always accepted.
+    ///
+    /// Default: `0`
+    pub min_version: u16,
+
+    /// The highest accepted ADNL version of the incoming packets.
+    /// Packets without version are always accepted.
+    ///
+    /// Default: `1`
+    pub max_version: u16,
+
+    /// Preferred cipher for the channel packets. ChaCha20-Poly1305 support is
+    /// advertised with a single `Nop` channel packet, and the cipher is used only
+    /// after the peer has sent a packet with it too (i.e. both sides prefer it).
+    /// Packets encrypted with any cipher are always accepted.
+    ///
+    /// Default: `aes_ctr`
+    pub channel_cipher: ChannelCipher,
 }
 
 impl Default for NodeOptions {
@@ -97,15 +301,89 @@ impl Default for NodeOptions {
         Self {
             query_min_timeout_ms: 500,
             query_default_timeout_ms: 5000,
+            query_adaptive_timeout: true,
+            query_coalescing_enabled: false,
+            query_cache_capacity: None,
+            query_stale_timeout_sec: 300,
+            query_max_size: None,
+            query_rate_limit: None,
+            max_answer_size: 16 << 20,
             transfer_timeout_sec: 3,
+            transfer_max_size: 16 << 20,
+            transfer_max_per_peer: 16,
+            transfers_memory_limit: 256 << 20,
+            transfer_resend_attempts: 0,
+            transfer_resend_interval_ms: 500,
+            background_tasks_interval_ms: 1000,
+            custom_messages_queue_capacity: 1024,
+            slow_subscriber_threshold_ms: None,
+            events_queue_capacity: 1024,
             clock_tolerance_sec: 60,
             channel_reset_timeout_sec: 30,
+            channels_enabled: true,
+            channel_rekey_interval_sec: None,
+            channel_rekey_bytes: None,
+            keepalive_interval_sec: None,
+            keepalive_max_failures: 3,
             address_list_timeout_sec: 1000,
             packet_history_enabled: false,
+            packet_history: Default::default(),
             packet_signature_required: true,
             force_use_priority_channels: true,
             use_loopback_for_neighbours: false,
+            packet_padding: Default::default(),
+            handshake_key_ttl_sec: 0,
+            handshake_secrets_cache_capacity: 4096,
             version: None,
+            min_version: 0,
+            max_version: ADNL_CHACHA20_POLY1305_VERSION,
+            channel_cipher: ChannelCipher::AesCtr,
+        }
+    }
+}
+
+/// ADNL query settings
+///
+/// See [`Node::query_with_options`], [`Node::query_raw_with_options`]
+#[derive(Debug, Copy, Clone)]
+pub struct QueryOptions {
+    /// Timeout of the first attempt in milliseconds.
+    /// Computed from the measured roundtrip time if not specified.
+    ///
+    /// Default: None
+    pub timeout: Option<u64>,
+
+    /// Number of retransmissions after the timeout.
+    ///
+    /// Default: `0`
+    pub retries: u32,
+
+    /// Timeout multiplier for each next attempt.
+    ///
+    /// Default: `2.0`
+    pub backoff: f64,
+
+    /// Whether to use priority channel.
+    /// [`NodeOptions::force_use_priority_channels`] is used if not specified.
+    ///
+    /// Default: None
+    pub priority: Option<bool>,
+
+    /// Absolute time after which the query is considered failed,
+    /// regardless of the remaining retries.
+    ///
+    /// Default: None
+    pub deadline: Option<Instant>,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            retries: 0,
+            backoff: 2.0,
+            priority: None,
+            deadline: None,
         }
     }
 }
@@ -114,35 +392,63 @@ impl Default for NodeOptions {
 pub struct Node {
     /// Socket address of the node
     socket_addr: SocketAddrV4,
-    /// Immutable keystore
-    keystore: Keystore,
+    /// Local keys
+    keystore: RwLock<Keystore>,
     /// Configuration
     options: NodeOptions,
 
     /// If specified, peers are only accepted if they match the filter
     peer_filter: Option<Arc<dyn PeerFilter>>,
+    /// Optional multipart transfers progress observer
+    transfer_observer: RwLock<Option<Arc<dyn TransferObserver>>>,
+    /// Dropped packets counters
+    packet_drops: PacketDrops,
+    /// Optional packet capture tap
+    packet_tap: RwLock<Option<Arc<dyn PacketTap>>>,
 
     /// Known peers for each local node id
-    peers: FastHashMap<NodeIdShort, Peers>,
+    peers: RwLock<FastHashMap<NodeIdShort, Arc<Peers>>>,
+
+    /// Cached shared secrets of the incoming handshake packets
+    handshake_secrets: HandshakeSecrets,
 
     /// Channels table used to fast search on incoming packets
-    channels_by_id: FastDashMap<AdnlChannelId, ChannelReceiver>,
+    channels_by_id: Arc<FastDashMap<AdnlChannelId, ChannelReceiver>>,
     /// Channels table used to fast search when sending messages
     channels_by_peers: FastDashMap<NodeIdShort, Arc<Channel>>,
 
     /// Pending transfers of large messages that were split
     incoming_transfers: Arc<FastDashMap<TransferId, Arc<Transfer>>>,
+    /// Limits for the incoming transfers
+    transfers_budget: Arc<TransfersBudget>,
+    /// Sent split messages with unconfirmed parts
+    outgoing_transfers: FastDashMap<TransferId, Arc<OutgoingTransfer>>,
 
     /// Pending queries
     queries: Arc<QueriesCache>,
+    /// Handled queries statistics
+    subscriber_stats: SubscriberStats,
+    /// Incoming queries limits
+    query_limiter: Arc<QueryLimiter>,
+    /// Query roundtrip stats for each remote peer
+    peer_rtts: FastDashMap<NodeIdShort, RttTracker>,
+
+    /// Incoming custom messages streams for each local id
+    custom_messages: FastDashMap<NodeIdShort, CustomMessagesTx>,
+    /// Node events broadcast
+    events_tx: NodeEventsTx,
 
     /// Outgoing packets queue
     sender_queue_tx: SenderQueueTx,
+    /// Messages between local ids
+    loopback_tx: LoopbackQueueTx,
     /// Stated used during initialization
     init_state: Mutex<Option<InitializationState>>,
 
     /// Node start timestamp. Used as reinit date for connections
     start_time: u32,
+    /// Date of the last sent `adnl.message.reinit`
+    last_reinit_date: AtomicU32,
 
     /// Token, used to cancel all spawned tasks
     cancellation_token: CancellationToken,
@@ -151,14 +457,24 @@ pub struct Node {
 impl Node {
     /// Create new ADNL node on the specified address
     pub fn new(
-        mut socket_addr: SocketAddrV4,
+        socket_addr: SocketAddrV4,
         keystore: Keystore,
         options: NodeOptions,
         peer_filter: Option<Arc<dyn PeerFilter>>,
     ) -> Result<Arc<Self>> {
         // Bind node socket
         let socket = make_udp_socket(socket_addr.port())?;
+        Self::with_socket(socket_addr, socket, keystore, options, peer_filter)
+    }
 
+    /// Create new ADNL node with the bound socket
+    pub(crate) fn with_socket(
+        mut socket_addr: SocketAddrV4,
+        socket: NodeSocket,
+        keystore: Keystore,
+        options: NodeOptions,
+        peer_filter: Option<Arc<dyn PeerFilter>>,
+    ) -> Result<Arc<Self>> {
         // Update socket addr with auto assigned port (in case of 0)
         if socket_addr.port() == 0 {
             let local_addr = socket.local_addr().context("Failed to select UDP port")?;
@@ -166,32 +482,58 @@ impl Node {
         }
 
         let (sender_queue_tx, sender_queue_rx) = mpsc::unbounded_channel();
+        let (loopback_tx, loopback_rx) = mpsc::unbounded_channel();
 
         // Add empty peers map for each local peer
         let mut peers =
             FastHashMap::with_capacity_and_hasher(keystore.keys().len(), Default::default());
         for key in keystore.keys().keys() {
-            peers.insert(*key, Peers::default());
+            peers.insert(*key, Arc::new(Peers::default()));
         }
 
         Ok(Arc::new(Self {
             socket_addr,
-            keystore,
+            keystore: RwLock::new(keystore),
             options,
             peer_filter,
-            peers,
+            transfer_observer: Default::default(),
+            packet_drops: Default::default(),
+            packet_tap: Default::default(),
+            peers: RwLock::new(peers),
+            handshake_secrets: HandshakeSecrets::new(options.handshake_secrets_cache_capacity),
             channels_by_id: Default::default(),
             channels_by_peers: Default::default(),
             incoming_transfers: Default::default(),
-            queries: Default::default(),
+            transfers_budget: Arc::new(TransfersBudget::new(
+                options.transfer_max_size,
+                options.transfer_max_per_peer,
+                options.transfers_memory_limit,
+            )),
+            outgoing_transfers: Default::default(),
+            custom_messages: Default::default(),
+            events_tx: broadcast::channel(options.events_queue_capacity.max(1)).0,
+            queries: Arc::new(match options.query_cache_capacity {
+                Some(capacity) => QueriesCache::with_capacity(capacity),
+                None => QueriesCache::default(),
+            }),
+            subscriber_stats: Default::default(),
+            query_limiter: Arc::new(QueryLimiter::new(
+                options.query_max_size,
+                options.query_rate_limit,
+            )),
+            peer_rtts: Default::default(),
             sender_queue_tx,
+            loopback_tx,
             init_state: Mutex::new(Some(InitializationState {
-                socket,
+                socket: Arc::new(socket),
                 sender_queue_rx,
+                loopback_rx,
                 message_subscribers: Default::default(),
                 query_subscribers: Default::default(),
+                background_tasks: Default::default(),
             })),
             start_time: now(),
+            last_reinit_date: Default::default(),
             cancellation_token: Default::default(),
         }))
     }
@@ -204,16 +546,51 @@ impl Node {
 
     /// Instant metrics
     pub fn metrics(&self) -> NodeMetrics {
+        let queries = self.queries.metrics();
         NodeMetrics {
-            peer_count: self.peers.values().map(|peers| peers.len()).sum(),
+            peer_count: self.peers.read().values().map(|peers| peers.len()).sum(),
             channels_by_id_len: self.channels_by_id.len(),
             channels_by_peers_len: self.channels_by_peers.len(),
             incoming_transfers_len: self.incoming_transfers.len(),
-            query_count: self.queries.len(),
+            incoming_transfers_memory: self.transfers_budget.memory_used(),
+            query_count: queries.len,
+            queries_expired: queries.expired,
+            queries_stale: queries.stale,
+            queries_rejected: queries.rejected,
         }
     }
 
-    /// Adds a new message subscriber brefore the node was started
+    /// Handled queries statistics for each query constructor and subscriber
+    pub fn subscriber_metrics(&self) -> SubscriberMetrics {
+        self.subscriber_stats.metrics()
+    }
+
+    /// Number of dropped incoming packets for each reason.
+    ///
+    /// Each drop is also reported as [`NodeEvent::PacketRejected`] (see [`Node::events`])
+    pub fn packet_drop_metrics(&self) -> PacketDropMetrics {
+        self.packet_drops.metrics()
+    }
+
+    pub(crate) fn subscriber_stats(&self) -> &SubscriberStats {
+        &self.subscriber_stats
+    }
+
+    pub(crate) fn query_limiter(&self) -> &QueryLimiter {
+        &self.query_limiter
+    }
+
+    /// Sets multipart transfers progress observer. Removes the observer if `None`
+    pub fn set_transfer_observer(&self, observer: Option<Arc<dyn TransferObserver>>) {
+        *self.transfer_observer.write() = observer;
+    }
+
+    /// Sets packet capture tap. Removes the tap if `None`
+    pub fn set_packet_tap(&self, tap: Option<Arc<dyn PacketTap>>) {
+        *self.packet_tap.write() = tap;
+    }
+
+    /// Adds a new message subscriber before the node was started
     pub fn add_message_subscriber(
         &self,
         message_subscriber: Arc<dyn MessageSubscriber>,
@@ -228,7 +605,7 @@ impl Node {
         }
     }
 
-    /// Adds a new query subscriber brefore the node was started
+    /// Adds a new query subscriber before the node was started
     pub fn add_query_subscriber(&self, query_subscriber: Arc<dyn QuerySubscriber>) -> Result<()> {
         let mut init = self.init_state.lock();
         match &mut *init {
@@ -240,6 +617,55 @@ impl Node {
         }
     }
 
+    /// Returns a stream of incoming custom messages for the local id.
+    ///
+    /// Message subscribers have priority over the stream. There is only one stream
+    /// for each local id, so the previous stream is closed.
+    pub fn custom_messages(&self, local_id: &NodeIdShort) -> Result<CustomMessages> {
+        self.key_by_id(local_id)?;
+
+        let (tx, stream) = CustomMessages::new(self.options.custom_messages_queue_capacity);
+        self.custom_messages.insert(*local_id, tx);
+        Ok(stream)
+    }
+
+    /// Returns a stream of the node events.
+    ///
+    /// Events which happened before the stream was created are not received.
+    pub fn events(&self) -> NodeEvents {
+        NodeEvents::new(self.events_tx.subscribe())
+    }
+
+    /// Sends the event if there are any events streams
+    fn emit_event<F>(&self, f: F)
+    where
+        F: FnOnce() -> NodeEvent,
+    {
+        if self.events_tx.receiver_count() > 0 {
+            self.events_tx.send(f()).ok();
+        }
+    }
+
+    /// Sends the event about the new ready channel
+    fn emit_channel_established(&self, channel: &Channel) {
+        self.emit_event(|| NodeEvent::ChannelEstablished {
+            local_id: *channel.local_id(),
+            peer_id: *channel.peer_id(),
+        });
+    }
+
+    /// Adds a new periodic background task before the node was started
+    pub fn add_background_task(&self, background_task: Arc<dyn BackgroundTask>) -> Result<()> {
+        let mut init = self.init_state.lock();
+        match &mut *init {
+            Some(init) => {
+                init.background_tasks.push(background_task);
+                Ok(())
+            }
+            None => Err(NodeError::AlreadyRunning.into()),
+        }
+    }
+
     /// Starts listening for incoming packets
     pub fn start(self: &Arc<Self>) -> Result<()> {
         // Consume receiver
@@ -254,9 +680,20 @@ impl Node {
         self.start_sender(init.socket.clone(), init.sender_queue_rx);
         self.start_receiver(
             init.socket,
+            init.loopback_rx,
             init.message_subscribers,
             init.query_subscribers,
         );
+        if let Some(interval_sec) = self.options.keepalive_interval_sec {
+            self.start_keepalive(interval_sec);
+        }
+        self.start_queries_gc();
+        if self.options.transfer_resend_attempts > 0 {
+            self.start_transfers_resend();
+        }
+        if !init.background_tasks.is_empty() {
+            self.start_background_tasks(init.background_tasks);
+        }
 
         // Done
         Ok(())
@@ -273,6 +710,23 @@ impl Node {
         std::cmp::max(self.options.query_min_timeout_ms, timeout)
     }
 
+    /// Computes ADNL query timeout for the remote peer, based on its measured roundtrip
+    pub fn compute_peer_query_timeout(&self, peer_id: &NodeIdShort) -> u64 {
+        let roundtrip = if self.options.query_adaptive_timeout {
+            self.peer_rtt(peer_id).map(|rtt| rtt.timeout_ms())
+        } else {
+            None
+        };
+        self.compute_query_timeout(roundtrip)
+    }
+
+    /// Returns query roundtrip stats for the remote peer.
+    ///
+    /// NOTE: Only successful queries are measured. Stats are shared by all local ids
+    pub fn peer_rtt(&self, peer_id: &NodeIdShort) -> Option<PeerRtt> {
+        self.peer_rtts.get(peer_id)?.stats()
+    }
+
     /// Socket address of the node
     #[inline(always)]
     pub fn socket_addr(&self) -> SocketAddrV4 {
@@ -291,6 +745,7 @@ impl Node {
             address: Some(proto::adnl::Address::from(&self.socket_addr)),
             version: now(),
             reinit_date: self.start_time,
+            priority: 0,
             expire_at: 0,
         }
     }
@@ -298,15 +753,154 @@ impl Node {
     /// Searches for the stored ADNL key by it's short id
     ///
     /// See [`Node::key_by_tag`]
-    pub fn key_by_id(&self, id: &NodeIdShort) -> Result<&Arc<Key>, KeystoreError> {
-        self.keystore.key_by_id(id)
+    pub fn key_by_id(&self, id: &NodeIdShort) -> Result<Arc<Key>, KeystoreError> {
+        self.keystore.read().key_by_id(id).cloned()
     }
 
     /// Searches for the stored ADNL key by it's tag
     ///
     /// See [`Node::key_by_id`]
-    pub fn key_by_tag(&self, tag: usize) -> Result<&Arc<Key>, KeystoreError> {
-        self.keystore.key_by_tag(tag)
+    pub fn key_by_tag(&self, tag: usize) -> Result<Arc<Key>, KeystoreError> {
+        self.keystore.read().key_by_tag(tag).cloned()
+    }
+
+    /// Adds a new local key with the specified tag to the running node.
+    ///
+    /// NOTE: duplicate keys or tags will cause this method to fail
+    pub fn add_key(&self, key: [u8; 32], tag: usize) -> Result<NodeIdShort> {
+        let local_id = self.keystore.write().add_key(key, tag)?;
+        self.peers
+            .write()
+            .entry(local_id)
+            .or_insert_with(|| Arc::new(Peers::default()));
+        Ok(local_id)
+    }
+
+    /// Removes the local key with all its peers and channels.
+    /// Returns whether the key existed
+    ///
+    /// NOTE: protocols which store the key (DHT, overlays) will fail to use it
+    pub fn delete_key(&self, local_id: &NodeIdShort) -> bool {
+        self.remove_local_key(local_id)
+    }
+
+    /// Replaces the key under the tag with a new one. The replaced key still accepts
+    /// incoming packets during the grace period and is removed after it.
+    /// Outgoing messages should use the new key (see [`Node::key_by_tag`]).
+    ///
+    /// Returns the short id of the new key.
+    ///
+    /// NOTE: protocols which store the key (DHT, overlays) keep using
+    /// the replaced key until they are recreated.
+    pub fn rotate_key(
+        self: &Arc<Self>,
+        key: [u8; 32],
+        tag: usize,
+        grace_period: Duration,
+    ) -> Result<NodeIdShort> {
+        let (new_id, prev_id) = self.keystore.write().rotate_key(key, tag)?;
+        self.peers
+            .write()
+            .entry(new_id)
+            .or_insert_with(|| Arc::new(Peers::default()));
+
+        if let Some(prev_id) = prev_id {
+            tracing::info!(%prev_id, %new_id, tag, "rotated ADNL key");
+            self.retire_key_after(prev_id, grace_period);
+        }
+
+        Ok(new_id)
+    }
+
+    /// Removes the untagged key after the specified timeout
+    fn retire_key_after(self: &Arc<Self>, local_id: NodeIdShort, timeout: Duration) {
+        use futures_util::future::{select, Either};
+
+        let complete_signal = self.cancellation_token.clone();
+        let node = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            tokio::pin!(let cancelled = complete_signal.cancelled(););
+            tokio::pin!(let sleep = tokio::time::sleep(timeout););
+            if let Either::Right(_) = select(sleep, &mut cancelled).await {
+                return;
+            }
+
+            if let Some(node) = node.upgrade() {
+                // Key could be tagged again during the grace period
+                if !node.keystore.read().is_tagged(&local_id) {
+                    node.remove_local_key(&local_id);
+                }
+            }
+        });
+    }
+
+    /// Removes the local key with all its peers and channels.
+    /// Returns whether the key existed
+    fn remove_local_key(&self, local_id: &NodeIdShort) -> bool {
+        if self.keystore.write().remove_key(local_id).is_none() {
+            return false;
+        }
+        let peers = self.peers.write().remove(local_id);
+        self.custom_messages.remove(local_id);
+        self.handshake_secrets.remove_local_key(local_id);
+
+        let channels_by_id = &self.channels_by_id;
+        self.channels_by_peers.retain(|_, channel| {
+            if channel.local_id() != local_id {
+                return true;
+            }
+            channels_by_id.remove(channel.ordinary_channel_in_id());
+            channels_by_id.remove(channel.priority_channel_in_id());
+            self.emit_event(|| NodeEvent::ChannelReset {
+                local_id: *local_id,
+                peer_id: *channel.peer_id(),
+            });
+            false
+        });
+
+        if let Some(peers) = peers {
+            for peer in peers.iter() {
+                self.emit_event(|| NodeEvent::PeerRemoved {
+                    local_id: *local_id,
+                    peer_id: *peer.key(),
+                });
+            }
+        }
+
+        tracing::info!(%local_id, "removed ADNL key");
+        true
+    }
+
+    /// Returns all `(tag, short id)` pairs of the local keys, ordered by tag
+    pub fn key_tags(&self) -> Vec<(usize, NodeIdShort)> {
+        self.keystore
+            .read()
+            .tags()
+            .map(|(tag, id)| (tag, *id))
+            .collect()
+    }
+
+    /// Returns local keys with tags from the range, ordered by tag
+    pub fn keys_by_tag_range<R>(&self, range: R) -> Vec<(usize, Arc<Key>)>
+    where
+        R: std::ops::RangeBounds<usize>,
+    {
+        self.keystore
+            .read()
+            .keys_by_tag_range(range)
+            .map(|(tag, key)| (tag, key.clone()))
+            .collect()
+    }
+
+    /// Binds an additional tag to the existing local key
+    pub fn add_key_tag(&self, local_id: &NodeIdShort, tag: usize) -> Result<(), KeystoreError> {
+        self.keystore.write().add_tag(local_id, tag)
+    }
+
+    /// Removes the tag without removing the key. Returns the short id of the tagged key
+    pub fn remove_key_tag(&self, tag: usize) -> Option<NodeIdShort> {
+        self.keystore.write().remove_tag(tag)
     }
 
     /// Adds new remote peer. Returns whether the peer was added
@@ -319,6 +913,21 @@ impl Node {
         peer_id: &NodeIdShort,
         addr: SocketAddrV4,
         peer_id_full: NodeIdFull,
+    ) -> Result<bool> {
+        self.add_peer_impl(ctx, local_id, peer_id, addr, 0, now(), peer_id_full)
+    }
+
+    /// Adds new remote peer or updates its address with the specified priority
+    /// and address list version
+    fn add_peer_impl(
+        &self,
+        ctx: NewPeerContext,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        addr: SocketAddrV4,
+        priority: u32,
+        version: u32,
+        peer_id_full: NodeIdFull,
     ) -> Result<bool> {
         use dashmap::mapref::entry::Entry;
 
@@ -337,17 +946,94 @@ impl Node {
         // Search remove peer in known peers
         match self.get_peers(local_id)?.entry(*peer_id) {
             // Update ip if peer is already known
-            Entry::Occupied(entry) => entry.get().set_addr(addr),
+            Entry::Occupied(entry) => entry.get().update_addr(addr, priority, version),
             // Create new peer state otherwise
             Entry::Vacant(entry) => {
-                entry.insert(Peer::new(self.start_time, addr, peer_id_full));
+                let peer = Peer::new(
+                    self.start_time,
+                    addr,
+                    peer_id_full,
+                    self.options.packet_history,
+                );
+                peer.update_addr(addr, priority, version);
+                entry.insert(peer);
                 tracing::trace!(%local_id, %peer_id, %addr, "added ADNL peer");
+                self.emit_event(|| NodeEvent::PeerAdded {
+                    local_id: *local_id,
+                    peer_id: *peer_id,
+                });
             }
         };
 
         Ok(true)
     }
 
+    /// Adds new remote peer and puts it into the specified groups.
+    ///
+    /// Groups are also updated if the peer is already known.
+    ///
+    /// See [`Node::add_peer`]
+    pub fn add_peer_with_tags(
+        &self,
+        ctx: NewPeerContext,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        addr: SocketAddrV4,
+        peer_id_full: NodeIdFull,
+        tags: &[&str],
+    ) -> Result<bool> {
+        if !self.add_peer(ctx, local_id, peer_id, addr, peer_id_full)? {
+            return Ok(false);
+        }
+
+        if let Some(peer) = self.get_peers(local_id)?.get(peer_id) {
+            for tag in tags {
+                peer.add_tag(tag);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Puts the known peer into the group.
+    ///
+    /// Returns `false` if the peer is unknown or already belongs to the group
+    pub fn add_peer_tag(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        tag: &str,
+    ) -> Result<bool> {
+        Ok(match self.get_peers(local_id)?.get(peer_id) {
+            Some(peer) => peer.add_tag(tag),
+            None => false,
+        })
+    }
+
+    /// Removes the known peer from the group.
+    ///
+    /// Returns `false` if the peer is unknown or doesn't belong to the group
+    pub fn remove_peer_tag(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        tag: &str,
+    ) -> Result<bool> {
+        Ok(match self.get_peers(local_id)?.get(peer_id) {
+            Some(peer) => peer.remove_tag(tag),
+            None => false,
+        })
+    }
+
+    /// Returns ids of all known peers from the group
+    pub fn peers_with_tag(&self, local_id: &NodeIdShort, tag: &str) -> Result<Vec<NodeIdShort>> {
+        Ok(self
+            .get_peers(local_id)?
+            .iter()
+            .filter(|peer| peer.has_tag(tag))
+            .map(|peer| *peer.key())
+            .collect())
+    }
+
     /// Removes remote peer.
     ///
     /// NOTE: This method will return an error if there is no peers table
@@ -364,7 +1050,42 @@ impl Node {
                 self.channels_by_id.remove(removed.priority_channel_in_id())
             });
 
-        Ok(peers.remove(peer_id).is_some())
+        self.peer_rtts.remove(peer_id);
+
+        let removed = peers.remove(peer_id).is_some();
+        if removed {
+            self.emit_event(|| NodeEvent::PeerRemoved {
+                local_id: *local_id,
+                peer_id: *peer_id,
+            });
+        }
+        Ok(removed)
+    }
+
+    /// Resets the local state of the peer pair (channel and packets histories)
+    /// and sends `adnl.message.reinit`, so the remote peer does the same.
+    ///
+    /// The channel is created again on the next message.
+    ///
+    /// NOTE: each reinit message has a strictly greater date than the previous one,
+    /// otherwise it is ignored by the remote peer
+    pub fn reinit_peer(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Result<()> {
+        self.reset_peer(local_id, peer_id)?;
+
+        let mut date = 0;
+        let _ = self
+            .last_reinit_date
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                date = std::cmp::max(now(), last + 1);
+                Some(date)
+            });
+
+        self.send_message(
+            local_id,
+            peer_id,
+            proto::adnl::Message::Reinit { date },
+            false,
+        )
     }
 
     /// Searches for remote peer socket address in the known peers
@@ -378,21 +1099,163 @@ impl Node {
         Some(peer.addr())
     }
 
-    /// Matches entries with peer id by socket address
+    /// Returns a snapshot of all known peers for the specified local id
     ///
-    /// NOTE: It is a quite expensive method that iterates over all peers
-    /// and may block new peers from being added during the execution time.
-    /// Use it with caution.
-    pub fn match_peer_addresses<T>(
-        &self,
-        local_id: &NodeIdShort,
-        mut entries: FastHashMap<SocketAddrV4, T>,
-    ) -> Option<FastHashMap<T, NodeIdShort>>
-    where
-        T: std::hash::Hash + Eq,
-    {
-        let peers = self.get_peers(local_id).ok()?;
-
+    /// NOTE: It iterates over all peers and may block new peers from being
+    /// added during the execution time.
+    pub fn peers(&self, local_id: &NodeIdShort) -> Result<Vec<PeerInfo>> {
+        let peers = self.get_peers(local_id)?;
+        Ok(peers
+            .iter()
+            .map(|entry| self.make_peer_info(local_id, entry.key(), entry.value()))
+            .collect())
+    }
+
+    /// Returns a snapshot of the known peer state
+    pub fn peer_info(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Option<PeerInfo> {
+        let peers = self.get_peers(local_id).ok()?;
+        let peer = peers.get(peer_id)?;
+        Some(self.make_peer_info(local_id, peer_id, peer.value()))
+    }
+
+    /// Returns a snapshot of the traffic metrics of all known peers for all local ids
+    ///
+    /// NOTE: It iterates over all peers and may block new peers from being
+    /// added during the execution time.
+    pub fn peer_metrics(&self) -> impl Iterator<Item = PeerMetrics> {
+        let now = now();
+        let mut result = Vec::new();
+        for (local_id, peers) in self.peers.read().iter() {
+            for entry in peers.iter() {
+                let (peer_id, peer) = entry.pair();
+                let traffic = peer.traffic();
+                let receiver_state = peer.receiver_state();
+
+                result.push(PeerMetrics {
+                    local_id: *local_id,
+                    peer_id: *peer_id,
+                    packets_in: traffic.packets_in(),
+                    bytes_in: traffic.bytes_in(),
+                    packets_out: traffic.packets_out(),
+                    bytes_out: traffic.bytes_out(),
+                    decrypt_failures: traffic.decrypt_failures(),
+                    duplicate_packets: receiver_state.history(false).duplicate_packets()
+                        + receiver_state.history(true).duplicate_packets(),
+                    last_activity: traffic.last_activity(),
+                    channel_age_sec: self
+                        .channels_by_peers
+                        .get(peer_id)
+                        .filter(|channel| channel.local_id() == local_id)
+                        .map(|channel| now.saturating_sub(channel.created_at())),
+                });
+            }
+        }
+        result.into_iter()
+    }
+
+    /// Returns statistics of the channel between the local key and the remote peer
+    pub fn channel_stats(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+    ) -> Option<ChannelStats> {
+        let channel = self.channels_by_peers.get(peer_id)?;
+        if channel.local_id() != local_id {
+            return None;
+        }
+        Some(channel.stats())
+    }
+
+    /// Proactively creates a channel with the remote peer.
+    ///
+    /// Returns `Ok(false)` if the channel was not confirmed within the timeout
+    /// or if channels are disabled (see [`NodeOptions::channels_enabled`]).
+    /// If timeout is not specified, it is computed from the measured roundtrip time
+    /// (see [`Node::compute_peer_query_timeout`]).
+    pub async fn establish_channel(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        timeout: Option<u64>,
+    ) -> Result<bool> {
+        let is_ready = || {
+            self.channels_by_peers
+                .get(peer_id)
+                .filter(|channel| channel.local_id() == local_id)
+                .map(|channel| channel.ready())
+                .unwrap_or_default()
+        };
+
+        if is_ready() {
+            return Ok(true);
+        } else if !self.options.channels_enabled {
+            return Ok(false);
+        }
+
+        // NOTE: `CreateChannel` is sent along with the query and
+        // `ConfirmChannel` is received along with the answer
+        self.ping(local_id, peer_id, timeout).await?;
+
+        Ok(is_ready())
+    }
+
+    /// Creates a channel with the remote peer from the pre-shared channel keys.
+    ///
+    /// Both peers must add the same pair of keys (in the opposite order), so the channel
+    /// becomes ready instantly without `CreateChannel`/`ConfirmChannel` roundtrip.
+    /// Static channels are never rekeyed or reset by timeouts, and are used even
+    /// if negotiated channels are disabled (see [`NodeOptions::channels_enabled`]).
+    /// Outgoing packets are encrypted with [`NodeOptions::channel_cipher`].
+    ///
+    /// NOTE: The remote peer must be added first, the channel is removed
+    /// along with it (see [`Node::add_peer`], [`Node::remove_peer`])
+    pub fn add_static_channel(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        channel_key: &ed25519::SecretKey,
+        peer_channel_public_key: ed25519::PublicKey,
+    ) -> Result<()> {
+        if !self.get_peers(local_id)?.contains_key(peer_id) {
+            return Err(NodeError::UnknownPeer.into());
+        }
+
+        let channel = Arc::new(Channel::new_static(
+            *local_id,
+            *peer_id,
+            &ed25519::KeyPair::from(channel_key),
+            peer_channel_public_key,
+        ));
+        channel.set_cipher(self.options.channel_cipher);
+        channel.set_version(self.options.version);
+
+        if let Some(removed) = self.channels_by_peers.insert(*peer_id, channel.clone()) {
+            self.channels_by_id.remove(removed.ordinary_channel_in_id());
+            self.channels_by_id.remove(removed.priority_channel_in_id());
+        }
+        self.insert_channel_receivers(channel.clone());
+        self.emit_channel_established(&channel);
+
+        tracing::trace!(%local_id, %peer_id, "added static channel");
+
+        Ok(())
+    }
+
+    /// Matches entries with peer id by socket address
+    ///
+    /// NOTE: It is a quite expensive method that iterates over all peers
+    /// and may block new peers from being added during the execution time.
+    /// Use it with caution.
+    pub fn match_peer_addresses<T>(
+        &self,
+        local_id: &NodeIdShort,
+        mut entries: FastHashMap<SocketAddrV4, T>,
+    ) -> Option<FastHashMap<T, NodeIdShort>>
+    where
+        T: std::hash::Hash + Eq,
+    {
+        let peers = self.get_peers(local_id).ok()?;
+
         let mut result = FastHashMap::with_capacity_and_hasher(entries.len(), Default::default());
         for peer in peers.iter() {
             if let Some(key) = entries.remove(&peer.addr()) {
@@ -426,6 +1289,36 @@ impl Node {
         }
     }
 
+    /// ADNL query without prefix to the remote peer with the explicit channel priority.
+    ///
+    /// NOTE: In case of timeout returns `Ok(None)`
+    pub async fn query_with_priority<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Q,
+        timeout: Option<u64>,
+        priority: bool,
+    ) -> Result<Option<A>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        match self
+            .query_raw_with_priority(
+                local_id,
+                peer_id,
+                make_query(None, query),
+                timeout,
+                priority,
+            )
+            .await?
+        {
+            Some(answer) => Ok(Some(tl_proto::deserialize(&answer)?)),
+            None => Ok(None),
+        }
+    }
+
     /// ADNL query with prefix to the remote peer
     ///
     /// NOTE: In case of timeout returns `Ok(None)`
@@ -452,6 +1345,9 @@ impl Node {
 
     /// ADNL query to the remote peer
     ///
+    /// If timeout is not specified, it is computed from the measured roundtrip time
+    /// (see [`Node::compute_peer_query_timeout`]).
+    ///
     /// NOTE: In case of timeout returns `Ok(None)`
     pub async fn query_raw(
         &self,
@@ -459,33 +1355,242 @@ impl Node {
         peer_id: &NodeIdShort,
         query: Bytes,
         timeout: Option<u64>,
-    ) -> Result<Option<Vec<u8>>> {
-        let query_id: QueryId = gen_fast_bytes();
-
-        let pending_query = self.queries.add_query(query_id);
-        self.send_message(
+    ) -> Result<Option<Bytes>> {
+        self.query_raw_with_priority(
             local_id,
             peer_id,
-            proto::adnl::Message::Query {
-                query_id: &query_id,
-                query: &query,
-            },
+            query,
+            timeout,
             self.options.force_use_priority_channels,
-        )?;
-        drop(query);
+        )
+        .await
+    }
+
+    /// ADNL query to the remote peer with the explicit channel priority.
+    ///
+    /// See [`Node::query_raw`]
+    pub async fn query_raw_with_priority(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Bytes,
+        timeout: Option<u64>,
+        priority: bool,
+    ) -> Result<Option<Bytes>> {
+        let options = QueryOptions {
+            timeout,
+            priority: Some(priority),
+            ..Default::default()
+        };
+        self.query_raw_with_options(local_id, peer_id, query, options)
+            .await
+    }
+
+    /// ADNL query without prefix to the remote peer with retries.
+    ///
+    /// NOTE: In case of timeout returns `Ok(None)`
+    pub async fn query_with_options<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Q,
+        options: QueryOptions,
+    ) -> Result<Option<A>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        match self
+            .query_raw_with_options(local_id, peer_id, make_query(None, query), options)
+            .await?
+        {
+            Some(answer) => Ok(Some(tl_proto::deserialize(&answer)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sends the same query to all specified peers simultaneously and returns
+    /// the first successful answer. Other queries are cancelled.
+    ///
+    /// Returns `Ok(None)` if there were no answers or an error if all queries failed
+    pub async fn query_first_of<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        peer_ids: &[NodeIdShort],
+        query: Q,
+        options: QueryOptions,
+    ) -> Result<Option<(NodeIdShort, A)>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        use futures_util::stream::{FuturesUnordered, StreamExt};
+
+        let query = make_query(None, query);
+
+        let mut queries = peer_ids
+            .iter()
+            .map(|peer_id| {
+                let query = query.clone();
+                async move {
+                    let answer = self
+                        .query_raw_with_options(local_id, peer_id, query, options)
+                        .await;
+                    (peer_id, answer)
+                }
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let mut last_error = None;
+        let mut has_timeouts = false;
+        while let Some((peer_id, answer)) = queries.next().await {
+            match answer {
+                Ok(Some(answer)) => match tl_proto::deserialize(&answer) {
+                    Ok(answer) => return Ok(Some((*peer_id, answer))),
+                    Err(error) => last_error = Some(error.into()),
+                },
+                Ok(None) => has_timeouts = true,
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        match last_error {
+            Some(error) if !has_timeouts => Err(error),
+            _ => Ok(None),
+        }
+    }
+
+    /// ADNL query to the remote peer with retries.
+    ///
+    /// The query is resent with the same id after each timeout, so the answer
+    /// to any of the attempts is accepted.
+    ///
+    /// NOTE: In case of timeout returns `Ok(None)`
+    #[tracing::instrument(
+        level = "debug",
+        name = "adnl_query",
+        skip_all,
+        fields(
+            %local_id,
+            %peer_id,
+            priority = ?options.priority,
+            constructor = %proto::debug::ConstructorDump(&query),
+        )
+    )]
+    pub async fn query_raw_with_options(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Bytes,
+        options: QueryOptions,
+    ) -> Result<Option<Bytes>> {
+        if self.options.query_coalescing_enabled {
+            match self.queries.coalesce((*local_id, *peer_id, query.clone())) {
+                CoalescedQuery::Leader(in_flight) => {
+                    let answer = self
+                        .send_query_with_options(local_id, peer_id, query, options)
+                        .await;
+                    if let Ok(answer) = &answer {
+                        in_flight.finish(answer);
+                    }
+                    return answer;
+                }
+                CoalescedQuery::Follower(shared) => {
+                    if let Some(answer) = shared.wait().await {
+                        return Ok(answer);
+                    }
+                    // Send own query if the leader query failed
+                }
+            }
+        }
+
+        self.send_query_with_options(local_id, peer_id, query, options)
+            .await
+    }
+
+    async fn send_query_with_options(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        query: Bytes,
+        options: QueryOptions,
+    ) -> Result<Option<Bytes>> {
+        let query_id: QueryId = gen_fast_bytes();
+        let priority = options
+            .priority
+            .unwrap_or(self.options.force_use_priority_channels);
+
+        let send_query = || {
+            self.send_message(
+                local_id,
+                peer_id,
+                proto::adnl::Message::Query {
+                    query_id: &query_id,
+                    query: &query,
+                },
+                priority,
+            )
+        };
+
+        let pending_query = self.queries.add_query(query_id)?;
+        let started_at = Instant::now();
+        send_query()?;
 
         let channel = self
             .channels_by_peers
             .get(peer_id)
             .map(|entry| entry.value().clone());
 
-        let timeout = timeout.unwrap_or(self.options.query_default_timeout_ms);
-        let answer = tokio::time::timeout(Duration::from_millis(timeout), pending_query.wait())
-            .await
-            .ok()
-            .flatten();
+        let mut timeout = match options.timeout {
+            Some(timeout) => timeout,
+            None => self.compute_peer_query_timeout(peer_id),
+        };
+
+        let pending_query = pending_query.wait();
+        tokio::pin!(pending_query);
+
+        let mut attempt = 0;
+        let answer = loop {
+            let mut attempt_timeout = Duration::from_millis(timeout);
+            if let Some(deadline) = options.deadline {
+                attempt_timeout = std::cmp::min(
+                    attempt_timeout,
+                    deadline.saturating_duration_since(Instant::now()),
+                );
+            }
+
+            match tokio::time::timeout(attempt_timeout, &mut pending_query).await {
+                Ok(answer) => break answer,
+                Err(_)
+                    if options
+                        .deadline
+                        .map_or(false, |deadline| Instant::now() >= deadline) =>
+                {
+                    break None
+                }
+                Err(_) if attempt < options.retries => {
+                    attempt += 1;
+                    timeout = (timeout as f64 * options.backoff) as u64;
+                    tracing::trace!(%local_id, %peer_id, attempt, "retrying ADNL query");
+                    send_query()?;
+                }
+                Err(_) => break None,
+            }
+        };
 
-        if answer.is_none() {
+        if answer.is_some() {
+            // NOTE: only unambiguous roundtrips are measured
+            if attempt == 0 {
+                self.peer_rtts
+                    .entry(*peer_id)
+                    .or_default()
+                    .add_sample(started_at.elapsed());
+            }
+        } else {
+            self.emit_event(|| NodeEvent::QueryTimedOut {
+                local_id: *local_id,
+                peer_id: *peer_id,
+            });
             if let Some(channel) = channel {
                 if channel.update_drop_timeout(now(), self.options.channel_reset_timeout_sec) {
                     self.reset_peer(local_id, peer_id)?;
@@ -511,31 +1616,395 @@ impl Node {
         )
     }
 
-    fn get_peers(&self, local_id: &NodeIdShort) -> Result<&Peers> {
-        if let Some(peers) = self.peers.get(local_id) {
-            Ok(peers)
+    /// Sends a one-way ADNL message with the explicit channel priority
+    pub fn send_custom_message_with_priority(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+        priority: bool,
+    ) -> Result<()> {
+        self.send_message(
+            local_id,
+            peer_id,
+            proto::adnl::Message::Custom { data },
+            priority,
+        )
+    }
+
+    /// Sends a one-way ADNL message to all known peers of the local id.
+    /// The message is serialized only once.
+    ///
+    /// Returns the number of peers to which the message was sent
+    pub fn broadcast_custom_message(&self, local_id: &NodeIdShort, data: &[u8]) -> Result<usize> {
+        // NOTE: collect ids first to release peers map before sending
+        let peer_ids = self
+            .get_peers(local_id)?
+            .iter()
+            .map(|peer| *peer.key())
+            .collect::<Vec<_>>();
+
+        Ok(self.send_custom_message_to_peers(local_id, &peer_ids, data))
+    }
+
+    /// Sends a one-way ADNL message to all known peers from the group.
+    /// The message is serialized only once.
+    ///
+    /// Returns the number of peers to which the message was sent
+    pub fn send_custom_message_to_group(
+        &self,
+        local_id: &NodeIdShort,
+        tag: &str,
+        data: &[u8],
+    ) -> Result<usize> {
+        let peer_ids = self.peers_with_tag(local_id, tag)?;
+        Ok(self.send_custom_message_to_peers(local_id, &peer_ids, data))
+    }
+
+    /// Sends ADNL query to all known peers from the group simultaneously.
+    ///
+    /// Returns answers (or errors) for each peer
+    pub async fn query_group<Q, A>(
+        &self,
+        local_id: &NodeIdShort,
+        tag: &str,
+        query: Q,
+        timeout: Option<u64>,
+    ) -> Result<Vec<(NodeIdShort, Result<Option<A>>)>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        let peer_ids = self.peers_with_tag(local_id, tag)?;
+        let query = make_query(None, query);
+
+        let answers = futures_util::future::join_all(peer_ids.iter().map(|peer_id| {
+            let query = query.clone();
+            async move {
+                match self.query_raw(local_id, peer_id, query, timeout).await? {
+                    Some(answer) => Ok(Some(tl_proto::deserialize(&answer)?)),
+                    None => Ok(None),
+                }
+            }
+        }))
+        .await;
+
+        Ok(peer_ids.into_iter().zip(answers).collect())
+    }
+
+    /// Sends a one-way ADNL message and returns a handle which resolves when
+    /// the remote peer confirms the receipt of the message.
+    ///
+    /// If `timeout` is not specified, the computed peer query timeout is used
+    /// (see [`Node::compute_peer_query_timeout`])
+    pub fn send_custom_message_with_confirmation(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+        priority: bool,
+        timeout: Option<u64>,
+    ) -> Result<DeliveryConfirmation> {
+        let sent = self
+            .send_message_tracked(
+                local_id,
+                peer_id,
+                proto::adnl::Message::Custom { data },
+                priority,
+            )?
+            .packet;
+
+        let timeout = Duration::from_millis(match timeout {
+            Some(timeout) => timeout,
+            None => self.compute_peer_query_timeout(peer_id),
+        });
+
+        self.make_delivery_confirmation(local_id, peer_id, sent, timeout)
+    }
+
+    /// Sends a one-way ADNL message and returns a handle which resolves when
+    /// all its parts are confirmed by the remote peer.
+    ///
+    /// Returns `None` if the message was sent as a single packet or if parts
+    /// retransmission is disabled (see [`NodeOptions::transfer_resend_attempts`])
+    pub fn send_custom_message_with_resend(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+        priority: bool,
+    ) -> Result<Option<TransferCompletion>> {
+        self.send_message_tracked(
+            local_id,
+            peer_id,
+            proto::adnl::Message::Custom { data },
+            priority,
+        )
+        .map(|sent| sent.transfer)
+    }
+
+    /// Sends `adnl.ping` query. Returns whether the valid answer was received
+    async fn ping(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        timeout: Option<u64>,
+    ) -> Result<bool> {
+        let value = u64::from_le_bytes(gen_fast_bytes());
+        Ok(matches!(
+            self.query::<_, proto::adnl::Pong>(
+                local_id,
+                peer_id,
+                proto::rpc::AdnlPing { value },
+                timeout
+            )
+            .await?,
+            Some(pong) if pong.value == value
+        ))
+    }
+
+    fn make_delivery_confirmation(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        sent: SentPacket,
+        timeout: Duration,
+    ) -> Result<DeliveryConfirmation> {
+        // Loopback messages are delivered immediately
+        if self.is_local_key(peer_id) {
+            return Ok(DeliveryConfirmation::confirmed(sent.priority));
+        }
+
+        let peers = self.get_peers(local_id)?;
+        let peer = peers.get(peer_id).ok_or(NodeError::UnknownPeer)?;
+        Ok(peer
+            .sender_state()
+            .delivery_confirmation(sent.priority, sent.seqno, timeout))
+    }
+
+    /// Sends the same message to the specified peers. Returns the number of successful sends
+    fn send_custom_message_to_peers(
+        &self,
+        local_id: &NodeIdShort,
+        peer_ids: &[NodeIdShort],
+        data: &[u8],
+    ) -> usize {
+        let message = tl_proto::serialize(proto::adnl::Message::Custom { data });
+        let priority = self.options.force_use_priority_channels;
+
+        let mut sent = 0;
+        for peer_id in peer_ids {
+            match self.send_serialized_message(local_id, peer_id, &message, priority, false) {
+                Ok(_) => sent += 1,
+                Err(error) => {
+                    tracing::trace!(%local_id, %peer_id, ?error, "failed to send message")
+                }
+            }
+        }
+        sent
+    }
+
+    /// Notifies the transfer observer. Returns whether the transfer should continue
+    fn notify_transfer_progress(&self, progress: TransferProgress) -> bool {
+        match &*self.transfer_observer.read() {
+            Some(observer) => observer.on_progress(&progress),
+            None => true,
+        }
+    }
+
+    /// Counts the dropped packet and notifies events streams
+    fn drop_packet(&self, reason: DropReason) {
+        self.packet_drops.record(reason);
+        self.emit_event(|| NodeEvent::PacketRejected { reason });
+    }
+
+    /// Passes the serialized packet to the tap (if any)
+    fn tap_packet(
+        &self,
+        direction: PacketDirection,
+        local_id: &NodeIdShort,
+        peer_id: Option<&NodeIdShort>,
+        priority: bool,
+        data: &[u8],
+    ) {
+        if let Some(tap) = &*self.packet_tap.read() {
+            tap.on_packet(&TappedPacket {
+                direction,
+                local_id,
+                peer_id,
+                priority,
+                timestamp: std::time::SystemTime::now(),
+                data,
+            });
+        }
+    }
+
+    fn get_peers(&self, local_id: &NodeIdShort) -> Result<Arc<Peers>> {
+        if let Some(peers) = self.peers.read().get(local_id) {
+            Ok(peers.clone())
         } else {
             Err(NodeError::PeersNotFound.into())
         }
     }
 
+    /// Whether the id belongs to one of the local keys
+    fn is_local_key(&self, id: &NodeIdShort) -> bool {
+        self.keystore.read().key_by_id(id).is_ok()
+    }
+
+    fn make_peer_info(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        peer: &Peer,
+    ) -> PeerInfo {
+        let channel = self
+            .channels_by_peers
+            .get(peer_id)
+            .filter(|channel| channel.local_id() == local_id);
+        let channel_state = match channel {
+            Some(channel) if channel.ready() => PeerChannelState::Ready,
+            Some(_) => PeerChannelState::Pending,
+            None => PeerChannelState::None,
+        };
+
+        let receiver_state = peer.receiver_state();
+        let sender_state = peer.sender_state();
+
+        PeerInfo {
+            id: *peer.id(),
+            short_id: *peer_id,
+            addr: peer.addr(),
+            addr_priority: peer.addr_priority(),
+            reinit_date: sender_state.reinit_date(),
+            channel_state,
+            in_seqno: receiver_state.history(false).seqno(),
+            in_priority_seqno: receiver_state.history(true).seqno(),
+            out_seqno: sender_state.history(false).seqno(),
+            out_priority_seqno: sender_state.history(true).seqno(),
+            duplicate_packets: receiver_state.history(false).duplicate_packets()
+                + receiver_state.history(true).duplicate_packets(),
+            outdated_packets: receiver_state.history(false).outdated_packets()
+                + receiver_state.history(true).outdated_packets(),
+        }
+    }
+
+    /// Checks whether the channel must be recreated according to the rekey options
+    fn is_rekey_required(&self, channel: &Channel) -> bool {
+        if !channel.ready() || channel.is_static() {
+            return false;
+        }
+
+        let age = now().saturating_sub(channel.created_at());
+        if age < MIN_CHANNEL_REKEY_AGE_SEC {
+            return false;
+        }
+
+        matches!(self.options.channel_rekey_interval_sec, Some(interval) if age >= interval)
+            || matches!(self.options.channel_rekey_bytes, Some(bytes) if channel.encrypted_bytes() >= bytes)
+    }
+
+    /// Replaces the channel with a new one, created with a new local channel key.
+    ///
+    /// New channel is not ready until the remote peer confirms it, so the next
+    /// outgoing packet will be a handshake packet with the `ConfirmChannel` message.
+    fn rekey_channel(&self, local_id: &NodeIdShort, channel: &Arc<Channel>) -> Result<()> {
+        use dashmap::mapref::entry::Entry;
+
+        // Channels are stored by peer id only, so skip channels of another local key
+        if channel.local_id() != local_id {
+            return Ok(());
+        }
+
+        let peer_id = channel.peer_id();
+
+        let peers = self.get_peers(local_id)?;
+        let mut peer = peers.get_mut(peer_id).ok_or(NodeError::UnknownPeer)?;
+
+        let mut entry = match self.channels_by_peers.entry(*peer_id) {
+            // Skip channels which were already replaced
+            Entry::Occupied(entry) if Arc::ptr_eq(entry.get(), channel) => entry,
+            _ => return Ok(()),
+        };
+
+        peer.regenerate_channel_key();
+
+        // NOTE: remote peer only accepts channels with a newer date
+        let date = std::cmp::max(now(), channel.peer_channel_date() + 1);
+        let new_channel = Arc::new(Channel::new(
+            *local_id,
+            *peer_id,
+            peer.channel_key(),
+            *channel.peer_channel_public_key(),
+            date,
+            ChannelCreationContext::CreateChannel,
+        ));
+        new_channel.set_cipher(channel.cipher());
+        new_channel.set_version(channel.version());
+
+        let old_channel = entry.insert(new_channel.clone());
+        self.insert_channel_receivers(new_channel);
+        self.retire_channel(old_channel);
+
+        tracing::trace!(%local_id, %peer_id, "rekeyed channel");
+
+        Ok(())
+    }
+
+    /// Registers incoming channel ids
+    fn insert_channel_receivers(&self, channel: Arc<Channel>) {
+        self.channels_by_id.insert(
+            *channel.ordinary_channel_in_id(),
+            ChannelReceiver::Ordinary(channel.clone()),
+        );
+        self.channels_by_id.insert(
+            *channel.priority_channel_in_id(),
+            ChannelReceiver::Priority(channel),
+        );
+    }
+
+    /// Keeps incoming channel ids of the replaced channel for some time
+    /// to receive packets which are still in flight
+    fn retire_channel(&self, channel: Arc<Channel>) {
+        let channels_by_id = self.channels_by_id.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RETIRED_CHANNEL_TIMEOUT).await;
+
+            let is_same = |_: &AdnlChannelId, receiver: &ChannelReceiver| {
+                Arc::ptr_eq(receiver.channel(), &channel)
+            };
+            channels_by_id.remove_if(channel.ordinary_channel_in_id(), is_same);
+            channels_by_id.remove_if(channel.priority_channel_in_id(), is_same);
+        });
+    }
+
     fn reset_peer(&self, local_id: &NodeIdShort, peer_id: &NodeIdShort) -> Result<()> {
         let peers = self.get_peers(local_id)?;
         let mut peer = peers.get_mut(peer_id).ok_or(NodeError::UnknownPeer)?;
 
         tracing::trace!(%local_id, %peer_id, "resetting peer pair");
 
-        self.channels_by_peers
-            .remove(peer_id)
-            .and_then(|(_, removed)| {
-                self.channels_by_id.remove(removed.ordinary_channel_in_id());
-                self.channels_by_id.remove(removed.priority_channel_in_id())
-            });
-
+        self.remove_peer_channel(peer_id);
         peer.reset();
 
         Ok(())
     }
+
+    /// Removes channel with the remote peer (if it is not static)
+    fn remove_peer_channel(&self, peer_id: &NodeIdShort) {
+        // NOTE: static channels can't be recreated, so they are kept
+        if let Some((_, removed)) = self
+            .channels_by_peers
+            .remove_if(peer_id, |_, channel| !channel.is_static())
+        {
+            self.channels_by_id.remove(removed.ordinary_channel_in_id());
+            self.channels_by_id.remove(removed.priority_channel_in_id());
+            self.emit_event(|| NodeEvent::ChannelReset {
+                local_id: *removed.local_id(),
+                peer_id: *peer_id,
+            });
+        }
+    }
 }
 
 impl Drop for Node {
@@ -556,18 +2025,113 @@ pub struct NodeMetrics {
     pub channels_by_peers_len: usize,
     /// Current multipart transfer count
     pub incoming_transfers_len: usize,
+    /// Memory reserved for the incoming multipart transfers in bytes
+    pub incoming_transfers_memory: usize,
     /// Current queries cache len
     pub query_count: usize,
+    /// Total number of queries without answer
+    pub queries_expired: u64,
+    /// Total number of queries removed by the stale entries sweep
+    pub queries_stale: u64,
+    /// Total number of queries rejected due to the queries cache capacity
+    pub queries_rejected: u64,
+}
+
+/// Traffic metrics of the remote peer
+///
+/// See [`Node::peer_metrics`]
+#[derive(Debug, Copy, Clone)]
+pub struct PeerMetrics {
+    /// Local id which communicates with the peer
+    pub local_id: NodeIdShort,
+    /// Short remote peer id
+    pub peer_id: NodeIdShort,
+    /// Number of received packets (including duplicates)
+    pub packets_in: u64,
+    /// Total size of the received packets in bytes
+    pub bytes_in: u64,
+    /// Number of sent packets
+    pub packets_out: u64,
+    /// Total size of the sent packets in bytes
+    pub bytes_out: u64,
+    /// Number of channel packets which could not be decrypted
+    pub decrypt_failures: u64,
+    /// Number of packets dropped as already received
+    ///
+    /// NOTE: Only counted when [`NodeOptions::packet_history_enabled`] is set
+    pub duplicate_packets: u64,
+    /// Unix timestamp of the last received packet (`0` if there were none)
+    pub last_activity: u32,
+    /// Age of the channel with the peer in seconds
+    pub channel_age_sec: Option<u32>,
+}
+
+/// Remote peer state snapshot
+///
+/// See [`Node::peers`], [`Node::peer_info`]
+#[derive(Debug, Copy, Clone)]
+pub struct PeerInfo {
+    /// Full remote peer id
+    pub id: NodeIdFull,
+    /// Short remote peer id
+    pub short_id: NodeIdShort,
+    /// Selected remote peer address.
+    ///
+    /// Known addresses are ordered by the send failures, the address list
+    /// priority and the update time
+    pub addr: SocketAddrV4,
+    /// Address list priority of the selected address (`0` if unknown)
+    pub addr_priority: u32,
+    /// Last known reinit date of the remote peer (`0` if unknown)
+    pub reinit_date: u32,
+    /// Channel state
+    pub channel_state: PeerChannelState,
+    /// Last received seqno (ordinary)
+    pub in_seqno: u64,
+    /// Last received seqno (priority)
+    pub in_priority_seqno: u64,
+    /// Last sent seqno (ordinary)
+    pub out_seqno: u64,
+    /// Last sent seqno (priority)
+    pub out_priority_seqno: u64,
+    /// Number of packets dropped as already received
+    ///
+    /// NOTE: Only counted when [`NodeOptions::packet_history_enabled`] is set
+    pub duplicate_packets: u64,
+    /// Number of packets dropped as out of the history window
+    ///
+    /// NOTE: Only counted when [`NodeOptions::packet_history_enabled`] is set
+    pub outdated_packets: u64,
+}
+
+/// Channel state with the remote peer
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PeerChannelState {
+    /// There is no channel, all packets are sent as handshake packets
+    None,
+    /// Channel was created but was not confirmed by the remote peer yet
+    Pending,
+    /// Channel was confirmed by both sides
+    Ready,
 }
 
 struct InitializationState {
-    socket: Arc<tokio::net::UdpSocket>,
+    socket: Arc<NodeSocket>,
     /// Receiver end of the outgoing packets queue
     sender_queue_rx: SenderQueueRx,
+    /// Receiver end of the messages queue between local ids
+    loopback_rx: LoopbackQueueRx,
     message_subscribers: Vec<Arc<dyn MessageSubscriber>>,
     query_subscribers: Vec<Arc<dyn QuerySubscriber>>,
+    background_tasks: Vec<Arc<dyn BackgroundTask>>,
 }
 
+/// Channels can't be recreated more often than this
+const MIN_CHANNEL_REKEY_AGE_SEC: u32 = 10;
+
+/// How long incoming packets are accepted for the replaced channel
+const RETIRED_CHANNEL_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn make_query<T>(prefix: Option<&[u8]>, query: T) -> Bytes
 where
     T: TlWrite,
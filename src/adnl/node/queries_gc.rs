@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::adnl::Node;
+
+impl Node {
+    /// Starts a process that periodically removes stale pending queries
+    /// and idle query rate limiter entries
+    pub(super) fn start_queries_gc(self: &Arc<Self>) {
+        use futures_util::future::{select, Either};
+
+        let complete_signal = self.cancellation_token.clone();
+        let queries = Arc::downgrade(&self.queries);
+        let query_limiter = Arc::downgrade(&self.query_limiter);
+        let ttl_sec = self.options.query_stale_timeout_sec;
+
+        tokio::spawn(async move {
+            tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+            loop {
+                tokio::pin!(let sleep = tokio::time::sleep(QUERIES_GC_INTERVAL););
+                if let Either::Right(_) = select(sleep, &mut cancelled).await {
+                    break;
+                }
+
+                let queries = match queries.upgrade() {
+                    Some(queries) => queries,
+                    None => break,
+                };
+
+                let removed = queries.remove_stale(ttl_sec);
+                if removed > 0 {
+                    tracing::debug!(removed, "removed stale ADNL queries");
+                }
+
+                if let Some(query_limiter) = query_limiter.upgrade() {
+                    query_limiter.remove_idle();
+                }
+            }
+
+            tracing::debug!("queries gc loop finished");
+        });
+    }
+}
+
+const QUERIES_GC_INTERVAL: Duration = Duration::from_secs(60);
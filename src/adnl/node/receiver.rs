@@ -1,18 +1,23 @@
 use std::borrow::Cow;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use bytes::Bytes;
 use everscale_crypto::ed25519;
 use tl_proto::TlRead;
-use tokio::net::UdpSocket;
 
+use super::sender::{LoopbackMessage, LoopbackQueueRx};
 use crate::adnl::channel::*;
+use crate::adnl::events::NodeEvent;
 use crate::adnl::handshake::*;
-use crate::adnl::node_id::{NodeIdFull, NodeIdShort};
+use crate::adnl::node_id::{NodeIdFull, NodeIdFullError, NodeIdShort};
+use crate::adnl::packet_drops::DropReason;
+use crate::adnl::packet_tap::PacketDirection;
 use crate::adnl::packet_view::*;
 use crate::adnl::peer::*;
 use crate::adnl::queries_cache::*;
+use crate::adnl::socket::NodeSocket;
 use crate::adnl::transfer::*;
 use crate::adnl::Node;
 use crate::proto;
@@ -23,7 +28,8 @@ impl Node {
     /// Starts a process that listens for and processes packets from the UDP socket
     pub(super) fn start_receiver(
         self: &Arc<Self>,
-        socket: Arc<UdpSocket>,
+        socket: Arc<NodeSocket>,
+        loopback_rx: LoopbackQueueRx,
         message_subscribers: Vec<Arc<dyn MessageSubscriber>>,
         query_subscribers: Vec<Arc<dyn QuerySubscriber>>,
     ) {
@@ -44,6 +50,42 @@ impl Node {
             query_subscribers,
         });
 
+        // Start processing messages between local ids
+        tokio::spawn({
+            let ctx = ctx.clone();
+            let complete_signal = complete_signal.clone();
+            let mut loopback_rx = loopback_rx;
+
+            async move {
+                tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+                while let Some(message) = {
+                    tokio::pin!(let recv = loopback_rx.recv(););
+                    match select(recv, &mut cancelled).await {
+                        Either::Left((message, _)) => message,
+                        Either::Right(_) => None,
+                    }
+                } {
+                    let ctx = ctx.clone();
+                    tokio::spawn(async move {
+                        if let Err(error) = ctx
+                            .node
+                            .handle_loopback_message(
+                                message,
+                                &ctx.message_subscribers,
+                                &ctx.query_subscribers,
+                            )
+                            .await
+                        {
+                            tracing::trace!(?error, "failed to handle loopback message");
+                        }
+                    });
+                }
+
+                tracing::debug!("loopback loop finished");
+            }
+        });
+
         tokio::spawn(async move {
             let mut buffer = None;
 
@@ -64,6 +106,7 @@ impl Node {
                     Either::Right(_) => break,
                 };
 
+                let received_at = Instant::now();
                 let len = match result {
                     Ok((0, _)) => continue,
                     Ok((len, _)) => len,
@@ -90,6 +133,7 @@ impl Node {
                         .node
                         .handle_received_data(
                             PacketView::from(buffer.as_mut_slice()),
+                            received_at,
                             &ctx.message_subscribers,
                             &ctx.query_subscribers,
                         )
@@ -105,57 +149,120 @@ impl Node {
     }
 
     /// Decrypts and processes received data
+    #[tracing::instrument(
+        level = "trace",
+        name = "adnl_packet",
+        skip_all,
+        fields(
+            len = data.len(),
+            local_id = tracing::field::Empty,
+            peer_id = tracing::field::Empty,
+            priority = tracing::field::Empty,
+        )
+    )]
     async fn handle_received_data(
         self: &Arc<Self>,
         mut data: PacketView<'_>,
+        received_at: Instant,
         message_subscribers: &[Arc<dyn MessageSubscriber>],
         query_subscribers: &[Arc<dyn QuerySubscriber>],
     ) -> Result<()> {
+        let packet_len = data.len();
+
         // Decrypt packet and extract peers
-        let (priority, local_id, peer_id, version) = if let Some((local_id, version)) =
-            parse_handshake_packet(self.keystore.keys(), &mut data)?
-        {
-            (false, local_id, None, version)
-        } else if let Some(channel) = self.channels_by_id.get(&data[0..32]) {
-            let (channel, priority) = match channel.value() {
-                ChannelReceiver::Priority(channel) => (channel, true),
-                ChannelReceiver::Ordinary(channel) => (channel, false),
+        let handshake = parse_handshake_packet(
+            self.keystore.read().keys(),
+            &self.handshake_secrets,
+            &mut data,
+        )
+        .map_err(|e| self.reject_packet(e.into()))?;
+        let (priority, local_id, peer_id, version, channel) =
+            if let Some((local_id, version)) = handshake {
+                (false, local_id, None, version, None)
+            } else if let Some(channel) = self.channels_by_id.get(&data[0..32]) {
+                let (channel, priority) = match channel.value() {
+                    ChannelReceiver::Priority(channel) => (channel.clone(), true),
+                    ChannelReceiver::Ordinary(channel) => (channel.clone(), false),
+                };
+                let version = match channel.decrypt(&mut data, priority) {
+                    Ok(version) => version,
+                    Err(e) => {
+                        if let Ok(peers) = self.get_peers(channel.local_id()) {
+                            if let Some(peer) = peers.get(channel.peer_id()) {
+                                peer.traffic().on_decrypt_failure();
+                            }
+                        }
+                        return Err(self.reject_packet(e.into()));
+                    }
+                };
+                if channel.set_ready() {
+                    self.emit_channel_established(&channel);
+                }
+                channel.reset_drop_timeout();
+                channel.refresh_last_activity(now());
+                (
+                    priority,
+                    *channel.local_id(),
+                    Some(*channel.peer_id()),
+                    version,
+                    Some(channel),
+                )
+            } else {
+                tracing::trace!(
+                    key_id = hex::encode(&data[0..32]),
+                    "received message to unknown key ID",
+                );
+                self.drop_packet(DropReason::UnknownKey);
+                return Ok(());
             };
-            let version = channel.decrypt(&mut data, priority)?;
-            channel.set_ready();
-            channel.reset_drop_timeout();
-            (
-                priority,
-                *channel.local_id(),
-                Some(*channel.peer_id()),
-                version,
-            )
-        } else {
-            tracing::trace!(
-                key_id = hex::encode(&data[0..32]),
-                "received message to unknown key ID",
-            );
-            return Ok(());
-        };
+
+        let span = tracing::Span::current();
+        span.record("local_id", tracing::field::display(&local_id));
+        span.record("priority", priority);
+
+        self.tap_packet(
+            PacketDirection::Incoming,
+            &local_id,
+            peer_id.as_ref(),
+            priority,
+            data.as_slice(),
+        );
 
         if let Some(version) = version {
-            if version != ADNL_INITIAL_VERSION {
-                return Err(AdnlReceiverError::UnsupportedVersion.into());
+            if version < self.options.min_version || version > self.options.max_version {
+                return Err(
+                    self.reject_packet(AdnlReceiverError::UnsupportedVersion(version).into())
+                );
             }
         }
 
         // Parse packet
         let mut packet =
             tl_proto::deserialize::<proto::adnl::IncomingPacketContents>(data.as_slice())
-                .map_err(|_| AdnlReceiverError::InvalidPacket)?;
+                .map_err(|_| self.reject_packet(AdnlReceiverError::InvalidPacket.into()))?;
 
         // Validate packet
-        let peer_id = match self.check_packet(&data, &mut packet, &local_id, peer_id, priority)? {
+        let peer_id = match self
+            .check_packet(&data, packet_len, &mut packet, &local_id, peer_id, priority)
+            .map_err(|e| self.reject_packet(e))?
+        {
             // New packet
             Some(peer_id) => peer_id,
             // Repeated packet
-            None => return Ok(()),
+            None => {
+                self.drop_packet(DropReason::Duplicate);
+                return Ok(());
+            }
         };
+        span.record("peer_id", tracing::field::display(&peer_id));
+
+        tracing::trace!(
+            target: "adnl::packets",
+            %local_id,
+            %peer_id,
+            packet = %proto::debug::PacketDump(&packet),
+            "received packet"
+        );
 
         // Process message(s)
         for message in packet.messages {
@@ -163,6 +270,7 @@ impl Node {
                 &local_id,
                 &peer_id,
                 message,
+                received_at,
                 message_subscribers,
                 query_subscribers,
                 priority,
@@ -170,15 +278,69 @@ impl Node {
             .await?;
         }
 
+        // Negotiate version of the channel packets.
+        // NOTE: only handshake packets contain the version advertised by the peer
+        if let Some(channel) = handshake.and_then(|_| self.channels_by_peers.get(&peer_id)) {
+            if channel.local_id() == &local_id {
+                channel.set_version(negotiate_version(self.options.version, version));
+            }
+        }
+
+        // Advertise ChaCha20-Poly1305 support over the ready channel, but switch
+        // to it only after the peer has used it too (i.e. both sides prefer it)
+        if let Some(channel) = &channel {
+            if self.options.channel_cipher == ChannelCipher::ChaCha20Poly1305 {
+                if version == Some(ADNL_CHACHA20_POLY1305_VERSION) {
+                    channel.set_cipher(ChannelCipher::ChaCha20Poly1305);
+                }
+                if channel.start_cipher_probe() {
+                    self.send_cipher_probe(channel)?;
+                }
+            }
+        }
+
         // Done
         Ok(())
     }
 
+    /// Counts the packet dropped with the error
+    fn reject_packet(&self, error: anyhow::Error) -> anyhow::Error {
+        self.drop_packet(drop_reason(&error));
+        error
+    }
+
+    /// Processes message from one local id to another
+    async fn handle_loopback_message(
+        self: &Arc<Self>,
+        message: LoopbackMessage,
+        message_subscribers: &[Arc<dyn MessageSubscriber>],
+        query_subscribers: &[Arc<dyn QuerySubscriber>],
+    ) -> Result<()> {
+        let data = tl_proto::deserialize::<proto::adnl::Message>(&message.data)?;
+        self.process_message(
+            &message.local_id,
+            &message.peer_id,
+            data,
+            message.queued_at,
+            message_subscribers,
+            query_subscribers,
+            message.priority,
+        )
+        .await
+    }
+
+    #[tracing::instrument(
+        level = "trace",
+        name = "adnl_message",
+        skip_all,
+        fields(%local_id, %peer_id, priority, constructor = tracing::field::Empty)
+    )]
     async fn process_message(
         self: &Arc<Self>,
         local_id: &NodeIdShort,
         peer_id: &NodeIdShort,
         message: proto::adnl::Message<'_>,
+        received_at: Instant,
         message_subscribers: &[Arc<dyn MessageSubscriber>],
         query_subscribers: &[Arc<dyn QuerySubscriber>],
         priority: bool,
@@ -197,7 +359,10 @@ impl Node {
             let transfer = match self.incoming_transfers.entry(transfer_id) {
                 // Create new transfer state if it was a new incoming transfer
                 Entry::Vacant(entry) => {
-                    let entry = entry.insert(Arc::new(Transfer::new(total_size as usize)));
+                    let permit = self
+                        .transfers_budget
+                        .try_acquire(peer_id, total_size as usize)?;
+                    let entry = entry.insert(Arc::new(Transfer::new(permit)));
                     let transfer = entry.value().clone();
                     tracing::debug!(
                         %local_id,
@@ -240,28 +405,63 @@ impl Node {
             transfer.timings().refresh();
 
             // Update transfer
-            match transfer.add_part(offset as usize, data.to_vec(), &transfer_id) {
-                Ok(Some(message)) => {
-                    self.incoming_transfers.remove(&transfer_id);
-                    Some(message)
-                }
+            let message = match transfer.add_part(offset as usize, data.to_vec(), &transfer_id) {
+                Ok(message) => message,
                 Err(error) => {
                     self.incoming_transfers.remove(&transfer_id);
                     return Err(error.into());
                 }
-                _ => return Ok(()),
+            };
+
+            if message.is_some() || !transfer.is_completed() {
+                let progress = TransferProgress {
+                    transfer_id: &transfer_id,
+                    direction: TransferDirection::Incoming,
+                    local_id,
+                    peer_id,
+                    bytes_done: transfer.received_len(),
+                    total: transfer.total_len(),
+                };
+                if !self.notify_transfer_progress(progress) && message.is_none() {
+                    // NOTE: aborted transfer is removed by timeout to ignore remaining parts
+                    transfer.abort();
+                    return Err(TransferError::Aborted.into());
+                }
+            }
+
+            match message {
+                Some(message) => {
+                    // NOTE: completed transfer is removed by timeout to ignore resent parts
+                    if self.options.transfer_resend_attempts == 0 {
+                        self.incoming_transfers.remove(&transfer_id);
+                    }
+                    Some(message)
+                }
+                None => return Ok(()),
             }
         } else {
             None
         };
-        let alt_message = match &alt_message {
+        let alt_buffer = alt_message.map(Bytes::from);
+        let alt_message = match &alt_buffer {
             Some(buffer) => Some(tl_proto::deserialize(buffer)?),
             None => None,
         };
 
         // Process message
-        match alt_message.unwrap_or(message) {
+        let message = alt_message.unwrap_or(message);
+        tracing::Span::current().record(
+            "constructor",
+            tracing::field::display(proto::debug::MessageConstructorDump(&message)),
+        );
+
+        match message {
             proto::adnl::Message::Answer { query_id, answer } => {
+                // NOTE: answers from the reassembled messages are not copied
+                let answer = match &alt_buffer {
+                    Some(buffer) => buffer.slice_ref(answer),
+                    None => Bytes::copy_from_slice(answer),
+                };
                 self.process_message_answer(query_id, answer);
                 Ok(())
             }
@@ -285,29 +485,43 @@ impl Node {
                     local_id,
                     peer_id,
                 };
-                if process_message_custom(ctx, message_subscribers, data).await? {
+                if process_message_custom(ctx, message_subscribers, data, received_at).await?
+                    || self.forward_custom_message(local_id, peer_id, data).await
+                {
                     Ok(())
                 } else {
                     Err(AdnlReceiverError::NoSubscribersForCustomMessage.into())
                 }
             }
-            proto::adnl::Message::Nop => Ok(()),
+            // NOTE: reinit is handled while checking the packet
+            proto::adnl::Message::Nop | proto::adnl::Message::Reinit { .. } => Ok(()),
             proto::adnl::Message::Query { query_id, query } => {
                 let ctx = SubscriberContext {
                     adnl: self,
                     local_id,
                     peer_id,
                 };
-                match process_query(ctx, query_subscribers, Cow::Borrowed(query)).await? {
-                    QueryProcessingResult::Processed(Some(answer)) => self.send_message(
-                        local_id,
-                        peer_id,
-                        proto::adnl::Message::Answer {
-                            query_id,
-                            answer: &answer,
-                        },
-                        priority,
-                    ),
+                match process_query(ctx, query_subscribers, Cow::Borrowed(query), received_at)
+                    .await?
+                {
+                    // NOTE: large answers must be requested via RLDP
+                    QueryProcessingResult::Processed(Some(answer))
+                        if answer.len() > self.options.max_answer_size =>
+                    {
+                        Err(AdnlReceiverError::AnswerTooBig(answer.len()).into())
+                    }
+                    QueryProcessingResult::Processed(Some(answer)) => {
+                        let answer = answer.collect().await?;
+                        self.send_message(
+                            local_id,
+                            peer_id,
+                            proto::adnl::Message::Answer {
+                                query_id,
+                                answer: &answer,
+                            },
+                            priority,
+                        )
+                    }
                     QueryProcessingResult::Processed(None) => Ok(()),
                     QueryProcessingResult::Rejected => {
                         Err(AdnlReceiverError::NoSubscribersForQuery.into())
@@ -318,7 +532,34 @@ impl Node {
         }
     }
 
-    fn process_message_answer(&self, query_id: &QueryId, answer: &[u8]) {
+    /// Passes the custom message to the stream of the local id (if any).
+    /// Returns whether the message was accepted
+    async fn forward_custom_message(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+    ) -> bool {
+        let tx = match self.custom_messages.get(local_id) {
+            Some(tx) => tx.clone(),
+            None => return false,
+        };
+
+        if tx
+            .send((*peer_id, Bytes::copy_from_slice(data)))
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+
+        // Remove closed stream unless it was already replaced
+        self.custom_messages
+            .remove_if(local_id, |_, tx| tx.is_closed());
+        false
+    }
+
+    fn process_message_answer(&self, query_id: &QueryId, answer: Bytes) {
         self.queries.update_query(query_id, answer);
     }
 
@@ -358,6 +599,7 @@ impl Node {
     fn check_packet(
         &self,
         raw_packet: &PacketView<'_>,
+        packet_len: usize,
         packet: &mut proto::adnl::IncomingPacketContents<'_>,
         local_id: &NodeIdShort,
         peer_id: Option<NodeIdShort>,
@@ -415,11 +657,13 @@ impl Node {
 
             if let Some(list) = &packet.address {
                 let addr = parse_address_list(list, self.options.clock_tolerance_sec)?;
-                self.add_peer(
+                self.add_peer_impl(
                     NewPeerContext::AdnlPacket,
                     local_id,
                     &peer_id,
                     addr,
+                    list.priority,
+                    list.version,
                     full_id,
                 )?;
             }
@@ -480,15 +724,34 @@ impl Node {
             }
         }
 
-        if self.options.packet_history_enabled {
-            if let Some(seqno) = packet.seqno {
-                if !peer
-                    .receiver_state()
-                    .history(priority)
-                    .deliver_packet(seqno)
-                {
-                    return Ok(None);
-                }
+        // NOTE: explicit reinit must be handled before the seqno check,
+        // because the remote peer starts with new packets histories.
+        // Replayed reinit messages are rejected by their date
+        let reinit_date = packet.messages.iter().find_map(|message| match message {
+            proto::adnl::Message::Reinit { date } => Some(*date),
+            _ => None,
+        });
+        if let Some(date) = reinit_date {
+            if date > now() + self.options.clock_tolerance_sec {
+                return Err(AdnlPacketError::SrcReinitDateTooNew.into());
+            }
+            if !peer.reinit_sender(date) {
+                return Err(AdnlPacketError::SrcReinitDateTooOld.into());
+            }
+
+            tracing::debug!(%local_id, %peer_id, date, "peer reinitialized");
+            self.remove_peer_channel(&peer_id);
+        }
+
+        peer.traffic().on_received(packet_len);
+
+        if let Some(seqno) = packet.seqno {
+            let history = peer.receiver_state().history(priority);
+            if !self.options.packet_history_enabled {
+                // Still track the last seqno to confirm received packets
+                history.update_seqno(seqno);
+            } else if !history.deliver_packet(seqno) {
+                return Ok(None);
             }
         }
 
@@ -497,6 +760,7 @@ impl Node {
             if confirm_seqno > sender_seqno {
                 return Err(AdnlPacketError::ConfirmationSeqnoTooNew.into());
             }
+            peer.sender_state().confirm_seqno(priority, confirm_seqno);
         }
 
         Ok(Some(peer_id))
@@ -512,6 +776,10 @@ impl Node {
     ) -> Result<()> {
         use dashmap::mapref::entry::Entry;
 
+        if !self.options.channels_enabled {
+            return Ok(());
+        }
+
         let peers = self.get_peers(local_id)?;
         let peer = match peers.get(peer_id) {
             Some(peer) => peer,
@@ -523,9 +791,15 @@ impl Node {
             Entry::Occupied(mut entry) => {
                 let channel = entry.get();
 
+                // Static channels are not replaced by the negotiated ones
+                if channel.is_static() {
+                    tracing::trace!(%local_id, %peer_id, "ignoring channel for the static one");
+                    return Ok(());
+                }
+
                 if channel.is_still_valid(&peer_channel_public_key, peer_channel_date) {
-                    if context == ChannelCreationContext::ConfirmChannel {
-                        channel.set_ready();
+                    if context == ChannelCreationContext::ConfirmChannel && channel.set_ready() {
+                        self.emit_channel_established(channel);
                     }
                     return Ok(());
                 }
@@ -540,19 +814,8 @@ impl Node {
                 ));
 
                 let old_channel = entry.insert(new_channel.clone());
-                self.channels_by_id
-                    .remove(old_channel.ordinary_channel_in_id());
-                self.channels_by_id
-                    .remove(old_channel.priority_channel_in_id());
-
-                self.channels_by_id.insert(
-                    *new_channel.ordinary_channel_in_id(),
-                    ChannelReceiver::Ordinary(new_channel.clone()),
-                );
-                self.channels_by_id.insert(
-                    *new_channel.priority_channel_in_id(),
-                    ChannelReceiver::Priority(new_channel),
-                );
+                self.insert_channel_receivers(new_channel);
+                self.retire_channel(old_channel);
             }
             Entry::Vacant(entry) => {
                 let new_channel = entry
@@ -565,48 +828,95 @@ impl Node {
                         context,
                     )))
                     .clone();
-                self.channels_by_id.insert(
-                    *new_channel.ordinary_channel_in_id(),
-                    ChannelReceiver::Ordinary(new_channel.clone()),
-                );
-                self.channels_by_id.insert(
-                    *new_channel.priority_channel_in_id(),
-                    ChannelReceiver::Priority(new_channel),
-                );
+                self.insert_channel_receivers(new_channel);
             }
         }
 
+        // NOTE: confirmed channels are created ready
+        if context == ChannelCreationContext::ConfirmChannel {
+            self.emit_event(|| NodeEvent::ChannelEstablished {
+                local_id: *local_id,
+                peer_id: *peer_id,
+            });
+        }
+
         tracing::trace!(%local_id, %peer_id, "{context} channel");
 
         Ok(())
     }
 }
 
+/// Classifies the error of the incoming packet processing
+fn drop_reason(error: &anyhow::Error) -> DropReason {
+    if error.is::<HandshakeError>() {
+        DropReason::InvalidHandshake
+    } else if error.is::<AdnlChannelError>() {
+        DropReason::InvalidChannelPacket
+    } else if error.is::<AdnlAddressListError>() {
+        DropReason::InvalidAddressList
+    } else if error.is::<NodeIdFullError>() {
+        DropReason::InvalidPacket
+    } else if let Some(error) = error.downcast_ref::<AdnlReceiverError>() {
+        match error {
+            AdnlReceiverError::UnsupportedVersion(_) => DropReason::UnsupportedVersion,
+            AdnlReceiverError::InvalidPacket => DropReason::InvalidPacket,
+            _ => DropReason::Other,
+        }
+    } else if let Some(error) = error.downcast_ref::<AdnlPacketError>() {
+        match error {
+            AdnlPacketError::ExplicitSourceForChannel
+            | AdnlPacketError::InvalidPeerId
+            | AdnlPacketError::NoKeyDataInPacket => DropReason::InvalidPacket,
+            AdnlPacketError::UnknownChannel => DropReason::UnknownChannel,
+            AdnlPacketError::UnknownPeer => DropReason::UnknownPeer,
+            AdnlPacketError::DstReinitDateTooNew
+            | AdnlPacketError::DstReinitDateTooOld
+            | AdnlPacketError::SrcReinitDateTooNew
+            | AdnlPacketError::SrcReinitDateTooOld => DropReason::InvalidReinitDate,
+            AdnlPacketError::ConfirmationSeqnoTooNew => DropReason::InvalidConfirmation,
+            AdnlPacketError::SignatureNotFound | AdnlPacketError::InvalidSignature => {
+                DropReason::InvalidSignature
+            }
+        }
+    } else {
+        DropReason::Other
+    }
+}
+
 /// Duplicated channel
 pub enum ChannelReceiver {
     Ordinary(Arc<Channel>),
     Priority(Arc<Channel>),
 }
 
+impl ChannelReceiver {
+    #[inline(always)]
+    pub fn channel(&self) -> &Arc<Channel> {
+        match self {
+            Self::Ordinary(channel) | Self::Priority(channel) => channel,
+        }
+    }
+}
+
 async fn process_message_custom<'a>(
     ctx: SubscriberContext<'a>,
     subscribers: &[Arc<dyn MessageSubscriber>],
     data: &[u8],
+    received_at: Instant,
 ) -> Result<bool> {
     let constructor = u32::read_from(data, &mut 0)?;
+    let slow_log = SlowSubscriberLog::new(ctx, "message", data, received_at);
     for subscriber in subscribers {
-        if subscriber
-            .try_consume_custom(ctx, constructor, data)
-            .await?
-        {
+        let started_at = Instant::now();
+        let consumed = subscriber.try_consume_custom(ctx, constructor, data).await;
+        slow_log.check(subscriber.name(), started_at.elapsed());
+        if consumed? {
             return Ok(true);
         }
     }
     Ok(false)
 }
 
-const ADNL_INITIAL_VERSION: u16 = 0;
-
 #[derive(thiserror::Error, Debug)]
 enum AdnlReceiverError {
     #[error("Invalid packet")]
@@ -619,8 +929,10 @@ enum AdnlReceiverError {
     NoSubscribersForCustomMessage,
     #[error("No subscribers for query")]
     NoSubscribersForQuery,
-    #[error("Unsupported version")]
-    UnsupportedVersion,
+    #[error("Answer is too big for ADNL: {0} bytes")]
+    AnswerTooBig(usize),
+    #[error("Unsupported version: {0}")]
+    UnsupportedVersion(u16),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -650,3 +962,275 @@ enum AdnlPacketError {
     #[error("Invalid signature")]
     InvalidSignature,
 }
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::adnl::{Keystore, NewPeerContext, NodeOptions};
+    use crate::test_utils::{connect_nodes, MemoryNetwork};
+
+    fn localhost() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0)
+    }
+
+    fn make_node(
+        network: &MemoryNetwork,
+        options: NodeOptions,
+    ) -> (Arc<Node>, NodeIdShort, NodeIdFull) {
+        let key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let keystore = Keystore::builder()
+            .with_tagged_keys([(key.to_bytes(), 0)])
+            .unwrap()
+            .build();
+        let node = network
+            .create_node(localhost(), keystore, options, None)
+            .unwrap();
+        let key = node.key_by_tag(0).unwrap();
+        (node, *key.id(), *key.full_id())
+    }
+
+    #[tokio::test]
+    async fn reinit_replay() {
+        let network = MemoryNetwork::new();
+        let options = NodeOptions {
+            channels_enabled: false,
+            ..Default::default()
+        };
+        let (left, left_id, left_full_id) = make_node(&network, options);
+        let (right, right_id, right_full_id) = make_node(&network, options);
+
+        // All packets from the left node go through the relay
+        let relay = network.bind(localhost()).unwrap();
+        left.add_peer(
+            NewPeerContext::AdnlPacket,
+            &left_id,
+            &right_id,
+            relay.local_addr(),
+            right_full_id,
+        )
+        .unwrap();
+        right
+            .add_peer(
+                NewPeerContext::AdnlPacket,
+                &right_id,
+                &left_id,
+                left.socket_addr(),
+                left_full_id,
+            )
+            .unwrap();
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let relay_packet = || async {
+            let mut buffer = [0; 2048];
+            let (len, _) = relay.recv_from(&mut buffer).await.unwrap();
+            buffer[..len].to_vec()
+        };
+        let settle = || tokio::time::sleep(Duration::from_millis(50));
+
+        left.reinit_peer(&left_id, &right_id).unwrap();
+        let packet = relay_packet().await;
+
+        relay.send_to(&packet, right.socket_addr());
+        settle().await;
+        assert_eq!(right.packet_drop_metrics().total(), 0);
+
+        // Replayed reinit packet is dropped
+        relay.send_to(&packet, right.socket_addr());
+        settle().await;
+        let drops = right.packet_drop_metrics();
+        assert_eq!(drops.get(DropReason::InvalidReinitDate), 1);
+        assert_eq!(drops.total(), 1);
+
+        // Next reinit is accepted
+        left.reinit_peer(&left_id, &right_id).unwrap();
+        let packet = relay_packet().await;
+        relay.send_to(&packet, right.socket_addr());
+        settle().await;
+        assert_eq!(right.packet_drop_metrics().total(), 1);
+    }
+
+    #[tokio::test]
+    async fn node_events() {
+        use futures_util::StreamExt;
+
+        let network = MemoryNetwork::new();
+        let (left, left_id, _) = make_node(&network, Default::default());
+        let (right, right_id, _) = make_node(&network, Default::default());
+        connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+        left.start().unwrap();
+        right.start().unwrap();
+
+        assert!(left
+            .establish_channel(&left_id, &right_id, Some(1000))
+            .await
+            .unwrap());
+
+        let mut events = right.events();
+        let timeout = Duration::from_secs(1);
+        // Packets to the unknown key are dropped without an error
+        let relay = network.bind(localhost()).unwrap();
+        relay.send_to(&[0xaa; 256], right.socket_addr());
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await.unwrap(),
+            Some(NodeEvent::PacketRejected {
+                reason: DropReason::UnknownKey
+            })
+        );
+
+        // Channels are reset with the local key
+        assert!(right.delete_key(&right_id));
+        assert_eq!(
+            tokio::time::timeout(timeout, events.next()).await.unwrap(),
+            Some(NodeEvent::ChannelReset {
+                local_id: right_id,
+                peer_id: left_id,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_cipher_negotiation() {
+        for (left_cipher, right_cipher, expected) in [
+            (
+                ChannelCipher::ChaCha20Poly1305,
+                ChannelCipher::ChaCha20Poly1305,
+                ChannelCipher::ChaCha20Poly1305,
+            ),
+            (
+                ChannelCipher::ChaCha20Poly1305,
+                ChannelCipher::AesCtr,
+                ChannelCipher::AesCtr,
+            ),
+        ] {
+            let network = MemoryNetwork::new();
+            let options = |channel_cipher| NodeOptions {
+                channel_cipher,
+                ..Default::default()
+            };
+            let (left, left_id, _) = make_node(&network, options(left_cipher));
+            let (right, right_id, _) = make_node(&network, options(right_cipher));
+            connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+            left.start().unwrap();
+            right.start().unwrap();
+
+            assert!(left
+                .establish_channel(&left_id, &right_id, Some(1000))
+                .await
+                .unwrap());
+
+            // Channel packets in both directions
+            for value in 0..3 {
+                let answer = left
+                    .query::<_, proto::adnl::Pong>(
+                        &left_id,
+                        &right_id,
+                        proto::rpc::AdnlPing { value },
+                        Some(1000),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(answer.map(|pong| pong.value), Some(value));
+            }
+
+            let left_stats = left.channel_stats(&left_id, &right_id).unwrap();
+            let right_stats = right.channel_stats(&right_id, &left_id).unwrap();
+            assert_eq!(left_stats.cipher, expected);
+            assert_eq!(right_stats.cipher, expected);
+            assert_eq!(left.packet_drop_metrics().total(), 0);
+            assert_eq!(right.packet_drop_metrics().total(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn externally_signed_handshake() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::adnl::Signer;
+
+        struct LocalSigner(ed25519::KeyPair);
+
+        #[async_trait::async_trait]
+        impl Signer for LocalSigner {
+            fn public_key(&self) -> &ed25519::PublicKey {
+                &self.0.public_key
+            }
+
+            async fn sign_raw(&self, data: &[u8]) -> Result<[u8; 64]> {
+                Ok(self.0.sign_raw(data))
+            }
+        }
+
+        #[derive(Default)]
+        struct Counter(AtomicUsize);
+
+        #[async_trait::async_trait]
+        impl MessageSubscriber for Counter {
+            async fn try_consume_custom<'a>(
+                &self,
+                _: SubscriberContext<'a>,
+                _: u32,
+                _: &'a [u8],
+            ) -> Result<bool> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Ok(true)
+            }
+        }
+
+        let network = MemoryNetwork::new();
+        let options = NodeOptions {
+            channels_enabled: false,
+            packet_signature_required: true,
+            ..Default::default()
+        };
+
+        let secret = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let mut keystore = Keystore::builder().build();
+        let left_id = keystore
+            .add_external_key(Arc::new(LocalSigner(ed25519::KeyPair::from(&secret))), 0)
+            .unwrap();
+        let left = network
+            .create_node(localhost(), keystore, options, None)
+            .unwrap();
+        let (right, right_id, _) = make_node(&network, options);
+        connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+
+        let counter = Arc::new(Counter::default());
+        right.add_message_subscriber(counter.clone()).unwrap();
+        left.start().unwrap();
+        right.start().unwrap();
+
+        left.send_custom_message(&left_id, &right_id, &[0; 8])
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        assert_eq!(right.packet_drop_metrics().total(), 0);
+    }
+
+    #[tokio::test]
+    async fn completed_transfers() {
+        for (resend_attempts, expected) in [(0, 0), (3, 1)] {
+            let network = MemoryNetwork::new();
+            let options = NodeOptions {
+                transfer_resend_attempts: resend_attempts,
+                ..Default::default()
+            };
+            let (left, left_id, _) = make_node(&network, options);
+            let (right, right_id, _) = make_node(&network, options);
+            connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+            left.start().unwrap();
+            right.start().unwrap();
+
+            left.send_custom_message(&left_id, &right_id, &[0xaa; 4096])
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let metrics = right.metrics();
+            assert_eq!(metrics.incoming_transfers_len, expected);
+            assert_eq!(metrics.incoming_transfers_memory, 0);
+        }
+    }
+}
@@ -1,17 +1,21 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use sha2::Digest;
 use tl_proto::TlWrite;
-use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 
 use crate::adnl::channel::*;
 use crate::adnl::handshake::*;
 use crate::adnl::keystore::Key;
 use crate::adnl::node_id::NodeIdShort;
+use crate::adnl::packet_tap::{PacketDirection, TappedPacket};
+use crate::adnl::padding::gen_padding;
 use crate::adnl::peer::*;
+use crate::adnl::socket::NodeSocket;
+use crate::adnl::transfer::*;
 use crate::adnl::Node;
 
 use crate::proto;
@@ -21,12 +25,13 @@ impl Node {
     /// Starts a process that forwards packets from the sender queue to the UDP socket
     pub(super) fn start_sender(
         self: &Arc<Self>,
-        socket: Arc<UdpSocket>,
+        socket: Arc<NodeSocket>,
         mut sender_queue_rx: SenderQueueRx,
     ) {
         use futures_util::future::{select, Either};
 
         let complete_signal = self.cancellation_token.clone();
+        let node = Arc::downgrade(self);
 
         tokio::spawn(async move {
             tokio::pin!(let cancelled = complete_signal.cancelled(););
@@ -42,11 +47,34 @@ impl Node {
                 }
             } {
                 // Send packet
-                socket.send_to(&packet.data, packet.destination).await.ok();
+                if let Err(e) = socket.send_to(&packet.data, packet.destination).await {
+                    match node.upgrade() {
+                        Some(node) => node.handle_send_failure(&packet, e),
+                        None => return,
+                    }
+                }
             }
         });
     }
 
+    /// Switches to the next known peer address if the selected one is unreachable
+    fn handle_send_failure(&self, packet: &PacketToSend, error: std::io::Error) {
+        let (local_id, peer_id, addr) = (&packet.local_id, &packet.peer_id, packet.addr);
+        tracing::debug!(%local_id, %peer_id, %addr, "failed to send packet: {error}");
+
+        let peers = match self.get_peers(local_id) {
+            Ok(peers) => peers,
+            Err(_) => return,
+        };
+        let new_addr = match peers.get(peer_id) {
+            Some(peer) => peer.on_send_failure(addr),
+            None => return,
+        };
+        if let Some(new_addr) = new_addr {
+            tracing::debug!(%local_id, %peer_id, %new_addr, "switched peer address");
+        }
+    }
+
     pub(super) fn send_message(
         &self,
         local_id: &NodeIdShort,
@@ -54,16 +82,111 @@ impl Node {
         message: proto::adnl::Message,
         priority: bool,
     ) -> Result<()> {
+        self.send_message_tracked(local_id, peer_id, message, priority)
+            .map(|_| ())
+    }
+
+    /// Sends `Nop` encrypted with ChaCha20-Poly1305 over the channel to advertise its support.
+    ///
+    /// NOTE: peers which don't support it just drop this packet
+    pub(super) fn send_cipher_probe(&self, channel: &Arc<Channel>) -> Result<()> {
+        let (local_id, peer_id) = (channel.local_id(), channel.peer_id());
+        let peers = self.get_peers(local_id)?;
+        let peer = match peers.get(peer_id) {
+            Some(peer) => peer,
+            None => return Err(AdnlSenderError::UnknownPeer.into()),
+        };
+
+        tracing::trace!(%local_id, %peer_id, "sending channel cipher probe");
+
+        let data = tl_proto::serialize(proto::adnl::Message::Nop);
+        let signer = MessageSigner::Channel {
+            channel,
+            priority: false,
+            version: Some(ADNL_CHACHA20_POLY1305_VERSION),
+        };
+        self.send_packet(
+            local_id,
+            peer_id,
+            peer.value(),
+            signer,
+            proto::adnl::OutgoingMessages::Single(&data),
+        )?;
+        Ok(())
+    }
+
+    /// Sends message and returns info about the last sent packet
+    #[tracing::instrument(
+        level = "trace",
+        name = "adnl_send",
+        skip_all,
+        fields(
+            %local_id,
+            %peer_id,
+            priority,
+            constructor = %proto::debug::MessageConstructorDump(&message),
+        )
+    )]
+    pub(super) fn send_message_tracked(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        message: proto::adnl::Message,
+        priority: bool,
+    ) -> Result<SentMessage> {
+        match message {
+            proto::adnl::Message::Answer { .. }
+            | proto::adnl::Message::ConfirmChannel { .. }
+            | proto::adnl::Message::Custom { .. }
+            | proto::adnl::Message::Nop
+            | proto::adnl::Message::Query { .. }
+            | proto::adnl::Message::Part { .. }
+            | proto::adnl::Message::Reinit { .. } => {}
+            _ => return Err(AdnlSenderError::UnexpectedMessageToSend.into()),
+        }
+
+        // NOTE: resent parts are always sent as is
+        let single_packet = matches!(message, proto::adnl::Message::Part { .. });
+        let data = tl_proto::serialize(message);
+        self.send_serialized_message(local_id, peer_id, &data, priority, single_packet)
+    }
+
+    /// Sends serialized message (splits it into parts if needed) and returns
+    /// info about the last sent packet
+    pub(super) fn send_serialized_message(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+        priority: bool,
+        single_packet: bool,
+    ) -> Result<SentMessage> {
         const MAX_ADNL_MESSAGE_SIZE: usize = 1024;
 
-        const MSG_ANSWER_SIZE: usize = 44;
         const MSG_CONFIRM_CHANNEL_SIZE: usize = 72;
         const MSG_CREATE_CHANNEL_SIZE: usize = 40;
-        const MSG_CUSTOM_SIZE: usize = 12;
-        const MSG_NOP_SIZE: usize = 4;
-        const MSG_QUERY_SIZE: usize = 44;
         const MSG_PART_PREFIX_SIZE: usize = 40;
 
+        // Deliver messages to our own keys directly
+        if self.is_local_key(peer_id) {
+            return self.send_loopback_message(local_id, peer_id, data, priority);
+        }
+
+        // Recreate channel if needed
+        if self.options.channel_rekey_interval_sec.is_some()
+            || self.options.channel_rekey_bytes.is_some()
+        {
+            let channel = self
+                .channels_by_peers
+                .get(peer_id)
+                .map(|entry| entry.value().clone());
+            if let Some(channel) = channel {
+                if self.is_rekey_required(&channel) {
+                    self.rekey_channel(local_id, &channel)?;
+                }
+            }
+        }
+
         // Find peer by id
         let peers = self.get_peers(local_id)?;
         let peer = match peers.get(peer_id) {
@@ -73,8 +196,12 @@ impl Node {
         let peer = peer.value();
 
         // Get local key
-        let local_key = self.keystore.key_by_id(local_id)?;
-        let channel = self.channels_by_peers.get(peer_id);
+        let local_key = self.key_by_id(local_id)?;
+        // NOTE: channel could be established from another local key (e.g. before rotation)
+        let channel = self
+            .channels_by_peers
+            .get(peer_id)
+            .filter(|channel| channel.local_id() == local_id);
         let mut force_handshake = false;
         let (additional_size, additional_message) = match &channel {
             Some(channel) if channel.ready() => (0, None),
@@ -91,6 +218,7 @@ impl Node {
                     }),
                 )
             }
+            None if !self.options.channels_enabled => (0, None),
             None => {
                 tracing::trace!(%local_id, %peer_id, "sending CreateChannel");
 
@@ -104,112 +232,182 @@ impl Node {
             }
         };
 
-        let mut size = additional_size;
-        size += match message {
-            proto::adnl::Message::Answer { answer, .. } => answer.len() + MSG_ANSWER_SIZE,
-            proto::adnl::Message::ConfirmChannel { .. } => MSG_CONFIRM_CHANNEL_SIZE,
-            proto::adnl::Message::Custom { data } => data.len() + MSG_CUSTOM_SIZE,
-            proto::adnl::Message::Nop => MSG_NOP_SIZE,
-            proto::adnl::Message::Query { query, .. } => query.len() + MSG_QUERY_SIZE,
-            _ => return Err(AdnlSenderError::UnexpectedMessageToSend.into()),
-        };
+        let size = additional_size + data.len();
 
         let signer = match channel.as_ref() {
             Some(channel) if !force_handshake => MessageSigner::Channel {
                 channel: channel.value(),
                 priority,
+                version: channel.packet_version(),
             },
-            _ => MessageSigner::Random(local_key),
+            _ => MessageSigner::Random(&local_key),
         };
 
-        if size <= MAX_ADNL_MESSAGE_SIZE {
-            let mut buffer = Vec::with_capacity(size);
+        if size <= MAX_ADNL_MESSAGE_SIZE || single_packet {
+            let mut buffer = Vec::new();
             let messages = match additional_message {
                 Some(additional_message) => {
+                    buffer.reserve(size);
                     additional_message.write_to(&mut buffer);
-                    message.write_to(&mut buffer);
+                    buffer.extend_from_slice(data);
                     proto::adnl::OutgoingMessages::Pair(&buffer)
                 }
-                None => {
-                    message.write_to(&mut buffer);
-                    proto::adnl::OutgoingMessages::Single(&buffer)
-                }
+                None => proto::adnl::OutgoingMessages::Single(data),
             };
 
-            self.send_packet(peer_id, peer, signer, messages)
+            let packet = ok!(self.send_packet(local_id, peer_id, peer, signer, messages));
+            Ok(SentMessage {
+                packet,
+                transfer: None,
+            })
         } else {
-            pub fn build_part_message<'a>(
-                data: &'a [u8],
-                hash: &'a [u8; 32],
-                max_size: usize,
-                offset: &mut usize,
-            ) -> proto::adnl::Message<'a> {
-                let len = std::cmp::min(data.len(), *offset + max_size);
-
-                let result = proto::adnl::Message::Part {
-                    hash,
-                    total_size: data.len() as u32,
-                    offset: *offset as u32,
-                    data: if *offset < len {
-                        &data[*offset..len]
-                    } else {
-                        &data[..0]
-                    },
-                };
-
-                *offset += len;
-                result
-            }
-
-            let data = tl_proto::serialize(message);
-            let hash: [u8; 32] = sha2::Sha256::digest(&data).into();
+            let hash: [u8; 32] = sha2::Sha256::digest(data).into();
             let mut offset = 0;
 
+            let resend = self.options.transfer_resend_attempts > 0;
+            let resend_interval = Duration::from_millis(self.options.transfer_resend_interval_ms);
+            let mut parts = Vec::new();
+            let mut track_part = |part_offset: usize, offset: usize, sent: SentPacket| {
+                if resend {
+                    parts.push(OutgoingPart {
+                        offset: part_offset,
+                        len: offset - part_offset,
+                        confirmation: peer.sender_state().delivery_confirmation(
+                            sent.priority,
+                            sent.seqno,
+                            resend_interval,
+                        ),
+                    });
+                }
+                sent
+            };
+
             let mut buffer = Vec::with_capacity(MAX_ADNL_MESSAGE_SIZE);
+            let mut last_packet = SentPacket::default();
             if let Some(additional_message) = additional_message {
                 additional_message.write_to(&mut buffer);
 
+                let part_offset = offset;
                 let message = build_part_message(
-                    &data,
+                    data,
                     &hash,
                     MAX_ADNL_MESSAGE_SIZE - MSG_PART_PREFIX_SIZE - additional_size,
                     &mut offset,
                 );
                 message.write_to(&mut buffer);
 
-                ok!(self.send_packet(
+                let sent = ok!(self.send_packet(
+                    local_id,
                     peer_id,
                     peer,
                     signer,
                     proto::adnl::OutgoingMessages::Pair(&buffer),
                 ));
+                last_packet = track_part(part_offset, offset, sent);
+                ok!(self.check_outgoing_transfer(local_id, peer_id, &hash, offset, data.len()));
             }
 
             while offset < data.len() {
                 buffer.clear();
-                let message = build_part_message(&data, &hash, MAX_ADNL_MESSAGE_SIZE, &mut offset);
+                let part_offset = offset;
+                let message = build_part_message(data, &hash, MAX_ADNL_MESSAGE_SIZE, &mut offset);
                 message.write_to(&mut buffer);
 
-                ok!(self.send_packet(
+                let sent = ok!(self.send_packet(
+                    local_id,
                     peer_id,
                     peer,
                     signer,
                     proto::adnl::OutgoingMessages::Single(&buffer),
                 ));
+                last_packet = track_part(part_offset, offset, sent);
+                ok!(self.check_outgoing_transfer(local_id, peer_id, &hash, offset, data.len()));
             }
 
+            let transfer = if resend {
+                let transfer = Arc::new(OutgoingTransfer::new(
+                    *local_id,
+                    *peer_id,
+                    last_packet.priority,
+                    data.to_vec(),
+                    hash,
+                    parts,
+                ));
+                let completion = transfer.subscribe();
+                self.outgoing_transfers.insert(hash, transfer);
+                Some(completion)
+            } else {
+                None
+            };
+
+            Ok(SentMessage {
+                packet: last_packet,
+                transfer,
+            })
+        }
+    }
+
+    /// Puts the message into the loopback queue
+    fn send_loopback_message(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        data: &[u8],
+        priority: bool,
+    ) -> Result<SentMessage> {
+        // Check that local id exists
+        self.key_by_id(local_id)?;
+
+        let message = LoopbackMessage {
+            local_id: *peer_id,
+            peer_id: *local_id,
+            data: data.to_vec(),
+            priority,
+            queued_at: Instant::now(),
+        };
+        if self.loopback_tx.send(message).is_err() {
+            return Err(AdnlSenderError::FailedToSendPacket.into());
+        }
+
+        Ok(SentMessage {
+            packet: SentPacket { seqno: 0, priority },
+            transfer: None,
+        })
+    }
+
+    /// Notifies the transfer observer about the sent part
+    fn check_outgoing_transfer(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        transfer_id: &TransferId,
+        bytes_done: usize,
+        total: usize,
+    ) -> Result<()> {
+        let progress = TransferProgress {
+            transfer_id,
+            direction: TransferDirection::Outgoing,
+            local_id,
+            peer_id,
+            bytes_done: std::cmp::min(bytes_done, total),
+            total,
+        };
+        if self.notify_transfer_progress(progress) {
             Ok(())
+        } else {
+            Err(TransferError::Aborted.into())
         }
     }
 
     /// Encodes and sends packet to the peer
     fn send_packet(
         &self,
+        local_id: &NodeIdShort,
         peer_id: &NodeIdShort,
         peer: &Peer,
         mut signer: MessageSigner,
         messages: proto::adnl::OutgoingMessages,
-    ) -> Result<()> {
+    ) -> Result<SentPacket> {
         const MAX_PRIORITY_ATTEMPTS: u64 = 10;
 
         // Determine whether priority channels are supported by remote peer
@@ -227,7 +425,8 @@ impl Node {
 
         // Adjust socket addr
         let mut local_addr = self.socket_addr;
-        let mut peer_addr = peer.addr();
+        let addr = peer.addr();
+        let mut peer_addr = addr;
 
         if self.options.use_loopback_for_neighbours
             && local_addr.ip() == peer_addr.ip()
@@ -237,26 +436,25 @@ impl Node {
             peer_addr.set_ip(Ipv4Addr::LOCALHOST);
         }
 
-        // Generate on-stack random data
-        let rand_bytes: [u8; 10] = gen_fast_bytes();
-
         let now = now();
         let address = proto::adnl::AddressList {
             address: Some(proto::adnl::Address::from(&local_addr)),
             version: now,
             reinit_date: self.start_time,
+            priority: 0,
             expire_at: now + self.options.address_list_timeout_sec,
         };
 
-        let mut packet = proto::adnl::OutgoingPacketContents {
-            rand1: &rand_bytes[..3],
+        let seqno = peer.sender_state().history(priority).bump_seqno();
+        let packet = proto::adnl::OutgoingPacketContents {
+            rand1: &[],
             from: match signer {
                 MessageSigner::Channel { .. } => None,
                 MessageSigner::Random(local_key) => Some(local_key.full_id().as_tl()),
             },
             messages,
             address,
-            seqno: peer.sender_state().history(priority).bump_seqno(),
+            seqno,
             confirm_seqno: peer.receiver_state().history(priority).seqno(),
             reinit_dates: match signer {
                 MessageSigner::Channel { .. } => None,
@@ -266,38 +464,88 @@ impl Node {
                 }),
             },
             signature: None,
-            rand2: &rand_bytes[3..],
+            rand2: &[],
+        };
+
+        let adnl_version = match &signer {
+            MessageSigner::Channel { version, .. } => *version,
+            // NOTE: handshake packets always use the configured version,
+            // because the remote peer might not support the others
+            MessageSigner::Random(..) => self.options.version,
+        };
+        let prefix_len = match &signer {
+            MessageSigner::Channel { .. } => Channel::compute_prefix_len(adnl_version),
+            MessageSigner::Random(..) => compute_handshake_prefix_len(adnl_version),
+        };
+
+        // Generate random padding
+        // NOTE: padding is signed, so the signature size is taken into account in advance
+        let signature_size = match signer {
+            MessageSigner::Random(_) => tl_proto::bytes_max_size_hint(64),
+            MessageSigner::Channel { .. } => 0,
+        };
+        let (rand1_len, rand2_len) = self
+            .options
+            .packet_padding
+            .compute_lengths(prefix_len + packet.max_size_hint() + signature_size);
+        let rand_bytes = gen_padding(rand1_len + rand2_len);
+        let mut packet = proto::adnl::OutgoingPacketContents {
+            rand1: &rand_bytes[..rand1_len],
+            rand2: &rand_bytes[rand1_len..],
+            ..packet
         };
 
         let signature = match signer {
             // Always sign handshake packets
-            MessageSigner::Random(signer) => Some(signer.sign(&packet)),
+            MessageSigner::Random(local_key) if local_key.is_external() => {
+                // NOTE: keys with an external signer can't sign packets synchronously
+                self.send_externally_signed_packet(
+                    local_id,
+                    peer_id,
+                    peer,
+                    local_key,
+                    &packet,
+                    peer_addr,
+                    prefix_len,
+                    adnl_version,
+                )?;
+                return Ok(SentPacket { seqno, priority });
+            }
+            MessageSigner::Random(local_key) => Some(local_key.try_sign(&packet)?),
             MessageSigner::Channel { .. } => None,
         };
         packet.signature = signature.as_ref().map(<[u8; 64]>::as_slice);
 
         // Serialize packet
-        let adnl_version = self.options.version;
-        let prefix_len = match &signer {
-            MessageSigner::Channel { .. } => Channel::compute_prefix_len(adnl_version),
-            MessageSigner::Random(..) => compute_handshake_prefix_len(adnl_version),
-        };
-
         let mut data = Vec::with_capacity(prefix_len + packet.max_size_hint());
         packet.write_to(&mut data);
 
+        self.tap_packet(
+            PacketDirection::Outgoing,
+            local_id,
+            Some(peer_id),
+            priority,
+            &data,
+        );
+
         match signer {
-            MessageSigner::Channel { channel, priority } => {
-                channel.encrypt(&mut data, priority, adnl_version)
-            }
+            MessageSigner::Channel {
+                channel, priority, ..
+            } => channel.encrypt(&mut data, priority, adnl_version),
             MessageSigner::Random(_) => {
-                build_handshake_packet(peer_id, peer.id(), &mut data, adnl_version)
+                let handshake_key = peer.handshake_key(self.options.handshake_key_ttl_sec);
+                build_handshake_packet(peer_id, &handshake_key, &mut data, adnl_version)
             }
         }
 
+        peer.traffic().on_sent(data.len());
+
         if self
             .sender_queue_tx
             .send(PacketToSend {
+                local_id: *local_id,
+                peer_id: *peer_id,
+                addr,
                 destination: peer_addr,
                 data,
             })
@@ -306,6 +554,99 @@ impl Node {
             return Err(AdnlSenderError::FailedToSendPacket.into());
         }
 
+        Ok(SentPacket { seqno, priority })
+    }
+
+    /// Signs the handshake packet with an external signer in the background
+    /// and queues it afterwards. The packet is dropped if the signer fails.
+    fn send_externally_signed_packet(
+        &self,
+        local_id: &NodeIdShort,
+        peer_id: &NodeIdShort,
+        peer: &Peer,
+        local_key: &Arc<Key>,
+        packet: &proto::adnl::OutgoingPacketContents<'_>,
+        destination: SocketAddrV4,
+        prefix_len: usize,
+        adnl_version: Option<u16>,
+    ) -> Result<()> {
+        let (messages, is_pair) = match packet.messages {
+            proto::adnl::OutgoingMessages::Single(data) => (data.to_vec(), false),
+            proto::adnl::OutgoingMessages::Pair(data) => (data.to_vec(), true),
+        };
+        let rand1 = packet.rand1.to_vec();
+        let rand2 = packet.rand2.to_vec();
+        let address = packet.address;
+        let seqno = packet.seqno;
+        let confirm_seqno = packet.confirm_seqno;
+        let reinit_dates = packet.reinit_dates;
+
+        let local_id = *local_id;
+        let peer_id = *peer_id;
+        let local_key = local_key.clone();
+        let handshake_key = peer.handshake_key(self.options.handshake_key_ttl_sec);
+        let addr = peer.addr();
+        let peers = self.get_peers(&local_id)?;
+        let packet_tap = self.packet_tap.read().clone();
+        let sender_queue_tx = self.sender_queue_tx.clone();
+
+        tokio::spawn(async move {
+            let mut packet = proto::adnl::OutgoingPacketContents {
+                rand1: &rand1,
+                from: Some(local_key.full_id().as_tl()),
+                messages: if is_pair {
+                    proto::adnl::OutgoingMessages::Pair(&messages)
+                } else {
+                    proto::adnl::OutgoingMessages::Single(&messages)
+                },
+                address,
+                seqno,
+                confirm_seqno,
+                reinit_dates,
+                signature: None,
+                rand2: &rand2,
+            };
+
+            let signature = match local_key.sign_async(&packet).await {
+                Ok(signature) => signature,
+                Err(e) => {
+                    tracing::warn!(%local_id, %peer_id, "failed to sign handshake packet: {e:?}");
+                    return;
+                }
+            };
+            packet.signature = Some(&signature);
+
+            let mut data = Vec::with_capacity(prefix_len + packet.max_size_hint());
+            packet.write_to(&mut data);
+
+            if let Some(tap) = &packet_tap {
+                tap.on_packet(&TappedPacket {
+                    direction: PacketDirection::Outgoing,
+                    local_id: &local_id,
+                    peer_id: Some(&peer_id),
+                    priority: false,
+                    timestamp: std::time::SystemTime::now(),
+                    data: &data,
+                });
+            }
+
+            build_handshake_packet(&peer_id, &handshake_key, &mut data, adnl_version);
+
+            if let Some(peer) = peers.get(&peer_id) {
+                peer.traffic().on_sent(data.len());
+            }
+
+            sender_queue_tx
+                .send(PacketToSend {
+                    local_id,
+                    peer_id,
+                    addr,
+                    destination,
+                    data,
+                })
+                .ok();
+        });
+
         Ok(())
     }
 }
@@ -315,11 +656,56 @@ enum MessageSigner<'a> {
     Channel {
         channel: &'a Arc<Channel>,
         priority: bool,
+        version: Option<u16>,
     },
     Random(&'a Arc<Key>),
 }
 
+/// Builds the next part of the split message and moves `offset` to its end
+fn build_part_message<'a>(
+    data: &'a [u8],
+    hash: &'a [u8; 32],
+    max_size: usize,
+    offset: &mut usize,
+) -> proto::adnl::Message<'a> {
+    let len = std::cmp::min(data.len(), *offset + max_size);
+
+    let result = proto::adnl::Message::Part {
+        hash,
+        total_size: data.len() as u32,
+        offset: *offset as u32,
+        data: if *offset < len {
+            &data[*offset..len]
+        } else {
+            &data[..0]
+        },
+    };
+
+    *offset = len;
+    result
+}
+
+/// Info about the sent message
+pub struct SentMessage {
+    /// Last sent packet
+    pub packet: SentPacket,
+    /// Resent parts tracker (for split messages)
+    pub transfer: Option<TransferCompletion>,
+}
+
+/// Info about the sent packet
+#[derive(Default, Copy, Clone)]
+pub struct SentPacket {
+    pub seqno: u64,
+    pub priority: bool,
+}
+
 pub struct PacketToSend {
+    local_id: NodeIdShort,
+    peer_id: NodeIdShort,
+    /// Selected peer address
+    addr: SocketAddrV4,
+    /// Actual destination (e.g. loopback address for neighbours)
     destination: SocketAddrV4,
     data: Vec<u8>,
 }
@@ -327,6 +713,22 @@ pub struct PacketToSend {
 pub type SenderQueueTx = mpsc::UnboundedSender<PacketToSend>;
 pub type SenderQueueRx = mpsc::UnboundedReceiver<PacketToSend>;
 
+/// Message from one local id to another
+pub struct LoopbackMessage {
+    /// Destination local id
+    pub local_id: NodeIdShort,
+    /// Source local id
+    pub peer_id: NodeIdShort,
+    /// Serialized message
+    pub data: Vec<u8>,
+    pub priority: bool,
+    /// Used to measure the queue time of the message
+    pub queued_at: Instant,
+}
+
+pub type LoopbackQueueTx = mpsc::UnboundedSender<LoopbackMessage>;
+pub type LoopbackQueueRx = mpsc::UnboundedReceiver<LoopbackMessage>;
+
 #[derive(thiserror::Error, Debug)]
 enum AdnlSenderError {
     #[error("Unknown peer")]
@@ -336,3 +738,37 @@ enum AdnlSenderError {
     #[error("Failed to send ADNL packet")]
     FailedToSendPacket,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_message_parts() {
+        let data = (0..2500).map(|i| i as u8).collect::<Vec<_>>();
+        let hash = [0; 32];
+
+        let mut offset = 0;
+        let mut received = Vec::new();
+        while offset < data.len() {
+            let prev_offset = offset;
+            match build_part_message(&data, &hash, 1000, &mut offset) {
+                proto::adnl::Message::Part {
+                    total_size,
+                    offset: part_offset,
+                    data: part,
+                    ..
+                } => {
+                    assert_eq!(total_size as usize, data.len());
+                    assert_eq!(part_offset as usize, prev_offset);
+                    assert_eq!(offset, prev_offset + part.len());
+                    received.extend_from_slice(part);
+                }
+                _ => panic!("unexpected message"),
+            }
+        }
+
+        assert_eq!(offset, data.len());
+        assert_eq!(received, data);
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::adnl::transfer::*;
+use crate::adnl::Node;
+use crate::proto;
+
+impl Node {
+    /// Starts a process that periodically resends unconfirmed parts of split messages
+    pub(super) fn start_transfers_resend(self: &Arc<Self>) {
+        use futures_util::future::{select, Either};
+
+        let complete_signal = self.cancellation_token.clone();
+        let node = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            let interval = match node.upgrade() {
+                Some(node) => Duration::from_millis(node.options.transfer_resend_interval_ms),
+                None => return,
+            };
+
+            tokio::pin!(let cancelled = complete_signal.cancelled(););
+
+            loop {
+                tokio::pin!(let sleep = tokio::time::sleep(interval););
+                if let Either::Right(_) = select(sleep, &mut cancelled).await {
+                    break;
+                }
+
+                let node = match node.upgrade() {
+                    Some(node) => node,
+                    None => break,
+                };
+
+                let transfers = node
+                    .outgoing_transfers
+                    .iter()
+                    .map(|transfer| transfer.value().clone())
+                    .collect::<Vec<_>>();
+
+                for transfer in transfers {
+                    if let Some(delivered) = node.resend_transfer_parts(&transfer) {
+                        node.outgoing_transfers.remove(transfer.hash());
+                        transfer.complete(delivered);
+                    }
+                }
+            }
+
+            tracing::debug!("transfers resend loop finished");
+        });
+    }
+
+    /// Resends unconfirmed parts of the transfer.
+    ///
+    /// Returns `Some` with the transfer result if it is finished
+    fn resend_transfer_parts(&self, transfer: &OutgoingTransfer) -> Option<bool> {
+        let mut parts = transfer.parts().lock();
+        parts.retain(|part| !part.confirmation.is_confirmed());
+
+        let local_id = transfer.local_id();
+        let peer_id = transfer.peer_id();
+        let data = transfer.data();
+
+        let unconfirmed = parts.iter().map(|part| part.len).sum::<usize>();
+        let progress = TransferProgress {
+            transfer_id: transfer.hash(),
+            direction: TransferDirection::Outgoing,
+            local_id,
+            peer_id,
+            bytes_done: data.len() - unconfirmed,
+            total: data.len(),
+        };
+        if parts.is_empty() {
+            self.notify_transfer_progress(progress);
+            return Some(true);
+        } else if !self.notify_transfer_progress(progress) {
+            return Some(false);
+        }
+
+        if transfer.add_attempt() >= self.options.transfer_resend_attempts {
+            tracing::debug!(
+                %local_id,
+                %peer_id,
+                transfer_id = %DisplayTransferId(transfer.hash()),
+                unconfirmed = parts.len(),
+                "ADNL transfer was not confirmed"
+            );
+            return Some(false);
+        }
+
+        for part in parts.iter_mut() {
+            let message = proto::adnl::Message::Part {
+                hash: transfer.hash(),
+                total_size: data.len() as u32,
+                offset: part.offset as u32,
+                data: &data[part.offset..part.offset + part.len],
+            };
+
+            let sent = match self.send_message_tracked(
+                local_id,
+                peer_id,
+                message,
+                transfer.priority(),
+            ) {
+                Ok(sent) => sent.packet,
+                Err(error) => {
+                    tracing::debug!(%local_id, %peer_id, ?error, "failed to resend ADNL transfer part");
+                    return Some(false);
+                }
+            };
+
+            let interval = Duration::from_millis(self.options.transfer_resend_interval_ms);
+            part.confirmation =
+                match self.make_delivery_confirmation(local_id, peer_id, sent, interval) {
+                    Ok(confirmation) => confirmation,
+                    Err(_) => return Some(false),
+                };
+        }
+
+        tracing::trace!(
+            %local_id,
+            %peer_id,
+            transfer_id = %DisplayTransferId(transfer.hash()),
+            resent = parts.len(),
+            "resent ADNL transfer parts"
+        );
+        None
+    }
+}
@@ -1,6 +1,8 @@
 use std::borrow::Borrow;
 use std::convert::TryFrom;
+use std::str::FromStr;
 
+use base64::Engine;
 use everscale_crypto::{ed25519, tl};
 use rand::Rng;
 
@@ -68,6 +70,81 @@ impl<'a> TryFrom<tl::PublicKey<'a>> for NodeIdFull {
     }
 }
 
+/// Full id of the supported public key types.
+///
+/// Unlike [`NodeIdFull`], it can represent keys which are used
+/// only as ids (e.g. DHT or overlay values keys).
+///
+/// See [`PublicKey`]
+///
+/// [`PublicKey`]: everscale_crypto::tl::PublicKey
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum KeyIdFull {
+    /// `pub.ed25519` key
+    Ed25519(NodeIdFull),
+    /// `pub.aes` shared secret
+    Aes([u8; 32]),
+    /// `pub.overlay` name
+    Overlay(Vec<u8>),
+}
+
+impl KeyIdFull {
+    /// Represents public key as a TL structure
+    pub fn as_tl(&self) -> tl::PublicKey<'_> {
+        match self {
+            Self::Ed25519(id) => id.as_tl(),
+            Self::Aes(key) => tl::PublicKey::Aes { key },
+            Self::Overlay(name) => tl::PublicKey::Overlay { name },
+        }
+    }
+
+    /// Returns inner ADNL node id for the `pub.ed25519` key
+    pub fn as_node_id(&self) -> Option<&NodeIdFull> {
+        match self {
+            Self::Ed25519(id) => Some(id),
+            _ => None,
+        }
+    }
+
+    /// Verifies the signature of an arbitrary serializable data.
+    ///
+    /// Only `pub.ed25519` keys can verify signatures
+    pub fn verify<T: tl_proto::TlWrite<Repr = tl_proto::Boxed>>(
+        &self,
+        data: T,
+        other_signature: &[u8],
+    ) -> Result<(), NodeIdFullError> {
+        match self {
+            Self::Ed25519(id) => id.verify(data, other_signature),
+            _ => Err(NodeIdFullError::SignatureNotSupported),
+        }
+    }
+
+    /// Hashes TL representation of the key
+    pub fn compute_short_id(&self) -> NodeIdShort {
+        NodeIdShort::new(tl_proto::hash(self.as_tl()))
+    }
+}
+
+impl From<NodeIdFull> for KeyIdFull {
+    fn from(id: NodeIdFull) -> Self {
+        Self::Ed25519(id)
+    }
+}
+
+impl<'a> TryFrom<tl::PublicKey<'a>> for KeyIdFull {
+    type Error = NodeIdFullError;
+
+    fn try_from(value: tl::PublicKey<'a>) -> Result<Self, Self::Error> {
+        match value {
+            tl::PublicKey::Ed25519 { .. } => NodeIdFull::try_from(value).map(Self::Ed25519),
+            tl::PublicKey::Aes { key } => Ok(Self::Aes(*key)),
+            tl::PublicKey::Overlay { name } => Ok(Self::Overlay(name.to_vec())),
+            tl::PublicKey::Unencoded { .. } => Err(NodeIdFullError::UnsupportedPublicKey),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum NodeIdFullError {
     #[error("Unsupported public key")]
@@ -76,6 +153,8 @@ pub enum NodeIdFullError {
     InvalidPublicKey,
     #[error("Invalid signature")]
     InvalidSignature,
+    #[error("Public key type doesn't support signatures")]
+    SignatureNotSupported,
 }
 
 /// Short ADNL node id.
@@ -105,10 +184,8 @@ impl NodeIdShort {
     pub fn is_zero(&self) -> bool {
         self == &[0; 32]
     }
-}
 
-impl std::fmt::Display for NodeIdShort {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn write_hex(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut output = [0u8; 64];
         hex::encode_to_slice(self.0, &mut output).ok();
 
@@ -118,13 +195,80 @@ impl std::fmt::Display for NodeIdShort {
     }
 }
 
+/// Hex by default, URL-safe base64 with the alternate flag (`{:#}`)
+impl std::fmt::Display for NodeIdShort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            let mut output = [0u8; 44];
+            base64::engine::general_purpose::URL_SAFE
+                .encode_slice(self.0, &mut output)
+                .ok();
+
+            // SAFETY: output is guaranteed to contain only base64 characters
+            let output = unsafe { std::str::from_utf8_unchecked(&output) };
+            f.write_str(output)
+        } else {
+            self.write_hex(f)
+        }
+    }
+}
+
 impl std::fmt::Debug for NodeIdShort {
     #[inline(always)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Display::fmt(self, f)
+        self.write_hex(f)
     }
 }
 
+/// Parses hex or base64 (both standard and URL-safe, with or without padding)
+impl FromStr for NodeIdShort {
+    type Err = NodeIdShortError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.len() {
+            64 => {
+                let mut id = [0u8; 32];
+                match hex::decode_to_slice(s, &mut id) {
+                    Ok(()) => Ok(Self(id)),
+                    Err(_) => Err(NodeIdShortError::InvalidHex),
+                }
+            }
+            43 | 44 => {
+                let s = s.trim_end_matches('=');
+                let engine = if s.contains(['-', '_']) {
+                    &base64::engine::general_purpose::URL_SAFE_NO_PAD
+                } else {
+                    &base64::engine::general_purpose::STANDARD_NO_PAD
+                };
+                match engine.decode(s).map(<[u8; 32]>::try_from) {
+                    Ok(Ok(id)) => Ok(Self(id)),
+                    _ => Err(NodeIdShortError::InvalidBase64),
+                }
+            }
+            _ => Err(NodeIdShortError::InvalidLength),
+        }
+    }
+}
+
+impl TryFrom<&str> for NodeIdShort {
+    type Error = NodeIdShortError;
+
+    #[inline(always)]
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Self::from_str(value)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum NodeIdShortError {
+    #[error("Invalid node id length")]
+    InvalidLength,
+    #[error("Invalid hex node id")]
+    InvalidHex,
+    #[error("Invalid base64 node id")]
+    InvalidBase64,
+}
+
 impl PartialEq<[u8]> for NodeIdShort {
     #[inline(always)]
     fn eq(&self, other: &[u8]) -> bool {
@@ -195,3 +339,56 @@ impl ComputeNodeIds for ed25519::PublicKey {
         (full_id, short_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_id_short_ids() {
+        let ed25519 = ed25519::PublicKey::from(&ed25519::SecretKey::from_bytes([1; 32]));
+        let node_id = NodeIdFull::new(ed25519);
+        let key_id = KeyIdFull::try_from(node_id.as_tl()).unwrap();
+        assert_eq!(key_id.compute_short_id(), node_id.compute_short_id());
+
+        for key in [
+            tl::PublicKey::Aes { key: &[2; 32] },
+            tl::PublicKey::Overlay { name: b"overlay" },
+        ] {
+            let key_id = KeyIdFull::try_from(key).unwrap();
+            assert_eq!(key_id.as_tl(), key);
+            assert_eq!(key_id.compute_short_id(), tl_proto::hash(key));
+            assert!(matches!(
+                key_id.verify(tl::PublicKey::Aes { key: &[0; 32] }, &[0; 64]),
+                Err(NodeIdFullError::SignatureNotSupported)
+            ));
+        }
+
+        assert!(KeyIdFull::try_from(tl::PublicKey::Unencoded { data: &[] }).is_err());
+    }
+
+    #[test]
+    fn short_id_formats() {
+        let id = NodeIdShort::new([0xfb; 32]);
+
+        let hex = id.to_string();
+        let base64 = format!("{id:#}");
+        assert_eq!(hex, hex::encode([0xfb; 32]));
+        assert_eq!(base64, "-_v7".repeat(10) + "-_s=");
+        assert_eq!(format!("{id:#?}"), hex);
+
+        for s in [
+            hex.as_str(),
+            base64.as_str(),
+            base64.trim_end_matches('='),
+            &base64::engine::general_purpose::STANDARD.encode([0xfb; 32]),
+        ] {
+            assert_eq!(NodeIdShort::from_str(s).unwrap(), id);
+        }
+        assert_eq!(NodeIdShort::try_from(hex.as_str()).unwrap(), id);
+
+        assert!(NodeIdShort::from_str("").is_err());
+        assert!(NodeIdShort::from_str(&"g".repeat(64)).is_err());
+        assert!(NodeIdShort::from_str(&"*".repeat(44)).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Reason why the incoming packet was dropped
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DropReason {
+    /// Packet was sent to the unknown local key or channel id
+    UnknownKey,
+    /// Failed to decrypt the handshake packet
+    InvalidHandshake,
+    /// Failed to decrypt the channel packet
+    InvalidChannelPacket,
+    /// Packet version is outside of the configured range
+    UnsupportedVersion,
+    /// Malformed packet contents
+    InvalidPacket,
+    /// Invalid or expired address list of the sender
+    InvalidAddressList,
+    /// Channel packet for the peer without channel
+    UnknownChannel,
+    /// Packet from the peer which was not added
+    UnknownPeer,
+    /// Missing or invalid packet signature
+    InvalidSignature,
+    /// Reinit date of either side doesn't match
+    InvalidReinitDate,
+    /// Packet seqno was already received
+    Duplicate,
+    /// Confirmed seqno is greater than the last sent one
+    InvalidConfirmation,
+    /// Any other error while receiving the packet
+    Other,
+}
+
+impl DropReason {
+    /// All drop reasons in the order of counters
+    pub const ALL: [Self; 13] = [
+        Self::UnknownKey,
+        Self::InvalidHandshake,
+        Self::InvalidChannelPacket,
+        Self::UnsupportedVersion,
+        Self::InvalidPacket,
+        Self::InvalidAddressList,
+        Self::UnknownChannel,
+        Self::UnknownPeer,
+        Self::InvalidSignature,
+        Self::InvalidReinitDate,
+        Self::Duplicate,
+        Self::InvalidConfirmation,
+        Self::Other,
+    ];
+
+    /// Snake case name of the reason, suitable for metrics labels
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::UnknownKey => "unknown_key",
+            Self::InvalidHandshake => "invalid_handshake",
+            Self::InvalidChannelPacket => "invalid_channel_packet",
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::InvalidPacket => "invalid_packet",
+            Self::InvalidAddressList => "invalid_address_list",
+            Self::UnknownChannel => "unknown_channel",
+            Self::UnknownPeer => "unknown_peer",
+            Self::InvalidSignature => "invalid_signature",
+            Self::InvalidReinitDate => "invalid_reinit_date",
+            Self::Duplicate => "duplicate",
+            Self::InvalidConfirmation => "invalid_confirmation",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Number of dropped packets for each reason
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PacketDropMetrics {
+    counts: [u64; DropReason::ALL.len()],
+}
+
+impl PacketDropMetrics {
+    /// Number of packets dropped for the reason
+    pub fn get(&self, reason: DropReason) -> u64 {
+        self.counts[reason as usize]
+    }
+
+    /// Total number of dropped packets
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Iterates over all reasons with their counts
+    pub fn iter(&self) -> impl Iterator<Item = (DropReason, u64)> + '_ {
+        DropReason::ALL.into_iter().zip(self.counts.iter().copied())
+    }
+}
+
+/// Dropped packets counters
+#[derive(Default)]
+pub(crate) struct PacketDrops {
+    counts: [AtomicU64; DropReason::ALL.len()],
+}
+
+impl PacketDrops {
+    pub fn record(&self, reason: DropReason) {
+        self.counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> PacketDropMetrics {
+        let mut metrics = PacketDropMetrics::default();
+        for (count, counter) in metrics.counts.iter_mut().zip(&self.counts) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drop_counters() {
+        for (i, reason) in DropReason::ALL.into_iter().enumerate() {
+            assert_eq!(reason as usize, i);
+        }
+
+        let drops = PacketDrops::default();
+        drops.record(DropReason::Duplicate);
+        drops.record(DropReason::Duplicate);
+        drops.record(DropReason::InvalidSignature);
+
+        let metrics = drops.metrics();
+        assert_eq!(metrics.get(DropReason::Duplicate), 2);
+        assert_eq!(metrics.get(DropReason::InvalidSignature), 1);
+        assert_eq!(metrics.get(DropReason::UnknownChannel), 0);
+        assert_eq!(metrics.total(), 3);
+        assert_eq!(metrics.iter().filter(|(_, count)| *count > 0).count(), 2);
+    }
+}
@@ -0,0 +1,387 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+
+use super::node_id::NodeIdShort;
+
+/// Observer of the decrypted incoming and not yet encrypted outgoing packets.
+///
+/// NOTE: it is called on the hot path, so it must not block
+pub trait PacketTap: Send + Sync {
+    /// Called for each packet
+    fn on_packet(&self, packet: &TappedPacket<'_>);
+}
+
+/// Packet direction
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PacketDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// Serialized ADNL packet contents
+///
+/// See [`PacketTap`]
+#[derive(Debug, Copy, Clone)]
+pub struct TappedPacket<'a> {
+    pub direction: PacketDirection,
+    pub local_id: &'a NodeIdShort,
+    /// Remote peer id. Unknown for the incoming handshake packets,
+    /// in which case it is only contained in the packet itself
+    pub peer_id: Option<&'a NodeIdShort>,
+    /// Whether the packet was sent over the priority channel
+    pub priority: bool,
+    pub timestamp: SystemTime,
+    /// Serialized `adnl.packetContents`
+    pub data: &'a [u8],
+}
+
+/// Packet tap which writes packets in the pcap-ng format.
+///
+/// All packets are written to the single interface with the custom link type
+/// ([`PcapNgWriter::DEFAULT_LINK_TYPE`] by default). Each packet starts with the header:
+///  - 0 - direction (`0` - incoming, `1` - outgoing)
+///  - 1 - flags (`0x01` - priority channel, `0x02` - peer id is known)
+///  - 2..=3 - reserved
+///  - 4..=35 - local id
+///  - 36..=67 - peer id (zeros if unknown)
+///  - 68..... - packet contents
+///
+/// Direction is also stored in the `epb_flags` option of each packet.
+///
+/// NOTE: writes are blocking, so use [`PcapNgTap`] to capture packets of the running node
+pub struct PcapNgWriter<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> PcapNgWriter<W> {
+    /// `LINKTYPE_USER0`
+    pub const DEFAULT_LINK_TYPE: u16 = 147;
+
+    /// Writes pcap-ng headers with the default link type
+    pub fn new(writer: W) -> std::io::Result<Self> {
+        Self::with_link_type(writer, Self::DEFAULT_LINK_TYPE)
+    }
+
+    /// Writes pcap-ng headers with the specified link type
+    pub fn with_link_type(mut writer: W, link_type: u16) -> std::io::Result<Self> {
+        let mut block = Vec::with_capacity(SHB_LEN + IDB_LEN);
+
+        // Section header block
+        block.extend_from_slice(&SHB_TYPE.to_le_bytes());
+        block.extend_from_slice(&(SHB_LEN as u32).to_le_bytes());
+        block.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        block.extend_from_slice(&1u16.to_le_bytes()); // major version
+        block.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        block.extend_from_slice(&(-1i64).to_le_bytes()); // unknown section length
+        block.extend_from_slice(&(SHB_LEN as u32).to_le_bytes());
+
+        // Interface description block (microseconds timestamps by default)
+        block.extend_from_slice(&IDB_TYPE.to_le_bytes());
+        block.extend_from_slice(&(IDB_LEN as u32).to_le_bytes());
+        block.extend_from_slice(&link_type.to_le_bytes());
+        block.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        block.extend_from_slice(&0u32.to_le_bytes()); // no snapshot length limit
+        block.extend_from_slice(&(IDB_LEN as u32).to_le_bytes());
+
+        writer.write_all(&block)?;
+
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Writes the packet as an enhanced packet block
+    pub fn write_packet(&self, packet: &TappedPacket<'_>) -> std::io::Result<()> {
+        self.writer.lock().write_all(&encode_packet(packet))
+    }
+
+    /// Flushes the underlying writer
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.writer.lock().flush()
+    }
+
+    /// Returns the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+}
+
+/// Packet tap which writes packets with [`PcapNgWriter`] in the background.
+///
+/// Packets are encoded on the caller side and passed through the bounded queue
+/// to the blocking writer task. Packets are skipped while the queue is full.
+/// Remaining packets are written and flushed after the tap is dropped.
+pub struct PcapNgTap {
+    tx: mpsc::Sender<Vec<u8>>,
+    skipped_packets: AtomicU64,
+    skipped_warning: Mutex<WarningLimiter>,
+}
+
+impl PcapNgTap {
+    /// Starts the writer task. Must be called from the context of the Tokio runtime
+    pub fn spawn<W>(writer: PcapNgWriter<W>, queue_capacity: usize) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(queue_capacity);
+
+        tokio::task::spawn_blocking(move || {
+            let mut writer = writer.writer.into_inner();
+            let mut warning = WarningLimiter::default();
+
+            while let Some(block) = rx.blocking_recv() {
+                if let Err(e) = writer.write_all(&block) {
+                    if let Some(suppressed) = warning.check() {
+                        tracing::warn!(suppressed, "failed to write captured packet: {e}");
+                    }
+                }
+            }
+            if let Err(e) = writer.flush() {
+                tracing::warn!("failed to flush captured packets: {e}");
+            }
+
+            tracing::debug!("packet capture writer finished");
+        });
+
+        Self {
+            tx,
+            skipped_packets: Default::default(),
+            skipped_warning: Default::default(),
+        }
+    }
+
+    /// Total number of packets skipped due to the full queue
+    pub fn skipped_packets(&self) -> u64 {
+        self.skipped_packets.load(Ordering::Relaxed)
+    }
+}
+
+impl PacketTap for PcapNgTap {
+    fn on_packet(&self, packet: &TappedPacket<'_>) {
+        if self.tx.try_send(encode_packet(packet)).is_ok() {
+            return;
+        }
+
+        self.skipped_packets.fetch_add(1, Ordering::Relaxed);
+        if let Some(suppressed) = self.skipped_warning.lock().check() {
+            tracing::warn!(suppressed, "packet capture queue is full, packet skipped");
+        }
+    }
+}
+
+/// Allows at most one warning per interval.
+#[derive(Default)]
+struct WarningLimiter {
+    last_warning: Option<Instant>,
+    suppressed: u64,
+}
+
+impl WarningLimiter {
+    /// Returns the number of suppressed warnings if the warning can be logged
+    fn check(&mut self) -> Option<u64> {
+        let now = Instant::now();
+        match self.last_warning {
+            Some(last) if now.duration_since(last) < WARNING_INTERVAL => {
+                self.suppressed += 1;
+                None
+            }
+            _ => {
+                self.last_warning = Some(now);
+                Some(std::mem::take(&mut self.suppressed))
+            }
+        }
+    }
+}
+
+const WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Encodes the packet as an enhanced packet block
+fn encode_packet(packet: &TappedPacket<'_>) -> Vec<u8> {
+    let captured_len = PACKET_HEADER_LEN + packet.data.len();
+    let padded_len = (captured_len + 3) & !3;
+    let block_len = EPB_LEN + padded_len + EPB_OPTIONS_LEN;
+
+    let timestamp = packet
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+
+    let (direction, epb_flags) = match packet.direction {
+        PacketDirection::Incoming => (0u8, EPB_FLAGS_INBOUND),
+        PacketDirection::Outgoing => (1u8, EPB_FLAGS_OUTBOUND),
+    };
+    let mut flags = 0u8;
+    if packet.priority {
+        flags |= 0x01;
+    }
+    if packet.peer_id.is_some() {
+        flags |= 0x02;
+    }
+
+    let mut block = Vec::with_capacity(block_len);
+    block.extend_from_slice(&EPB_TYPE.to_le_bytes());
+    block.extend_from_slice(&(block_len as u32).to_le_bytes());
+    block.extend_from_slice(&0u32.to_le_bytes()); // interface id
+    block.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    block.extend_from_slice(&(captured_len as u32).to_le_bytes());
+    block.extend_from_slice(&(captured_len as u32).to_le_bytes());
+
+    // Packet header
+    block.extend_from_slice(&[direction, flags, 0, 0]);
+    block.extend_from_slice(packet.local_id.as_slice());
+    block.extend_from_slice(match packet.peer_id {
+        Some(peer_id) => peer_id.as_slice(),
+        None => &[0; 32],
+    });
+
+    // Packet data
+    block.extend_from_slice(packet.data);
+    block.resize(block.len() + padded_len - captured_len, 0);
+
+    // Options
+    block.extend_from_slice(&EPB_FLAGS_OPTION.to_le_bytes());
+    block.extend_from_slice(&4u16.to_le_bytes());
+    block.extend_from_slice(&epb_flags.to_le_bytes());
+    block.extend_from_slice(&[0; 4]); // end of options
+
+    block.extend_from_slice(&(block_len as u32).to_le_bytes());
+
+    block
+}
+
+const SHB_TYPE: u32 = 0x0A0D0D0A;
+const IDB_TYPE: u32 = 0x00000001;
+const EPB_TYPE: u32 = 0x00000006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+
+const SHB_LEN: usize = 28;
+const IDB_LEN: usize = 20;
+/// Enhanced packet block length without data and options
+const EPB_LEN: usize = 32;
+/// `epb_flags` option and the end of options
+const EPB_OPTIONS_LEN: usize = 12;
+
+const EPB_FLAGS_OPTION: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0b01;
+const EPB_FLAGS_OUTBOUND: u32 = 0b10;
+
+const PACKET_HEADER_LEN: usize = 4 + 32 + 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcap_ng_blocks() {
+        let writer = PcapNgWriter::new(Vec::new()).unwrap();
+
+        let local_id = NodeIdShort::new([1; 32]);
+        let peer_id = NodeIdShort::new([2; 32]);
+        for (direction, peer_id) in [
+            (PacketDirection::Incoming, None),
+            (PacketDirection::Outgoing, Some(&peer_id)),
+        ] {
+            writer
+                .write_packet(&TappedPacket {
+                    direction,
+                    local_id: &local_id,
+                    peer_id,
+                    priority: false,
+                    timestamp: SystemTime::now(),
+                    data: &[1, 2, 3],
+                })
+                .unwrap();
+        }
+
+        let data = writer.into_inner();
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let block_type = read_u32(offset);
+            let block_len = read_u32(offset + 4) as usize;
+            assert_eq!(block_len % 4, 0);
+            assert_eq!(read_u32(offset + block_len - 4) as usize, block_len);
+            blocks.push((block_type, offset));
+            offset += block_len;
+        }
+        assert_eq!(offset, data.len());
+
+        let block_types = blocks.iter().map(|(ty, _)| *ty).collect::<Vec<_>>();
+        assert_eq!(block_types, [SHB_TYPE, IDB_TYPE, EPB_TYPE, EPB_TYPE]);
+
+        // Check packet headers
+        let (_, incoming) = blocks[2];
+        assert_eq!(read_u32(incoming + 20) as usize, PACKET_HEADER_LEN + 3);
+        assert_eq!(&data[incoming + 28..incoming + 32], [0, 0, 0, 0]);
+        assert_eq!(&data[incoming + 64..incoming + 96], [0; 32]);
+
+        let (_, outgoing) = blocks[3];
+        assert_eq!(&data[outgoing + 28..outgoing + 32], [1, 0x02, 0, 0]);
+        assert_eq!(&data[outgoing + 64..outgoing + 96], peer_id.as_slice());
+        assert_eq!(&data[outgoing + 96..outgoing + 99], [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn pcap_ng_tap() {
+        /// Writer which is blocked while the buffer is locked
+        #[derive(Clone, Default)]
+        struct SharedBuffer(std::sync::Arc<Mutex<Vec<u8>>>);
+
+        impl Write for SharedBuffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = SharedBuffer::default();
+        let writer = PcapNgWriter::new(buffer.clone()).unwrap();
+        let headers_len = buffer.0.lock().len();
+
+        let local_id = NodeIdShort::new([1; 32]);
+        let packet = TappedPacket {
+            direction: PacketDirection::Incoming,
+            local_id: &local_id,
+            peer_id: None,
+            priority: false,
+            timestamp: SystemTime::now(),
+            data: &[1, 2, 3, 4],
+        };
+        let block_len = encode_packet(&packet).len();
+
+        // Packets are skipped without blocking while the writer is stuck
+        let tap = PcapNgTap::spawn(writer, 1);
+        let written = {
+            let _guard = buffer.0.lock();
+            for _ in 0..10 {
+                tap.on_packet(&packet);
+            }
+            let skipped = tap.skipped_packets();
+            assert!(skipped >= 8);
+            10 - skipped as usize
+        };
+
+        drop(tap);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while buffer.0.lock().len() < headers_len + written * block_len {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(buffer.0.lock().len(), headers_len + written * block_len);
+    }
+}
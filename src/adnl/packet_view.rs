@@ -15,6 +15,12 @@ impl<'a> PacketView<'a> {
         self.bytes
     }
 
+    /// Returns the remaining bytes with the original lifetime
+    #[inline(always)]
+    pub fn into_slice(self) -> &'a [u8] {
+        self.bytes
+    }
+
     #[inline(always)]
     pub fn len(&self) -> usize {
         self.bytes.len()
@@ -0,0 +1,121 @@
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+
+use crate::util::fast_thread_rng;
+
+/// Random padding of the outgoing packets.
+///
+/// Each packet contains two random byte strings (`rand1` and `rand2`),
+/// which can be used to hide the actual packet size.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PacketPaddingConfig {
+    /// Whether to choose random lengths of the `rand1` and `rand2` fields
+    /// (from `0` to `max_random_len` bytes) instead of the fixed 3 and 7 bytes.
+    ///
+    /// Default: `false`
+    pub randomize_lengths: bool,
+
+    /// Max length of the randomized fields.
+    ///
+    /// Default: `15`
+    pub max_random_len: u8,
+
+    /// Pads packets up to the multiple of this size (in bytes) by extending `rand2`.
+    /// Will be rounded up to the multiple of 4. Packets are not padded beyond
+    /// [`MAX_PADDED_PACKET_SIZE`] bytes.
+    ///
+    /// Default: `None`
+    pub bucket_size: Option<u16>,
+}
+
+impl Default for PacketPaddingConfig {
+    fn default() -> Self {
+        Self {
+            randomize_lengths: false,
+            max_random_len: 15,
+            bucket_size: None,
+        }
+    }
+}
+
+impl PacketPaddingConfig {
+    /// Computes lengths of the `rand1` and `rand2` fields.
+    ///
+    /// `packet_size` is the size of the encrypted packet with empty random fields
+    pub(crate) fn compute_lengths(&self, packet_size: usize) -> (usize, usize) {
+        let (rand1_len, mut rand2_len) = if self.randomize_lengths {
+            let mut rng = fast_thread_rng();
+            (
+                rng.gen_range(0..=self.max_random_len as usize),
+                rng.gen_range(0..=self.max_random_len as usize),
+            )
+        } else {
+            (3, 7)
+        };
+
+        if let Some(bucket_size) = self.bucket_size {
+            let bucket_size = std::cmp::max(align4(bucket_size as usize), 4);
+
+            let rand2_size = tl_proto::bytes_max_size_hint(rand2_len);
+            let size = packet_size - 2 * EMPTY_BYTES_SIZE
+                + tl_proto::bytes_max_size_hint(rand1_len)
+                + rand2_size;
+
+            let target_size = (size + bucket_size - 1) / bucket_size * bucket_size;
+            if target_size <= MAX_PADDED_PACKET_SIZE {
+                // NOTE: bytes with `len + 4` size are always encoded with the same padding
+                rand2_len = rand2_size + (target_size - size) - 4;
+            }
+        }
+
+        (rand1_len, rand2_len)
+    }
+}
+
+/// Generates random bytes for the `rand1` and `rand2` fields
+pub(crate) fn gen_padding(len: usize) -> SmallVec<[u8; 32]> {
+    let mut padding = SmallVec::from_elem(0, len);
+    fast_thread_rng().fill_bytes(&mut padding);
+    padding
+}
+
+/// Max size of the packet padded to the bucket size (to avoid IP fragmentation)
+pub const MAX_PADDED_PACKET_SIZE: usize = 1472;
+
+/// Encoded size of the empty bytes
+const EMPTY_BYTES_SIZE: usize = 4;
+
+fn align4(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucketed_padding() {
+        let config = PacketPaddingConfig {
+            randomize_lengths: true,
+            max_random_len: 255,
+            bucket_size: Some(250),
+        };
+
+        for packet_size in (64..700).step_by(4) {
+            let (rand1_len, rand2_len) = config.compute_lengths(packet_size);
+            let size = packet_size - 2 * EMPTY_BYTES_SIZE
+                + tl_proto::bytes_max_size_hint(rand1_len)
+                + tl_proto::bytes_max_size_hint(rand2_len);
+            assert_eq!(size % 252, 0);
+        }
+
+        // Packets are not padded beyond the limit
+        let config = PacketPaddingConfig {
+            bucket_size: Some(1024),
+            ..Default::default()
+        };
+        assert_eq!(config.compute_lengths(1100), (3, 7));
+    }
+}
@@ -1,8 +1,14 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
 
 use everscale_crypto::ed25519;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use smallvec::SmallVec;
+use tokio::sync::watch;
 
+use super::handshake::HandshakeKey;
 use super::node_id::{NodeIdFull, NodeIdShort};
 use crate::util::*;
 
@@ -12,25 +18,51 @@ pub type Peers = FastDashMap<NodeIdShort, Peer>;
 pub struct Peer {
     /// Remove peer public key
     id: NodeIdFull,
-    /// IPv4 address
+    /// Selected IPv4 address
     addr: AtomicU64,
+    /// Known addresses of the peer
+    addresses: Mutex<PeerAddresses>,
     /// Adnl channel key pair to encrypt messages from our side
     channel_key: ed25519::KeyPair,
+    /// Temporary key of the handshake packets with its creation time
+    handshake_key: Mutex<Option<(HandshakeKey, u32)>>,
     /// Packets receiver state
     receiver_state: PeerState,
     /// Packets sender state
     sender_state: PeerState,
+    /// Date of the last accepted `adnl.message.reinit`
+    reinit_message_date: AtomicU32,
+    /// Received packets deduplication settings
+    history_config: PacketsHistoryConfig,
+    /// Peer groups
+    tags: RwLock<FastHashSet<String>>,
+    /// Traffic counters
+    traffic: PeerTraffic,
 }
 
 impl Peer {
     /// Creates new peer with receiver state initialized with the local reinit date
-    pub fn new(local_reinit_date: u32, addr: SocketAddrV4, id: NodeIdFull) -> Self {
+    pub fn new(
+        local_reinit_date: u32,
+        addr: SocketAddrV4,
+        id: NodeIdFull,
+        history_config: PacketsHistoryConfig,
+    ) -> Self {
         Self {
             id,
             addr: AtomicU64::new(pack_socket_addr(&addr)),
+            addresses: Mutex::new(PeerAddresses::new(addr)),
             channel_key: ed25519::KeyPair::generate(&mut rand::thread_rng()),
-            receiver_state: PeerState::for_receive_with_reinit_date(local_reinit_date),
+            handshake_key: Default::default(),
+            receiver_state: PeerState::for_receive_with_reinit_date(
+                local_reinit_date,
+                history_config,
+            ),
             sender_state: PeerState::for_send(),
+            reinit_message_date: Default::default(),
+            history_config,
+            tags: Default::default(),
+            traffic: Default::default(),
         }
     }
 
@@ -45,10 +77,7 @@ impl Peer {
             std::cmp::Ordering::Greater => {
                 self.sender_state.set_reinit_date(reinit_date);
                 if sender_reinit_date != 0 {
-                    self.sender_state.history(false).reset();
-                    self.sender_state.history(true).reset();
-                    self.receiver_state.history(false).reset();
-                    self.receiver_state.history(true).reset();
+                    self.reset_histories();
                 }
                 true
             }
@@ -56,20 +85,107 @@ impl Peer {
         }
     }
 
+    /// Handles explicit reinit of the remote peer (`adnl.message.reinit`).
+    ///
+    /// Resets packets histories only if the date is strictly greater than the date
+    /// of the previous reinit message, so the captured reinit packets can't be replayed.
+    pub fn reinit_sender(&self, reinit_date: u32) -> bool {
+        let updated = self
+            .reinit_message_date
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |prev| {
+                (reinit_date > prev).then_some(reinit_date)
+            })
+            .is_ok();
+        if updated {
+            self.reset_histories();
+        }
+        updated
+    }
+
+    fn reset_histories(&self) {
+        self.sender_state.history(false).reset();
+        self.sender_state.history(true).reset();
+        self.sender_state.reset_confirmations();
+        self.receiver_state.history(false).reset();
+        self.receiver_state.history(true).reset();
+    }
+
+    /// Returns the temporary key of the handshake packets to the peer.
+    ///
+    /// The key is reused for `ttl_sec` seconds, new key is generated for each packet if it is zero
+    pub fn handshake_key(&self, ttl_sec: u32) -> HandshakeKey {
+        if ttl_sec == 0 {
+            return HandshakeKey::generate(&self.id);
+        }
+
+        let now = now();
+        let mut handshake_key = self.handshake_key.lock();
+        match &*handshake_key {
+            Some((key, created_at)) if now < created_at.saturating_add(ttl_sec) => *key,
+            _ => {
+                let key = HandshakeKey::generate(&self.id);
+                *handshake_key = Some((key, now));
+                key
+            }
+        }
+    }
+
     /// Returns peer full id (public key)
     #[inline(always)]
     pub fn id(&self) -> &NodeIdFull {
         &self.id
     }
 
+    /// Address which is used to send packets to the peer
     #[inline(always)]
     pub fn addr(&self) -> SocketAddrV4 {
         unpack_socket_addr(self.addr.load(Ordering::Acquire))
     }
 
-    #[inline(always)]
-    pub fn set_addr(&self, addr: SocketAddrV4) {
-        self.addr.store(pack_socket_addr(&addr), Ordering::Release);
+    /// Priority of the selected address
+    pub fn addr_priority(&self) -> u32 {
+        self.addresses.lock().selected().priority
+    }
+
+    /// Updates the known peer address and selects the best one.
+    ///
+    /// `version` is the version of the address list the address was taken from
+    pub fn update_addr(&self, addr: SocketAddrV4, priority: u32, version: u32) {
+        let mut addresses = self.addresses.lock();
+        addresses.update(addr, priority, version);
+        self.addr.store(
+            pack_socket_addr(&addresses.selected().addr),
+            Ordering::Release,
+        );
+    }
+
+    /// Marks the address as unreachable and selects the next one.
+    /// Returns the new selected address if it has changed
+    pub fn on_send_failure(&self, addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        let mut addresses = self.addresses.lock();
+        let prev = addresses.selected().addr;
+        addresses.on_send_failure(addr);
+
+        let selected = addresses.selected().addr;
+        self.addr
+            .store(pack_socket_addr(&selected), Ordering::Release);
+        (selected != prev).then_some(selected)
+    }
+
+    /// Adds peer to the group. Returns whether the tag is new
+    pub fn add_tag(&self, tag: &str) -> bool {
+        let mut tags = self.tags.write();
+        !tags.contains(tag) && tags.insert(tag.to_owned())
+    }
+
+    /// Removes peer from the group. Returns whether the tag existed
+    pub fn remove_tag(&self, tag: &str) -> bool {
+        self.tags.write().remove(tag)
+    }
+
+    /// Returns whether the peer belongs to the group
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.read().contains(tag)
     }
 
     /// Adnl channel key pair to encrypt messages from our side
@@ -90,6 +206,17 @@ impl Peer {
         &self.sender_state
     }
 
+    /// Traffic counters
+    #[inline(always)]
+    pub fn traffic(&self) -> &PeerTraffic {
+        &self.traffic
+    }
+
+    /// Generates new channel key pair without touching receiver/sender states
+    pub fn regenerate_channel_key(&mut self) {
+        self.channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+    }
+
     /// Generates new channel key pair and resets receiver/sender states
     ///
     /// NOTE: Receiver state increments its reinit date so the peer will reset states
@@ -100,11 +227,91 @@ impl Peer {
         let reinit_date = self.receiver_state.reinit_date();
 
         self.channel_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
-        self.receiver_state = PeerState::for_receive_with_reinit_date(reinit_date + 1);
+        self.receiver_state =
+            PeerState::for_receive_with_reinit_date(reinit_date + 1, self.history_config);
         self.sender_state = PeerState::for_send();
     }
 }
 
+/// Known peer addresses, ordered by the preference
+struct PeerAddresses {
+    items: SmallVec<[PeerAddress; MAX_PEER_ADDRESSES]>,
+}
+
+impl PeerAddresses {
+    fn new(addr: SocketAddrV4) -> Self {
+        let mut items = SmallVec::new();
+        items.push(PeerAddress::new(addr, 0, 0));
+        Self { items }
+    }
+
+    fn selected(&self) -> &PeerAddress {
+        // NOTE: there is always at least one address
+        &self.items[0]
+    }
+
+    fn update(&mut self, addr: SocketAddrV4, priority: u32, version: u32) {
+        match self.items.iter_mut().find(|item| item.addr == addr) {
+            Some(item) => *item = PeerAddress::new(addr, priority, version),
+            None => {
+                if self.items.len() >= MAX_PEER_ADDRESSES {
+                    // Replace the least preferred address
+                    self.items.pop();
+                }
+                self.items.push(PeerAddress::new(addr, priority, version));
+            }
+        }
+        self.sort();
+    }
+
+    fn on_send_failure(&mut self, addr: SocketAddrV4) {
+        if let Some(item) = self.items.iter_mut().find(|item| item.addr == addr) {
+            item.send_failures = item.send_failures.saturating_add(1);
+            self.sort();
+        }
+    }
+
+    fn sort(&mut self) {
+        // Prefer reachable addresses, then from the newest address list.
+        // Priority is only compared within the same list version
+        self.items.sort_by(|a, b| {
+            a.send_failures
+                .cmp(&b.send_failures)
+                .then(b.version.cmp(&a.version))
+                .then(b.priority.cmp(&a.priority))
+                .then(b.updated_at.cmp(&a.updated_at))
+        });
+    }
+}
+
+struct PeerAddress {
+    addr: SocketAddrV4,
+    /// Priority from the address list
+    priority: u32,
+    /// Version of the address list
+    version: u32,
+    /// Number of failed attempts to send packets since the last update
+    send_failures: u32,
+    /// Logical time of the last update
+    updated_at: u64,
+}
+
+impl PeerAddress {
+    fn new(addr: SocketAddrV4, priority: u32, version: u32) -> Self {
+        static UPDATES: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            addr,
+            priority,
+            version,
+            send_failures: 0,
+            updated_at: UPDATES.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+const MAX_PEER_ADDRESSES: usize = 4;
+
 pub fn pack_socket_addr(addr: &SocketAddrV4) -> u64 {
     let mut result = [0; 8];
     result[0..4].copy_from_slice(&addr.ip().octets());
@@ -122,18 +329,78 @@ pub fn unpack_socket_addr(addr: u64) -> SocketAddrV4 {
     )
 }
 
+/// Packets and bytes counters of the remote peer
+#[derive(Default)]
+pub struct PeerTraffic {
+    packets_in: AtomicU64,
+    bytes_in: AtomicU64,
+    packets_out: AtomicU64,
+    bytes_out: AtomicU64,
+    decrypt_failures: AtomicU64,
+    last_activity: AtomicU32,
+}
+
+impl PeerTraffic {
+    /// Counts the received packet and updates the last activity timestamp
+    pub fn on_received(&self, bytes: usize) {
+        self.packets_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_activity.fetch_max(now(), Ordering::Relaxed);
+    }
+
+    /// Counts the sent packet
+    pub fn on_sent(&self, bytes: usize) {
+        self.packets_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts the channel packet which could not be decrypted
+    pub fn on_decrypt_failure(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packets_in(&self) -> u64 {
+        self.packets_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn packets_out(&self) -> u64 {
+        self.packets_out.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn decrypt_failures(&self) -> u64 {
+        self.decrypt_failures.load(Ordering::Relaxed)
+    }
+
+    /// Unix timestamp of the last received packet (`0` if there were none)
+    pub fn last_activity(&self) -> u32 {
+        self.last_activity.load(Ordering::Relaxed)
+    }
+}
+
 /// Connection side packets histories and reinit date
 pub struct PeerState {
     ordinary_history: PacketsHistory,
     priority_history: PacketsHistory,
+    ordinary_confirmed: watch::Sender<ConfirmedSeqno>,
+    priority_confirmed: watch::Sender<ConfirmedSeqno>,
     reinit_date: AtomicU32,
 }
 
 impl PeerState {
-    fn for_receive_with_reinit_date(reinit_date: u32) -> Self {
+    fn for_receive_with_reinit_date(reinit_date: u32, config: PacketsHistoryConfig) -> Self {
         Self {
-            ordinary_history: PacketsHistory::for_recv(),
-            priority_history: PacketsHistory::for_recv(),
+            ordinary_history: PacketsHistory::for_recv_with_config(config),
+            priority_history: PacketsHistory::for_recv_with_config(config),
+            ordinary_confirmed: watch::channel(Default::default()).0,
+            priority_confirmed: watch::channel(Default::default()).0,
             reinit_date: AtomicU32::new(reinit_date),
         }
     }
@@ -142,6 +409,8 @@ impl PeerState {
         Self {
             ordinary_history: PacketsHistory::for_send(),
             priority_history: PacketsHistory::for_send(),
+            ordinary_confirmed: watch::channel(Default::default()).0,
+            priority_confirmed: watch::channel(Default::default()).0,
             reinit_date: Default::default(),
         }
     }
@@ -155,6 +424,54 @@ impl PeerState {
         }
     }
 
+    /// Updates the highest seqno confirmed by the remote peer
+    pub fn confirm_seqno(&self, priority: bool, seqno: u64) {
+        self.confirmed(priority).send_if_modified(|confirmed| {
+            let modified = seqno > confirmed.seqno;
+            if modified {
+                confirmed.seqno = seqno;
+            }
+            modified
+        });
+    }
+
+    /// Invalidates all pending delivery confirmations
+    pub fn reset_confirmations(&self) {
+        for priority in [false, true] {
+            self.confirmed(priority).send_modify(|confirmed| {
+                confirmed.epoch = confirmed.epoch.wrapping_add(1);
+                confirmed.seqno = 0;
+            });
+        }
+    }
+
+    /// Creates a handle which waits until the packet with the specified seqno is confirmed
+    pub fn delivery_confirmation(
+        &self,
+        priority: bool,
+        seqno: u64,
+        timeout: Duration,
+    ) -> DeliveryConfirmation {
+        let confirmed = self.confirmed(priority).subscribe();
+        let epoch = confirmed.borrow().epoch;
+        DeliveryConfirmation {
+            seqno,
+            priority,
+            epoch,
+            timeout,
+            confirmed,
+        }
+    }
+
+    #[inline(always)]
+    fn confirmed(&self, priority: bool) -> &watch::Sender<ConfirmedSeqno> {
+        if priority {
+            &self.priority_confirmed
+        } else {
+            &self.ordinary_confirmed
+        }
+    }
+
     pub fn reinit_date(&self) -> u32 {
         self.reinit_date.load(Ordering::Acquire)
     }
@@ -164,8 +481,73 @@ impl PeerState {
     }
 }
 
+#[derive(Default, Copy, Clone)]
+struct ConfirmedSeqno {
+    epoch: u32,
+    seqno: u64,
+}
+
+/// Awaitable handle for the sent ADNL message
+///
+/// The message is considered delivered when the remote peer confirms
+/// the seqno of the last packet of this message (`confirm_seqno` field
+/// of its incoming packets with the same priority). Confirmations only arrive
+/// along with the outgoing traffic from the remote peer, so this is
+/// a "probably delivered" signal rather than a strict acknowledgement.
+pub struct DeliveryConfirmation {
+    seqno: u64,
+    priority: bool,
+    epoch: u32,
+    timeout: Duration,
+    confirmed: watch::Receiver<ConfirmedSeqno>,
+}
+
+impl DeliveryConfirmation {
+    /// Creates an already confirmed handle (e.g. for the loopback messages)
+    pub(crate) fn confirmed(priority: bool) -> Self {
+        Self {
+            seqno: 0,
+            priority,
+            epoch: 0,
+            timeout: Duration::ZERO,
+            confirmed: watch::channel(ConfirmedSeqno::default()).1,
+        }
+    }
+
+    /// Seqno of the last packet of the message
+    pub fn seqno(&self) -> u64 {
+        self.seqno
+    }
+
+    /// Whether the message was sent over the priority channel
+    pub fn priority(&self) -> bool {
+        self.priority
+    }
+
+    /// Returns whether the message is already confirmed
+    pub fn is_confirmed(&self) -> bool {
+        let confirmed = self.confirmed.borrow();
+        confirmed.epoch == self.epoch && confirmed.seqno >= self.seqno
+    }
+
+    /// Waits until the message is confirmed by the remote peer.
+    ///
+    /// Returns `false` if the timeout is reached or the peer state was reset
+    pub async fn wait(mut self) -> bool {
+        let (epoch, seqno) = (self.epoch, self.seqno);
+        let confirmed = self
+            .confirmed
+            .wait_for(|confirmed| confirmed.epoch != epoch || confirmed.seqno >= seqno);
+
+        match tokio::time::timeout(self.timeout, confirmed).await {
+            Ok(Ok(confirmed)) => confirmed.epoch == epoch,
+            _ => false,
+        }
+    }
+}
+
 /// The context in which the new peer is added
-#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum NewPeerContext {
     AdnlPacket,
     Dht,
@@ -190,4 +572,58 @@ mod tests {
         let unpacked = unpack_socket_addr(packed);
         assert_eq!(unpacked, test);
     }
+
+    #[test]
+    fn address_selection() {
+        let addr = |port| SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+
+        let mut addresses = PeerAddresses::new(addr(1));
+
+        // The most recent address is preferred
+        addresses.update(addr(2), 0, 10);
+        assert_eq!(addresses.selected().addr, addr(2));
+
+        // Address with a higher priority is preferred within the same list version
+        addresses.update(addr(3), 1, 20);
+        addresses.update(addr(4), 0, 20);
+        assert_eq!(addresses.selected().addr, addr(3));
+
+        // Fallback on send failure
+        addresses.on_send_failure(addr(3));
+        assert_eq!(addresses.selected().addr, addr(4));
+
+        // Failures are reset on update
+        addresses.update(addr(3), 1, 20);
+        assert_eq!(addresses.selected().addr, addr(3));
+
+        // Newer list wins regardless of priority
+        addresses.update(addr(5), 0, 30);
+        assert_eq!(addresses.selected().addr, addr(5));
+
+        // Stale list doesn't displace the newer address
+        addresses.update(addr(3), 1, 20);
+        assert_eq!(addresses.selected().addr, addr(5));
+
+        // The least preferred address is replaced
+        assert_eq!(addresses.items.len(), MAX_PEER_ADDRESSES);
+        assert!(addresses.items.iter().all(|item| item.addr != addr(1)));
+    }
+
+    #[test]
+    fn reinit_message_replay() {
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 123);
+        let id = NodeIdFull::new(ed25519::KeyPair::generate(&mut rand::thread_rng()).public_key);
+        let peer = Peer::new(1, addr, id, Default::default());
+
+        assert!(peer.receiver_state().history(false).deliver_packet(1));
+        assert!(peer.reinit_sender(10));
+        assert!(peer.receiver_state().history(false).deliver_packet(1));
+
+        // Same or older reinit must not reset histories
+        assert!(!peer.reinit_sender(10));
+        assert!(!peer.reinit_sender(9));
+        assert!(!peer.receiver_state().history(false).deliver_packet(1));
+
+        assert!(peer.reinit_sender(11));
+    }
 }
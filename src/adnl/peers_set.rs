@@ -127,6 +127,13 @@ impl PeersSet {
         self.state.write().insert(peer_id)
     }
 
+    /// Removes a value from the set.
+    ///
+    /// If the set did have this value present, `true` is returned.
+    pub fn remove(&self, peer_id: &NodeIdShort) -> bool {
+        self.state.write().remove(peer_id)
+    }
+
     pub fn extend<I>(&self, peers: I)
     where
         I: IntoIterator<Item = NodeIdShort>,
@@ -302,6 +309,25 @@ impl PeersSetState {
 
         true
     }
+
+    fn remove(&mut self, peer_id: &NodeIdShort) -> bool {
+        let index = match self.cache.remove(Wrapper::wrap(peer_id)) {
+            Some(index) => index as usize,
+            None => return false,
+        };
+        self.version += 1;
+
+        self.index.swap_remove(index);
+        if let Some(moved) = self.index.get(index) {
+            if let Some(moved_index) = self.cache.get_mut(moved) {
+                *moved_index = index as u32;
+            }
+        }
+
+        // Fill the free slot on the next insertion
+        self.upper = self.index.len() as u32;
+        true
+    }
 }
 
 // SAFETY: internal Rcs are not exposed by the api and the reference
@@ -525,4 +551,36 @@ mod tests {
             assert!(state.is_full());
         }
     }
+
+    #[test]
+    fn test_removal() {
+        let cache = PeersSet::with_capacity(3);
+
+        let peers = std::iter::repeat_with(NodeIdShort::random)
+            .take(4)
+            .collect::<Vec<_>>();
+        for peer_id in peers.iter().take(3) {
+            assert!(cache.insert(*peer_id));
+        }
+
+        assert!(cache.remove(&peers[0]));
+        assert!(!cache.remove(&peers[0]));
+        assert!(!cache.contains(&peers[0]));
+        assert!(!cache.is_full());
+
+        // Moved peer is still accessible
+        assert!(cache.contains(&peers[1]));
+        assert!(cache.contains(&peers[2]));
+
+        // Free slot is filled without evicting other peers
+        assert!(cache.insert(peers[3]));
+        assert!(cache.is_full());
+        for peer_id in &peers[1..] {
+            assert!(cache.contains(peer_id));
+        }
+        assert!(cache.remove(&peers[2]));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&peers[1]));
+        assert!(cache.contains(&peers[3]));
+    }
 }
@@ -1,42 +1,126 @@
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Weak};
 
-use tokio::sync::oneshot;
+use bytes::Bytes;
+use tokio::sync::{oneshot, watch};
 
-use crate::util::FastDashMap;
+use super::node_id::NodeIdShort;
+use crate::util::{now, FastDashMap};
 
 pub type QueryId = [u8; 32];
 
-#[derive(Default)]
 pub struct QueriesCache {
-    queries: FastDashMap<QueryId, DataTx>,
+    queries: FastDashMap<QueryId, PendingQueryEntry>,
+    in_flight: FastDashMap<CoalescingKey, SharedAnswerRx>,
+    /// Max number of pending queries
+    capacity: usize,
+    /// Number of queries without answer
+    expired: AtomicU64,
+    /// Number of queries removed by the stale entries sweep
+    stale: AtomicU64,
+    /// Number of queries rejected due to the capacity limit
+    rejected: AtomicU64,
+}
+
+impl Default for QueriesCache {
+    fn default() -> Self {
+        Self::with_capacity(usize::MAX)
+    }
 }
 
 impl QueriesCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            queries: Default::default(),
+            in_flight: Default::default(),
+            capacity,
+            expired: Default::default(),
+            stale: Default::default(),
+            rejected: Default::default(),
+        }
+    }
+
+    pub fn metrics(&self) -> QueriesCacheMetrics {
+        QueriesCacheMetrics {
+            len: self.queries.len(),
+            expired: self.expired.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
     #[allow(unused)]
     pub fn is_empty(&self) -> bool {
         self.queries.is_empty()
     }
 
-    pub fn len(&self) -> usize {
-        self.queries.len()
-    }
+    pub fn add_query(
+        self: &Arc<Self>,
+        query_id: QueryId,
+    ) -> Result<PendingAdnlQuery, QueriesCacheError> {
+        // NOTE: capacity is not strict in case of concurrent insertions
+        if self.capacity != usize::MAX && self.queries.len() >= self.capacity {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(QueriesCacheError::CapacityExceeded);
+        }
 
-    pub fn add_query(self: &Arc<Self>, query_id: QueryId) -> PendingAdnlQuery {
         let (tx, rx) = oneshot::channel();
 
-        self.queries.insert(query_id, tx);
+        self.queries.insert(
+            query_id,
+            PendingQueryEntry {
+                data_tx: tx,
+                created_at: now(),
+            },
+        );
 
-        PendingAdnlQuery {
+        Ok(PendingAdnlQuery {
             query_id,
             data_rx: Some(rx),
             cache: Arc::downgrade(self),
             finished: false,
+        })
+    }
+
+    /// Removes queries which are older than the specified amount of time.
+    /// Returns the number of removed entries
+    pub fn remove_stale(&self, ttl_sec: u32) -> usize {
+        let now = now();
+        let mut removed = 0;
+        self.queries.retain(|_, query| {
+            let retain = query.created_at.saturating_add(ttl_sec) > now;
+            removed += !retain as usize;
+            retain
+        });
+
+        self.stale.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    /// Joins an identical in-flight query or registers a new one
+    pub fn coalesce(self: &Arc<Self>, key: CoalescingKey) -> CoalescedQuery {
+        use dashmap::mapref::entry::Entry;
+
+        match self.in_flight.entry(key) {
+            Entry::Occupied(entry) => CoalescedQuery::Follower(SharedAnswer {
+                answer_rx: entry.get().clone(),
+            }),
+            Entry::Vacant(entry) => {
+                let (answer_tx, answer_rx) = watch::channel(None);
+                let key = entry.key().clone();
+                entry.insert(answer_rx);
+                CoalescedQuery::Leader(InFlightQuery {
+                    key: Some(key),
+                    answer_tx,
+                    cache: Arc::downgrade(self),
+                })
+            }
         }
     }
 
-    pub fn update_query(&self, query_id: &QueryId, answer: &[u8]) {
-        if let Some((_, tx)) = self.queries.remove(query_id) {
-            tx.send(answer.to_vec()).ok();
+    pub fn update_query(&self, query_id: &QueryId, answer: Bytes) {
+        if let Some((_, query)) = self.queries.remove(query_id) {
+            query.data_tx.send(answer).ok();
         }
     }
 }
@@ -49,7 +133,7 @@ pub struct PendingAdnlQuery {
 }
 
 impl PendingAdnlQuery {
-    pub async fn wait(mut self) -> Option<Vec<u8>> {
+    pub async fn wait(mut self) -> Option<Bytes> {
         // SAFETY: `data_rx` is guaranteed to be `Some`
         let data_rx = unsafe { self.data_rx.take().unwrap_unchecked() };
         let data = data_rx.await.ok();
@@ -65,10 +149,114 @@ impl Drop for PendingAdnlQuery {
         }
 
         if let Some(cache) = self.cache.upgrade() {
-            cache.queries.remove(&self.query_id);
+            if cache.queries.remove(&self.query_id).is_some() {
+                cache.expired.fetch_add(1, Ordering::Relaxed);
+            }
         }
     }
 }
 
-type DataTx = oneshot::Sender<Vec<u8>>;
-type DataRx = oneshot::Receiver<Vec<u8>>;
+/// Local id, peer id and the query data
+pub type CoalescingKey = (NodeIdShort, NodeIdShort, Bytes);
+
+pub enum CoalescedQuery {
+    /// There are no identical queries in flight, the query must be sent
+    Leader(InFlightQuery),
+    /// The identical query was already sent
+    Follower(SharedAnswer),
+}
+
+/// Registered in-flight query. Its answer is shared with all followers
+pub struct InFlightQuery {
+    key: Option<CoalescingKey>,
+    answer_tx: SharedAnswerTx,
+    cache: Weak<QueriesCache>,
+}
+
+impl InFlightQuery {
+    /// Sends the answer to all followers
+    pub fn finish(mut self, answer: &Option<Bytes>) {
+        self.unregister();
+        self.answer_tx.send_replace(Some(answer.clone()));
+    }
+
+    fn unregister(&mut self) {
+        if let (Some(key), Some(cache)) = (self.key.take(), self.cache.upgrade()) {
+            cache.in_flight.remove(&key);
+        }
+    }
+}
+
+impl Drop for InFlightQuery {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Answer of the identical query
+pub struct SharedAnswer {
+    answer_rx: SharedAnswerRx,
+}
+
+impl SharedAnswer {
+    /// Waits for the answer of the leader query.
+    ///
+    /// Returns `None` if the leader query was cancelled or failed
+    pub async fn wait(mut self) -> Option<Option<Bytes>> {
+        match self.answer_rx.wait_for(Option::is_some).await {
+            Ok(answer) => answer.clone(),
+            Err(_) => None,
+        }
+    }
+}
+
+type SharedAnswerTx = watch::Sender<Option<Option<Bytes>>>;
+type SharedAnswerRx = watch::Receiver<Option<Option<Bytes>>>;
+
+struct PendingQueryEntry {
+    data_tx: DataTx,
+    created_at: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct QueriesCacheMetrics {
+    pub len: usize,
+    pub expired: u64,
+    pub stale: u64,
+    pub rejected: u64,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum QueriesCacheError {
+    #[error("Too many pending queries")]
+    CapacityExceeded,
+}
+
+type DataTx = oneshot::Sender<Bytes>;
+type DataRx = oneshot::Receiver<Bytes>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_and_stale_queries() {
+        let cache = Arc::new(QueriesCache::with_capacity(2));
+
+        let first = cache.add_query([1; 32]).unwrap();
+        let _second = cache.add_query([2; 32]).unwrap();
+        assert!(cache.add_query([3; 32]).is_err());
+
+        drop(first);
+        let _third = cache.add_query([3; 32]).unwrap();
+
+        assert_eq!(cache.remove_stale(10), 0);
+        assert_eq!(cache.remove_stale(0), 2);
+
+        let metrics = cache.metrics();
+        assert_eq!(metrics.len, 0);
+        assert_eq!(metrics.expired, 1);
+        assert_eq!(metrics.stale, 2);
+        assert_eq!(metrics.rejected, 1);
+    }
+}
@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+/// Query roundtrip time tracker
+///
+/// Smoothed RTT and its variation are computed in the same way as in TCP (RFC 6298).
+#[derive(Default)]
+pub struct RttTracker {
+    state: Mutex<RttState>,
+}
+
+impl RttTracker {
+    /// Adds new roundtrip sample
+    pub fn add_sample(&self, rtt: Duration) {
+        let sample = rtt.as_micros().min(u64::MAX as u128) as u64;
+
+        let mut state = self.state.lock();
+        if state.sample_count == 0 {
+            state.smoothed = sample;
+            state.variation = sample / 2;
+        } else {
+            let diff = state.smoothed.abs_diff(sample);
+            state.variation = (state.variation * 3 + diff) / 4;
+            state.smoothed = (state.smoothed * 7 + sample) / 8;
+        }
+
+        let offset = (state.sample_count % RTT_HISTORY_LEN as u64) as usize;
+        state.history[offset] = sample;
+        state.sample_count += 1;
+    }
+
+    /// Returns current stats or `None` if there were no samples
+    pub fn stats(&self) -> Option<PeerRtt> {
+        let (smoothed, variation, sample_count, mut history) = {
+            let state = self.state.lock();
+            if state.sample_count == 0 {
+                return None;
+            }
+            (
+                state.smoothed,
+                state.variation,
+                state.sample_count,
+                state.history,
+            )
+        };
+
+        let history = &mut history[..std::cmp::min(sample_count, RTT_HISTORY_LEN as u64) as usize];
+        history.sort_unstable();
+
+        let percentile = |p: usize| to_ms(history[(history.len() - 1) * p / 100]);
+
+        Some(PeerRtt {
+            smoothed_ms: to_ms(smoothed),
+            variation_ms: to_ms(variation),
+            min_ms: percentile(0),
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+            max_ms: percentile(100),
+            sample_count,
+        })
+    }
+}
+
+struct RttState {
+    smoothed: u64,
+    variation: u64,
+    sample_count: u64,
+    history: [u64; RTT_HISTORY_LEN],
+}
+
+impl Default for RttState {
+    fn default() -> Self {
+        Self {
+            smoothed: 0,
+            variation: 0,
+            sample_count: 0,
+            history: [0; RTT_HISTORY_LEN],
+        }
+    }
+}
+
+/// Query roundtrip time stats for the remote peer
+///
+/// Percentiles are computed using the last 64 samples
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PeerRtt {
+    /// Smoothed roundtrip time (EWMA)
+    pub smoothed_ms: u64,
+    /// Roundtrip time variation
+    pub variation_ms: u64,
+    pub min_ms: u64,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+    /// Total number of samples
+    pub sample_count: u64,
+}
+
+impl PeerRtt {
+    /// Retransmission timeout computed from the smoothed roundtrip time
+    pub fn timeout_ms(&self) -> u64 {
+        self.smoothed_ms + self.variation_ms * 4
+    }
+}
+
+#[inline(always)]
+fn to_ms(micros: u64) -> u64 {
+    (micros + 999) / 1000
+}
+
+const RTT_HISTORY_LEN: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_stats() {
+        let tracker = RttTracker::default();
+        assert!(tracker.stats().is_none());
+
+        tracker.add_sample(Duration::from_millis(100));
+        let stats = tracker.stats().unwrap();
+        assert_eq!(stats.smoothed_ms, 100);
+        assert_eq!(stats.variation_ms, 50);
+        assert_eq!(stats.timeout_ms(), 300);
+
+        for i in 1..=100 {
+            tracker.add_sample(Duration::from_millis(i));
+        }
+        let stats = tracker.stats().unwrap();
+        assert_eq!(stats.sample_count, 101);
+        assert_eq!(stats.min_ms, 37);
+        assert_eq!(stats.max_ms, 100);
+        assert!(stats.p50_ms < stats.p90_ms);
+        assert!(stats.p90_ms <= stats.p99_ms);
+    }
+}
@@ -1,10 +1,45 @@
-use std::net::Ipv4Addr;
-use std::sync::Arc;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 
 use anyhow::Result;
 use tokio::net::UdpSocket;
 
-pub fn make_udp_socket(port: u16) -> Result<Arc<UdpSocket>> {
+/// Transport of the ADNL packets
+pub enum NodeSocket {
+    Udp(UdpSocket),
+    #[cfg(feature = "test-utils")]
+    Memory(crate::test_utils::MemorySocket),
+}
+
+impl NodeSocket {
+    pub async fn send_to(&self, data: &[u8], addr: SocketAddrV4) -> std::io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send_to(data, addr).await.map(|_| ()),
+            #[cfg(feature = "test-utils")]
+            Self::Memory(socket) => {
+                socket.send_to(data, addr);
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self {
+            Self::Udp(socket) => socket.recv_from(buffer).await,
+            #[cfg(feature = "test-utils")]
+            Self::Memory(socket) => socket.recv_from(buffer).await,
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        match self {
+            Self::Udp(socket) => socket.local_addr(),
+            #[cfg(feature = "test-utils")]
+            Self::Memory(socket) => Ok(SocketAddr::V4(socket.local_addr())),
+        }
+    }
+}
+
+pub fn make_udp_socket(port: u16) -> Result<NodeSocket> {
     let udp_socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
     udp_socket.set_nonblocking(true)?;
 
@@ -17,7 +52,7 @@ pub fn make_udp_socket(port: u16) -> Result<Arc<UdpSocket>> {
         set_reuse_port(fd, true)?;
     }
 
-    Ok(Arc::new(UdpSocket::from_std(udp_socket)?))
+    Ok(NodeSocket::Udp(UdpSocket::from_std(udp_socket)?))
 }
 
 #[cfg(unix)]
@@ -1,7 +1,12 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use parking_lot::Mutex;
 use sha2::Digest;
+use tokio::sync::watch;
 
+use super::node_id::NodeIdShort;
+use super::peer::DeliveryConfirmation;
 use crate::util::*;
 
 pub type TransferId = [u8; 32];
@@ -18,21 +23,51 @@ pub struct Transfer {
     received_len: AtomicUsize,
     /// Total data length
     total_len: usize,
+    /// Whether all parts were received. Parts are ignored after that
+    completed: AtomicBool,
+    /// Reserved memory, released when the transfer is completed or dropped
+    permit: Mutex<Option<TransferPermit>>,
     /// Transfer timings used to check its validity
     timings: UpdatedAt,
 }
 
 impl Transfer {
     /// Creates new multipart transfer with target length in bytes
-    pub fn new(total_len: usize) -> Self {
+    pub fn new(permit: TransferPermit) -> Self {
         Self {
             parts: FastDashMap::with_capacity_and_hasher(0, Default::default()),
             received_len: Default::default(),
-            total_len,
+            total_len: permit.size,
+            completed: Default::default(),
+            permit: Mutex::new(Some(permit)),
             timings: Default::default(),
         }
     }
 
+    /// Whether all parts were received or the transfer was aborted
+    #[inline(always)]
+    pub fn is_completed(&self) -> bool {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    /// Received data length
+    pub fn received_len(&self) -> usize {
+        std::cmp::min(self.received_len.load(Ordering::Acquire), self.total_len)
+    }
+
+    /// Total data length
+    #[inline(always)]
+    pub fn total_len(&self) -> usize {
+        self.total_len
+    }
+
+    /// Drops all received parts and ignores new parts
+    pub fn abort(&self) {
+        self.completed.store(true, Ordering::Release);
+        self.parts.clear();
+        self.permit.lock().take();
+    }
+
     /// Returns transfer timings info (when it was last updated)
     #[inline(always)]
     pub fn timings(&self) -> &UpdatedAt {
@@ -41,13 +76,17 @@ impl Transfer {
 
     /// Tries to add new part to the transfer at given offset
     ///
-    /// Will do nothing if part at given offset already exists
+    /// Will do nothing if part at given offset already exists or the transfer is completed
     pub fn add_part(
         &self,
         offset: usize,
         data: Vec<u8>,
         transfer_id: &TransferId,
     ) -> Result<Option<Vec<u8>>, TransferError> {
+        if self.is_completed() {
+            return Ok(None);
+        }
+
         let len = data.len();
         if self.parts.insert(offset, data).is_some() {
             return Ok(None);
@@ -98,7 +137,10 @@ impl Transfer {
                     return Err(TransferError::InvalidHash);
                 }
 
-                // Done
+                // Done. Keep the transfer itself to ignore resent parts
+                self.completed.store(true, Ordering::Release);
+                self.parts.clear();
+                self.permit.lock().take();
                 Ok(Some(buffer))
             }
             std::cmp::Ordering::Greater => Err(TransferError::ReceivedTooMuch),
@@ -115,6 +157,311 @@ impl Transfer {
     }
 }
 
+/// Multipart transfers progress observer
+pub trait TransferObserver: Send + Sync {
+    /// Called after each sent or received part.
+    ///
+    /// Returns `false` to abort the transfer
+    fn on_progress(&self, progress: &TransferProgress) -> bool;
+}
+
+/// Multipart transfer progress
+///
+/// See [`TransferObserver`]
+#[derive(Debug, Copy, Clone)]
+pub struct TransferProgress<'a> {
+    /// Transfer id (hash of the data)
+    pub transfer_id: &'a TransferId,
+    pub direction: TransferDirection,
+    pub local_id: &'a NodeIdShort,
+    pub peer_id: &'a NodeIdShort,
+    /// Sent, confirmed or received data length
+    pub bytes_done: usize,
+    /// Total data length
+    pub total: usize,
+}
+
+/// Multipart transfer direction
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransferDirection {
+    Incoming,
+    Outgoing,
+}
+
+/// Limits for the incoming multipart transfers
+pub struct TransfersBudget {
+    /// Max total size of a single transfer
+    max_size: usize,
+    /// Max number of concurrent transfers from the same peer
+    max_per_peer: usize,
+    /// Max total size of all concurrent transfers
+    memory_limit: usize,
+    /// Max number of concurrent transfers from all peers
+    max_transfers: usize,
+    /// Currently reserved memory
+    memory_used: AtomicUsize,
+    /// Number of concurrent transfers
+    transfers: AtomicUsize,
+    /// Number of concurrent transfers for each peer
+    transfers_per_peer: FastDashMap<NodeIdShort, usize>,
+}
+
+impl TransfersBudget {
+    pub fn new(max_size: usize, max_per_peer: usize, memory_limit: usize) -> Self {
+        Self {
+            max_size,
+            max_per_peer,
+            memory_limit,
+            max_transfers: usize::MAX,
+            memory_used: Default::default(),
+            transfers: Default::default(),
+            transfers_per_peer: Default::default(),
+        }
+    }
+
+    /// Limits the number of concurrent transfers from all peers
+    #[cfg(feature = "rldp")]
+    pub fn with_max_transfers(mut self, max_transfers: usize) -> Self {
+        self.max_transfers = max_transfers;
+        self
+    }
+
+    /// Number of concurrent transfers
+    #[cfg(feature = "rldp")]
+    pub fn transfers(&self) -> usize {
+        self.transfers.load(Ordering::Acquire)
+    }
+
+    /// Currently reserved memory in bytes
+    pub fn memory_used(&self) -> usize {
+        self.memory_used.load(Ordering::Acquire)
+    }
+
+    /// Tries to reserve memory for the new transfer from the specified peer
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        peer_id: &NodeIdShort,
+        size: usize,
+    ) -> Result<TransferPermit, TransferError> {
+        self.acquire(peer_id, size, size)
+    }
+
+    /// Tries to start the new transfer from the specified peer without reserving memory.
+    /// Memory is reserved with [`TransferPermit::try_grow`] as the data arrives.
+    #[cfg(feature = "rldp")]
+    pub fn try_acquire_lazy(
+        self: &Arc<Self>,
+        peer_id: &NodeIdShort,
+        total_size: usize,
+    ) -> Result<TransferPermit, TransferError> {
+        self.acquire(peer_id, total_size, 0)
+    }
+
+    fn acquire(
+        self: &Arc<Self>,
+        peer_id: &NodeIdShort,
+        total_size: usize,
+        size: usize,
+    ) -> Result<TransferPermit, TransferError> {
+        if total_size > self.max_size {
+            return Err(TransferError::TooBig);
+        }
+
+        let reserved =
+            self.transfers
+                .fetch_update(Ordering::Release, Ordering::Acquire, |transfers| {
+                    (transfers < self.max_transfers).then_some(transfers + 1)
+                });
+        if reserved.is_err() {
+            return Err(TransferError::TooManyTransfers);
+        }
+
+        {
+            let mut transfers = self.transfers_per_peer.entry(*peer_id).or_default();
+            if *transfers >= self.max_per_peer {
+                self.transfers.fetch_sub(1, Ordering::Release);
+                return Err(TransferError::TooManyTransfers);
+            }
+            *transfers += 1;
+        }
+
+        if !self.try_reserve(size) {
+            self.release_peer_slot(peer_id);
+            self.transfers.fetch_sub(1, Ordering::Release);
+            return Err(TransferError::OutOfMemory);
+        }
+
+        Ok(TransferPermit {
+            budget: self.clone(),
+            peer_id: *peer_id,
+            size,
+        })
+    }
+
+    fn try_reserve(&self, size: usize) -> bool {
+        self.memory_used
+            .fetch_update(Ordering::Release, Ordering::Acquire, |used| {
+                let used = used.checked_add(size)?;
+                (used <= self.memory_limit).then_some(used)
+            })
+            .is_ok()
+    }
+
+    fn release_peer_slot(&self, peer_id: &NodeIdShort) {
+        self.transfers_per_peer
+            .remove_if_mut(peer_id, |_, transfers| {
+                *transfers = transfers.saturating_sub(1);
+                *transfers == 0
+            });
+    }
+}
+
+/// Memory reserved for the incoming transfer
+pub struct TransferPermit {
+    budget: Arc<TransfersBudget>,
+    peer_id: NodeIdShort,
+    size: usize,
+}
+
+impl TransferPermit {
+    /// Tries to reserve more memory for the received data
+    #[cfg(feature = "rldp")]
+    pub fn try_grow(&mut self, additional: usize) -> Result<(), TransferError> {
+        if self.budget.try_reserve(additional) {
+            self.size += additional;
+            Ok(())
+        } else {
+            Err(TransferError::OutOfMemory)
+        }
+    }
+}
+
+impl Drop for TransferPermit {
+    fn drop(&mut self) {
+        self.budget
+            .memory_used
+            .fetch_sub(self.size, Ordering::Release);
+        self.budget.transfers.fetch_sub(1, Ordering::Release);
+        self.budget.release_peer_slot(&self.peer_id);
+    }
+}
+
+/// Split message which parts are resent until confirmed by the remote peer
+pub struct OutgoingTransfer {
+    local_id: NodeIdShort,
+    peer_id: NodeIdShort,
+    priority: bool,
+    /// Serialized message
+    data: Vec<u8>,
+    /// Message hash (transfer id)
+    hash: TransferId,
+    /// Parts which were not confirmed yet
+    parts: Mutex<Vec<OutgoingPart>>,
+    /// Number of retransmissions
+    attempts: AtomicU32,
+    completion: watch::Sender<Option<bool>>,
+}
+
+impl OutgoingTransfer {
+    pub fn new(
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+        priority: bool,
+        data: Vec<u8>,
+        hash: TransferId,
+        parts: Vec<OutgoingPart>,
+    ) -> Self {
+        Self {
+            local_id,
+            peer_id,
+            priority,
+            data,
+            hash,
+            parts: Mutex::new(parts),
+            attempts: Default::default(),
+            completion: watch::channel(None).0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn local_id(&self) -> &NodeIdShort {
+        &self.local_id
+    }
+
+    #[inline(always)]
+    pub fn peer_id(&self) -> &NodeIdShort {
+        &self.peer_id
+    }
+
+    #[inline(always)]
+    pub fn priority(&self) -> bool {
+        self.priority
+    }
+
+    #[inline(always)]
+    pub fn hash(&self) -> &TransferId {
+        &self.hash
+    }
+
+    /// Serialized message
+    #[inline(always)]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Unconfirmed parts
+    #[inline(always)]
+    pub fn parts(&self) -> &Mutex<Vec<OutgoingPart>> {
+        &self.parts
+    }
+
+    /// Increments retransmissions counter and returns its previous value
+    pub fn add_attempt(&self) -> u32 {
+        self.attempts.fetch_add(1, Ordering::AcqRel)
+    }
+
+    /// Resolves all completion futures
+    pub fn complete(&self, delivered: bool) {
+        self.completion.send_replace(Some(delivered));
+    }
+
+    /// Creates a handle which waits until the transfer is completed
+    pub fn subscribe(&self) -> TransferCompletion {
+        TransferCompletion {
+            completion: self.completion.subscribe(),
+        }
+    }
+}
+
+/// Part of the outgoing transfer
+pub struct OutgoingPart {
+    pub offset: usize,
+    pub len: usize,
+    /// Confirmation of the last packet with this part
+    pub confirmation: DeliveryConfirmation,
+}
+
+/// Awaitable handle for the split message with resent parts
+pub struct TransferCompletion {
+    completion: watch::Receiver<Option<bool>>,
+}
+
+impl TransferCompletion {
+    /// Returns `Some` with the transfer result if it was completed
+    pub fn result(&self) -> Option<bool> {
+        *self.completion.borrow()
+    }
+
+    /// Waits until all parts are confirmed by the remote peer or retransmission
+    /// attempts are exhausted. Returns whether all parts were confirmed
+    pub async fn wait(mut self) -> bool {
+        match self.completion.wait_for(Option::is_some).await {
+            Ok(result) => result.unwrap_or_default(),
+            Err(_) => false,
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct DisplayTransferId<'a>(pub &'a TransferId);
 
@@ -137,4 +484,99 @@ pub enum TransferError {
     PartMissing,
     #[error("Invalid transfer data hash")]
     InvalidHash,
+    #[error("Transfer is too big")]
+    TooBig,
+    #[error("Too many concurrent transfers")]
+    TooManyTransfers,
+    #[error("Transfers memory limit exceeded")]
+    OutOfMemory,
+    #[error("Transfer aborted")]
+    Aborted,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_budget() {
+        let budget = Arc::new(TransfersBudget::new(100, 2, 150));
+        let peer = NodeIdShort::new([1; 32]);
+        let other_peer = NodeIdShort::new([2; 32]);
+
+        assert!(matches!(
+            budget.try_acquire(&peer, 101),
+            Err(TransferError::TooBig)
+        ));
+
+        let first = budget.try_acquire(&peer, 50).unwrap();
+        let _second = budget.try_acquire(&peer, 50).unwrap();
+        assert!(matches!(
+            budget.try_acquire(&peer, 10),
+            Err(TransferError::TooManyTransfers)
+        ));
+        assert!(matches!(
+            budget.try_acquire(&other_peer, 60),
+            Err(TransferError::OutOfMemory)
+        ));
+        assert_eq!(budget.memory_used(), 100);
+
+        drop(first);
+        assert_eq!(budget.memory_used(), 50);
+        let _third = budget.try_acquire(&peer, 100).unwrap();
+        assert_eq!(budget.memory_used(), 150);
+        #[cfg(feature = "rldp")]
+        assert_eq!(budget.transfers(), 2);
+    }
+
+    #[cfg(feature = "rldp")]
+    #[test]
+    fn transfers_budget_lazy() {
+        let budget = Arc::new(TransfersBudget::new(100, 2, 150));
+        let peer = NodeIdShort::new([1; 32]);
+
+        assert!(matches!(
+            budget.try_acquire_lazy(&peer, 101),
+            Err(TransferError::TooBig)
+        ));
+
+        // Declared size is not reserved
+        let mut first = budget.try_acquire_lazy(&peer, 100).unwrap();
+        let mut second = budget.try_acquire_lazy(&peer, 100).unwrap();
+        assert_eq!(budget.memory_used(), 0);
+
+        first.try_grow(100).unwrap();
+        second.try_grow(40).unwrap();
+        assert!(matches!(
+            second.try_grow(20),
+            Err(TransferError::OutOfMemory)
+        ));
+        assert_eq!(budget.memory_used(), 140);
+
+        drop(first);
+        assert_eq!(budget.memory_used(), 40);
+        second.try_grow(60).unwrap();
+        drop(second);
+        assert_eq!(budget.memory_used(), 0);
+        assert_eq!(budget.transfers(), 0);
+    }
+
+    #[cfg(feature = "rldp")]
+    #[test]
+    fn transfers_budget_max_transfers() {
+        let budget = Arc::new(TransfersBudget::new(100, 2, 1000).with_max_transfers(2));
+        let first = budget.try_acquire(&NodeIdShort::new([1; 32]), 10).unwrap();
+        let _second = budget.try_acquire(&NodeIdShort::new([2; 32]), 10).unwrap();
+
+        let other_peer = NodeIdShort::new([3; 32]);
+        assert!(matches!(
+            budget.try_acquire(&other_peer, 10),
+            Err(TransferError::TooManyTransfers)
+        ));
+        assert_eq!(budget.transfers(), 2);
+
+        drop(first);
+        let _third = budget.try_acquire(&other_peer, 10).unwrap();
+        assert_eq!(budget.memory_used(), 20);
+    }
 }
@@ -0,0 +1,260 @@
+//! ADNL packets wire format.
+//!
+//! Builds and parses handshake and channel packets without running a [`Node`],
+//! so test harnesses, fuzzers and network analyzers can reuse the same logic.
+//!
+//! Packet data is a serialized `adnl.packetContents`
+//! (see [`proto::adnl::OutgoingPacketContents`] and [`DecryptedPacket::contents`]).
+//!
+//! [`Node`]: crate::adnl::Node
+
+use everscale_crypto::ed25519;
+
+use super::channel::{AdnlChannelError, Channel, ChannelCreationContext};
+use super::handshake::{self, HandshakeError};
+use super::node_id::{NodeIdFull, NodeIdShort};
+use super::packet_view::PacketView;
+use crate::proto;
+
+pub use super::channel::ADNL_CHACHA20_POLY1305_VERSION;
+
+/// Returns the short id of the handshake packet destination
+pub fn handshake_packet_destination(packet: &[u8]) -> Option<NodeIdShort> {
+    let id: [u8; 32] = packet.get(..32)?.try_into().ok()?;
+    Some(NodeIdShort::new(id))
+}
+
+/// Modifies `data` in-place to contain the handshake packet to the specified peer.
+///
+/// Handshake packets are encrypted with a new random key each time
+pub fn build_handshake_packet(peer_id_full: &NodeIdFull, data: &mut Vec<u8>, version: Option<u16>) {
+    let peer_id = peer_id_full.compute_short_id();
+    handshake::build_handshake_packet(
+        &peer_id,
+        &handshake::HandshakeKey::generate(peer_id_full),
+        data,
+        version,
+    );
+}
+
+/// Decrypts the handshake packet addressed to the local key.
+///
+/// **NOTE: even on failure packet can be modified**
+pub fn parse_handshake_packet<'a>(
+    local_key: &ed25519::KeyPair,
+    packet: &'a mut [u8],
+) -> Result<DecryptedPacket<'a>, WireError> {
+    let local_id = NodeIdFull::new(local_key.public_key).compute_short_id();
+    if handshake_packet_destination(packet) != Some(local_id) {
+        return Err(WireError::UnknownDestination);
+    }
+
+    let mut buffer = PacketView::from(packet);
+    let version = handshake::decrypt_handshake_packet(&local_key.secret_key, &mut buffer)?;
+    Ok(DecryptedPacket {
+        version,
+        priority: false,
+        data: buffer.into_slice(),
+    })
+}
+
+/// Channel packets encryption state of one side.
+///
+/// Both sides must use the same channel keys and dates as in
+/// `adnl.message.createChannel` and `adnl.message.confirmChannel`
+pub struct ChannelCodec {
+    channel: Channel,
+}
+
+impl ChannelCodec {
+    pub fn new(
+        local_id: NodeIdShort,
+        peer_id: NodeIdShort,
+        channel_key: &ed25519::KeyPair,
+        peer_channel_public_key: ed25519::PublicKey,
+    ) -> Self {
+        Self {
+            channel: Channel::new(
+                local_id,
+                peer_id,
+                channel_key,
+                peer_channel_public_key,
+                0,
+                ChannelCreationContext::ConfirmChannel,
+            ),
+        }
+    }
+
+    /// Id of the incoming packets (prefix of the channel packet)
+    pub fn in_id(&self, priority: bool) -> &[u8; 32] {
+        if priority {
+            self.channel.priority_channel_in_id()
+        } else {
+            self.channel.ordinary_channel_in_id()
+        }
+    }
+
+    /// Modifies `data` in-place to contain the channel packet.
+    ///
+    /// Packets with [`ADNL_CHACHA20_POLY1305_VERSION`] are encrypted with ChaCha20-Poly1305
+    pub fn encrypt(&self, data: &mut Vec<u8>, priority: bool, version: Option<u16>) {
+        self.channel.encrypt(data, priority, version);
+    }
+
+    /// Decrypts the channel packet from the remote peer.
+    ///
+    /// **NOTE: even on failure packet can be modified**
+    pub fn decrypt<'a>(&self, packet: &'a mut [u8]) -> Result<DecryptedPacket<'a>, WireError> {
+        let priority = match packet.get(..32) {
+            Some(id) if id == self.in_id(false) => false,
+            Some(id) if id == self.in_id(true) => true,
+            Some(_) => return Err(WireError::UnknownChannel),
+            None => return Err(WireError::PacketTooShort),
+        };
+
+        let mut buffer = PacketView::from(packet);
+        let version = self.channel.decrypt(&mut buffer, priority)?;
+        Ok(DecryptedPacket {
+            version,
+            priority,
+            data: buffer.into_slice(),
+        })
+    }
+}
+
+/// Decrypted ADNL packet
+#[derive(Debug, Copy, Clone)]
+pub struct DecryptedPacket<'a> {
+    /// ADNL version of the packet
+    pub version: Option<u16>,
+    /// Whether the packet was received from the priority channel
+    pub priority: bool,
+    /// Serialized `adnl.packetContents`
+    pub data: &'a [u8],
+}
+
+impl<'a> DecryptedPacket<'a> {
+    /// Parses packet contents. Signature is not checked
+    pub fn contents(&self) -> tl_proto::TlResult<proto::adnl::IncomingPacketContents<'a>> {
+        tl_proto::deserialize(self.data)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum WireError {
+    #[error("Packet is too short")]
+    PacketTooShort,
+    #[error("Packet is addressed to another key")]
+    UnknownDestination,
+    #[error("Unknown channel id")]
+    UnknownChannel,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Invalid packet checksum")]
+    InvalidChecksum,
+}
+
+impl From<HandshakeError> for WireError {
+    fn from(e: HandshakeError) -> Self {
+        match e {
+            HandshakeError::BadHandshakePacketLength => Self::PacketTooShort,
+            HandshakeError::BadHandshakePacketChecksum => Self::InvalidChecksum,
+            HandshakeError::InvalidPublicKey => Self::InvalidPublicKey,
+            // NOTE: local key is always known here
+            HandshakeError::ExternalSigner => Self::UnknownDestination,
+        }
+    }
+}
+
+impl From<AdnlChannelError> for WireError {
+    fn from(e: AdnlChannelError) -> Self {
+        match e {
+            AdnlChannelError::ChannelMessageIsTooShort(_) => Self::PacketTooShort,
+            AdnlChannelError::InvalidChannelMessageChecksum => Self::InvalidChecksum,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_packet_contents() -> Vec<u8> {
+        let messages = tl_proto::serialize(proto::adnl::Message::Nop);
+        tl_proto::serialize(proto::adnl::OutgoingPacketContents {
+            rand1: &[1; 3],
+            from: None,
+            messages: proto::adnl::OutgoingMessages::Single(&messages),
+            address: proto::adnl::AddressList {
+                address: None,
+                version: 0,
+                reinit_date: 0,
+                priority: 0,
+                expire_at: 0,
+            },
+            seqno: 10,
+            confirm_seqno: 0,
+            reinit_dates: None,
+            signature: None,
+            rand2: &[2; 7],
+        })
+    }
+
+    #[test]
+    fn handshake_roundtrip() {
+        let local_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let local_id_full = NodeIdFull::new(local_key.public_key);
+
+        let data = make_packet_contents();
+        for version in [None, Some(0)] {
+            let mut packet = data.clone();
+            build_handshake_packet(&local_id_full, &mut packet, version);
+            assert_eq!(
+                handshake_packet_destination(&packet),
+                Some(local_id_full.compute_short_id())
+            );
+
+            let decrypted = parse_handshake_packet(&local_key, &mut packet).unwrap();
+            assert_eq!(decrypted.version, version);
+            assert_eq!(decrypted.data, data);
+            assert_eq!(decrypted.contents().unwrap().seqno, Some(10));
+        }
+
+        let other_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let mut packet = data;
+        build_handshake_packet(&local_id_full, &mut packet, None);
+        assert!(matches!(
+            parse_handshake_packet(&other_key, &mut packet),
+            Err(WireError::UnknownDestination)
+        ));
+    }
+
+    #[test]
+    fn channel_roundtrip() {
+        let left_id = NodeIdShort::new([1; 32]);
+        let left_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+        let right_id = NodeIdShort::new([2; 32]);
+        let right_key = ed25519::KeyPair::generate(&mut rand::thread_rng());
+
+        let left = ChannelCodec::new(left_id, right_id, &left_key, right_key.public_key);
+        let right = ChannelCodec::new(right_id, left_id, &right_key, left_key.public_key);
+
+        let data = make_packet_contents();
+        for priority in [false, true] {
+            let mut packet = data.clone();
+            left.encrypt(&mut packet, priority, None);
+            assert_eq!(&packet[..32], right.in_id(priority));
+
+            let decrypted = right.decrypt(&mut packet).unwrap();
+            assert_eq!(decrypted.priority, priority);
+            assert_eq!(decrypted.data, data);
+        }
+
+        let mut packet = data;
+        left.encrypt(&mut packet, false, None);
+        assert!(matches!(
+            left.decrypt(&mut packet),
+            Err(WireError::UnknownChannel)
+        ));
+    }
+}
@@ -0,0 +1,62 @@
+use std::convert::TryFrom;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::node::Node;
+use crate::adnl;
+
+impl Node {
+    /// Signs the local DHT node with the current address list and sends it
+    /// to the peers closest to the local id, so they add it to their buckets.
+    ///
+    /// Returns the number of peers which have received the local node
+    pub async fn announce_self(&self) -> Result<usize> {
+        self.update_query_prefix().await?;
+
+        let nodes = self.find_nodes(self.key().id().as_slice()).await?;
+        let peer_ids = nodes
+            .iter()
+            .filter_map(|node| {
+                adnl::NodeIdFull::try_from(node.id.as_equivalent_ref())
+                    .map(|full_id| full_id.compute_short_id())
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+
+        let k = self.options().lookup_k;
+        let results = futures_util::future::join_all(
+            peer_ids
+                .iter()
+                .map(|peer_id| self.query_dht_nodes(peer_id, k, true)),
+        )
+        .await;
+
+        Ok(results.iter().filter(|result| result.is_ok()).count())
+    }
+
+    /// Starts a process that periodically announces the local DHT node
+    pub(super) fn start_announce_loop(self: &Arc<Self>) {
+        let interval = Duration::from_secs(self.options().announce_interval_sec as u64);
+        let dht = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let dht = match Weak::upgrade(&dht) {
+                    Some(dht) => dht,
+                    None => break,
+                };
+
+                match dht.announce_self().await {
+                    Ok(announced) => tracing::debug!(announced, "announced local DHT node"),
+                    Err(e) => tracing::warn!("failed to announce local DHT node: {e:?}"),
+                }
+            }
+
+            tracing::debug!("DHT announce loop finished");
+        });
+    }
+}
@@ -1,43 +1,280 @@
 use std::borrow::Borrow;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+
+use parking_lot::Mutex;
 
 use crate::adnl;
 use crate::proto;
 use crate::util::*;
 
+pub struct BucketsOptions {
+    pub max_bucket_size: usize,
+    pub subnet_prefix_len: u8,
+    pub max_bucket_nodes_per_subnet: Option<usize>,
+    pub max_nodes_per_subnet: Option<usize>,
+}
+
 /// DHT nodes, distributed by max equal bits
 pub struct Buckets {
     local_id: [u8; 32],
-    buckets: Box<[FastDashMap<adnl::NodeIdShort, proto::dht::NodeOwned>; 256]>,
+    options: BucketsOptions,
+    buckets: Box<[Bucket; 256]>,
+    /// Candidates for the full buckets, which will replace
+    /// the least recently seen nodes if they don't respond
+    replacements: Box<[Mutex<Option<Replacement>>; 256]>,
+    /// Number of nodes in each subnet.
+    /// NOTE: all insertions and removals are made under this lock
+    subnets: Mutex<FastHashMap<u32, usize>>,
+    /// Last traffic timestamp for each bucket
+    last_activity: Box<[AtomicU32; 256]>,
+}
+
+pub type Bucket = FastDashMap<adnl::NodeIdShort, BucketEntry>;
+
+type Replacement = (adnl::NodeIdShort, proto::dht::NodeOwned);
+
+/// DHT node in the bucket
+pub struct BucketEntry {
+    pub node: proto::dht::NodeOwned,
+    pub last_seen: Instant,
 }
 
 impl Buckets {
-    pub fn new(local_id: &adnl::NodeIdShort) -> Self {
+    pub fn new(local_id: &adnl::NodeIdShort, mut options: BucketsOptions) -> Self {
+        options.max_bucket_size = std::cmp::max(options.max_bucket_size, 1);
+        options.subnet_prefix_len = std::cmp::min(options.subnet_prefix_len, 32);
+
         Self {
             local_id: *local_id.as_slice(),
+            options,
             buckets: Box::new([(); 256].map(|_| Default::default())),
+            replacements: Box::new([(); 256].map(|_| Default::default())),
+            subnets: Default::default(),
+            last_activity: Box::new([(); 256].map(|_| AtomicU32::new(now()))),
         }
     }
 
+    /// Max number of nodes in each bucket
+    pub fn max_bucket_size(&self) -> usize {
+        self.options.max_bucket_size
+    }
+
     /// Returns iterator over all buckets, starting from the most distant
-    pub fn iter(&self) -> std::slice::Iter<FastDashMap<adnl::NodeIdShort, proto::dht::NodeOwned>> {
+    pub fn iter(&self) -> std::slice::Iter<Bucket> {
         self.buckets.iter()
     }
 
-    /// Inserts DHT node into the bucket based on its distance
-    pub fn insert(&self, peer_id: &adnl::NodeIdShort, peer: proto::dht::NodeOwned) {
-        use dashmap::mapref::entry::Entry;
+    /// Inserts DHT node into the bucket based on its distance.
+    ///
+    /// If the bucket is full, the node is remembered as a replacement
+    /// for the least recently seen node. Nodes from the subnets which
+    /// have reached their limits are ignored in favor of the existing ones.
+    ///
+    /// NOTE: inserting an existing node doesn't refresh its last seen time,
+    /// it is only updated on direct responses (see [`Buckets::touch`]).
+    ///
+    /// Returns whether the node was inserted
+    pub fn insert(&self, peer_id: &adnl::NodeIdShort, peer: proto::dht::NodeOwned) -> bool {
+        let affinity = get_affinity(&self.local_id, peer_id.borrow());
+        self.touch_bucket(affinity);
+
+        let bucket = &self.buckets[affinity as usize];
+        let subnet = self.subnet(&peer);
+
+        let mut subnets = self.subnets.lock();
+
+        // NOTE: the reference must be dropped before iterating over the bucket
+        let old_subnet = bucket.get(peer_id).map(|entry| self.subnet(&entry.node));
+        if let Some(old_subnet) = old_subnet {
+            let allowed = old_subnet == subnet || self.has_room(&subnets, bucket, subnet);
+            if let Some(mut entry) = bucket.get_mut(peer_id) {
+                if allowed && entry.node.version < peer.version {
+                    entry.node = peer;
+                    remove_from_subnet(&mut subnets, old_subnet);
+                    add_to_subnet(&mut subnets, subnet);
+                }
+            }
+            return true;
+        }
+
+        if !self.has_room(&subnets, bucket, subnet) {
+            return false;
+        }
+
+        // NOTE: `len` must not be called while holding an entry
+        if bucket.len() < self.options.max_bucket_size {
+            bucket.insert(
+                *peer_id,
+                BucketEntry {
+                    node: peer,
+                    last_seen: Instant::now(),
+                },
+            );
+            add_to_subnet(&mut subnets, subnet);
+            true
+        } else {
+            *self.replacements[affinity as usize].lock() = Some((*peer_id, peer));
+            false
+        }
+    }
 
+    /// Removes DHT node from the bucket, filling its place with the replacement.
+    /// Returns whether the node was removed
+    pub fn remove(&self, peer_id: &adnl::NodeIdShort) -> bool {
         let affinity = get_affinity(&self.local_id, peer_id.borrow());
-        match self.buckets[affinity as usize].entry(*peer_id) {
-            Entry::Occupied(mut entry) => {
-                if entry.get().version < peer.version {
-                    entry.insert(peer);
+        let bucket = &self.buckets[affinity as usize];
+
+        let mut subnets = self.subnets.lock();
+        let entry = match bucket.remove(peer_id) {
+            Some((_, entry)) => entry,
+            None => return false,
+        };
+        remove_from_subnet(&mut subnets, self.subnet(&entry.node));
+
+        if let Some((peer_id, node)) = self.replacements[affinity as usize].lock().take() {
+            let subnet = self.subnet(&node);
+            if self.has_room(&subnets, bucket, subnet) {
+                bucket.insert(
+                    peer_id,
+                    BucketEntry {
+                        node,
+                        last_seen: Instant::now(),
+                    },
+                );
+                add_to_subnet(&mut subnets, subnet);
+            }
+        }
+        true
+    }
+
+    /// Checks whether a new node from the subnet can be added to the bucket
+    fn has_room(
+        &self,
+        subnets: &FastHashMap<u32, usize>,
+        bucket: &Bucket,
+        subnet: Option<u32>,
+    ) -> bool {
+        let subnet = match subnet {
+            Some(subnet) => subnet,
+            None => return true,
+        };
+
+        if let Some(max_nodes) = self.options.max_nodes_per_subnet {
+            if subnets.get(&subnet).copied().unwrap_or_default() >= max_nodes {
+                return false;
+            }
+        }
+
+        if let Some(max_nodes) = self.options.max_bucket_nodes_per_subnet {
+            let count = bucket
+                .iter()
+                .filter(|entry| self.subnet(&entry.node) == Some(subnet))
+                .count();
+            if count >= max_nodes {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Returns the subnet of the node IPv4 address.
+    /// Local addresses are not limited, so they don't have a subnet
+    fn subnet(&self, node: &proto::dht::NodeOwned) -> Option<u32> {
+        let mask = u32::MAX
+            .checked_shl(32 - self.options.subnet_prefix_len as u32)
+            .unwrap_or_default();
+        match node.addr_list.address? {
+            proto::adnl::Address::Udp { ip, .. } => {
+                let addr = std::net::Ipv4Addr::from(ip);
+                if addr.is_loopback() || addr.is_private() || addr.is_link_local() {
+                    None
+                } else {
+                    Some(ip & mask)
                 }
             }
-            Entry::Vacant(entry) => {
-                entry.insert(peer);
+            proto::adnl::Address::Udp6 { .. } => None,
+        }
+    }
+
+    /// Returns the least recently seen nodes of the buckets with pending replacements
+    pub fn least_recently_seen(&self) -> Vec<(u8, adnl::NodeIdShort)> {
+        let mut result = Vec::new();
+        for (affinity, replacement) in self.replacements.iter().enumerate() {
+            if replacement.lock().is_none() {
+                continue;
+            }
+
+            let oldest = self.buckets[affinity]
+                .iter()
+                .min_by_key(|entry| entry.last_seen)
+                .map(|entry| *entry.key());
+            if let Some(peer_id) = oldest {
+                result.push((affinity as u8, peer_id));
             }
         }
+        result
+    }
+
+    /// Forgets the replacement of the specified bucket
+    pub fn discard_replacement(&self, affinity: u8) {
+        *self.replacements[affinity as usize].lock() = None;
+    }
+
+    /// Marks the specified node and its bucket as active
+    pub fn touch(&self, peer_id: &adnl::NodeIdShort) {
+        let affinity = get_affinity(&self.local_id, peer_id.borrow());
+        self.touch_bucket(affinity);
+        if let Some(mut entry) = self.buckets[affinity as usize].get_mut(peer_id) {
+            entry.last_seen = Instant::now();
+        }
+    }
+
+    /// Marks the specified bucket as active
+    pub fn touch_bucket(&self, affinity: u8) {
+        self.last_activity[affinity as usize].store(now(), Ordering::Relaxed);
+    }
+
+    /// Returns indices of the buckets without traffic since `since`.
+    ///
+    /// Buckets deeper than the deepest non-empty bucket are skipped
+    pub fn inactive_since(&self, since: u32) -> Vec<u8> {
+        let depth = match self.buckets.iter().rposition(|bucket| !bucket.is_empty()) {
+            Some(depth) => depth,
+            None => return Vec::new(),
+        };
+
+        (0..=depth)
+            .filter(|&i| self.last_activity[i].load(Ordering::Relaxed) < since)
+            .map(|i| i as u8)
+            .collect()
+    }
+
+    /// Generates random id which falls into the specified bucket
+    pub fn random_id_in_bucket(&self, affinity: u8) -> [u8; 32] {
+        let mut id = gen_fast_bytes::<32>();
+
+        let byte = affinity as usize / 8;
+        let bit = affinity % 8;
+
+        // Copy common prefix and invert the next bit
+        id[..byte].copy_from_slice(&self.local_id[..byte]);
+        let bit_mask = 0x80u8 >> bit;
+        let prefix_mask = !(0xffu8 >> bit);
+        id[byte] = (self.local_id[byte] & prefix_mask)
+            | (!self.local_id[byte] & bit_mask)
+            | (id[byte] & !prefix_mask & !bit_mask);
+
+        id
+    }
+
+    /// Returns DHT node info by its id
+    pub fn get(&self, peer_id: &adnl::NodeIdShort) -> Option<proto::dht::NodeOwned> {
+        let affinity = get_affinity(&self.local_id, peer_id.borrow());
+        let item = self.buckets[affinity as usize].get(peer_id)?;
+        Some(item.node.clone())
     }
 
     /// Finds `k` closest DHT nodes for the given `peer_id`
@@ -45,58 +282,69 @@ impl Buckets {
     where
         T: Borrow<[u8; 32]>,
     {
-        let key1 = &self.local_id;
-        let key2: &[u8; 32] = peer_id.borrow();
-
-        let mut nodes = Vec::new();
+        let key: &[u8; 32] = peer_id.borrow();
+        let k = k as usize;
 
-        // Iterate over buckets
-        'outer: for i in 0..32 {
-            let mut distance = i as u8 * 8;
-
-            // Compare bytes
-            let mut diff = key1[i] ^ key2[i];
-
-            // If they are not equal (otherwise we will just add 8 bits
-            // to the distance and continue to the next byte)
-            while diff != 0 {
-                // Get equal bit count
-                let equal_bits = diff.leading_zeros() as u8; // 0..=7
-                distance += equal_bits;
-
-                // Add all nodes from this distance to the result
-                let bucket = &self.buckets[distance as usize];
+        // Max-heap of the closest nodes, the most distant node is on the top
+        let mut closest = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            for bucket in self.buckets.iter() {
                 for item in bucket.iter() {
-                    nodes.push(item.value().clone());
-                    if nodes.len() >= k as usize {
-                        break 'outer;
+                    let distance = xor_distance(key, item.key().as_slice());
+                    if closest.len() < k {
+                        closest.push((distance, *item.key()));
+                    } else if matches!(closest.peek(), Some((max, _)) if &distance < max) {
+                        closest.pop();
+                        closest.push((distance, *item.key()));
                     }
                 }
-
-                // Skip one different bit:
-                if equal_bits < 7 {
-                    diff <<= equal_bits + 1;
-                    distance = distance.saturating_add(1);
-                } else {
-                    continue 'outer;
-                }
             }
         }
 
-        // Done
+        let nodes = closest
+            .into_sorted_vec()
+            .into_iter()
+            .filter_map(|(_, peer_id)| self.get(&peer_id))
+            .collect();
+
         proto::dht::NodesOwned { nodes }
     }
 }
 
 impl<'a> IntoIterator for &'a Buckets {
-    type Item = &'a FastDashMap<adnl::NodeIdShort, proto::dht::NodeOwned>;
-    type IntoIter = std::slice::Iter<'a, FastDashMap<adnl::NodeIdShort, proto::dht::NodeOwned>>;
+    type Item = &'a Bucket;
+    type IntoIter = std::slice::Iter<'a, Bucket>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.iter()
     }
 }
 
+fn add_to_subnet(subnets: &mut FastHashMap<u32, usize>, subnet: Option<u32>) {
+    if let Some(subnet) = subnet {
+        *subnets.entry(subnet).or_default() += 1;
+    }
+}
+
+fn remove_from_subnet(subnets: &mut FastHashMap<u32, usize>, subnet: Option<u32>) {
+    use std::collections::hash_map::Entry;
+
+    if let Some(Entry::Occupied(mut entry)) = subnet.map(|subnet| subnets.entry(subnet)) {
+        *entry.get_mut() -= 1;
+        if *entry.get() == 0 {
+            entry.remove();
+        }
+    }
+}
+
+fn xor_distance(key1: &[u8; 32], key2: &[u8; 32]) -> [u8; 32] {
+    let mut distance = *key1;
+    for (a, b) in distance.iter_mut().zip(key2) {
+        *a ^= *b;
+    }
+    distance
+}
+
 /// Returns the length of the longest common prefix of two keys
 pub fn get_affinity(key1: &[u8; 32], key2: &[u8; 32]) -> u8 {
     for i in 0..32 {
@@ -116,4 +364,152 @@ mod tests {
     fn same_affinity() {
         assert_eq!(get_affinity(&[0xaa; 32], &[0xaa; 32]), 255);
     }
+
+    #[test]
+    fn random_id_in_bucket() {
+        let local_id = adnl::NodeIdShort::new([0xaa; 32]);
+        let buckets = Buckets::new(&local_id, make_options(20));
+        for affinity in 0..255 {
+            let id = buckets.random_id_in_bucket(affinity);
+            assert_eq!(get_affinity(local_id.as_slice(), &id), affinity);
+        }
+    }
+
+    #[test]
+    fn full_bucket_replacement() {
+        let buckets = Buckets::new(&adnl::NodeIdShort::new([0; 32]), make_options(2));
+
+        let make_peer = |i: u8| {
+            let mut id = [0xff; 32];
+            id[31] = i;
+            (adnl::NodeIdShort::new(id), make_node(id))
+        };
+
+        let (a, a_node) = make_peer(1);
+        let (b, b_node) = make_peer(2);
+        let (c, c_node) = make_peer(3);
+        assert!(buckets.insert(&a, a_node));
+        assert!(buckets.insert(&b, b_node));
+        assert!(!buckets.insert(&c, c_node));
+        assert!(buckets.get(&c).is_none());
+
+        // `a` is the least recently seen node
+        assert_eq!(buckets.least_recently_seen(), vec![(0, a)]);
+
+        // Gossip doesn't refresh the node
+        assert!(buckets.insert(&a, make_peer(1).1));
+        assert_eq!(buckets.least_recently_seen(), vec![(0, a)]);
+
+        // Direct response does
+        buckets.touch(&a);
+        assert_eq!(buckets.least_recently_seen(), vec![(0, b)]);
+
+        // Replacement takes place of the removed node
+        assert!(buckets.remove(&a));
+        assert!(buckets.get(&a).is_none());
+        assert!(buckets.get(&c).is_some());
+        assert!(buckets.least_recently_seen().is_empty());
+    }
+
+    #[test]
+    fn find_closest_across_buckets() {
+        let buckets = Buckets::new(&adnl::NodeIdShort::new([0; 32]), make_options(64));
+
+        let mut ids = Vec::new();
+        for i in 0..64u8 {
+            let mut id = [0; 32];
+            id[0] = i.reverse_bits() | 1;
+            id[31] = i;
+            ids.push(id);
+            buckets.insert(&adnl::NodeIdShort::new(id), make_node(id));
+        }
+
+        let key = [0xc3; 32];
+        let nodes = buckets.find(key, 10).nodes;
+        assert_eq!(nodes.len(), 10);
+
+        ids.sort_by_key(|id| xor_distance(&key, id));
+        for (node, id) in nodes.iter().zip(&ids) {
+            assert!(matches!(
+                &node.id,
+                everscale_crypto::tl::PublicKeyOwned::Ed25519 { key } if key == id
+            ));
+        }
+    }
+
+    #[test]
+    fn subnet_limits() {
+        let buckets = Buckets::new(
+            &adnl::NodeIdShort::new([0; 32]),
+            BucketsOptions {
+                max_bucket_nodes_per_subnet: Some(2),
+                max_nodes_per_subnet: Some(3),
+                ..make_options(20)
+            },
+        );
+
+        let make_peer = |i: u8, ip: u32| {
+            let mut id = [0; 32];
+            id[0] = i;
+            let mut node = make_node(id);
+            node.addr_list.address = Some(proto::adnl::Address::Udp { ip, port: 30000 });
+            (adnl::NodeIdShort::new(id), node)
+        };
+
+        // Bucket limit
+        let (a, a_node) = make_peer(0x80, 0x5db80001);
+        let (b, b_node) = make_peer(0x81, 0x5db80002);
+        let (c, c_node) = make_peer(0x82, 0x5db80003);
+        assert!(buckets.insert(&a, a_node));
+        assert!(buckets.insert(&b, b_node));
+        assert!(!buckets.insert(&c, c_node));
+
+        // Other subnets are not affected
+        let (d, d_node) = make_peer(0x83, 0x5db80101);
+        assert!(buckets.insert(&d, d_node));
+
+        // Overall limit
+        let (e, e_node) = make_peer(0x40, 0x5db80004);
+        let (f, f_node) = make_peer(0x20, 0x5db80005);
+        assert!(buckets.insert(&e, e_node));
+        assert!(!buckets.insert(&f, f_node));
+
+        // Removed nodes free their place
+        assert!(buckets.remove(&a));
+        let (f, f_node) = make_peer(0x20, 0x5db80005);
+        assert!(buckets.insert(&f, f_node));
+
+        // Local addresses are not limited
+        for (i, ip) in [0x7f000001, 0x7f000002, 0x7f000003, 0xc0a80001, 0xc0a80002]
+            .into_iter()
+            .enumerate()
+        {
+            let (id, node) = make_peer(0x90 + i as u8, ip);
+            assert!(buckets.insert(&id, node));
+        }
+    }
+
+    fn make_options(max_bucket_size: usize) -> BucketsOptions {
+        BucketsOptions {
+            max_bucket_size,
+            subnet_prefix_len: 24,
+            max_bucket_nodes_per_subnet: None,
+            max_nodes_per_subnet: None,
+        }
+    }
+
+    fn make_node(id: [u8; 32]) -> proto::dht::NodeOwned {
+        proto::dht::NodeOwned {
+            id: everscale_crypto::tl::PublicKeyOwned::Ed25519 { key: id },
+            addr_list: proto::adnl::AddressList {
+                address: None,
+                version: 0,
+                reinit_date: 0,
+                priority: 0,
+                expire_at: 0,
+            },
+            version: 0,
+            signature: Default::default(),
+        }
+    }
 }
@@ -0,0 +1,110 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+
+use super::node::Node;
+use crate::overlay;
+
+impl Node {
+    /// Joins the public overlay: publishes the local overlay node into the DHT
+    /// (see [`Node::publish_overlay_node`]) and adds overlay nodes found by the
+    /// overlay `nodes` key as public peers.
+    ///
+    /// New overlay peers are searched in the background until the overlay is dropped
+    /// or [`Node::leave_public_overlay`] is called.
+    ///
+    /// Returns the number of new overlay peers
+    ///
+    /// See [`NodeOptions::overlay_discovery_interval_sec`]
+    ///
+    /// [`NodeOptions::overlay_discovery_interval_sec`]: super::NodeOptions::overlay_discovery_interval_sec
+    pub async fn join_public_overlay(
+        self: &Arc<Self>,
+        overlay_id_full: overlay::IdFull,
+        overlay: &Arc<overlay::Overlay>,
+    ) -> Result<usize> {
+        let overlay_id = overlay_id_full.compute_short_id();
+        if overlay.id() != &overlay_id {
+            return Err(DiscoveryError::OverlayIdMismatch.into());
+        }
+
+        self.joined_overlays()
+            .insert(overlay_id, Arc::downgrade(overlay));
+
+        let node = overlay.sign_local_node_async().await?;
+        if let Err(e) = self.publish_overlay_node(overlay_id_full, node).await {
+            tracing::warn!(%overlay_id, "failed to publish overlay node: {e:?}");
+        }
+
+        self.discover_overlay_peers(overlay).await
+    }
+
+    /// Stops searching for the overlay peers and republishing the local overlay node.
+    /// Returns whether the overlay was joined
+    pub fn leave_public_overlay(&self, overlay_id: &overlay::IdShort) -> bool {
+        self.unpublish_overlay_node(overlay_id);
+        self.joined_overlays().remove(overlay_id).is_some()
+    }
+
+    /// Searches overlay nodes in the DHT and adds the verified ones as public peers
+    async fn discover_overlay_peers(self: &Arc<Self>, overlay: &overlay::Overlay) -> Result<usize> {
+        let nodes = self.find_overlay_nodes(overlay.id()).await?;
+        let new_peers = overlay.add_public_peers(
+            self.adnl(),
+            nodes
+                .iter()
+                .map(|(addr, node)| (*addr, node.as_equivalent_ref())),
+        )?;
+        Ok(new_peers.len())
+    }
+
+    /// Starts a process that periodically searches peers for the joined overlays
+    pub(super) fn start_overlay_discovery_loop(self: &Arc<Self>) {
+        let interval = Duration::from_secs(self.options().overlay_discovery_interval_sec as u64);
+        let dht = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let dht = match Weak::upgrade(&dht) {
+                    Some(dht) => dht,
+                    None => break,
+                };
+
+                // Forget dropped overlays
+                dht.joined_overlays()
+                    .retain(|_, overlay| overlay.strong_count() > 0);
+
+                let overlays = dht
+                    .joined_overlays()
+                    .iter()
+                    .filter_map(|item| item.value().upgrade())
+                    .collect::<Vec<_>>();
+
+                for overlay in overlays {
+                    match dht.discover_overlay_peers(&overlay).await {
+                        Ok(new_peers) => tracing::debug!(
+                            overlay_id = %overlay.id(),
+                            new_peers,
+                            "discovered overlay peers"
+                        ),
+                        Err(e) => tracing::warn!(
+                            overlay_id = %overlay.id(),
+                            "failed to discover overlay peers: {e:?}"
+                        ),
+                    }
+                }
+            }
+
+            tracing::debug!("DHT overlay discovery loop finished");
+        });
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DiscoveryError {
+    #[error("Overlay id mismatch")]
+    OverlayIdMismatch,
+}
@@ -5,38 +5,84 @@ use anyhow::Result;
 use tl_proto::BoxedConstructor;
 
 use super::futures::StoreValue;
-use super::node::Node;
+use super::node::{verify_signed_dht_value, Node};
 use super::streams::DhtValuesStream;
 use crate::adnl;
 use crate::proto;
 use crate::util::now;
 
-/// DHT entry builder
+/// Application-defined DHT key
 #[must_use]
-#[derive(Copy, Clone)]
-pub struct Entry<'a> {
-    dht: &'a Arc<Node>,
+#[derive(Debug, Copy, Clone)]
+pub struct DhtKeyBuilder<'a> {
     id: &'a [u8; 32],
     name: &'a str,
-    key_index: u32,
+    idx: u32,
 }
 
-impl<'a> Entry<'a> {
-    pub(super) fn new<T>(dht: &'a Arc<Node>, id: &'a T, name: &'a str) -> Self
+impl<'a> DhtKeyBuilder<'a> {
+    /// Creates a key of the value with the specified owner id, name and index
+    pub fn new<T>(id: &'a T, name: &'a str, idx: u32) -> Self
     where
         T: Borrow<[u8; 32]>,
     {
         Self {
-            dht,
             id: id.borrow(),
             name,
-            key_index: 0,
+            idx,
+        }
+    }
+
+    /// Returns an entry interface for manipulating values of this key
+    pub fn entry(self, dht: &'a Arc<Node>) -> Entry<'a> {
+        Entry { dht, key: self }
+    }
+
+    /// Checks that the value belongs to this key and verifies its signatures
+    pub fn verify(&self, value: &proto::dht::ValueOwned) -> Result<()> {
+        if value.key.key.as_equivalent_ref() != self.key() {
+            return Err(EntryError::KeyMismatch.into());
+        }
+        if value.key.update_rule != proto::dht::UpdateRule::Signature {
+            return Err(EntryError::UnsignedValue.into());
         }
+        verify_signed_dht_value(&mut value.as_equivalent_ref())
+    }
+
+    /// Returns the key hash, which is used as a storage key
+    pub fn key_id(&self) -> [u8; 32] {
+        tl_proto::hash_as_boxed(self.key())
+    }
+
+    /// Returns TL representation of the key
+    pub fn key(&self) -> proto::dht::Key<'a> {
+        proto::dht::Key {
+            id: self.id,
+            name: self.name.as_bytes(),
+            idx: self.idx,
+        }
+    }
+}
+
+/// DHT entry builder
+#[must_use]
+#[derive(Copy, Clone)]
+pub struct Entry<'a> {
+    dht: &'a Arc<Node>,
+    key: DhtKeyBuilder<'a>,
+}
+
+impl<'a> Entry<'a> {
+    pub(super) fn new<T>(dht: &'a Arc<Node>, id: &'a T, name: &'a str) -> Self
+    where
+        T: Borrow<[u8; 32]>,
+    {
+        DhtKeyBuilder::new(id, name, 0).entry(dht)
     }
 
     /// Sets the key index. Default: `0`
     pub fn with_key_index(mut self, idx: u32) -> Self {
-        self.key_index = idx;
+        self.key.idx = idx;
         self
     }
 
@@ -77,6 +123,23 @@ impl<'a> Entry<'a> {
         DhtValuesStream::new(self.dht.clone(), self.key())
     }
 
+    /// Searches for the value using an iterative lookup.
+    ///
+    /// Signatures of the found value are verified. See [`Node::find_value`]
+    pub async fn find<T>(self) -> Result<Option<(proto::dht::KeyDescriptionOwned, T)>>
+    where
+        for<'tl> T: tl_proto::TlRead<'tl, Repr = tl_proto::Boxed> + 'static,
+    {
+        self.dht.find_value(self.key()).await
+    }
+
+    /// Checks that the value belongs to this entry and verifies its signatures.
+    ///
+    /// Useful for the values received outside of the DHT
+    pub fn verify(&self, value: &proto::dht::ValueOwned) -> Result<()> {
+        self.key.verify(value)
+    }
+
     /// Queries a value from the given peer.
     pub async fn value_from<T>(
         self,
@@ -85,7 +148,7 @@ impl<'a> Entry<'a> {
     where
         for<'tl> T: tl_proto::TlRead<'tl, Repr = tl_proto::Boxed> + Send + 'static,
     {
-        let key_id = tl_proto::hash_as_boxed(self.key());
+        let key_id = self.key_id();
         let query = tl_proto::serialize(proto::rpc::DhtFindValue { key: &key_id, k: 6 }).into();
 
         match self.dht.query_raw(peer_id, query).await? {
@@ -94,13 +157,14 @@ impl<'a> Entry<'a> {
         }
     }
 
+    /// Returns the entry key hash, which is used as a storage key
+    pub fn key_id(&self) -> [u8; 32] {
+        self.key.key_id()
+    }
+
     /// Returns TL representation of the entry key.
     pub fn key(&self) -> proto::dht::Key<'a> {
-        proto::dht::Key {
-            id: self.id,
-            name: self.name.as_bytes(),
-            idx: self.key_index,
-        }
+        self.key.key()
     }
 }
 
@@ -124,6 +188,10 @@ impl<'a> EntryWithData<'a> {
     }
 
     /// Creates signed TL representation of the entry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key uses an external signer, see [`EntryWithData::sign_async`]
     pub fn sign(self, key: &adnl::Key) -> proto::dht::ValueOwned {
         let mut value = self.make_value(key);
 
@@ -136,16 +204,48 @@ impl<'a> EntryWithData<'a> {
         value.as_equivalent_owned()
     }
 
+    /// Creates signed TL representation of the entry. Supports keys with an external signer.
+    pub async fn sign_async(self, key: &adnl::Key) -> Result<proto::dht::ValueOwned> {
+        let mut value = self.make_value(key);
+
+        let key_signature = key.sign_async(value.key.as_boxed()).await?;
+        value.key.signature = &key_signature;
+
+        let value_signature = key.sign_async(value.as_boxed()).await?;
+        value.signature = &value_signature;
+
+        Ok(value.as_equivalent_owned())
+    }
+
     /// Creates signed TL representation of the entry and stores it in the DHT.
     ///
+    /// NOTE: fails for the key with an external signer, use
+    /// [`EntryWithData::sign_and_store_async`] instead
+    ///
     /// See [`StoreValue`]
     pub fn sign_and_store(self, key: &adnl::Key) -> Result<StoreValue> {
         let mut value = self.make_value(key);
 
-        let key_signature = key.sign(value.key.as_boxed());
+        let key_signature = key.try_sign(value.key.as_boxed())?;
         value.key.signature = &key_signature;
 
-        let value_signature = key.sign(value.as_boxed());
+        let value_signature = key.try_sign(value.as_boxed())?;
+        value.signature = &value_signature;
+
+        StoreValue::new(self.inner.dht.clone(), value)
+    }
+
+    /// Creates signed TL representation of the entry and stores it in the DHT.
+    /// Supports keys with an external signer.
+    ///
+    /// See [`StoreValue`]
+    pub async fn sign_and_store_async(self, key: &adnl::Key) -> Result<StoreValue> {
+        let mut value = self.make_value(key);
+
+        let key_signature = key.sign_async(value.key.as_boxed()).await?;
+        value.key.signature = &key_signature;
+
+        let value_signature = key.sign_async(value.as_boxed()).await?;
         value.signature = &value_signature;
 
         StoreValue::new(self.inner.dht.clone(), value)
@@ -170,3 +270,11 @@ impl<'a> EntryWithData<'a> {
         }
     }
 }
+
+#[derive(thiserror::Error, Debug)]
+enum EntryError {
+    #[error("Value key mismatch")]
+    KeyMismatch,
+    #[error("Value is not signed")]
+    UnsignedValue,
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+
+use crate::proto;
+
+/// DHT protocol features advertised by the node.
+///
+/// Features are stored in the lowest bits of the `dht.node` version, which is
+/// otherwise a unix timestamp, so the announcement format stays the same.
+/// Reference implementation nodes use the raw timestamp as a version, so
+/// features are only used as a hint for the peers selection.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct NodeFeatures(u32);
+
+impl NodeFeatures {
+    /// No features advertised (e.g. reference implementation nodes)
+    pub const NONE: Self = Self(0);
+
+    /// Node answers `dht.findNode` and `dht.findValue` with the closest
+    /// nodes across all buckets
+    pub const CLOSEST_NODES: Self = Self(1);
+
+    /// Node replicates stored values to the closest nodes
+    pub const STORE_REPLICATION: Self = Self(1 << 1);
+
+    /// Features supported by this implementation
+    pub const SUPPORTED: Self = Self(Self::CLOSEST_NODES.0 | Self::STORE_REPLICATION.0);
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns `true` if all features from `other` are present
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Extracts features advertised by the DHT node
+    pub fn of(node: &proto::dht::NodeOwned) -> Self {
+        Self(node.version & Self::VERSION_MASK)
+    }
+
+    /// Replaces the lowest bits of the `dht.node` version with features
+    pub const fn apply_to_version(self, version: u32) -> u32 {
+        (version & !Self::VERSION_MASK) | (self.0 & Self::VERSION_MASK)
+    }
+
+    /// Bits of the `dht.node` version which are used for features
+    const VERSION_MASK: u32 = 0b1111;
+}
+
+impl std::ops::BitOr for NodeFeatures {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitOrAssign for NodeFeatures {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_in_version() {
+        let version = 1_700_000_123;
+        let node = proto::dht::NodeOwned {
+            id: everscale_crypto::tl::PublicKeyOwned::Ed25519 { key: [0; 32] },
+            addr_list: proto::adnl::AddressList {
+                address: None,
+                version,
+                reinit_date: 0,
+                priority: 0,
+                expire_at: 0,
+            },
+            version: NodeFeatures::SUPPORTED.apply_to_version(version),
+            signature: Default::default(),
+        };
+        assert_eq!(node.version >> 4, version >> 4);
+        assert_eq!(NodeFeatures::of(&node), NodeFeatures::SUPPORTED);
+        assert!(NodeFeatures::of(&node).contains(NodeFeatures::CLOSEST_NODES));
+    }
+}
@@ -22,6 +22,7 @@ pub struct StoreValue {
     query: Bytes,
     futures: FuturesUnordered<StoreFuture>,
     started: bool,
+    stored: usize,
 }
 
 impl StoreValue {
@@ -37,6 +38,7 @@ impl StoreValue {
             query,
             futures: Default::default(),
             started: false,
+            stored: 0,
         })
     }
 
@@ -57,24 +59,24 @@ impl StoreValue {
 }
 
 impl Future for StoreValue {
-    type Output = ();
+    /// Number of nodes which have stored the value
+    type Output = usize;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         if !self.started {
-            for &peer_id in self.dht.iter_known_peers() {
-                let dht = self.dht.clone();
-                let query = self.query.clone();
-                self.futures.push(Box::pin(async move {
-                    dht.query_raw(&peer_id, query).await.ok();
-                }));
-            }
+            let dht = self.dht.clone();
+            let key_id = tl_proto::hash_as_boxed(self.key.as_equivalent_ref());
+            let query = self.query.clone();
+            self.futures.push(Box::pin(async move {
+                dht.store_to_closest_nodes(&key_id, query).await
+            }));
             self.started = true;
         }
 
         loop {
             match self.futures.poll_next_unpin(cx) {
-                Poll::Ready(Some(_)) => continue,
-                Poll::Ready(None) => break Poll::Ready(()),
+                Poll::Ready(Some(stored)) => self.stored += stored,
+                Poll::Ready(None) => break Poll::Ready(self.stored),
                 Poll::Pending => break Poll::Pending,
             }
         }
@@ -135,4 +137,4 @@ where
     }
 }
 
-type StoreFuture = BoxFuture<'static, ()>;
+type StoreFuture = BoxFuture<'static, usize>;
@@ -0,0 +1,45 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use super::node::Node;
+
+impl Node {
+    /// Pings the least recently seen nodes of the full buckets.
+    /// Unresponsive nodes are replaced with the pending ones
+    async fn check_full_buckets(&self) {
+        let nodes = self.buckets().least_recently_seen();
+
+        futures_util::future::join_all(nodes.into_iter().map(|(affinity, peer_id)| async move {
+            match self.ping(&peer_id).await {
+                Ok(true) => {
+                    self.buckets().touch(&peer_id);
+                    self.buckets().discard_replacement(affinity);
+                }
+                result => {
+                    tracing::debug!(%peer_id, ?result, "replacing unresponsive DHT peer");
+                    self.buckets().remove(&peer_id);
+                }
+            }
+        }))
+        .await;
+    }
+
+    /// Starts a process that periodically checks the full buckets
+    pub(super) fn start_bucket_ping_loop(self: &Arc<Self>) {
+        let interval = Duration::from_millis(self.options().bucket_ping_interval_ms);
+        let dht = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                match Weak::upgrade(&dht) {
+                    Some(dht) => dht.check_full_buckets().await,
+                    None => break,
+                }
+            }
+
+            tracing::debug!("DHT bucket ping loop finished");
+        });
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tl_proto::{BoxedWrapper, TlRead};
+
+use super::features::NodeFeatures;
+use super::node::{verify_signed_dht_value, Node};
+use crate::adnl;
+use crate::proto;
+use crate::util::now;
+
+impl Node {
+    /// Iteratively searches for the value in the DHT.
+    ///
+    /// Queries the closest known peers (by XOR distance to the key) in parallel
+    /// and moves towards the key using the returned nodes, until the value
+    /// is found or the closest peers are not improving anymore.
+    ///
+    /// See [`NodeOptions::lookup_parallelism`], [`NodeOptions::lookup_max_stale_rounds`]
+    ///
+    /// [`NodeOptions::lookup_parallelism`]: super::NodeOptions::lookup_parallelism
+    /// [`NodeOptions::lookup_max_stale_rounds`]: super::NodeOptions::lookup_max_stale_rounds
+    pub async fn find_value<T>(
+        &self,
+        key: proto::dht::Key<'_>,
+    ) -> Result<Option<(proto::dht::KeyDescriptionOwned, T)>>
+    where
+        for<'a> T: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        let key_id = tl_proto::hash_as_boxed(key);
+        let query = Bytes::from(tl_proto::serialize(proto::rpc::DhtFindValue {
+            key: &key_id,
+            k: self.options().lookup_k,
+        }));
+
+        let started_at = Instant::now();
+        let mut lookup = Lookup::new(self, key_id, self.options().lookup_k);
+        let result = lookup
+            .run(&query, |answer| parse_lookup_answer(key, &answer))
+            .await;
+
+        self.record_lookup(started_at.elapsed());
+        result
+    }
+
+    /// Iteratively searches for the DHT nodes closest to the key.
+    ///
+    /// Returns at most [`NodeOptions::lookup_k`] responding nodes,
+    /// ordered by XOR distance to the key.
+    ///
+    /// [`NodeOptions::lookup_k`]: super::NodeOptions::lookup_k
+    pub async fn find_nodes(&self, key_id: &[u8; 32]) -> Result<Vec<proto::dht::NodeOwned>> {
+        self.find_closest_nodes(key_id, self.options().lookup_k)
+            .await
+    }
+
+    /// Iteratively searches for at most `k` responding DHT nodes closest to the key
+    pub(super) async fn find_closest_nodes(
+        &self,
+        key_id: &[u8; 32],
+        k: u32,
+    ) -> Result<Vec<proto::dht::NodeOwned>> {
+        let query = Bytes::from(tl_proto::serialize(proto::rpc::DhtFindNode {
+            key: key_id,
+            k,
+        }));
+
+        let started_at = Instant::now();
+        let mut lookup = Lookup::new(self, *key_id, k);
+        let result = lookup
+            .run::<(), _>(&query, |answer| {
+                let BoxedWrapper(proto::dht::NodesOwned { nodes }) =
+                    tl_proto::deserialize(&answer)?;
+                Ok(LookupStep::Nodes(nodes))
+            })
+            .await;
+
+        self.record_lookup(started_at.elapsed());
+        result?;
+        Ok(lookup.into_closest_nodes())
+    }
+}
+
+/// Parses and verifies `dht.findValue` answer
+fn parse_lookup_answer<T>(
+    key: proto::dht::Key<'_>,
+    answer: &[u8],
+) -> Result<LookupStep<(proto::dht::KeyDescriptionOwned, T)>>
+where
+    for<'a> T: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+{
+    match tl_proto::deserialize::<proto::dht::ValueResult>(answer)? {
+        proto::dht::ValueResult::ValueFound(BoxedWrapper(mut value)) => {
+            if value.key.key != key {
+                return Err(LookupError::UnexpectedValueKey.into());
+            }
+            if value.ttl <= now() {
+                return Err(LookupError::ValueExpired.into());
+            }
+            if value.key.update_rule == proto::dht::UpdateRule::Signature {
+                verify_signed_dht_value(&mut value)?;
+            }
+
+            let parsed = tl_proto::deserialize(value.value)?;
+            Ok(LookupStep::Found((value.key.as_equivalent_owned(), parsed)))
+        }
+        proto::dht::ValueResult::ValueNotFound(proto::dht::NodesOwned { nodes }) => {
+            Ok(LookupStep::Nodes(nodes))
+        }
+    }
+}
+
+/// Iterative lookup state
+struct Lookup<'a> {
+    dht: &'a Node,
+    key_id: [u8; 32],
+    /// Number of the closest peers tracked
+    k: usize,
+    /// Candidates ordered by XOR distance to the key
+    candidates: BTreeMap<[u8; 32], Candidate>,
+}
+
+impl<'a> Lookup<'a> {
+    fn new(dht: &'a Node, key_id: [u8; 32], k: u32) -> Self {
+        let mut lookup = Self {
+            dht,
+            key_id,
+            k: std::cmp::max(k, 1) as usize,
+            candidates: Default::default(),
+        };
+
+        for peer_id in dht.known_peers().clone_inner() {
+            if dht.is_bad_peer(&peer_id) {
+                continue;
+            }
+            if let Some(node) = dht.buckets().get(&peer_id) {
+                lookup.insert(peer_id, node);
+            }
+        }
+
+        lookup
+    }
+
+    async fn run<R, F>(&mut self, query: &Bytes, mut parse: F) -> Result<Option<R>>
+    where
+        F: FnMut(Bytes) -> Result<LookupStep<R>>,
+    {
+        let options = self.dht.options();
+        let parallelism = std::cmp::max(options.lookup_parallelism, 1);
+
+        let mut stale_rounds = 0;
+        loop {
+            // Select the closest peers which were not queried yet,
+            // preferring the ones with required features and then the most reliable ones
+            let mut batch = self
+                .candidates
+                .iter()
+                .filter(|(_, candidate)| candidate.state != CandidateState::Failed)
+                .take(self.k)
+                .filter(|(_, candidate)| candidate.state == CandidateState::New)
+                .map(|(distance, candidate)| {
+                    let preferred =
+                        NodeFeatures::of(&candidate.node).contains(options.preferred_peer_features);
+                    let score = self.dht.peer_scores().get(&candidate.peer_id);
+                    (preferred, score, *distance, candidate.peer_id)
+                })
+                .collect::<Vec<_>>();
+            // NOTE: stable sort keeps the distance order for the equal scores
+            batch.sort_by(|(a_pref, a, ..), (b_pref, b, ..)| {
+                b_pref.cmp(a_pref).then_with(|| b.total_cmp(a))
+            });
+            let batch = batch
+                .into_iter()
+                .take(parallelism)
+                .map(|(_, _, distance, peer_id)| (distance, peer_id))
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+
+            let closest = self.closest_distance();
+
+            let answers = futures_util::future::join_all(
+                batch
+                    .iter()
+                    .map(|(_, peer_id)| self.dht.query_raw(peer_id, query.clone())),
+            )
+            .await;
+
+            for ((distance, peer_id), answer) in batch.into_iter().zip(answers) {
+                let step = match answer {
+                    Ok(Some(answer)) => {
+                        let step = parse(answer);
+                        if step.is_err() {
+                            self.dht.report_invalid_answer(&peer_id);
+                        }
+                        step
+                    }
+                    Ok(None) => Err(LookupError::NoAnswer.into()),
+                    Err(e) => Err(e),
+                };
+
+                let state = match step {
+                    Ok(LookupStep::Found(value)) => return Ok(Some(value)),
+                    Ok(LookupStep::Nodes(nodes)) => {
+                        for node in nodes {
+                            self.add_node(node);
+                        }
+                        CandidateState::Queried
+                    }
+                    Err(e) => {
+                        tracing::debug!(%peer_id, "DHT lookup query failed: {e:?}");
+                        CandidateState::Failed
+                    }
+                };
+
+                if let Some(candidate) = self.candidates.get_mut(&distance) {
+                    candidate.state = state;
+                }
+            }
+
+            match (self.closest_distance(), closest) {
+                (Some(new), Some(old)) if new >= old => {
+                    stale_rounds += 1;
+                    if stale_rounds >= options.lookup_max_stale_rounds {
+                        break;
+                    }
+                }
+                _ => stale_rounds = 0,
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the closest nodes which responded to the query
+    fn into_closest_nodes(self) -> Vec<proto::dht::NodeOwned> {
+        self.candidates
+            .into_values()
+            .filter(|candidate| candidate.state == CandidateState::Queried)
+            .take(self.k)
+            .map(|candidate| candidate.node)
+            .collect()
+    }
+
+    fn closest_distance(&self) -> Option<[u8; 32]> {
+        self.candidates
+            .iter()
+            .find(|(_, candidate)| candidate.state != CandidateState::Failed)
+            .map(|(distance, _)| *distance)
+    }
+
+    fn add_node(&mut self, node: proto::dht::NodeOwned) {
+        let peer_id = match adnl::NodeIdFull::try_from(node.id.as_equivalent_ref()) {
+            Ok(full_id) => full_id.compute_short_id(),
+            Err(_) => return,
+        };
+
+        // NOTE: node signature is checked here and the peer is registered in ADNL
+        if let Err(e) = self.dht.add_dht_peer(node.clone()) {
+            tracing::debug!(%peer_id, "failed to add DHT peer: {e:?}");
+            return;
+        }
+        if !self.dht.known_peers().contains(&peer_id) || self.dht.is_bad_peer(&peer_id) {
+            return;
+        }
+
+        self.insert(peer_id, node);
+    }
+
+    fn insert(&mut self, peer_id: adnl::NodeIdShort, node: proto::dht::NodeOwned) {
+        let mut distance = self.key_id;
+        for (a, b) in distance.iter_mut().zip(peer_id.as_slice()) {
+            *a ^= *b;
+        }
+
+        self.candidates.entry(distance).or_insert(Candidate {
+            peer_id,
+            node,
+            state: CandidateState::New,
+        });
+    }
+}
+
+enum LookupStep<R> {
+    Found(R),
+    Nodes(Vec<proto::dht::NodeOwned>),
+}
+
+struct Candidate {
+    peer_id: adnl::NodeIdShort,
+    node: proto::dht::NodeOwned,
+    state: CandidateState,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum CandidateState {
+    New,
+    Queried,
+    Failed,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum LookupError {
+    #[error("No answer")]
+    NoAnswer,
+    #[error("Unexpected value key")]
+    UnexpectedValueKey,
+    #[error("Value expired")]
+    ValueExpired,
+}
+
+#[cfg(test)]
+mod tests {
+    use tl_proto::BoxedConstructor;
+
+    use super::*;
+
+    fn make_value(key: &adnl::Key, name: &[u8], ttl: u32) -> proto::dht::ValueOwned {
+        let data = tl_proto::serialize(proto::dht::Pong { random_id: 123 });
+        let mut value = proto::dht::ValueOwned {
+            key: proto::dht::KeyDescriptionOwned {
+                key: proto::dht::KeyOwned {
+                    id: *key.id().as_slice(),
+                    name: name.to_vec().into(),
+                    idx: 0,
+                },
+                id: key.full_id().as_tl().as_equivalent_owned(),
+                update_rule: proto::dht::UpdateRule::Signature,
+                signature: Default::default(),
+            },
+            value: data.into(),
+            ttl,
+            signature: Default::default(),
+        };
+        value.key.signature = key
+            .sign(value.key.as_equivalent_ref().as_boxed())
+            .to_vec()
+            .into();
+        value.signature = key
+            .sign(value.as_equivalent_ref().as_boxed())
+            .to_vec()
+            .into();
+        value
+    }
+
+    fn make_answer(value: proto::dht::ValueOwned) -> Vec<u8> {
+        tl_proto::serialize(proto::dht::ValueResultOwned::ValueFound(value.into_boxed()))
+    }
+
+    #[test]
+    fn lookup_answer_verification() {
+        let key = adnl::Key::from_bytes([1; 32]);
+        let dht_key = proto::dht::Key {
+            id: key.id().as_slice(),
+            name: b"test",
+            idx: 0,
+        };
+        let parse = |answer: Vec<u8>| parse_lookup_answer::<proto::dht::Pong>(dht_key, &answer);
+
+        // Valid value
+        let answer = make_answer(make_value(&key, b"test", now() + 100));
+        match parse(answer).unwrap() {
+            LookupStep::Found((_, pong)) => assert_eq!(pong.random_id, 123),
+            LookupStep::Nodes(_) => panic!("value must be found"),
+        }
+
+        // Expired value
+        let answer = make_answer(make_value(&key, b"test", now() - 1));
+        assert!(matches!(
+            parse(answer).err().unwrap().downcast_ref::<LookupError>(),
+            Some(LookupError::ValueExpired)
+        ));
+
+        // Value for another key
+        let answer = make_answer(make_value(&key, b"other", now() + 100));
+        assert!(matches!(
+            parse(answer).err().unwrap().downcast_ref::<LookupError>(),
+            Some(LookupError::UnexpectedValueKey)
+        ));
+
+        // Invalid signature
+        let mut value = make_value(&key, b"test", now() + 100);
+        value.ttl += 1;
+        assert!(parse(make_answer(value)).is_err());
+
+        // Nodes
+        let answer = tl_proto::serialize(proto::dht::ValueResultOwned::ValueNotFound(
+            proto::dht::NodesOwned { nodes: Vec::new() },
+        ));
+        assert!(matches!(parse(answer).unwrap(), LookupStep::Nodes(nodes) if nodes.is_empty()));
+    }
+}
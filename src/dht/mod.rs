@@ -8,16 +8,26 @@ use anyhow::Result;
 use frunk_core::hlist::{HCons, HList, IntoTuple2, Selector};
 use frunk_core::indices::There;
 
-pub use entry::Entry;
+pub use entry::{DhtKeyBuilder, Entry};
+pub use features::NodeFeatures;
 pub use node::{Node, NodeMetrics, NodeOptions};
 
 use crate::adnl;
 use crate::util::{DeferredInitialization, NetworkBuilder};
 
+mod announce;
 mod buckets;
+#[cfg(feature = "overlay")]
+mod discovery;
 mod entry;
+mod features;
+mod liveness;
+mod lookup;
 mod node;
 mod peers_iter;
+mod refresh;
+mod republish;
+mod scores;
 mod storage;
 
 /// DHT helper futures
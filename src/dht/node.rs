@@ -1,19 +1,25 @@
 use std::borrow::{Borrow, Cow};
 use std::convert::TryFrom;
 use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use bytes::Bytes;
 use futures_util::stream::FuturesUnordered;
 use futures_util::StreamExt;
+use parking_lot::RwLock;
 use smallvec::smallvec;
 use tl_proto::{BoxedConstructor, BoxedWrapper, TlRead, TlWrite};
+use tokio::sync::Semaphore;
 
-use super::buckets::Buckets;
+use super::buckets::{Buckets, BucketsOptions};
 use super::entry::Entry;
+use super::features::NodeFeatures;
 use super::futures::StoreValue;
+use super::republish::{RepublishedKey, RepublishedValue};
+use super::scores::PeerScores;
 use super::storage::{Storage, StorageOptions};
 use super::{KEY_ADDRESS, KEY_NODES, MAX_DHT_PEERS};
 use crate::adnl;
@@ -42,12 +48,26 @@ pub struct NodeOptions {
     /// Default: `5`
     pub default_value_batch_len: usize,
 
-    /// Max peer penalty points. On each unsuccessful query every peer gains 2 points,
-    /// and then they are reduced by one on each good action.
+    /// Max peer penalty points. On each unsuccessful query (error, timeout or invalid answer)
+    /// every peer gains 2 points, and then they are reduced by one on each good action.
+    /// Peers which exceed this threshold are quarantined.
     ///
     /// Default: `5`
     pub bad_peer_threshold: usize,
 
+    /// Max number of outgoing DHT queries in flight. Other queries wait
+    /// for the free slot, so lookups don't overflow the ADNL sender queue.
+    ///
+    /// Default: `256`
+    pub max_concurrent_queries: usize,
+
+    /// How long the peers which exceeded [`NodeOptions::bad_peer_threshold`]
+    /// are skipped in lookups and not added to the buckets. Zero disables the quarantine,
+    /// bad peers are still removed from the buckets.
+    ///
+    /// Default: `600` seconds
+    pub quarantine_duration_sec: u32,
+
     /// Max allowed `k` value for DHT `FindValue` query.
     ///
     /// Default: `5`
@@ -67,10 +87,180 @@ pub struct NodeOptions {
     /// Default: `15`
     pub max_key_index: u32,
 
+    /// Max total size of the stored values in bytes.
+    /// Values with the shortest remaining TTL are evicted first.
+    /// Storage size is not limited if not specified.
+    ///
+    /// Default: `None`
+    pub max_storage_size: Option<usize>,
+
+    /// Max number of stored values with the same key id.
+    ///
+    /// This is the per key prefix limit: values are grouped only by the `id` field
+    /// of the [`proto::dht::Key`], regardless of the name and index. Signed values are
+    /// stored under the signer id, so it also limits values per signer.
+    /// Values with the shortest remaining TTL are evicted first.
+    /// The number of values is not limited if not specified.
+    ///
+    /// Default: `None`
+    pub max_values_per_key_id: Option<usize>,
+
+    /// Max time in seconds the stored value is kept, regardless of its TTL.
+    /// Values are kept until their TTL if not specified.
+    ///
+    /// NOTE: the value itself is not changed (its TTL is signed), it is just
+    /// removed from the local storage earlier.
+    ///
+    /// Default: `None`
+    pub max_ttl_sec: Option<u32>,
+
+    /// Max stored value size in bytes. Bigger values are rejected,
+    /// merged overlay nodes values are truncated to this size.
+    ///
+    /// An overlay node takes ~140 bytes, so the default fits ~29 nodes
+    /// and still keeps a `dht.valueFound` answer within a few ADNL packets.
+    ///
+    /// Default: `4096` bytes
+    pub max_value_size: usize,
+
+    /// Max number of incoming `dht.findValue` queries per second from each peer
+    /// (bursts up to this amount are allowed). Excess queries are dropped without an answer.
+    /// The rate is not limited if not specified.
+    ///
+    /// Default: None
+    pub find_value_rate_limit: Option<u32>,
+
+    /// Max number of incoming `dht.store` queries per second from each peer
+    /// (bursts up to this amount are allowed). Excess queries are dropped without an answer.
+    /// The rate is not limited if not specified.
+    ///
+    /// Default: None
+    pub store_rate_limit: Option<u32>,
+
+    /// Half-life of the peer reliability scores, used to prefer
+    /// reliable peers during the iterative lookup
+    ///
+    /// Default: `600` seconds
+    pub peer_score_half_life_sec: u32,
+
+    /// Peers advertising all of these features are queried first during the iterative lookup
+    ///
+    /// Default: [`NodeFeatures::NONE`]
+    pub preferred_peer_features: NodeFeatures,
+
+    /// How long successfully resolved addresses are cached (see [`Node::find_address`]).
+    /// Zero disables the cache.
+    ///
+    /// Default: `60` seconds
+    pub address_cache_ttl_sec: u32,
+
+    /// How long failed address resolutions are cached (see [`Node::find_address`]).
+    /// Zero disables the negative cache.
+    ///
+    /// Default: `10` seconds
+    pub address_cache_negative_ttl_sec: u32,
+
     /// Storage GC interval. Will remove all outdated entries
     ///
     /// Default: `10000` ms
     pub storage_gc_interval_ms: u64,
+
+    /// Max number of values verified in one blocking task in [`Node::insert_values`]
+    ///
+    /// Default: `256`
+    pub verification_batch_len: usize,
+
+    /// Max number of blocking signature verification tasks in flight
+    /// (for incoming `dht.store` queries and [`Node::insert_values`]).
+    /// Incoming stores of big values are rejected while all tasks are busy.
+    ///
+    /// Default: `16`
+    pub max_concurrent_verifications: usize,
+
+    /// Number of peers queried in parallel during the iterative lookup
+    /// (see [`Node::find_value`], [`Node::find_nodes`]). Works as a per-lookup
+    /// concurrency budget, the total budget is [`NodeOptions::max_concurrent_queries`].
+    ///
+    /// Default: `3`
+    pub lookup_parallelism: usize,
+
+    /// Number of the closest peers tracked during the iterative lookup
+    ///
+    /// Default: `10`
+    pub lookup_k: u32,
+
+    /// Iterative lookup stops after this number of consecutive rounds
+    /// which haven't found closer peers
+    ///
+    /// Default: `3`
+    pub lookup_max_stale_rounds: usize,
+
+    /// Number of the closest nodes to which stored values are replicated
+    /// (see [`Node::store_value`])
+    ///
+    /// Default: `10`
+    pub store_replication_factor: u32,
+
+    /// Interval of republishing own values (see [`Node::publish_address`],
+    /// [`Node::publish_overlay_node`]). Should be less than `value_ttl_sec`.
+    ///
+    /// Default: `900` seconds
+    pub republish_interval_sec: u32,
+
+    /// Max random delay added to the republish interval
+    ///
+    /// Default: `60` seconds
+    pub republish_jitter_sec: u32,
+
+    /// Interval of searching new peers for the joined overlays
+    /// (see [`Node::join_public_overlay`])
+    ///
+    /// Default: `60` seconds
+    pub overlay_discovery_interval_sec: u32,
+
+    /// Max number of nodes in each bucket
+    ///
+    /// Default: `20`
+    pub max_bucket_size: usize,
+
+    /// Prefix length of the subnets used for the node limits
+    /// (see [`NodeOptions::max_bucket_nodes_per_subnet`], [`NodeOptions::max_nodes_per_subnet`]).
+    ///
+    /// NOTE: loopback, private and link-local addresses are not limited,
+    /// so that local networks and tests are not affected.
+    ///
+    /// Default: `24`
+    pub subnet_prefix_len: u8,
+
+    /// Max number of nodes from the same subnet in each bucket.
+    /// Existing nodes are preferred over the new ones.
+    ///
+    /// Default: `Some(2)`
+    pub max_bucket_nodes_per_subnet: Option<usize>,
+
+    /// Max number of nodes from the same subnet in all buckets.
+    /// Existing nodes are preferred over the new ones.
+    ///
+    /// Default: `Some(10)`
+    pub max_nodes_per_subnet: Option<usize>,
+
+    /// Interval of pinging the least recently seen nodes of the full buckets.
+    /// Unresponsive nodes are replaced with the new ones.
+    ///
+    /// Default: `5000` ms
+    pub bucket_ping_interval_ms: u64,
+
+    /// Interval of announcing the local DHT node to the closest peers
+    /// (see [`Node::announce_self`])
+    ///
+    /// Default: `600` seconds
+    pub announce_interval_sec: u32,
+
+    /// Buckets without traffic during this interval are refreshed
+    /// by searching for a random id in their range (see [`Node::refresh_buckets`])
+    ///
+    /// Default: `3600` seconds
+    pub bucket_refresh_interval_sec: u32,
 }
 
 impl Default for NodeOptions {
@@ -80,10 +270,38 @@ impl Default for NodeOptions {
             query_timeout_ms: 1000,
             default_value_batch_len: 5,
             bad_peer_threshold: 5,
+            quarantine_duration_sec: 600,
+            max_concurrent_queries: 256,
             max_allowed_k: 20,
             max_key_name_len: 127,
             max_key_index: 15,
+            max_storage_size: None,
+            max_values_per_key_id: None,
+            max_ttl_sec: None,
+            max_value_size: 4096,
+            find_value_rate_limit: None,
+            store_rate_limit: None,
+            peer_score_half_life_sec: 600,
+            preferred_peer_features: NodeFeatures::NONE,
+            address_cache_ttl_sec: 60,
+            address_cache_negative_ttl_sec: 10,
             storage_gc_interval_ms: 10000,
+            verification_batch_len: 256,
+            max_concurrent_verifications: 16,
+            lookup_parallelism: 3,
+            lookup_k: 10,
+            lookup_max_stale_rounds: 3,
+            store_replication_factor: 10,
+            republish_interval_sec: 900,
+            republish_jitter_sec: 60,
+            overlay_discovery_interval_sec: 60,
+            max_bucket_size: 20,
+            subnet_prefix_len: 24,
+            max_bucket_nodes_per_subnet: Some(2),
+            max_nodes_per_subnet: Some(10),
+            bucket_ping_interval_ms: 5000,
+            announce_interval_sec: 600,
+            bucket_refresh_interval_sec: 3600,
         }
     }
 }
@@ -97,24 +315,48 @@ pub struct Node {
     local_id: adnl::NodeIdShort,
 
     /// Serialized [`proto::rpc::DhtQuery`] with own DHT node info
-    query_prefix: Vec<u8>,
+    ///
+    /// NOTE: computed lazily, because keys with an external signer can't sign it synchronously
+    query_prefix: RwLock<Option<Vec<u8>>>,
 
     /// Configuration
     options: NodeOptions,
 
     /// State
     state: Arc<NodeState>,
+
+    /// Own values which are republished in the background
+    republished: FastDashMap<RepublishedKey, RepublishedValue>,
+
+    /// Overlays for which new peers are searched in the background
+    #[cfg(feature = "overlay")]
+    joined_overlays: FastDashMap<overlay::IdShort, std::sync::Weak<overlay::Overlay>>,
+
+    /// Outgoing queries concurrency budget
+    query_semaphore: Semaphore,
 }
 
 impl Node {
     /// Create new DHT node on top of ADNL node
     pub fn new(adnl: Arc<adnl::Node>, key_tag: usize, options: NodeOptions) -> Result<Arc<Self>> {
-        let key = adnl.key_by_tag(key_tag)?.clone();
-
-        let buckets = Buckets::new(key.id());
+        let key = adnl.key_by_tag(key_tag)?;
+
+        let buckets = Buckets::new(
+            key.id(),
+            BucketsOptions {
+                max_bucket_size: options.max_bucket_size,
+                subnet_prefix_len: options.subnet_prefix_len,
+                max_bucket_nodes_per_subnet: options.max_bucket_nodes_per_subnet,
+                max_nodes_per_subnet: options.max_nodes_per_subnet,
+            },
+        );
         let storage = Storage::new(StorageOptions {
             max_key_name_len: options.max_key_name_len,
             max_key_index: options.max_key_index,
+            max_total_size: options.max_storage_size,
+            max_values_per_key_id: options.max_values_per_key_id,
+            max_value_size: options.max_value_size,
+            max_ttl_sec: options.max_ttl_sec,
         });
 
         let state = Arc::new(NodeState {
@@ -122,24 +364,34 @@ impl Node {
             known_peers: adnl::PeersSet::with_capacity(MAX_DHT_PEERS),
             penalties: Default::default(),
             buckets,
-            storage,
+            storage: Arc::new(storage),
+            verification_semaphore: Arc::new(Semaphore::new(std::cmp::max(
+                options.max_concurrent_verifications,
+                1,
+            ))),
             max_allowed_k: options.max_allowed_k,
+            bad_peer_threshold: options.bad_peer_threshold,
+            quarantine: Default::default(),
+            quarantine_duration_sec: options.quarantine_duration_sec,
+            find_value_limiter: QueryLimiter::new(None, options.find_value_rate_limit),
+            store_limiter: QueryLimiter::new(None, options.store_rate_limit),
+            peer_scores: PeerScores::new(options.peer_score_half_life_sec),
+            address_cache: Default::default(),
+            stats: Default::default(),
         });
 
         adnl.add_query_subscriber(state.clone())?;
 
-        let query_prefix = tl_proto::serialize(proto::rpc::DhtQuery {
-            node: state
-                .sign_local_node(adnl.build_address_list())
-                .as_equivalent_ref(),
-        });
-
         let dht_node = Arc::new(Self {
             adnl,
             local_id: *key.id(),
-            query_prefix,
+            query_prefix: Default::default(),
             options,
             state,
+            republished: Default::default(),
+            #[cfg(feature = "overlay")]
+            joined_overlays: Default::default(),
+            query_semaphore: Semaphore::new(std::cmp::max(options.max_concurrent_queries, 1)),
         });
 
         let state = Arc::downgrade(&dht_node.state);
@@ -147,12 +399,35 @@ impl Node {
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(interval).await;
-                if let Some(state) = state.upgrade() {
-                    state.storage.gc();
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    None => break,
+                };
+
+                let removed = state.storage.gc();
+                if removed > 0 {
+                    tracing::debug!(removed, "removed expired DHT values");
                 }
+
+                let now = now();
+                state
+                    .address_cache
+                    .retain(|_, cached| cached.expires_at > now);
+                state.quarantine.retain(|_, until| *until > now);
+
+                state.peer_scores.remove_stale();
+                state.find_value_limiter.remove_idle();
+                state.store_limiter.remove_idle();
             }
         });
 
+        dht_node.start_republish_loop();
+        dht_node.start_bucket_refresh_loop();
+        dht_node.start_bucket_ping_loop();
+        dht_node.start_announce_loop();
+        #[cfg(feature = "overlay")]
+        dht_node.start_overlay_discovery_loop();
+
         Ok(dht_node)
     }
 
@@ -188,12 +463,50 @@ impl Node {
         self.state.add_dht_peer(&self.adnl, peer)
     }
 
+    /// Returns all DHT nodes from the routing table.
+    ///
+    /// The snapshot can be saved (e.g. as [`proto::dht::NodesOwned`]) and later
+    /// imported with [`Node::import_peers`] for a fast warm start
+    pub fn export_peers(&self) -> Vec<proto::dht::NodeOwned> {
+        self.state
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.iter().map(|entry| entry.node.clone()))
+            .collect()
+    }
+
+    /// Adds DHT nodes from the routing table snapshot (see [`Node::export_peers`]).
+    ///
+    /// Nodes with invalid signatures or addresses are skipped.
+    /// Returns the number of new peers
+    pub fn import_peers<I>(&self, nodes: I) -> usize
+    where
+        I: IntoIterator<Item = proto::dht::NodeOwned>,
+    {
+        let mut count = 0;
+        for node in nodes {
+            match self.add_dht_peer(node) {
+                Ok(peer_id) => count += peer_id.is_some() as usize,
+                Err(e) => tracing::debug!("failed to import DHT peer: {e:?}"),
+            }
+        }
+        count
+    }
+
+    /// Returns quarantined peers with their quarantine end time
+    pub fn quarantined_peers(&self) -> Vec<(adnl::NodeIdShort, u32)> {
+        let now = now();
+        self.state
+            .quarantine
+            .iter()
+            .filter(|item| *item.value() > now)
+            .map(|item| (*item.key(), *item.value()))
+            .collect()
+    }
+
     /// Checks whether the specified peer was marked as bad
     pub fn is_bad_peer(&self, peer: &adnl::NodeIdShort) -> bool {
-        matches!(
-            self.state.penalties.get(peer),
-            Some(penalty) if *penalty > self.options.bad_peer_threshold
-        )
+        self.state.is_bad_peer(peer)
     }
 
     /// Sends ping query to the given peer
@@ -320,6 +633,11 @@ impl Node {
                 });
             }
 
+            // Stop if there are no new peers to resolve
+            if futures.is_empty() {
+                break;
+            }
+
             // Wait all results
             while let Some((ip, node)) = futures.next().await {
                 match ip {
@@ -339,33 +657,149 @@ impl Node {
         Ok(result)
     }
 
-    /// Searches for the first stored IP address for the given peer id
+    /// Searches for the stored IP address for the given peer id.
+    ///
+    /// Uses an iterative lookup (see [`Node::find_value`]). Returns the verified address
+    /// together with the full peer id, ready to be used in [`adnl::Node::add_peer`].
+    ///
+    /// Results are cached, see [`NodeOptions::address_cache_ttl_sec`] and
+    /// [`NodeOptions::address_cache_negative_ttl_sec`]
     pub async fn find_address(
         self: &Arc<Self>,
         peer_id: &adnl::NodeIdShort,
     ) -> Result<(SocketAddrV4, adnl::NodeIdFull)> {
-        let mut values = self.entry(peer_id, KEY_ADDRESS).values();
-        while let Some((key, BoxedWrapper(value))) = values.next().await {
-            match (
-                parse_address_list(&value, self.adnl.options().clock_tolerance_sec),
-                adnl::NodeIdFull::try_from(key.id.as_equivalent_ref()),
-            ) {
-                (Ok(addr), Ok(full_id)) => return Ok((addr, full_id)),
-                _ => continue,
+        let now = now();
+        if let Some(cached) = self.state.address_cache.get(peer_id) {
+            if cached.expires_at > now {
+                return cached
+                    .resolved
+                    .ok_or_else(|| DhtNodeError::NoAddressFound.into());
             }
         }
 
-        Err(DhtNodeError::NoAddressFound.into())
+        let key = proto::dht::Key {
+            id: peer_id.as_slice(),
+            name: KEY_ADDRESS.as_bytes(),
+            idx: 0,
+        };
+
+        let (key, BoxedWrapper(address_list)) = match self
+            .find_value::<BoxedWrapper<proto::adnl::AddressListFull>>(key)
+            .await?
+        {
+            Some(value) => value,
+            None => {
+                self.cache_address(
+                    peer_id,
+                    None,
+                    now + self.options.address_cache_negative_ttl_sec,
+                );
+                return Err(DhtNodeError::NoAddressFound.into());
+            }
+        };
+
+        let address_list =
+            parse_address_list_full(&address_list, self.adnl.options().clock_tolerance_sec)?;
+        if address_list.expired {
+            return Err(AdnlAddressListError::Expired.into());
+        }
+        let addr = match address_list.best_ipv4() {
+            Some(addr) => addr,
+            None => {
+                let addr = address_list.addresses[0];
+                return Err(AdnlAddressListError::UnsupportedAddress(addr).into());
+            }
+        };
+        let full_id = adnl::NodeIdFull::try_from(key.id.as_equivalent_ref())?;
+
+        let mut expires_at = now + self.options.address_cache_ttl_sec;
+        if address_list.expire_at != 0 {
+            expires_at = std::cmp::min(expires_at, address_list.expire_at);
+        }
+        self.cache_address(peer_id, Some((addr, full_id)), expires_at);
+
+        Ok((addr, full_id))
+    }
+
+    /// Removes the cached address resolution result for the given peer id
+    pub fn forget_address(&self, peer_id: &adnl::NodeIdShort) {
+        self.state.address_cache.remove(peer_id);
     }
 
-    /// Returns a future which stores value into multiple DHT nodes.
+    fn cache_address(
+        &self,
+        peer_id: &adnl::NodeIdShort,
+        resolved: Option<(SocketAddrV4, adnl::NodeIdFull)>,
+        expires_at: u32,
+    ) {
+        if expires_at > now() {
+            self.state.address_cache.insert(
+                *peer_id,
+                CachedAddress {
+                    resolved,
+                    expires_at,
+                },
+            );
+        }
+    }
+
+    /// Returns a future which stores value into the closest DHT nodes.
+    ///
+    /// The value is replicated to [`NodeOptions::store_replication_factor`] nodes
+    /// found by the iterative lookup (see [`Node::find_nodes`]). The future
+    /// resolves to the number of nodes which have successfully stored the value.
     ///
     /// See [`Node::entry`] for more convenient API
     pub fn store_value(self: &Arc<Self>, value: proto::dht::Value<'_>) -> Result<StoreValue> {
         StoreValue::new(self.clone(), value)
     }
 
-    /// Stores given overlay node into multiple DHT nodes
+    /// Inserts multiple values into the local storage.
+    ///
+    /// Signatures are verified in batches on the blocking thread pool,
+    /// so it is preferable to use this method when syncing lots of values.
+    ///
+    /// Returns insertion result for each value
+    pub async fn insert_values(&self, values: Vec<proto::dht::ValueOwned>) -> Vec<Result<bool>> {
+        let batch_len = std::cmp::max(self.options.verification_batch_len, 1);
+
+        let values_len = values.len();
+        let mut values = values.into_iter();
+        let mut tasks = Vec::new();
+        loop {
+            let chunk = values.by_ref().take(batch_len).collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
+
+            let permit = self
+                .state
+                .verification_semaphore
+                .clone()
+                .acquire_owned()
+                .await;
+            let storage = self.state.storage.clone();
+            tasks.push(tokio::task::spawn_blocking(move || {
+                let _permit = permit;
+                let values = chunk
+                    .iter()
+                    .map(|value| value.as_equivalent_ref())
+                    .collect::<Vec<_>>();
+                storage.insert_many(&values)
+            }));
+        }
+
+        let mut results = Vec::with_capacity(values_len);
+        for task in futures_util::future::join_all(tasks).await {
+            match task {
+                Ok(chunk_results) => results.extend(chunk_results),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+        results
+    }
+
+    /// Stores given overlay node into the closest DHT nodes
     ///
     /// Returns and error if stored value is incorrect
     pub async fn store_overlay_node(
@@ -413,7 +847,7 @@ impl Node {
             .await
     }
 
-    /// Stores given socket address into multiple DHT nodes
+    /// Signs and stores given socket address into the closest DHT nodes
     pub async fn store_address(
         self: &Arc<Self>,
         key: &adnl::Key,
@@ -427,6 +861,7 @@ impl Node {
                     address: Some(proto::adnl::Address::from(&addr)),
                     version: now(),
                     reinit_date: self.adnl.start_time(),
+                    priority: 0,
                     expire_at: 0,
                 }
                 .into_boxed(),
@@ -448,13 +883,55 @@ impl Node {
             .await
     }
 
+    /// Sends store query to the nodes closest to the key.
+    /// Returns the number of successful stores
+    pub(super) async fn store_to_closest_nodes(&self, key_id: &[u8; 32], query: Bytes) -> usize {
+        let k = self.options.store_replication_factor;
+        let mut nodes = match self.find_closest_nodes(key_id, k).await {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                tracing::debug!("failed to find closest DHT nodes: {e:?}");
+                Vec::new()
+            }
+        };
+        if nodes.is_empty() {
+            nodes = self.state.buckets.find(key_id, k).nodes;
+        }
+
+        let peer_ids = nodes.iter().filter_map(|node| {
+            adnl::NodeIdFull::try_from(node.id.as_equivalent_ref())
+                .map(|full_id| full_id.compute_short_id())
+                .ok()
+        });
+
+        let results = futures_util::future::join_all(peer_ids.map(|peer_id| {
+            let query = query.clone();
+            async move { self.query_raw(&peer_id, query).await }
+        }))
+        .await;
+
+        results
+            .into_iter()
+            .filter(|result| match result {
+                Ok(Some(answer)) => tl_proto::deserialize::<proto::dht::Stored>(answer).is_ok(),
+                _ => false,
+            })
+            .count()
+    }
+
     async fn query<Q, A>(&self, peer_id: &adnl::NodeIdShort, query: Q) -> Result<Option<A>>
     where
         Q: TlWrite,
         for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
     {
+        let _permit = self.query_semaphore.acquire().await.ok();
+        let started_at = Instant::now();
         let result = self.adnl.query(&self.local_id, peer_id, query, None).await;
-        self.state.update_peer_status(peer_id, result.is_ok());
+        self.state
+            .update_peer_status(peer_id, matches!(result, Ok(Some(_))));
+        self.state
+            .peer_scores
+            .record(peer_id, matches!(result, Ok(Some(_))), started_at.elapsed());
         result
     }
 
@@ -462,7 +939,9 @@ impl Node {
         &self,
         peer_id: &adnl::NodeIdShort,
         query: Bytes,
-    ) -> Result<Option<Vec<u8>>> {
+    ) -> Result<Option<Bytes>> {
+        let _permit = self.query_semaphore.acquire().await.ok();
+        let started_at = Instant::now();
         let result = self
             .adnl
             .query_raw(
@@ -472,7 +951,11 @@ impl Node {
                 Some(self.options.query_timeout_ms),
             )
             .await;
-        self.state.update_peer_status(peer_id, result.is_ok());
+        self.state
+            .update_peer_status(peer_id, matches!(result, Ok(Some(_))));
+        self.state
+            .peer_scores
+            .record(peer_id, matches!(result, Ok(Some(_))), started_at.elapsed());
         result
     }
 
@@ -485,11 +968,23 @@ impl Node {
         Q: TlWrite,
         for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
     {
+        let query_prefix = self.query_prefix.read().clone();
+        let query_prefix = match query_prefix {
+            Some(query_prefix) => query_prefix,
+            None => self.update_query_prefix().await?,
+        };
+
+        let _permit = self.query_semaphore.acquire().await.ok();
+        let started_at = Instant::now();
         let result = self
             .adnl
-            .query_with_prefix::<Q, A>(&self.local_id, peer_id, &self.query_prefix, query, None)
+            .query_with_prefix::<Q, A>(&self.local_id, peer_id, &query_prefix, query, None)
             .await;
-        self.state.update_peer_status(peer_id, result.is_ok());
+        self.state
+            .update_peer_status(peer_id, matches!(result, Ok(Some(_))));
+        self.state
+            .peer_scores
+            .record(peer_id, matches!(result, Ok(Some(_))), started_at.elapsed());
         result
     }
 
@@ -520,11 +1015,55 @@ impl Node {
         }
     }
 
+    /// Penalizes the peer for the answer which failed validation
+    pub(super) fn report_invalid_answer(&self, peer_id: &adnl::NodeIdShort) {
+        self.state.update_peer_status(peer_id, false);
+    }
+
+    /// Signs the local DHT node with the current address list
+    pub(super) async fn update_query_prefix(&self) -> Result<Vec<u8>> {
+        let query_prefix = make_query_prefix(&self.state, &self.adnl).await?;
+        *self.query_prefix.write() = Some(query_prefix.clone());
+        Ok(query_prefix)
+    }
+
     #[inline(always)]
     pub(super) fn known_peers(&self) -> &adnl::PeersSet {
         &self.state.known_peers
     }
 
+    #[inline(always)]
+    pub(super) fn buckets(&self) -> &Buckets {
+        &self.state.buckets
+    }
+
+    #[inline(always)]
+    pub(super) fn republished(&self) -> &FastDashMap<RepublishedKey, RepublishedValue> {
+        &self.republished
+    }
+
+    #[cfg(feature = "overlay")]
+    #[inline(always)]
+    pub(super) fn joined_overlays(
+        &self,
+    ) -> &FastDashMap<overlay::IdShort, std::sync::Weak<overlay::Overlay>> {
+        &self.joined_overlays
+    }
+
+    /// Updates lookup metrics
+    pub(super) fn record_lookup(&self, duration: Duration) {
+        let stats = &self.state.stats;
+        NodeStats::inc(&stats.lookups);
+        stats
+            .lookup_total_time_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(super) fn peer_scores(&self) -> &PeerScores {
+        &self.state.peer_scores
+    }
+
     #[inline(always)]
     pub(super) fn storage(&self) -> &Storage {
         &self.state.storage
@@ -543,31 +1082,85 @@ struct NodeState {
     /// DHT nodes organized by buckets
     buckets: Buckets,
     /// Local DHT values storage
-    storage: Storage,
+    storage: Arc<Storage>,
+    /// Blocking signature verification tasks budget
+    verification_semaphore: Arc<Semaphore>,
 
     /// Max allowed `k` value for DHT `FindValue` query.
     max_allowed_k: u32,
+    /// Max peer penalty points
+    bad_peer_threshold: usize,
+    /// Peers which exceeded the penalty threshold, with the quarantine end time
+    quarantine: FastDashMap<adnl::NodeIdShort, u32>,
+    quarantine_duration_sec: u32,
+
+    /// Incoming `dht.findValue` queries limits
+    find_value_limiter: QueryLimiter,
+    /// Incoming `dht.store` queries limits
+    store_limiter: QueryLimiter,
+
+    /// DHT peers reliability scores
+    peer_scores: PeerScores,
+
+    /// Address resolution results
+    address_cache: FastDashMap<adnl::NodeIdShort, CachedAddress>,
+
+    /// Counters for metrics
+    stats: NodeStats,
 }
 
 impl NodeState {
     fn metrics(&self) -> NodeMetrics {
+        let max_bucket_size = self.buckets.max_bucket_size();
+        let (mut bucket_peer_count, mut non_empty_buckets, mut full_buckets) = (0, 0, 0);
+        for bucket in &self.buckets {
+            let len = bucket.len();
+            bucket_peer_count += len;
+            non_empty_buckets += (len > 0) as usize;
+            full_buckets += (len >= max_bucket_size) as usize;
+        }
+
+        let lookups = self.stats.lookups.load(Ordering::Relaxed);
+        let lookup_total_time_ms = self.stats.lookup_total_time_ms.load(Ordering::Relaxed);
+
         NodeMetrics {
             known_peers_len: self.known_peers.len(),
-            bucket_peer_count: self.buckets.iter().map(|bucket| bucket.len()).sum(),
+            bad_peers_len: self.quarantine.len(),
+            bucket_peer_count,
+            non_empty_buckets,
+            full_buckets,
             storage_len: self.storage.len(),
             storage_total_size: self.storage.total_size(),
+            storage_evicted: self.storage.evicted_count(),
+            storage_quota_evicted: self.storage.quota_evicted_count(),
+            lookups,
+            lookup_avg_time_ms: lookup_total_time_ms
+                .checked_div(lookups)
+                .unwrap_or_default(),
+            failed_queries: self.stats.failed_queries.load(Ordering::Relaxed),
+            ping_queries: self.stats.ping_queries.load(Ordering::Relaxed),
+            find_node_queries: self.stats.find_node_queries.load(Ordering::Relaxed),
+            find_value_queries: self.stats.find_value_queries.load(Ordering::Relaxed),
+            get_signed_address_list_queries: self
+                .stats
+                .get_signed_address_list_queries
+                .load(Ordering::Relaxed),
+            store_queries: self.stats.store_queries.load(Ordering::Relaxed),
         }
     }
 
-    fn sign_local_node(&self, addr_list: proto::adnl::AddressList) -> proto::dht::NodeOwned {
+    async fn sign_local_node(
+        &self,
+        addr_list: proto::adnl::AddressList,
+    ) -> Result<proto::dht::NodeOwned> {
         let mut node = proto::dht::NodeOwned {
             id: self.key.full_id().as_tl().as_equivalent_owned(),
             addr_list,
-            version: addr_list.version,
+            version: NodeFeatures::SUPPORTED.apply_to_version(addr_list.version),
             signature: Default::default(),
         };
-        node.signature = self.key.sign(node.as_boxed()).to_vec().into();
-        node
+        node.signature = self.key.sign_async(node.as_boxed()).await?.to_vec().into();
+        Ok(node)
     }
 
     fn add_dht_peer(
@@ -602,11 +1195,12 @@ impl NodeState {
         }
 
         // Add new peer to the bucket
-        if self.known_peers.insert(peer_id) {
-            self.buckets.insert(&peer_id, peer);
-        } else {
+        if !self.known_peers.insert(peer_id) {
             self.set_good_peer(&peer_id);
         }
+        if !self.is_bad_peer(&peer_id) {
+            self.buckets.insert(&peer_id, peer);
+        }
 
         Ok(Some(peer_id))
     }
@@ -615,19 +1209,46 @@ impl NodeState {
         use dashmap::mapref::entry::Entry;
 
         if is_good {
+            self.buckets.touch(peer);
             self.set_good_peer(peer);
         } else {
-            match self.penalties.entry(*peer) {
+            NodeStats::inc(&self.stats.failed_queries);
+            let penalty = match self.penalties.entry(*peer) {
                 Entry::Occupied(mut entry) => {
                     *entry.get_mut() += 2;
+                    *entry.get()
                 }
-                Entry::Vacant(entry) => {
-                    entry.insert(0);
-                }
+                Entry::Vacant(entry) => *entry.insert(0),
+            };
+
+            if penalty > self.bad_peer_threshold {
+                self.quarantine(peer);
             }
         }
     }
 
+    /// Skips the peer in lookups and removes it from the buckets
+    /// for the quarantine duration
+    fn quarantine(&self, peer: &adnl::NodeIdShort) {
+        self.penalties.remove(peer);
+        if self.buckets.remove(peer) {
+            tracing::debug!(%peer, "removed bad DHT peer from the bucket");
+        }
+
+        // NOTE: bad peers are just evicted if the quarantine is disabled
+        if self.quarantine_duration_sec == 0 {
+            return;
+        }
+
+        self.quarantine
+            .insert(*peer, now() + self.quarantine_duration_sec);
+        tracing::debug!(%peer, "DHT peer quarantined");
+    }
+
+    fn is_bad_peer(&self, peer: &adnl::NodeIdShort) -> bool {
+        matches!(self.quarantine.get(peer), Some(until) if *until > now())
+    }
+
     fn set_good_peer(&self, peer: &adnl::NodeIdShort) {
         if let Some(mut count) = self.penalties.get_mut(peer) {
             *count.value_mut() = count.saturating_sub(1);
@@ -635,7 +1256,8 @@ impl NodeState {
     }
 
     fn process_find_node(&self, query: proto::rpc::DhtFindNode<'_>) -> proto::dht::NodesOwned {
-        self.buckets.find(query.key, query.k)
+        self.buckets
+            .find(query.key, std::cmp::min(query.k, self.max_allowed_k))
     }
 
     fn process_find_value(
@@ -649,24 +1271,28 @@ impl NodeState {
         Ok(if let Some(value) = self.storage.get_ref(query.key) {
             proto::dht::ValueResultOwned::ValueFound(value.clone().into_boxed())
         } else {
-            let mut nodes = Vec::with_capacity(query.k as usize);
-
-            'outer: for bucket in &self.buckets {
-                for peer in bucket {
-                    nodes.push(peer.clone());
-
-                    if nodes.len() >= query.k as usize {
-                        break 'outer;
-                    }
-                }
-            }
-
-            proto::dht::ValueResultOwned::ValueNotFound(proto::dht::NodesOwned { nodes })
+            proto::dht::ValueResultOwned::ValueNotFound(self.buckets.find(query.key, query.k))
         })
     }
 
-    fn process_store(&self, query: proto::rpc::DhtStore<'_>) -> Result<proto::dht::Stored> {
-        self.storage.insert(query.value)?;
+    async fn process_store(&self, query: proto::rpc::DhtStore<'_>) -> Result<proto::dht::Stored> {
+        // NOTE: small values are cheaper to verify than to move to another thread
+        if query.value.value.len() <= INLINE_VERIFICATION_MAX_SIZE {
+            self.storage.insert(query.value)?;
+            return Ok(proto::dht::Stored);
+        }
+
+        // NOTE: bigger values are verified on the blocking thread pool to keep
+        // the reactor responsive, and are rejected while the budget is exhausted
+        // so that sync storms don't pile up waiting queries
+        let _permit = match self.verification_semaphore.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => return Err(DhtNodeError::TooManyVerifications.into()),
+        };
+        let value = query.value.as_equivalent_owned();
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.insert(value.as_equivalent_ref())).await??;
+
         Ok(proto::dht::Stored)
     }
 }
@@ -681,24 +1307,34 @@ impl QuerySubscriber for NodeState {
     ) -> Result<QueryConsumingResult<'a>> {
         match constructor {
             proto::rpc::DhtPing::TL_ID => {
+                NodeStats::inc(&self.stats.ping_queries);
                 let proto::rpc::DhtPing { random_id } = tl_proto::deserialize(&query)?;
                 QueryConsumingResult::consume(proto::dht::Pong { random_id })
             }
             proto::rpc::DhtFindNode::TL_ID => {
+                NodeStats::inc(&self.stats.find_node_queries);
                 let query = tl_proto::deserialize(&query)?;
                 QueryConsumingResult::consume(self.process_find_node(query).into_boxed())
             }
             proto::rpc::DhtFindValue::TL_ID => {
+                NodeStats::inc(&self.stats.find_value_queries);
+                self.find_value_limiter.check(ctx.peer_id, query.len())?;
                 let query = tl_proto::deserialize(&query)?;
                 QueryConsumingResult::consume(self.process_find_value(query)?)
             }
-            proto::rpc::DhtGetSignedAddressList::TL_ID => QueryConsumingResult::consume(
-                self.sign_local_node(ctx.adnl.build_address_list())
-                    .into_boxed(),
-            ),
+            proto::rpc::DhtGetSignedAddressList::TL_ID => {
+                NodeStats::inc(&self.stats.get_signed_address_list_queries);
+                QueryConsumingResult::consume(
+                    self.sign_local_node(ctx.adnl.build_address_list())
+                        .await?
+                        .into_boxed(),
+                )
+            }
             proto::rpc::DhtStore::TL_ID => {
+                NodeStats::inc(&self.stats.store_queries);
+                self.store_limiter.check(ctx.peer_id, query.len())?;
                 let query = tl_proto::deserialize(&query)?;
-                QueryConsumingResult::consume(self.process_store(query)?)
+                QueryConsumingResult::consume(self.process_store(query).await?)
             }
             proto::rpc::DhtQuery::TL_ID => {
                 let mut offset = 0;
@@ -718,6 +1354,9 @@ impl QuerySubscriber for NodeState {
                     QueryConsumingResult::Consumed(answer) => {
                         Ok(QueryConsumingResult::Consumed(answer))
                     }
+                    QueryConsumingResult::ConsumedStream(answer) => {
+                        Ok(QueryConsumingResult::ConsumedStream(answer))
+                    }
                     QueryConsumingResult::Rejected(_) => Err(DhtNodeError::UnexpectedQuery.into()),
                 }
             }
@@ -726,13 +1365,19 @@ impl QuerySubscriber for NodeState {
     }
 }
 
-fn verify_signed_dht_value(value: &mut proto::dht::Value<'_>) -> Result<()> {
-    if value.key.key.id != &tl_proto::hash(value.key.id) {
+async fn make_query_prefix(state: &NodeState, adnl: &adnl::Node) -> Result<Vec<u8>> {
+    let node = state.sign_local_node(adnl.build_address_list()).await?;
+    Ok(tl_proto::serialize(proto::rpc::DhtQuery {
+        node: node.as_equivalent_ref(),
+    }))
+}
+
+pub(super) fn verify_signed_dht_value(value: &mut proto::dht::Value<'_>) -> Result<()> {
+    let full_id = adnl::KeyIdFull::try_from(value.key.id)?;
+    if value.key.key.id != full_id.compute_short_id().as_slice() {
         return Err(DhtNodeError::InvalidValueKey.into());
     }
 
-    let full_id = adnl::NodeIdFull::try_from(value.key.id)?;
-
     let key_signature = std::mem::take(&mut value.key.signature);
     full_id.verify(value.key.as_boxed(), key_signature)?;
     value.key.signature = key_signature;
@@ -747,14 +1392,71 @@ fn verify_signed_dht_value(value: &mut proto::dht::Value<'_>) -> Result<()> {
 /// Instant DHT node metrics
 #[derive(Debug, Copy, Clone)]
 pub struct NodeMetrics {
+    /// Known DHT peer count
     pub known_peers_len: usize,
+    /// Number of quarantined DHT peers
+    pub bad_peers_len: usize,
+    /// Total node count in all buckets
     pub bucket_peer_count: usize,
+    /// Number of buckets with at least one node
+    pub non_empty_buckets: usize,
+    /// Number of buckets which have reached [`NodeOptions::max_bucket_size`]
+    pub full_buckets: usize,
+    /// Number of stored values
     pub storage_len: usize,
+    /// Total size of stored values in bytes
     pub storage_total_size: usize,
+    /// Total number of removed expired values
+    pub storage_evicted: u64,
+    /// Total number of values removed due to storage quotas
+    pub storage_quota_evicted: u64,
+    /// Total number of iterative lookups
+    pub lookups: u64,
+    /// Average iterative lookup duration
+    pub lookup_avg_time_ms: u64,
+    /// Total number of failed outgoing queries
+    pub failed_queries: u64,
+    /// Total number of incoming `dht.ping` queries
+    pub ping_queries: u64,
+    /// Total number of incoming `dht.findNode` queries
+    pub find_node_queries: u64,
+    /// Total number of incoming `dht.findValue` queries
+    pub find_value_queries: u64,
+    /// Total number of incoming `dht.getSignedAddressList` queries
+    pub get_signed_address_list_queries: u64,
+    /// Total number of incoming `dht.store` queries
+    pub store_queries: u64,
+}
+
+#[derive(Default)]
+struct NodeStats {
+    lookups: AtomicU64,
+    lookup_total_time_ms: AtomicU64,
+    failed_queries: AtomicU64,
+    ping_queries: AtomicU64,
+    find_node_queries: AtomicU64,
+    find_value_queries: AtomicU64,
+    get_signed_address_list_queries: AtomicU64,
+    store_queries: AtomicU64,
+}
+
+impl NodeStats {
+    #[inline(always)]
+    fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 type Penalties = FastDashMap<adnl::NodeIdShort, usize>;
 
+struct CachedAddress {
+    resolved: Option<(SocketAddrV4, adnl::NodeIdFull)>,
+    expires_at: u32,
+}
+
+/// Max size of the incoming value which is verified without the blocking thread pool
+const INLINE_VERIFICATION_MAX_SIZE: usize = 1024;
+
 #[derive(thiserror::Error, Debug)]
 enum DhtNodeError {
     #[error("No address found")]
@@ -765,4 +1467,330 @@ enum DhtNodeError {
     InvalidNodeCountLimit,
     #[error("Invalid value key")]
     InvalidValueKey,
+    #[error("Too many values are being verified")]
+    TooManyVerifications,
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::dht::DhtKeyBuilder;
+    use crate::test_utils::*;
+
+    fn make_dht(network: &MemoryNetwork, options: NodeOptions) -> Arc<Node> {
+        let keystore = adnl::Keystore::builder()
+            .with_tagged_keys([(rand::random(), 0)])
+            .unwrap()
+            .build();
+        let adnl = network
+            .create_node(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                keystore,
+                adnl::NodeOptions {
+                    query_default_timeout_ms: 50,
+                    query_min_timeout_ms: 10,
+                    query_adaptive_timeout: false,
+                    ..Default::default()
+                },
+                None,
+            )
+            .unwrap();
+        let dht = Node::new(adnl.clone(), 0, options).unwrap();
+        adnl.start().unwrap();
+        dht
+    }
+
+    /// Adds the signed local node of `peer` to the known peers of `dht`
+    async fn add_local_node(dht: &Node, peer: &Node) {
+        let node = peer
+            .state
+            .sign_local_node(peer.adnl().build_address_list())
+            .await
+            .unwrap();
+        dht.add_dht_peer(node).unwrap().unwrap();
+    }
+
+    /// Signed DHT node with an address which is never answered
+    fn make_unresponsive_peer() -> proto::dht::NodeOwned {
+        let key = adnl::Key::from_bytes(rand::random());
+        let now = now();
+        let addr_list = proto::adnl::AddressList {
+            address: Some(proto::adnl::Address::Udp {
+                ip: u32::from(Ipv4Addr::LOCALHOST),
+                port: 1,
+            }),
+            version: now,
+            reinit_date: now,
+            priority: 0,
+            expire_at: 0,
+        };
+        let mut node = proto::dht::NodeOwned {
+            id: key.full_id().as_tl().as_equivalent_owned(),
+            addr_list,
+            version: now,
+            signature: Default::default(),
+        };
+        node.signature = key.sign(node.as_boxed()).to_vec().into();
+        node
+    }
+
+    #[tokio::test]
+    async fn bad_peers_are_evicted() {
+        for quarantine_duration_sec in [0, 600] {
+            let network = MemoryNetwork::new();
+            let dht = make_dht(
+                &network,
+                NodeOptions {
+                    bad_peer_threshold: 1,
+                    quarantine_duration_sec,
+                    ..Default::default()
+                },
+            );
+
+            let peer_id = dht.add_dht_peer(make_unresponsive_peer()).unwrap().unwrap();
+            assert_eq!(dht.export_peers().len(), 1);
+
+            // First failure sets zero penalty, the second one exceeds the threshold
+            for _ in 0..2 {
+                assert!(!dht.ping(&peer_id).await.unwrap());
+            }
+
+            assert!(dht.export_peers().is_empty());
+            assert_eq!(dht.is_bad_peer(&peer_id), quarantine_duration_sec > 0);
+            assert_eq!(
+                dht.quarantined_peers().len(),
+                (quarantine_duration_sec > 0) as usize
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn store_verification_budget() {
+        let network = MemoryNetwork::new();
+        let dht = make_dht(
+            &network,
+            NodeOptions {
+                max_concurrent_verifications: 1,
+                ..Default::default()
+            },
+        );
+        let key = dht.key().clone();
+        let store = |value: proto::dht::ValueOwned| {
+            let dht = dht.clone();
+            async move {
+                dht.state
+                    .process_store(proto::rpc::DhtStore {
+                        value: value.as_equivalent_ref(),
+                    })
+                    .await
+            }
+        };
+
+        let small_data = vec![0xaa; INLINE_VERIFICATION_MAX_SIZE - 1];
+        let small = dht
+            .entry(key.id(), "small")
+            .with_data_raw(&small_data)
+            .sign(&key);
+        let big_data = vec![0xaa; INLINE_VERIFICATION_MAX_SIZE + 1];
+        let big = dht
+            .entry(key.id(), "big")
+            .with_data_raw(&big_data)
+            .sign(&key);
+
+        // Small values are verified inline even without budget
+        let permit = dht.state.verification_semaphore.try_acquire().unwrap();
+        store(small).await.unwrap();
+
+        match store(big.clone()).await {
+            Err(e) => assert!(matches!(
+                e.downcast_ref::<DhtNodeError>(),
+                Some(DhtNodeError::TooManyVerifications)
+            )),
+            Ok(_) => panic!("big value must be rejected without budget"),
+        }
+
+        drop(permit);
+        store(big).await.unwrap();
+        assert_eq!(dht.metrics().storage_len, 2);
+    }
+
+    #[cfg(feature = "overlay")]
+    fn make_overlay_node(
+        key: &adnl::Key,
+        overlay_id: &overlay::IdShort,
+    ) -> proto::overlay::NodeOwned {
+        let version = now();
+        let signature = key.sign(proto::overlay::NodeToSign {
+            id: key.id().as_slice(),
+            overlay: overlay_id.as_slice(),
+            version,
+        });
+        proto::overlay::NodeOwned {
+            id: key.full_id().as_tl().as_equivalent_owned(),
+            overlay: *overlay_id.as_slice(),
+            version,
+            signature: signature.to_vec().into(),
+        }
+    }
+
+    #[cfg(feature = "overlay")]
+    #[tokio::test]
+    async fn stored_values_are_found() {
+        let network = MemoryNetwork::new();
+        let dhts = (0..3)
+            .map(|_| make_dht(&network, Default::default()))
+            .collect::<Vec<_>>();
+        let (publisher, relay, client) = (&dhts[0], &dhts[1], &dhts[2]);
+        add_local_node(publisher, relay).await;
+        add_local_node(client, relay).await;
+
+        let key = publisher.key().clone();
+        let addr = publisher.adnl().socket_addr();
+        assert!(publisher.store_address(&key, addr).await.unwrap());
+
+        let overlay_id_full = overlay::IdFull::for_workchain_overlay(0, &[2; 32]);
+        let overlay_id = overlay_id_full.compute_short_id();
+        let node = make_overlay_node(&key, &overlay_id);
+        assert!(publisher
+            .store_overlay_node(&overlay_id_full, node.as_equivalent_ref())
+            .await
+            .unwrap());
+
+        // Values are replicated to the relay and found by the client through it
+        let (found_addr, found_id) = client.find_address(key.id()).await.unwrap();
+        assert_eq!(found_addr, addr);
+        assert_eq!(&found_id, key.full_id());
+
+        let nodes = client.find_overlay_nodes(&overlay_id).await.unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].0, addr);
+        assert_eq!(nodes[0].1.id, node.id);
+    }
+
+    #[cfg(feature = "overlay")]
+    #[tokio::test]
+    async fn overlay_nodes_without_addresses() {
+        let network = MemoryNetwork::new();
+        let left = make_dht(&network, Default::default());
+        let right = make_dht(&network, Default::default());
+
+        add_local_node(&left, &right).await;
+
+        // Overlay node without the stored address
+        let overlay_id_full = overlay::IdFull::for_workchain_overlay(0, &[1; 32]);
+        let overlay_id = overlay_id_full.compute_short_id();
+        let key = adnl::Key::from_bytes(rand::random());
+        let node = make_overlay_node(&key, &overlay_id);
+        right
+            .store_overlay_node(&overlay_id_full, node.as_equivalent_ref())
+            .await
+            .unwrap();
+
+        // Search stops when there are no new nodes to resolve
+        let nodes = tokio::time::timeout(
+            Duration::from_secs(10),
+            left.find_overlay_nodes(&overlay_id),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(nodes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn iterative_lookup() {
+        let network = MemoryNetwork::new();
+        let dhts = (0..3)
+            .map(|_| make_dht(&network, Default::default()))
+            .collect::<Vec<_>>();
+        let (publisher, relay, client) = (&dhts[0], &dhts[1], &dhts[2]);
+        add_local_node(publisher, relay).await;
+        add_local_node(client, relay).await;
+
+        let key = publisher.key().clone();
+        let stored = publisher
+            .entry(key.id(), "test")
+            .with_data(proto::dht::Pong { random_id: 123 })
+            .with_ttl(600)
+            .sign_and_store(&key)
+            .unwrap()
+            .await;
+        assert!(stored > 0);
+
+        // Value is found through the relay
+        let (key_description, pong) = client
+            .entry(key.id(), "test")
+            .find::<proto::dht::Pong>()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(key_description.key.name.as_ref(), b"test");
+        assert_eq!(pong.random_id, 123);
+
+        // Missing value
+        assert!(client
+            .entry(key.id(), "missing")
+            .find::<proto::dht::Pong>()
+            .await
+            .unwrap()
+            .is_none());
+
+        // Only responding nodes are returned
+        let nodes = client.find_nodes(key.id().as_slice()).await.unwrap();
+        assert!(!nodes.is_empty());
+        assert!(nodes.iter().any(|node| {
+            adnl::NodeIdFull::try_from(node.id.as_equivalent_ref())
+                .map(|id| id.compute_short_id() == *relay.key().id())
+                .unwrap_or_default()
+        }));
+    }
+
+    #[tokio::test]
+    async fn custom_keys() {
+        let network = MemoryNetwork::new();
+        let dhts = (0..2)
+            .map(|_| make_dht(&network, Default::default()))
+            .collect::<Vec<_>>();
+        let (publisher, client) = (&dhts[0], &dhts[1]);
+        add_local_node(publisher, client).await;
+        add_local_node(client, publisher).await;
+
+        let key = publisher.key().clone();
+        let custom_key = DhtKeyBuilder::new(key.id(), "custom", 3);
+
+        let value = custom_key
+            .entry(publisher)
+            .with_data(proto::dht::Pong { random_id: 123 })
+            .sign(&key);
+        assert_eq!(value.key.key.idx, 3);
+        custom_key.verify(&value).unwrap();
+
+        // Values of other keys are rejected
+        let other_key = DhtKeyBuilder::new(key.id(), "custom", 4);
+        assert!(other_key.verify(&value).is_err());
+
+        let stored = custom_key
+            .entry(publisher)
+            .with_data(proto::dht::Pong { random_id: 123 })
+            .sign_and_store(&key)
+            .unwrap()
+            .await;
+        assert!(stored > 0);
+
+        let (_, pong) = custom_key
+            .entry(client)
+            .find::<proto::dht::Pong>()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pong.random_id, 123);
+        assert!(other_key
+            .entry(client)
+            .find::<proto::dht::Pong>()
+            .await
+            .unwrap()
+            .is_none());
+    }
 }
@@ -0,0 +1,55 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use super::node::Node;
+use crate::util::*;
+
+impl Node {
+    /// Searches for new nodes in the buckets which haven't seen any traffic
+    /// during the last [`NodeOptions::bucket_refresh_interval_sec`].
+    ///
+    /// Returns the number of refreshed buckets
+    ///
+    /// [`NodeOptions::bucket_refresh_interval_sec`]: super::NodeOptions::bucket_refresh_interval_sec
+    pub async fn refresh_buckets(&self) -> usize {
+        let interval_sec = self.options().bucket_refresh_interval_sec;
+        let inactive = self
+            .buckets()
+            .inactive_since(now().saturating_sub(interval_sec));
+
+        for &affinity in &inactive {
+            let key_id = self.buckets().random_id_in_bucket(affinity);
+            match self.find_nodes(&key_id).await {
+                Ok(nodes) => tracing::trace!(affinity, found = nodes.len(), "refreshed DHT bucket"),
+                Err(e) => tracing::debug!(affinity, "failed to refresh DHT bucket: {e:?}"),
+            }
+            self.buckets().touch_bucket(affinity);
+        }
+
+        inactive.len()
+    }
+
+    /// Starts a process that periodically refreshes inactive buckets
+    pub(super) fn start_bucket_refresh_loop(self: &Arc<Self>) {
+        let interval = Duration::from_secs(self.options().bucket_refresh_interval_sec as u64);
+        let dht = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let dht = match Weak::upgrade(&dht) {
+                    Some(dht) => dht,
+                    None => break,
+                };
+
+                let refreshed = dht.refresh_buckets().await;
+                if refreshed > 0 {
+                    tracing::debug!(refreshed, "refreshed inactive DHT buckets");
+                }
+            }
+
+            tracing::debug!("DHT bucket refresh loop finished");
+        });
+    }
+}
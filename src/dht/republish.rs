@@ -0,0 +1,161 @@
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::Rng;
+
+use super::node::Node;
+use crate::adnl;
+use crate::overlay;
+use crate::proto;
+use crate::util::*;
+
+impl Node {
+    /// Stores given socket address into multiple DHT nodes and keeps
+    /// republishing it in the background before its TTL expires.
+    ///
+    /// The value is signed again on each republish.
+    ///
+    /// See [`Node::store_address`], [`NodeOptions::republish_interval_sec`]
+    ///
+    /// [`NodeOptions::republish_interval_sec`]: super::NodeOptions::republish_interval_sec
+    pub async fn publish_address(
+        self: &Arc<Self>,
+        key: Arc<adnl::Key>,
+        addr: SocketAddrV4,
+    ) -> Result<bool> {
+        self.republished().insert(
+            RepublishedKey::Address(*key.id()),
+            RepublishedValue::Address {
+                key: key.clone(),
+                addr,
+            },
+        );
+        self.store_address(&key, addr).await
+    }
+
+    /// Stores given overlay node into multiple DHT nodes and keeps
+    /// republishing it in the background before its TTL expires.
+    ///
+    /// See [`Node::store_overlay_node`], [`NodeOptions::republish_interval_sec`]
+    ///
+    /// [`NodeOptions::republish_interval_sec`]: super::NodeOptions::republish_interval_sec
+    pub async fn publish_overlay_node(
+        self: &Arc<Self>,
+        overlay_id_full: overlay::IdFull,
+        node: proto::overlay::NodeOwned,
+    ) -> Result<bool> {
+        self.republished().insert(
+            RepublishedKey::OverlayNode(overlay_id_full.compute_short_id()),
+            RepublishedValue::OverlayNode {
+                overlay_id_full,
+                node: node.clone(),
+            },
+        );
+        self.store_overlay_node(&overlay_id_full, node.as_equivalent_ref())
+            .await
+    }
+
+    /// Stops republishing the address of the specified local key.
+    /// Returns whether the address was published
+    pub fn unpublish_address(&self, local_id: &adnl::NodeIdShort) -> bool {
+        self.republished()
+            .remove(&RepublishedKey::Address(*local_id))
+            .is_some()
+    }
+
+    /// Stops republishing the overlay node for the specified overlay.
+    /// Returns whether the overlay node was published
+    pub fn unpublish_overlay_node(&self, overlay_id: &overlay::IdShort) -> bool {
+        self.republished()
+            .remove(&RepublishedKey::OverlayNode(*overlay_id))
+            .is_some()
+    }
+
+    /// Starts a process that periodically republishes own values
+    pub(super) fn start_republish_loop(self: &Arc<Self>) {
+        let interval_sec = self.options().republish_interval_sec as u64;
+        let jitter_sec = self.options().republish_jitter_sec as u64;
+        let dht = Arc::downgrade(self);
+
+        tokio::spawn(async move {
+            loop {
+                let jitter_ms = fast_thread_rng().gen_range(0..=jitter_sec * 1000);
+                let interval = Duration::from_secs(interval_sec) + Duration::from_millis(jitter_ms);
+                tokio::time::sleep(interval).await;
+
+                match Weak::upgrade(&dht) {
+                    Some(dht) => dht.republish_values().await,
+                    None => break,
+                }
+            }
+
+            tracing::debug!("DHT republish loop finished");
+        });
+    }
+
+    async fn republish_values(self: &Arc<Self>) {
+        let values = self
+            .republished()
+            .iter()
+            .map(|item| item.value().clone())
+            .collect::<Vec<_>>();
+
+        futures_util::future::join_all(values.into_iter().map(|value| async move {
+            let result = match &value {
+                RepublishedValue::Address { key, addr } => self.store_address(key, *addr).await,
+                RepublishedValue::OverlayNode {
+                    overlay_id_full,
+                    node,
+                } => {
+                    self.store_overlay_node(overlay_id_full, node.as_equivalent_ref())
+                        .await
+                }
+            };
+
+            match result {
+                Ok(true) => tracing::trace!(?value, "republished DHT value"),
+                Ok(false) => tracing::debug!(?value, "DHT value was not republished"),
+                Err(e) => tracing::warn!(?value, "failed to republish DHT value: {e:?}"),
+            }
+        }))
+        .await;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub(super) enum RepublishedKey {
+    Address(adnl::NodeIdShort),
+    OverlayNode(overlay::IdShort),
+}
+
+#[derive(Clone)]
+pub(super) enum RepublishedValue {
+    Address {
+        key: Arc<adnl::Key>,
+        addr: SocketAddrV4,
+    },
+    OverlayNode {
+        overlay_id_full: overlay::IdFull,
+        node: proto::overlay::NodeOwned,
+    },
+}
+
+impl std::fmt::Debug for RepublishedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Address { key, addr } => f
+                .debug_struct("Address")
+                .field("local_id", key.id())
+                .field("addr", addr)
+                .finish(),
+            Self::OverlayNode {
+                overlay_id_full, ..
+            } => f
+                .debug_struct("OverlayNode")
+                .field("overlay_id", &overlay_id_full.compute_short_id())
+                .finish(),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+
+use crate::adnl;
+use crate::util::*;
+
+/// DHT peers reliability scores
+pub struct PeerScores {
+    half_life_sec: f64,
+    scores: FastDashMap<adnl::NodeIdShort, PeerScore>,
+}
+
+impl PeerScores {
+    pub fn new(half_life_sec: u32) -> Self {
+        Self {
+            half_life_sec: std::cmp::max(half_life_sec, 1) as f64,
+            scores: Default::default(),
+        }
+    }
+
+    /// Updates peer score with the query result
+    pub fn record(&self, peer_id: &adnl::NodeIdShort, success: bool, latency: Duration) {
+        let now = Instant::now();
+        let mut score = self.scores.entry(*peer_id).or_insert(PeerScore {
+            success_rate: NEUTRAL_SUCCESS_RATE,
+            latency_ms: 0.0,
+            updated_at: now,
+        });
+
+        score.success_rate = self.decayed_success_rate(&score, now);
+        score.success_rate += (success as u8 as f64 - score.success_rate) * SMOOTHING;
+        if success {
+            let latency_ms = latency.as_secs_f64() * 1000.0;
+            score.latency_ms = match score.latency_ms {
+                prev if prev > 0.0 => prev + (latency_ms - prev) * SMOOTHING,
+                _ => latency_ms,
+            };
+        }
+        score.updated_at = now;
+    }
+
+    /// Returns peer score in range `0..=1`. Higher is better.
+    ///
+    /// Old results are gradually forgotten, so the score of inactive
+    /// peers tends to the score of the unknown peer.
+    pub fn get(&self, peer_id: &adnl::NodeIdShort) -> f64 {
+        match self.scores.get(peer_id) {
+            Some(score) => {
+                let success_rate = self.decayed_success_rate(&score, Instant::now());
+                success_rate / (1.0 + score.latency_ms / 1000.0)
+            }
+            None => NEUTRAL_SUCCESS_RATE,
+        }
+    }
+
+    /// Removes scores which are almost completely forgotten.
+    /// Returns the number of removed entries
+    pub fn remove_stale(&self) -> usize {
+        let max_age = Duration::from_secs_f64(self.half_life_sec * STALE_HALF_LIVES);
+        let len = self.scores.len();
+        self.scores
+            .retain(|_, score| score.updated_at.elapsed() < max_age);
+        len.saturating_sub(self.scores.len())
+    }
+
+    fn decayed_success_rate(&self, score: &PeerScore, now: Instant) -> f64 {
+        let elapsed = now.duration_since(score.updated_at).as_secs_f64();
+        let weight = 0.5f64.powf(elapsed / self.half_life_sec);
+        NEUTRAL_SUCCESS_RATE + (score.success_rate - NEUTRAL_SUCCESS_RATE) * weight
+    }
+}
+
+struct PeerScore {
+    success_rate: f64,
+    latency_ms: f64,
+    updated_at: Instant,
+}
+
+const NEUTRAL_SUCCESS_RATE: f64 = 0.5;
+const SMOOTHING: f64 = 0.2;
+const STALE_HALF_LIVES: f64 = 8.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reliable_peers_have_higher_score() {
+        let scores = PeerScores::new(600);
+        let good = adnl::NodeIdShort::new([1; 32]);
+        let slow = adnl::NodeIdShort::new([2; 32]);
+        let bad = adnl::NodeIdShort::new([3; 32]);
+        let unknown = adnl::NodeIdShort::new([4; 32]);
+
+        for _ in 0..10 {
+            scores.record(&good, true, Duration::from_millis(50));
+            scores.record(&slow, true, Duration::from_millis(900));
+            scores.record(&bad, false, Duration::from_millis(1000));
+        }
+
+        assert!(scores.get(&good) > scores.get(&slow));
+        assert!(scores.get(&slow) > scores.get(&bad));
+        assert!(scores.get(&good) > scores.get(&unknown));
+        assert!(scores.get(&unknown) > scores.get(&bad));
+        assert_eq!(scores.remove_stale(), 0);
+    }
+}
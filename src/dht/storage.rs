@@ -1,7 +1,10 @@
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::Result;
+use parking_lot::Mutex;
 use smallvec::SmallVec;
 use tl_proto::{BoxedConstructor, HashWrapper, TlWrite};
 
@@ -14,19 +17,32 @@ use crate::util::*;
 pub struct StorageOptions {
     pub max_key_name_len: usize,
     pub max_key_index: u32,
+    pub max_total_size: Option<usize>,
+    pub max_values_per_key_id: Option<usize>,
+    pub max_value_size: usize,
+    pub max_ttl_sec: Option<u32>,
 }
 
 /// Local DHT data storage
 pub struct Storage {
-    storage: FastDashMap<StorageKeyId, proto::dht::ValueOwned>,
+    storage: FastDashMap<StorageKeyId, StoredValue>,
+    /// NOTE: all modifications of the storage are made under this lock
+    index: Mutex<StorageIndex>,
     options: StorageOptions,
+    /// Number of removed expired values
+    evicted: AtomicU64,
+    /// Number of values removed due to quotas
+    quota_evicted: AtomicU64,
 }
 
 impl Storage {
     pub fn new(options: StorageOptions) -> Self {
         Self {
             storage: Default::default(),
+            index: Default::default(),
             options,
+            evicted: Default::default(),
+            quota_evicted: Default::default(),
         }
     }
 
@@ -37,92 +53,206 @@ impl Storage {
 
     /// Returns total size of stored values in bytes
     pub fn total_size(&self) -> usize {
-        self.storage.iter().map(|item| item.value.len()).sum()
+        self.index.lock().total_size
     }
 
-    /// Returns value reference by key
+    /// Returns total number of removed expired values
+    pub fn evicted_count(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Returns total number of values removed due to quotas
+    pub fn quota_evicted_count(&self) -> u64 {
+        self.quota_evicted.load(Ordering::Relaxed)
+    }
+
+    /// Returns value reference by key. Removes the value if it has expired
     pub fn get_ref(
         &self,
         key: &StorageKeyId,
     ) -> Option<impl Deref<Target = proto::dht::ValueOwned> + '_> {
+        let now = now();
         match self.storage.get(key) {
-            Some(item) if item.ttl > now() => Some(item),
-            _ => None,
+            Some(item) if item.expires_at > now => return Some(item.map(|item| &item.value)),
+            Some(_) => {}
+            None => return None,
         }
+
+        let mut index = self.index.lock();
+        // NOTE: the value could have been updated since the check
+        if let Some((key, value)) = self
+            .storage
+            .remove_if(key, |_, value| value.expires_at <= now)
+        {
+            index.remove(&key, &value);
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        None
     }
 
     /// Inserts value into the local storage
     ///
     /// NOTE: Values with `UpdateRule::Anybody` can't be inserted
     pub fn insert(&self, value: proto::dht::Value<'_>) -> Result<bool> {
-        if value.ttl <= now() {
-            return Err(StorageError::ValueExpired.into());
+        let key_id = self.check_value(&value)?;
+        match value.key.update_rule {
+            proto::dht::UpdateRule::Signature => {
+                verify_signed_value(value, &key_id)?;
+                self.insert_signed_value(value)
+            }
+            proto::dht::UpdateRule::OverlayNodes => self.insert_overlay_nodes(value, &key_id),
+            _ => Err(StorageError::UnsupportedUpdateRule.into()),
         }
+    }
 
-        if !(0..=self.options.max_key_name_len).contains(&value.key.key.name.len())
-            || value.key.key.idx > self.options.max_key_index
-        {
-            return Err(StorageError::InvalidKey.into());
+    /// Inserts multiple values into the local storage.
+    ///
+    /// Signatures of all signed values are verified as a single batch,
+    /// which is much faster than verifying them one by one.
+    ///
+    /// Returns insertion result for each value (see [`Storage::insert`])
+    pub fn insert_many(&self, values: &[proto::dht::Value<'_>]) -> Vec<Result<bool>> {
+        let mut results = Vec::with_capacity(values.len());
+
+        // (value index, public key, key data, value data)
+        let mut signed = Vec::new();
+        for (i, value) in values.iter().enumerate() {
+            let key_id = match self.check_value(value) {
+                Ok(key_id) => key_id,
+                Err(e) => {
+                    results.push(Err(e));
+                    continue;
+                }
+            };
+
+            match (&key_id, value.key.update_rule) {
+                (adnl::KeyIdFull::Ed25519(id), proto::dht::UpdateRule::Signature) => {
+                    let (key_data, value_data) = make_signed_data(*value);
+                    signed.push((i, *id.public_key().as_bytes(), key_data, value_data));
+                    // NOTE: will be replaced after the verification
+                    results.push(Ok(false));
+                }
+                (_, proto::dht::UpdateRule::Signature) => {
+                    results.push(Err(adnl::NodeIdFullError::SignatureNotSupported.into()))
+                }
+                (_, proto::dht::UpdateRule::OverlayNodes) => {
+                    results.push(self.insert_overlay_nodes(*value, &key_id))
+                }
+                _ => results.push(Err(StorageError::UnsupportedUpdateRule.into())),
+            }
         }
 
-        if value.key.key.id != &tl_proto::hash(value.key.id) {
-            return Err(StorageError::InvalidKey.into());
+        if signed.is_empty() {
+            return results;
         }
 
-        match value.key.update_rule {
-            proto::dht::UpdateRule::Signature => self.insert_signed_value(value),
-            proto::dht::UpdateRule::OverlayNodes => self.insert_overlay_nodes(value),
-            _ => Err(StorageError::UnsupportedUpdateRule.into()),
+        let items = signed
+            .iter()
+            .flat_map(|(i, public_key, key_data, value_data)| {
+                let value = &values[*i];
+                [
+                    SignatureItem {
+                        public_key,
+                        message: key_data,
+                        signature: value.key.signature,
+                    },
+                    SignatureItem {
+                        public_key,
+                        message: value_data,
+                        signature: value.signature,
+                    },
+                ]
+            })
+            .collect::<Vec<_>>();
+        let valid = verify_signatures(&items);
+
+        for ((i, ..), valid) in signed.iter().zip(valid.chunks_exact(2)) {
+            results[*i] = if valid[0] && valid[1] {
+                self.insert_signed_value(values[*i])
+            } else {
+                Err(adnl::NodeIdFullError::InvalidSignature.into())
+            };
         }
+
+        results
     }
 
     /// Removes all outdated value
-    pub fn gc(&self) {
+    ///
+    /// Returns the number of removed values
+    pub fn gc(&self) -> usize {
         let now = now();
-        self.storage.retain(|_, value| value.ttl > now);
+
+        let mut index = self.index.lock();
+        let expired = index
+            .by_ttl
+            .range(..=(now, [u8::MAX; 32]))
+            .map(|(_, key)| *key)
+            .collect::<Vec<_>>();
+
+        for key in &expired {
+            if let Some((key, value)) = self.storage.remove(key) {
+                index.remove(&key, &value);
+            }
+        }
+
+        self.evicted
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        expired.len()
     }
 
-    /// Inserts signed value into the storage
-    fn insert_signed_value(&self, mut value: proto::dht::Value<'_>) -> Result<bool> {
-        use dashmap::mapref::entry::Entry;
+    /// Checks value lifetime and key description. Returns parsed key id
+    fn check_value(&self, value: &proto::dht::Value<'_>) -> Result<adnl::KeyIdFull> {
+        if value.ttl <= now() {
+            return Err(StorageError::ValueExpired.into());
+        }
+
+        if !(0..=self.options.max_key_name_len).contains(&value.key.key.name.len())
+            || value.key.key.idx > self.options.max_key_index
+        {
+            return Err(StorageError::InvalidKey.into());
+        }
 
-        let full_id = adnl::NodeIdFull::try_from(value.key.id)?;
+        if value.value.len() > self.options.max_value_size {
+            return Err(StorageError::ValueTooBig.into());
+        }
 
-        let key_signature = std::mem::take(&mut value.key.signature);
-        full_id.verify(value.key.as_boxed(), key_signature)?;
-        value.key.signature = key_signature;
+        let key_id = adnl::KeyIdFull::try_from(value.key.id)?;
+        if value.key.key.id != key_id.compute_short_id().as_slice() {
+            return Err(StorageError::InvalidKey.into());
+        }
 
-        let value_signature = std::mem::take(&mut value.signature);
-        full_id.verify(value.as_boxed(), value_signature)?;
-        value.signature = value_signature;
+        Ok(key_id)
+    }
 
+    /// Inserts signed value with already verified signatures into the storage
+    fn insert_signed_value(&self, value: proto::dht::Value<'_>) -> Result<bool> {
         let key = tl_proto::hash_as_boxed(value.key.key);
-        Ok(match self.storage.entry(key) {
-            Entry::Occupied(mut entry) if entry.get().ttl < value.ttl => {
-                entry.insert(value.as_equivalent_owned());
-                true
-            }
-            Entry::Occupied(_) => false,
-            Entry::Vacant(entry) => {
-                entry.insert(value.as_equivalent_owned());
-                true
-            }
-        })
+
+        let mut index = self.index.lock();
+        if matches!(self.storage.get(&key), Some(old) if old.value.ttl >= value.ttl) {
+            return Ok(false);
+        }
+        self.put(&mut index, key, value.as_equivalent_owned())?;
+
+        Ok(true)
     }
 
     /// Special case of inserting overlay nodes value.
     ///
     /// It requires empty signatures and special update rule
-    fn insert_overlay_nodes(&self, value: proto::dht::Value) -> Result<bool> {
-        use dashmap::mapref::entry::Entry;
-
+    fn insert_overlay_nodes(
+        &self,
+        value: proto::dht::Value,
+        key_id: &adnl::KeyIdFull,
+    ) -> Result<bool> {
         if !value.signature.is_empty() || !value.key.signature.is_empty() {
             return Err(StorageError::InvalidSignatureValue.into());
         }
 
-        let overlay_id = match value.key.id {
-            everscale_crypto::tl::PublicKey::Overlay { .. } => {
-                overlay::IdShort::from(tl_proto::hash(value.key.id))
+        let overlay_id = match key_id {
+            adnl::KeyIdFull::Overlay(_) => {
+                overlay::IdShort::from(<[u8; 32]>::from(key_id.compute_short_id()))
             }
             _ => return Err(StorageError::InvalidKeyDescription.into()),
         };
@@ -150,25 +280,192 @@ impl Storage {
         }
 
         let key = tl_proto::hash_as_boxed(value.key.key);
-        match self.storage.entry(key) {
-            Entry::Occupied(mut entry) => {
-                let value = {
-                    let old_nodes = match entry.get().ttl {
-                        old_ttl if old_ttl < now() => None,
-                        old_ttl if old_ttl > value.ttl => return Ok(false),
-                        _ => Some(deserialize_overlay_nodes(&entry.get().value)?),
-                    };
-                    make_overlay_nodes_value(value, new_nodes, old_nodes)
+
+        let mut index = self.index.lock();
+        let value = match self.storage.get(&key) {
+            Some(old) => {
+                let old_nodes = match old.value.ttl {
+                    _ if old.expires_at <= now() => None,
+                    old_ttl if old_ttl > value.ttl => return Ok(false),
+                    _ => Some(deserialize_overlay_nodes(&old.value.value)?),
                 };
-                entry.insert(value);
+                make_overlay_nodes_value(value, new_nodes, old_nodes, self.options.max_value_size)
             }
-            Entry::Vacant(entry) => {
-                entry.insert(make_overlay_nodes_value(value, new_nodes, None));
+            None => make_overlay_nodes_value(value, new_nodes, None, self.options.max_value_size),
+        };
+        self.put(&mut index, key, value)?;
+
+        Ok(true)
+    }
+
+    /// Replaces the value in the storage, enforcing quotas.
+    ///
+    /// Values with the shortest remaining TTL are evicted first. The new value
+    /// is rejected if it has the shortest TTL itself.
+    fn put(
+        &self,
+        index: &mut StorageIndex,
+        key: StorageKeyId,
+        value: proto::dht::ValueOwned,
+    ) -> Result<()> {
+        let expires_at = match self.options.max_ttl_sec {
+            Some(max_ttl) => std::cmp::min(value.ttl, now().saturating_add(max_ttl)),
+            None => value.ttl,
+        };
+        let value = StoredValue { value, expires_at };
+
+        // Remove the previous value
+        let old = self.storage.remove(&key);
+        if let Some((key, old)) = &old {
+            index.remove(key, old);
+        }
+
+        // NOTE: victims are selected first so that a rejected value
+        // doesn't affect the storage
+        let victims = match self.select_victims(index, &value) {
+            Ok(victims) => victims,
+            Err(e) => {
+                // Restore the previous value
+                if let Some((key, old)) = old {
+                    index.insert(&key, &old);
+                    self.storage.insert(key, old);
+                }
+                return Err(e);
             }
+        };
+
+        if matches!(&old, Some((_, old)) if old.expires_at <= now()) {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        for victim in victims {
+            self.evict(index, &victim);
         }
 
-        Ok(true)
+        index.insert(&key, &value);
+        self.storage.insert(key, value);
+        Ok(())
     }
+
+    /// Selects values which must be evicted to insert the new value
+    fn select_victims(
+        &self,
+        index: &StorageIndex,
+        value: &StoredValue,
+    ) -> Result<Vec<StorageKeyId>> {
+        let mut victims = Vec::new();
+
+        if let Some(max_values) = self.options.max_values_per_key_id {
+            let values = index.by_key_id.get(&value.value.key.key.id);
+            let excess = (values.map(|values| values.len()).unwrap_or_default() + 1)
+                .saturating_sub(max_values);
+            let mut values = values.into_iter().flatten();
+            for _ in 0..excess {
+                match values.next() {
+                    Some(&(ttl, victim)) if ttl < value.expires_at => victims.push(victim),
+                    _ => return Err(StorageError::QuotaExceeded.into()),
+                }
+            }
+        }
+
+        if let Some(max_total_size) = self.options.max_total_size {
+            let value_size = |key: &StorageKeyId| {
+                self.storage
+                    .get(key)
+                    .map(|value| value.value.value.len())
+                    .unwrap_or_default()
+            };
+
+            let mut total_size = index.total_size + value.value.value.len();
+            for victim in &victims {
+                total_size -= value_size(victim);
+            }
+
+            let mut values = index.by_ttl.iter();
+            while total_size > max_total_size {
+                match values.next() {
+                    Some((ttl, victim)) if *ttl < value.expires_at => {
+                        if !victims.contains(victim) {
+                            total_size -= value_size(victim);
+                            victims.push(*victim);
+                        }
+                    }
+                    _ => return Err(StorageError::QuotaExceeded.into()),
+                }
+            }
+        }
+
+        Ok(victims)
+    }
+
+    fn evict(&self, index: &mut StorageIndex, key: &StorageKeyId) {
+        if let Some((key, value)) = self.storage.remove(key) {
+            index.remove(&key, &value);
+            self.quota_evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Value with its local expiration time
+struct StoredValue {
+    value: proto::dht::ValueOwned,
+    /// Value TTL, limited by the max TTL option
+    expires_at: u32,
+}
+
+/// Stored values ordered by TTL
+#[derive(Default)]
+struct StorageIndex {
+    total_size: usize,
+    by_ttl: BTreeSet<(u32, StorageKeyId)>,
+    by_key_id: FastHashMap<[u8; 32], BTreeSet<(u32, StorageKeyId)>>,
+}
+
+impl StorageIndex {
+    fn insert(&mut self, key: &StorageKeyId, value: &StoredValue) {
+        self.total_size += value.value.value.len();
+        self.by_ttl.insert((value.expires_at, *key));
+        self.by_key_id
+            .entry(value.value.key.key.id)
+            .or_default()
+            .insert((value.expires_at, *key));
+    }
+
+    fn remove(&mut self, key: &StorageKeyId, value: &StoredValue) {
+        use std::collections::hash_map::Entry;
+
+        self.total_size -= value.value.value.len();
+        self.by_ttl.remove(&(value.expires_at, *key));
+        if let Entry::Occupied(mut entry) = self.by_key_id.entry(value.value.key.key.id) {
+            entry.get_mut().remove(&(value.expires_at, *key));
+            if entry.get().is_empty() {
+                entry.remove();
+            }
+        }
+    }
+}
+
+// Verifies key and value signatures of the signed value
+fn verify_signed_value(
+    mut value: proto::dht::Value<'_>,
+    full_id: &adnl::KeyIdFull,
+) -> Result<(), adnl::NodeIdFullError> {
+    let key_signature = std::mem::take(&mut value.key.signature);
+    full_id.verify(value.key.as_boxed(), key_signature)?;
+    value.key.signature = key_signature;
+
+    let value_signature = std::mem::take(&mut value.signature);
+    full_id.verify(value.as_boxed(), value_signature)
+}
+
+// Serializes data which is signed by the key signature and the value signature
+fn make_signed_data(mut value: proto::dht::Value<'_>) -> (Vec<u8>, Vec<u8>) {
+    let key_signature = std::mem::take(&mut value.key.signature);
+    let key_data = tl_proto::serialize(value.key.as_boxed());
+    value.key.signature = key_signature;
+
+    value.signature = Default::default();
+    let value_data = tl_proto::serialize(value.as_boxed());
+    (key_data, value_data)
 }
 
 // Merges old and new overlay nodes and returns updated value
@@ -176,6 +473,7 @@ fn make_overlay_nodes_value<const N: usize>(
     value: proto::dht::Value<'_>,
     new_nodes: SmallVec<[proto::overlay::Node<'_>; N]>,
     old_nodes: Option<SmallVec<[proto::overlay::Node<'_>; N]>>,
+    max_len: usize,
 ) -> proto::dht::ValueOwned {
     use std::collections::hash_map::Entry;
 
@@ -200,15 +498,24 @@ fn make_overlay_nodes_value<const N: usize>(
         }
     }
 
-    let capacity = result
-        .values()
-        .map(|item| item.max_size_hint())
-        .sum::<usize>();
+    // Keep the most recent nodes which fit into the max value size
+    let mut nodes = result.into_values().collect::<Vec<_>>();
+    nodes.sort_unstable_by_key(|item| std::cmp::Reverse(item.version));
 
-    let mut stored_value = Vec::with_capacity(4 + 4 + capacity);
+    let mut capacity = 4 + 4;
+    let len = nodes
+        .iter()
+        .take_while(|item| {
+            capacity += item.max_size_hint();
+            capacity <= max_len
+        })
+        .count();
+    nodes.truncate(len);
+
+    let mut stored_value = Vec::with_capacity(capacity);
     stored_value.extend_from_slice(&proto::overlay::Nodes::TL_ID.to_le_bytes());
-    stored_value.extend_from_slice(&(result.len() as u32).to_le_bytes());
-    for node in result.into_values() {
+    stored_value.extend_from_slice(&(nodes.len() as u32).to_le_bytes());
+    for node in nodes {
         node.write_to(&mut stored_value);
     }
 
@@ -247,4 +554,296 @@ enum StorageError {
     ValueExpired,
     #[error("Invalid key")]
     InvalidKey,
+    #[error("Storage quota exceeded")]
+    QuotaExceeded,
+    #[error("Value is too big")]
+    ValueTooBig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_signed_value(key: &adnl::Key, name: &[u8], data: &[u8]) -> proto::dht::ValueOwned {
+        make_signed_value_with_ttl(key, name, data, now() + 3600)
+    }
+
+    fn make_signed_value_with_ttl(
+        key: &adnl::Key,
+        name: &[u8],
+        data: &[u8],
+        ttl: u32,
+    ) -> proto::dht::ValueOwned {
+        let mut value = proto::dht::Value {
+            key: proto::dht::KeyDescription {
+                key: proto::dht::Key {
+                    id: key.id().as_slice(),
+                    name,
+                    idx: 0,
+                },
+                id: key.full_id().as_tl(),
+                update_rule: proto::dht::UpdateRule::Signature,
+                signature: Default::default(),
+            },
+            value: data,
+            ttl,
+            signature: Default::default(),
+        };
+
+        let key_signature = key.sign(value.key.as_boxed());
+        value.key.signature = &key_signature;
+        let value_signature = key.sign(value.as_boxed());
+        value.signature = &value_signature;
+        value.as_equivalent_owned()
+    }
+
+    #[test]
+    fn max_value_size_rejects_big_values() {
+        let storage = Storage::new(StorageOptions {
+            max_key_name_len: 127,
+            max_key_index: 15,
+            max_total_size: None,
+            max_values_per_key_id: None,
+            max_value_size: 64,
+            max_ttl_sec: None,
+        });
+
+        let key = adnl::Key::from_bytes([1; 32]);
+        let exact = make_signed_value(&key, b"exact", &[0; 64]);
+        assert!(storage.insert(exact.as_equivalent_ref()).unwrap());
+
+        let big = make_signed_value(&key, b"big", &[0; 65]);
+        assert!(matches!(
+            storage
+                .insert(big.as_equivalent_ref())
+                .unwrap_err()
+                .downcast_ref::<StorageError>(),
+            Some(StorageError::ValueTooBig)
+        ));
+        assert_eq!(storage.len(), 1);
+    }
+
+    #[test]
+    fn max_value_size_truncates_overlay_nodes() {
+        let overlay_id_full = overlay::IdFull::for_workchain_overlay(0, &[1; 32]);
+        let overlay_id = overlay_id_full.compute_short_id();
+
+        let make_node = |i: u8| {
+            let key = adnl::Key::from_bytes([i; 32]);
+            let version = i as u32;
+            let signature = key.sign(proto::overlay::NodeToSign {
+                id: key.id().as_slice(),
+                overlay: overlay_id.as_slice(),
+                version,
+            });
+            proto::overlay::NodeOwned {
+                id: key.full_id().as_tl().as_equivalent_owned(),
+                overlay: *overlay_id.as_slice(),
+                version,
+                signature: signature.to_vec().into(),
+            }
+        };
+        let nodes = (1..=3).map(make_node).collect::<Vec<_>>();
+        let node_size = nodes[0].as_equivalent_ref().max_size_hint();
+
+        // Only two nodes fit into the value
+        let storage = Storage::new(StorageOptions {
+            max_key_name_len: 127,
+            max_key_index: 15,
+            max_total_size: None,
+            max_values_per_key_id: None,
+            max_value_size: 4 + 4 + node_size * 2,
+            max_ttl_sec: None,
+        });
+
+        let ttl = now() + 3600;
+        for node in &nodes {
+            let data = tl_proto::serialize_as_boxed(proto::overlay::Nodes {
+                nodes: smallvec::smallvec![node.as_equivalent_ref()],
+            });
+            let value = proto::dht::Value {
+                key: proto::dht::KeyDescription {
+                    key: proto::dht::Key {
+                        id: overlay_id.as_slice(),
+                        name: KEY_NODES.as_bytes(),
+                        idx: 0,
+                    },
+                    id: everscale_crypto::tl::PublicKey::Overlay {
+                        name: overlay_id_full.as_slice(),
+                    },
+                    update_rule: proto::dht::UpdateRule::OverlayNodes,
+                    signature: Default::default(),
+                },
+                value: &data,
+                ttl,
+                signature: Default::default(),
+            };
+            assert!(storage.insert(value).unwrap());
+        }
+
+        // The most recent nodes are kept
+        let key = tl_proto::hash_as_boxed(proto::dht::Key {
+            id: overlay_id.as_slice(),
+            name: KEY_NODES.as_bytes(),
+            idx: 0,
+        });
+        let stored = storage.get_ref(&key).unwrap();
+        let mut versions = deserialize_overlay_nodes(&stored.value)
+            .unwrap()
+            .iter()
+            .map(|node| node.version)
+            .collect::<Vec<_>>();
+        versions.sort_unstable();
+        assert_eq!(versions, [2, 3]);
+    }
+
+    #[test]
+    fn insert_many_verifies_signatures() {
+        let storage = Storage::new(StorageOptions {
+            max_key_name_len: 127,
+            max_key_index: 15,
+            max_total_size: None,
+            max_values_per_key_id: None,
+            max_value_size: 4096,
+            max_ttl_sec: None,
+        });
+
+        let mut values = (0..10u8)
+            .map(|i| {
+                let key = adnl::Key::from_bytes([i + 1; 32]);
+                make_signed_value(&key, b"address", &[i; 16])
+            })
+            .collect::<Vec<_>>();
+
+        let mut signature = values[7].signature.to_vec();
+        signature[0] ^= 1;
+        values[7].signature = signature.into();
+
+        let values = values
+            .iter()
+            .map(|value| value.as_equivalent_ref())
+            .collect::<Vec<_>>();
+        let results = storage.insert_many(&values);
+
+        assert_eq!(results.len(), values.len());
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.is_ok(), i != 7);
+        }
+        assert_eq!(storage.len(), 9);
+
+        // Values are already stored
+        assert!(storage
+            .insert_many(&values[..3])
+            .iter()
+            .all(|result| matches!(result, Ok(false))));
+    }
+
+    #[cfg(feature = "test-utils")]
+    #[test]
+    fn expired_values_eviction() {
+        use crate::test_utils::MockClock;
+
+        MockClock::set(now());
+
+        let storage = Storage::new(StorageOptions {
+            max_key_name_len: 127,
+            max_key_index: 15,
+            max_total_size: None,
+            max_values_per_key_id: None,
+            max_value_size: 4096,
+            max_ttl_sec: None,
+        });
+
+        let key = adnl::Key::from_bytes([1; 32]);
+        let short = make_signed_value_with_ttl(&key, b"short", &[0; 16], now() + 1);
+        let long = make_signed_value(&key, b"long", &[0; 16]);
+        assert!(storage.insert(short.as_equivalent_ref()).unwrap());
+        assert!(storage.insert(long.as_equivalent_ref()).unwrap());
+
+        MockClock::advance(1);
+
+        // Expired value is removed lazily
+        let short_key = tl_proto::hash_as_boxed(short.key.key.as_equivalent_ref());
+        assert!(storage.get_ref(&short_key).is_none());
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.evicted_count(), 1);
+
+        // Nothing else to remove
+        assert_eq!(storage.gc(), 0);
+        assert_eq!(storage.len(), 1);
+
+        MockClock::reset();
+    }
+
+    #[test]
+    fn max_ttl_limits_lifetime() {
+        let make_storage = |max_ttl_sec| {
+            Storage::new(StorageOptions {
+                max_key_name_len: 127,
+                max_key_index: 15,
+                max_total_size: None,
+                max_values_per_key_id: None,
+                max_value_size: 4096,
+                max_ttl_sec,
+            })
+        };
+
+        let key = adnl::Key::from_bytes([1; 32]);
+        let value = make_signed_value_with_ttl(&key, b"long", &[0; 16], now() + 100000);
+        let value_key = tl_proto::hash_as_boxed(value.key.key.as_equivalent_ref());
+
+        // Value is kept while its lifetime is less than the max TTL
+        let storage = make_storage(Some(3600));
+        assert!(storage.insert(value.as_equivalent_ref()).unwrap());
+        let stored = storage.get_ref(&value_key).unwrap();
+        assert_eq!(stored.ttl, value.ttl);
+        drop(stored);
+
+        // Value is removed after the max TTL
+        let storage = make_storage(Some(0));
+        assert!(storage.insert(value.as_equivalent_ref()).unwrap());
+        assert!(storage.get_ref(&value_key).is_none());
+        assert_eq!(storage.evicted_count(), 1);
+    }
+
+    #[test]
+    fn quotas_evict_shortest_ttl_first() {
+        let storage = Storage::new(StorageOptions {
+            max_key_name_len: 127,
+            max_key_index: 15,
+            max_total_size: Some(64),
+            max_values_per_key_id: Some(2),
+            max_value_size: 4096,
+            max_ttl_sec: None,
+        });
+        let now = now();
+
+        // Values per key id
+        let key = adnl::Key::from_bytes([1; 32]);
+        let a = make_signed_value_with_ttl(&key, b"a", &[0; 8], now + 100);
+        let b = make_signed_value_with_ttl(&key, b"b", &[0; 8], now + 200);
+        let c = make_signed_value_with_ttl(&key, b"c", &[0; 8], now + 300);
+        let d = make_signed_value_with_ttl(&key, b"d", &[0; 8], now + 50);
+        assert!(storage.insert(a.as_equivalent_ref()).unwrap());
+        assert!(storage.insert(b.as_equivalent_ref()).unwrap());
+        assert!(storage.insert(c.as_equivalent_ref()).unwrap());
+        assert!(storage.insert(d.as_equivalent_ref()).is_err());
+
+        let a_key = tl_proto::hash_as_boxed(a.key.key.as_equivalent_ref());
+        assert!(storage.get_ref(&a_key).is_none());
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage.quota_evicted_count(), 1);
+
+        // Total size
+        let other = adnl::Key::from_bytes([2; 32]);
+        let e = make_signed_value_with_ttl(&other, b"e", &[0; 49], now + 250);
+        assert!(storage.insert(e.as_equivalent_ref()).unwrap());
+        assert_eq!(storage.len(), 2);
+        assert_eq!(storage.total_size(), 57);
+        assert_eq!(storage.quota_evicted_count(), 2);
+
+        let f = make_signed_value_with_ttl(&other, b"f", &[0; 65], now + 1000);
+        assert!(storage.insert(f.as_equivalent_ref()).is_err());
+        assert_eq!(storage.total_size(), 57);
+    }
 }
@@ -79,6 +79,7 @@ where
                         Ok(None) => None,
                         Err(e) => {
                             tracing::warn!("failed to parse queried value: {e}");
+                            dht.report_invalid_answer(&peer_id);
                             None
                         }
                     },
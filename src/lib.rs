@@ -15,7 +15,11 @@ macro_rules! ok {
 pub use everscale_crypto as crypto;
 pub use tl_proto as tl;
 
-pub use subscriber::{MessageSubscriber, QueryConsumingResult, QuerySubscriber, SubscriberContext};
+pub use subscriber::{
+    AnswerStream, BackgroundTask, MessageSubscriber, PrefixQuerySubscriber, QueryConsumingResult,
+    QueryHandler, QueryStats, QuerySubscriber, SubscriberContext, SubscriberMetrics, TypedQuery,
+    TypedQuerySubscriber,
+};
 pub use util::NetworkBuilder;
 
 pub mod adnl;
@@ -26,4 +30,6 @@ pub mod proto;
 #[cfg(feature = "rldp")]
 pub mod rldp;
 mod subscriber;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 pub mod util;
@@ -14,6 +14,8 @@ mod overlay_id;
 #[cfg(feature = "overlay")]
 mod broadcast_receiver;
 #[cfg(feature = "overlay")]
+mod neighbours;
+#[cfg(feature = "overlay")]
 mod node;
 #[cfg(feature = "overlay")]
 #[allow(clippy::module_inception)]
@@ -27,6 +29,7 @@ mod node_impl {
     use frunk_core::hlist::{HCons, HList, IntoTuple2, Selector};
     use frunk_core::indices::There;
 
+    pub use super::neighbours::NeighbourStats;
     pub use super::node::Node;
     pub use super::overlay::{
         BroadcastTarget, ExistingPeersFilter, IncomingBroadcastInfo, OutgoingBroadcastInfo,
@@ -0,0 +1,255 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use super::overlay::{Overlay, OverlayStats};
+use crate::adnl;
+use crate::util::*;
+
+impl Overlay {
+    /// Returns current neighbours (including the pinned ones) with their health stats
+    pub fn neighbours(&self) -> Vec<(adnl::NodeIdShort, NeighbourStats)> {
+        let mut neighbours = self.pinned_neighbours();
+        neighbours.extend(
+            self.neighbours_set()
+                .clone_inner()
+                .into_iter()
+                .filter(|peer_id| !self.is_pinned_neighbour(peer_id)),
+        );
+        neighbours
+            .into_iter()
+            .map(|peer_id| {
+                let stats = self.neighbour_stats(&peer_id);
+                (peer_id, stats)
+            })
+            .collect()
+    }
+
+    /// Pins the peer as a permanent neighbour. Pinned neighbours are never rotated out,
+    /// always receive broadcasts and are the first ones in [`Overlay::select_query_peers`].
+    ///
+    /// Returns `false` if the peer is not a member of the private overlay or is already pinned
+    pub fn pin_neighbour(&self, peer_id: adnl::NodeIdShort) -> bool {
+        if !self.is_member(&peer_id) {
+            return false;
+        }
+        self.known_peers().insert(peer_id);
+        self.pinned_neighbours_set().insert(peer_id)
+    }
+
+    /// Returns the peer to the ordinary neighbours rotation
+    pub fn unpin_neighbour(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        self.pinned_neighbours_set().remove(peer_id).is_some()
+    }
+
+    /// Whether the peer is pinned as a permanent neighbour
+    pub fn is_pinned_neighbour(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        self.pinned_neighbours_set().contains(peer_id)
+    }
+
+    /// Returns all pinned neighbours
+    pub fn pinned_neighbours(&self) -> Vec<adnl::NodeIdShort> {
+        self.pinned_neighbours_set()
+            .iter()
+            .map(|item| *item.key())
+            .collect()
+    }
+
+    /// Returns at most `amount` neighbours to send queries to, in the order they
+    /// should be tried: pinned neighbours first, then responsive neighbours,
+    /// both ordered by roundtrip
+    pub fn select_query_peers(&self, amount: usize) -> Vec<adnl::NodeIdShort> {
+        let by_roundtrip = |peers: &mut Vec<adnl::NodeIdShort>| {
+            peers.sort_by_cached_key(|peer_id| {
+                self.neighbour_stats(peer_id)
+                    .roundtrip_ms
+                    .unwrap_or(u64::MAX)
+            })
+        };
+
+        let mut result = self.pinned_neighbours();
+        by_roundtrip(&mut result);
+
+        if result.len() < amount {
+            let mut neighbours = self
+                .neighbours_set()
+                .clone_inner()
+                .into_iter()
+                .filter(|peer_id| {
+                    !self.is_pinned_neighbour(peer_id) && self.is_responsive_neighbour(peer_id)
+                })
+                .collect::<Vec<_>>();
+            by_roundtrip(&mut neighbours);
+            result.extend(neighbours);
+        }
+
+        result.truncate(amount);
+        result
+    }
+
+    /// Returns health stats of the overlay peer
+    pub fn neighbour_stats(&self, peer_id: &adnl::NodeIdShort) -> NeighbourStats {
+        self.peer_stats()
+            .get(peer_id)
+            .map(|stats| *stats)
+            .unwrap_or_default()
+    }
+
+    /// Whether the peer has not failed too many queries in a row. Failed peers
+    /// become responsive again after a cooldown to get a probe query, and are
+    /// delayed for another cooldown if it fails too.
+    ///
+    /// See [`OverlayOptions::max_neighbour_failures`] and [`OverlayOptions::neighbour_retry_interval_sec`]
+    ///
+    /// [`OverlayOptions::max_neighbour_failures`]: super::OverlayOptions::max_neighbour_failures
+    /// [`OverlayOptions::neighbour_retry_interval_sec`]: super::OverlayOptions::neighbour_retry_interval_sec
+    pub fn is_responsive_neighbour(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        let stats = self.neighbour_stats(peer_id);
+        if stats.consecutive_failures < self.options().max_neighbour_failures {
+            return true;
+        }
+
+        let retry_interval = self.options().neighbour_retry_interval_sec;
+        match stats.last_failure_at {
+            Some(last_failure_at) => last_failure_at as u64 + retry_interval <= now() as u64,
+            None => true,
+        }
+    }
+
+    /// Updates peer health stats with the query result
+    pub(super) fn record_query(&self, peer_id: &adnl::NodeIdShort, roundtrip: Option<Duration>) {
+        OverlayStats::inc(&self.stats().sent_queries);
+        if roundtrip.is_none() {
+            OverlayStats::inc(&self.stats().failed_queries);
+        }
+
+        let mut stats = self.peer_stats().entry(*peer_id).or_default();
+        stats.queries += 1;
+        match roundtrip {
+            Some(roundtrip) => {
+                let roundtrip_ms = roundtrip.as_millis() as u64;
+                stats.consecutive_failures = 0;
+                stats.last_success_at = Some(now());
+                stats.roundtrip_ms = Some(match stats.roundtrip_ms {
+                    Some(prev) => (prev * 3 + roundtrip_ms) / 4,
+                    None => roundtrip_ms,
+                });
+            }
+            None => {
+                stats.failed_queries += 1;
+                stats.consecutive_failures += 1;
+                stats.last_failure_at = Some(now());
+            }
+        }
+    }
+
+    /// Replaces unresponsive neighbours with the most reliable known peers
+    /// and asks a random neighbour for new peers. Pinned neighbours are never replaced.
+    ///
+    /// See [`Overlay::is_responsive_neighbour`]
+    pub async fn refresh_neighbours(&self, adnl: &adnl::Node) {
+        let is_alive = |peer_id: &adnl::NodeIdShort| self.is_responsive_neighbour(peer_id);
+
+        // Drop unresponsive neighbours
+        let neighbours = self.neighbours_set();
+        for peer_id in neighbours.clone_inner() {
+            if !is_alive(&peer_id) && neighbours.remove(&peer_id) {
+                tracing::debug!(overlay_id = %self.id(), %peer_id, "removed unresponsive neighbour");
+            }
+        }
+
+        // Fill free slots with the fastest responsive peers
+        let free_slots = (self.options().max_neighbours as usize).saturating_sub(neighbours.len());
+        if free_slots > 0 {
+            let mut candidates = self
+                .known_peers()
+                .clone_inner()
+                .into_iter()
+                .filter(|peer_id| {
+                    !neighbours.contains(peer_id)
+                        && !self.is_pinned_neighbour(peer_id)
+                        && !self.is_ignored_peer(peer_id)
+                        && is_alive(peer_id)
+                })
+                .map(|peer_id| {
+                    let roundtrip = self.neighbour_stats(&peer_id).roundtrip_ms;
+                    (roundtrip.unwrap_or(u64::MAX), peer_id)
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_unstable_by_key(|(roundtrip, _)| *roundtrip);
+
+            neighbours.extend(
+                candidates
+                    .into_iter()
+                    .take(free_slots)
+                    .map(|(_, peer_id)| peer_id),
+            );
+        }
+
+        // Private overlays have a fixed set of members
+        if self.is_private() {
+            return;
+        }
+
+        let peer_id = match neighbours.get_random_peers(1, None).into_iter().next() {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        match self.exchange_random_peers(adnl, &peer_id, None).await {
+            Ok(Some(new_peers)) => {
+                tracing::trace!(
+                    overlay_id = %self.id(),
+                    %peer_id,
+                    new_peers = new_peers.len(),
+                    "exchanged random peers"
+                );
+            }
+            Ok(None) => {}
+            Err(e) => {
+                tracing::debug!(overlay_id = %self.id(), %peer_id, "failed to exchange random peers: {e:?}");
+            }
+        }
+    }
+
+    /// Starts a process that periodically refreshes neighbours
+    pub(super) fn start_neighbours_refresh_loop(self: &Arc<Self>, adnl: &Arc<adnl::Node>) {
+        let interval_ms = self.options().neighbours_refresh_interval_ms;
+        if interval_ms == 0 {
+            return;
+        }
+
+        let interval = Duration::from_millis(interval_ms);
+        let overlay = Arc::downgrade(self);
+        let adnl = Arc::downgrade(adnl);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let (overlay, adnl) = match (Weak::upgrade(&overlay), Weak::upgrade(&adnl)) {
+                    (Some(overlay), Some(adnl)) => (overlay, adnl),
+                    _ => break,
+                };
+                overlay.refresh_neighbours(&adnl).await;
+            }
+
+            tracing::debug!("overlay neighbours refresh loop finished");
+        });
+    }
+}
+
+/// Overlay peer health stats
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NeighbourStats {
+    /// Total number of queries sent to the peer
+    pub queries: u64,
+    /// Number of failed or timed out queries
+    pub failed_queries: u64,
+    /// Number of failed queries in a row
+    pub consecutive_failures: u32,
+    /// Smoothed roundtrip of the successful queries
+    pub roundtrip_ms: Option<u64>,
+    /// Unix timestamp of the last successful query
+    pub last_success_at: Option<u32>,
+    /// Unix timestamp of the last failed query
+    pub last_failure_at: Option<u32>,
+}
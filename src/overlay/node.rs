@@ -4,7 +4,7 @@ use std::sync::Arc;
 use anyhow::Result;
 use tl_proto::{BoxedConstructor, TlRead};
 
-use super::overlay::{Overlay, OverlayMetrics, OverlayOptions};
+use super::overlay::{Overlay, OverlayMetrics, OverlayOptions, OverlayStats};
 use super::overlay_id::IdShort;
 use crate::adnl;
 use crate::proto;
@@ -23,7 +23,7 @@ pub struct Node {
 
 impl Node {
     pub fn new(adnl: Arc<adnl::Node>, key_tag: usize) -> Result<Arc<Self>> {
-        let node_key = adnl.key_by_tag(key_tag)?.clone();
+        let node_key = adnl.key_by_tag(key_tag)?;
         let state = Arc::new(NodeState::default());
 
         adnl.add_query_subscriber(state.clone())?;
@@ -81,15 +81,19 @@ impl Node {
 
         match self.state.overlays.entry(*overlay_id) {
             Entry::Vacant(entry) => {
-                let overlay = Overlay::new(self.node_key.clone(), *overlay_id, &[], options);
+                let overlay = Overlay::new(self.node_key.clone(), *overlay_id, &[], false, options);
                 entry.insert(overlay.clone());
+                overlay.start_neighbours_refresh_loop(&self.adnl);
                 (overlay, true)
             }
             Entry::Occupied(entry) => (entry.get().clone(), false),
         }
     }
 
-    /// Creates new private overlay
+    /// Creates new private overlay.
+    ///
+    /// Only the specified peers are allowed to send queries and broadcasts
+    /// (see [`Overlay::add_member`], [`Overlay::add_certificate_issuer`])
     pub fn add_private_overlay(
         &self,
         overlay_id: &IdShort,
@@ -101,14 +105,37 @@ impl Node {
 
         match self.state.overlays.entry(*overlay_id) {
             Entry::Vacant(entry) => {
-                let overlay = Overlay::new(overlay_key, *overlay_id, peers, options);
+                let overlay = Overlay::new(overlay_key, *overlay_id, peers, true, options);
                 entry.insert(overlay.clone());
+                overlay.start_neighbours_refresh_loop(&self.adnl);
                 (overlay, true)
             }
             Entry::Occupied(entry) => (entry.get().clone(), false),
         }
     }
 
+    /// Removes overlay and its queries subscriber. Incoming messages and queries
+    /// for this overlay will be rejected.
+    ///
+    /// NOTE: Background tasks of the overlay stop as soon as all other references are dropped
+    pub fn remove_overlay(&self, overlay_id: &IdShort) -> Option<Arc<Overlay>> {
+        self.state.subscribers.remove(overlay_id);
+        self.state
+            .overlays
+            .remove(overlay_id)
+            .map(|(_, overlay)| overlay)
+    }
+
+    /// Removes overlay queries subscriber
+    pub fn remove_overlay_subscriber(&self, overlay_id: &IdShort) -> bool {
+        self.state.subscribers.remove(overlay_id).is_some()
+    }
+
+    /// Returns ids of all overlays
+    pub fn overlay_ids(&self) -> Vec<IdShort> {
+        self.state.overlays.iter().map(|item| *item.key()).collect()
+    }
+
     /// Returns overlay by specified id
     #[inline(always)]
     pub fn get_overlay(&self, overlay_id: &IdShort) -> Result<Arc<Overlay>> {
@@ -152,6 +179,11 @@ impl MessageSubscriber for NodeState {
         // TODO: check that offset == data.len()
 
         let overlay = self.get_overlay(&overlay_id)?;
+        if !overlay.is_member(ctx.peer_id) {
+            OverlayStats::inc(&overlay.stats().unauthorized_broadcasts);
+            return Err(NodeError::NotAMember.into());
+        }
+
         match broadcast {
             proto::overlay::Broadcast::Broadcast(broadcast) => {
                 overlay
@@ -184,13 +216,19 @@ impl QuerySubscriber for NodeState {
 
         let mut offset = 4; // skip `rpc::OverlayQuery` constructor
         let overlay_id = IdShort::from(<[u8; 32]>::read_from(&query, &mut offset)?);
+        if let Some(overlay) = self.overlays.get(&overlay_id) {
+            if !overlay.is_member(ctx.peer_id) {
+                return Err(NodeError::NotAMember.into());
+            }
+            OverlayStats::inc(&overlay.stats().received_queries);
+        }
 
         let constructor = u32::read_from(&query, &mut std::convert::identity(offset))?;
         if constructor == proto::rpc::OverlayGetRandomPeers::TL_ID {
             let query = proto::rpc::OverlayGetRandomPeers::read_from(&query, &mut offset)?;
             let overlay = self.get_overlay(&overlay_id)?;
             return QueryConsumingResult::consume(
-                overlay.process_get_random_peers(query).into_boxed(),
+                overlay.process_get_random_peers(query).await.into_boxed(),
             );
         }
 
@@ -204,6 +242,9 @@ impl QuerySubscriber for NodeState {
             .await?
         {
             QueryConsumingResult::Consumed(result) => Ok(QueryConsumingResult::Consumed(result)),
+            QueryConsumingResult::ConsumedStream(answer) => {
+                Ok(QueryConsumingResult::ConsumedStream(answer))
+            }
             QueryConsumingResult::Rejected(_) => Err(NodeError::UnsupportedQuery.into()),
         }
     }
@@ -219,4 +260,6 @@ enum NodeError {
     NoConsumerFound,
     #[error("Unsupported query")]
     UnsupportedQuery,
+    #[error("Peer is not a member of the private overlay")]
+    NotAMember,
 }
@@ -1,22 +1,25 @@
 use std::convert::TryFrom;
 use std::net::SocketAddrV4;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use crossbeam_queue::SegQueue;
 use parking_lot::Mutex;
 use sha2::Digest;
 use smallvec::SmallVec;
-use tl_proto::{HashWrapper, TlWrite};
+use tl_proto::{HashWrapper, TlRead, TlWrite};
 use tokio::sync::mpsc;
 
+use super::neighbours::NeighbourStats;
 use super::overlay_id::IdShort;
 use super::{broadcast_receiver::*, MAX_OVERLAY_PEERS};
 use crate::adnl;
 use crate::proto;
 use crate::rldp::{self, compression, RaptorQDecoder, RaptorQEncoder};
+use crate::subscriber::limits::QueryLimitsError;
+use crate::subscriber::QueryLimiter;
 use crate::util::*;
 
 /// Overlay configuration
@@ -43,6 +46,28 @@ pub struct OverlayOptions {
     /// Default: `60000` ms
     pub overlay_peers_timeout_ms: u64,
 
+    /// Interval of replacing unresponsive neighbours and requesting new
+    /// random peers (see [`Overlay::refresh_neighbours`]). Zero disables the refresh.
+    ///
+    /// Default: `10000` ms
+    pub neighbours_refresh_interval_ms: u64,
+
+    /// Neighbours which failed this number of queries in a row are replaced.
+    ///
+    /// Default: `3`
+    pub max_neighbour_failures: u32,
+
+    /// Interval after the last failed query when an unresponsive peer
+    /// can be selected as a neighbour again to probe it.
+    ///
+    /// Default: `60` sec
+    pub neighbour_retry_interval_sec: u64,
+
+    /// Max number of peers to try in [`Overlay::query`].
+    ///
+    /// Default: `3`
+    pub query_attempts: u32,
+
     /// Packets with length bigger than this will be sent using FEC broadcast.
     /// See [`Overlay::broadcast`]
     ///
@@ -79,6 +104,36 @@ pub struct OverlayOptions {
     /// Default: `60` sec
     pub broadcast_timeout_sec: u64,
 
+    /// Broadcasts dated further in the future are ignored. Received broadcast ids
+    /// are kept for `broadcast_timeout_sec + max_broadcast_date_skew_sec`, so that
+    /// a broadcast can't be accepted twice while its date is still valid.
+    ///
+    /// Default: `10` sec
+    pub max_broadcast_date_skew_sec: u64,
+
+    /// Broadcasts with bigger data are dropped. For FEC broadcasts the declared
+    /// size of the whole data is checked on the first received part.
+    /// The size is not limited if not specified, but compressed broadcasts
+    /// are never decompressed beyond 10 MiB.
+    ///
+    /// Default: None
+    pub max_broadcast_size: Option<usize>,
+
+    /// Max number of new broadcasts per second from each source
+    /// (bursts up to this amount are allowed). Excess broadcasts are neither
+    /// delivered nor redistributed. The rate is not limited if not specified.
+    ///
+    /// Default: None
+    pub broadcast_rate_limit: Option<u32>,
+
+    /// Broadcasts older than this are still delivered, but are not redistributed
+    /// to the neighbours anymore. Broadcasts don't have a hop counter, so their age
+    /// is used to limit how far they spread. Broadcasts are redistributed
+    /// while they are not outdated if not specified.
+    ///
+    /// Default: None
+    pub max_broadcast_relay_age_sec: Option<u64>,
+
     /// Whether requests will be compressed.
     ///
     /// Default: `false`
@@ -92,6 +147,10 @@ impl Default for OverlayOptions {
             max_broadcast_log: 1000,
             broadcast_gc_interval_ms: 1000,
             overlay_peers_timeout_ms: 60000,
+            neighbours_refresh_interval_ms: 10000,
+            max_neighbour_failures: 3,
+            neighbour_retry_interval_sec: 60,
+            query_attempts: 3,
             max_ordinary_broadcast_len: 768,
             broadcast_target_count: 5,
             secondary_broadcast_target_count: 3,
@@ -99,6 +158,10 @@ impl Default for OverlayOptions {
             fec_broadcast_wave_len: 20,
             fec_broadcast_wave_interval_ms: 10,
             broadcast_timeout_sec: 60,
+            max_broadcast_date_skew_sec: 10,
+            max_broadcast_size: None,
+            broadcast_rate_limit: None,
+            max_broadcast_relay_age_sec: None,
             force_compression: false,
         }
     }
@@ -119,12 +182,23 @@ pub struct Overlay {
     finished_broadcasts: SegQueue<BroadcastId>,
     /// Broadcasts removal queue len
     finished_broadcast_count: AtomicU32,
+    /// Incoming broadcasts limits by the source
+    broadcast_limiter: QueryLimiter,
+    /// Broadcasts and queries counters
+    stats: OverlayStats,
 
     /// New peers to add
     received_peers: Arc<Mutex<ReceivedPeersMap>>,
     /// Complete incoming broadcasts queue
     received_broadcasts: Arc<BroadcastReceiver<IncomingBroadcastInfo>>,
 
+    /// Private overlay members. `None` for public overlays
+    members: Option<FastDashSet<adnl::NodeIdShort>>,
+    /// Keys which are allowed to issue broadcast certificates in the private overlay
+    certificate_issuers: FastDashSet<adnl::NodeIdShort>,
+    /// Certificates attached to the outgoing broadcasts, by the source key
+    certificates: FastDashMap<adnl::NodeIdShort, proto::overlay::CertificateOwned>,
+
     /// Raw overlay nodes
     nodes: FastDashMap<adnl::NodeIdShort, proto::overlay::NodeOwned>,
     /// Peers to exclude from random selection
@@ -133,6 +207,10 @@ pub struct Overlay {
     known_peers: adnl::PeersSet,
     /// Random peers subset
     neighbours: adnl::PeersSet,
+    /// Neighbours which are never rotated out
+    pinned_neighbours: FastDashSet<adnl::NodeIdShort>,
+    /// Health stats of the queried peers
+    peer_stats: FastDashMap<adnl::NodeIdShort, NeighbourStats>,
 
     /// Serialized [`proto::rpc::OverlayQuery`] with own overlay id
     query_prefix: Vec<u8>,
@@ -146,6 +224,7 @@ impl Overlay {
         node_key: Arc<adnl::Key>,
         id: IdShort,
         peers: &[adnl::NodeIdShort],
+        is_private: bool,
         options: OverlayOptions,
     ) -> Arc<Self> {
         let query_prefix = tl_proto::serialize(proto::rpc::OverlayQuery {
@@ -164,12 +243,22 @@ impl Overlay {
             owned_broadcasts: FastDashMap::default(),
             finished_broadcasts: SegQueue::new(),
             finished_broadcast_count: AtomicU32::new(0),
+            broadcast_limiter: QueryLimiter::new(
+                options.max_broadcast_size,
+                options.broadcast_rate_limit,
+            ),
+            stats: OverlayStats::default(),
             received_peers: Arc::new(Default::default()),
             received_broadcasts: Arc::new(BroadcastReceiver::default()),
+            members: is_private.then(|| peers.iter().copied().collect()),
+            certificate_issuers: FastDashSet::default(),
+            certificates: FastDashMap::default(),
             nodes: FastDashMap::default(),
             ignored_peers: FastDashSet::default(),
             known_peers,
             neighbours: adnl::PeersSet::with_capacity(options.max_neighbours),
+            pinned_neighbours: FastDashSet::default(),
+            peer_stats: FastDashMap::default(),
             query_prefix,
             message_prefix,
         });
@@ -197,6 +286,7 @@ impl Overlay {
                 peers_timeout += options.broadcast_gc_interval_ms;
                 if peers_timeout > options.overlay_peers_timeout_ms {
                     overlay.update_neighbours(1);
+                    overlay.broadcast_limiter.remove_idle();
                     peers_timeout = 0;
                 }
 
@@ -215,12 +305,38 @@ impl Overlay {
 
     /// Instant metrics
     pub fn metrics(&self) -> OverlayMetrics {
+        let incomplete_fec_broadcasts = self
+            .owned_broadcasts
+            .iter()
+            .filter(|item| match item.value().as_ref() {
+                OwnedBroadcast::Incoming(transfer) => !transfer.completed.load(Ordering::Acquire),
+                OwnedBroadcast::Other => false,
+            })
+            .count();
+
+        let stats = &self.stats;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
         OverlayMetrics {
             owned_broadcasts_len: self.owned_broadcasts.len(),
             finished_broadcasts_len: self.finished_broadcast_count.load(Ordering::Acquire),
+            incomplete_fec_broadcasts,
+            received_broadcasts: load(&stats.received_broadcasts),
+            completed_fec_broadcasts: load(&stats.completed_fec_broadcasts),
+            failed_fec_broadcasts: load(&stats.failed_fec_broadcasts),
+            relayed_broadcast_messages: load(&stats.relayed_broadcast_messages),
+            outdated_broadcasts: load(&stats.outdated_broadcasts),
+            unauthorized_broadcasts: load(&stats.unauthorized_broadcasts),
+            invalid_broadcasts: load(&stats.invalid_broadcasts),
+            oversized_broadcasts: load(&stats.oversized_broadcasts),
+            throttled_broadcasts: load(&stats.throttled_broadcasts),
+            sent_queries: load(&stats.sent_queries),
+            failed_queries: load(&stats.failed_queries),
+            received_queries: load(&stats.received_queries),
             node_count: self.nodes.len(),
             known_peers: self.known_peers.len(),
             neighbours: self.neighbours.len(),
+            pinned_neighbours: self.pinned_neighbours.len(),
             received_broadcasts_data_len: self.received_broadcasts.data_len(),
             received_broadcasts_barrier_count: self.received_broadcasts.barriers_len(),
         }
@@ -315,6 +431,7 @@ impl Overlay {
             return false;
         }
         tracing::warn!(overlay_id = %self.id, %peer_id, "removing public overlay peer");
+        self.pinned_neighbours.remove(peer_id);
         if self.neighbours.contains(peer_id) {
             self.update_neighbours(self.options.max_neighbours);
         }
@@ -334,9 +451,94 @@ impl Overlay {
         self.known_peers.contains(peer_id) && !self.ignored_peers.contains(peer_id)
     }
 
-    /// Fill `dst` with `amount` peers from known peers
+    /// Whether the overlay is private
+    pub fn is_private(&self) -> bool {
+        self.members.is_some()
+    }
+
+    /// Checks whether the peer is allowed to participate in the overlay.
+    /// Always `true` for public overlays
+    pub fn is_member(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        match &self.members {
+            Some(members) => members.contains(peer_id),
+            None => true,
+        }
+    }
+
+    /// Adds new member to the private overlay. Returns `false` if the
+    /// overlay is public or the peer is already a member
+    pub fn add_member(&self, peer_id: adnl::NodeIdShort) -> bool {
+        match &self.members {
+            Some(members) => {
+                self.ignored_peers.remove(&peer_id);
+                self.known_peers.insert(peer_id);
+                members.insert(peer_id)
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the member from the private overlay
+    pub fn remove_member(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        match &self.members {
+            Some(members) if members.remove(peer_id).is_some() => {
+                self.ignored_peers.insert(*peer_id);
+                self.pinned_neighbours.remove(peer_id);
+                if self.neighbours.contains(peer_id) {
+                    self.update_neighbours(self.options.max_neighbours);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Allows the key to issue broadcast certificates for the private overlay.
+    ///
+    /// Once at least one issuer is specified, members (except issuers) must
+    /// present a valid certificate to broadcast.
+    pub fn add_certificate_issuer(&self, issuer_id: adnl::NodeIdShort) -> bool {
+        self.certificate_issuers.insert(issuer_id)
+    }
+
+    /// Signs a certificate which allows the node to broadcast messages
+    /// up to `max_size` bytes until `expire_at`
+    pub fn issue_certificate(
+        &self,
+        issuer: &adnl::Key,
+        node_id: &adnl::NodeIdShort,
+        expire_at: u32,
+        max_size: u32,
+    ) -> Result<proto::overlay::CertificateOwned, adnl::KeystoreError> {
+        let signature = issuer.try_sign(proto::overlay::CertificateId {
+            overlay_id: self.id.as_slice(),
+            node: node_id.as_slice(),
+            expire_at,
+            max_size,
+        })?;
+
+        Ok(proto::overlay::CertificateOwned::Certificate {
+            issued_by: issuer.full_id().as_tl().as_equivalent_owned(),
+            expire_at,
+            max_size,
+            signature: signature.to_vec().into(),
+        })
+    }
+
+    /// Sets the certificate which will be attached to the broadcasts from the specified key
+    pub fn set_certificate(
+        &self,
+        source_id: adnl::NodeIdShort,
+        certificate: proto::overlay::CertificateOwned,
+    ) {
+        self.certificates.insert(source_id, certificate);
+    }
+
+    /// Fill `dst` with `amount` peers from known peers and all pinned neighbours
     pub fn write_cached_peers(&self, amount: u32, dst: &adnl::PeersSet) {
         dst.randomly_fill_from(&self.known_peers, amount, Some(&self.ignored_peers));
+        // NOTE: pinned neighbours are inserted last so that they are not replaced
+        dst.extend(self.pinned_neighbours.iter().map(|item| *item.key()));
     }
 
     /// Serialized [`proto::rpc::OverlayQuery`] with own overlay id
@@ -383,12 +585,24 @@ impl Overlay {
     {
         let local_id = self.overlay_key().id();
         type Value = tl_proto::OwnedRawBytes<tl_proto::Boxed>;
-        match adnl
+
+        let started_at = Instant::now();
+        let answer = adnl
             .query_with_prefix::<Q, Value>(local_id, peer_id, self.query_prefix(), query, timeout)
-            .await?
-        {
-            Some(answer) => Ok(Some(answer.into_inner())),
-            None => Ok(None),
+            .await;
+        match answer {
+            Ok(Some(answer)) => {
+                self.record_query(peer_id, Some(started_at.elapsed()));
+                Ok(Some(answer.into_inner()))
+            }
+            Ok(None) => {
+                self.record_query(peer_id, None);
+                Ok(None)
+            }
+            Err(e) => {
+                self.record_query(peer_id, None);
+                Err(e)
+            }
         }
     }
 
@@ -412,7 +626,62 @@ impl Overlay {
         query_data.extend_from_slice(prefix);
         query.write_to(&mut query_data);
 
-        rldp.query(local_id, peer_id, query_data, roundtrip).await
+        let started_at = Instant::now();
+        let result = rldp.query(local_id, peer_id, query_data, roundtrip).await;
+        match &result {
+            Ok((Some(_), _)) => self.record_query(peer_id, Some(started_at.elapsed())),
+            _ => self.record_query(peer_id, None),
+        }
+        result
+    }
+
+    /// Sends typed ADNL query to the given peer. If there is no valid answer,
+    /// the query is retried on other neighbours (see [`Overlay::select_query_peers`]).
+    ///
+    /// Timeout for each peer is computed from its measured roundtrip.
+    ///
+    /// See [`OverlayOptions::query_attempts`]
+    ///
+    /// NOTE: In case none of the peers answered returns `Ok(None)`
+    pub async fn query<Q, A>(
+        &self,
+        adnl: &adnl::Node,
+        peer_id: &adnl::NodeIdShort,
+        query: Q,
+    ) -> Result<Option<A>>
+    where
+        Q: TlWrite,
+        for<'a> A: TlRead<'a, Repr = tl_proto::Boxed> + 'static,
+    {
+        let attempts = std::cmp::max(self.options.query_attempts, 1) as usize;
+
+        let mut peers = vec![*peer_id];
+        peers.extend(
+            self.select_query_peers(attempts)
+                .into_iter()
+                .filter(|id| id != peer_id),
+        );
+        peers.truncate(attempts);
+
+        for peer_id in &peers {
+            let timeout = self
+                .neighbour_stats(peer_id)
+                .roundtrip_ms
+                .map(|roundtrip| adnl.compute_query_timeout(Some(roundtrip * 2)));
+
+            match self.adnl_query(adnl, peer_id, &query, timeout).await {
+                Ok(Some(answer)) => match tl_proto::deserialize(&answer) {
+                    Ok(answer) => return Ok(Some(answer)),
+                    Err(e) => {
+                        tracing::debug!(overlay_id = %self.id, %peer_id, "invalid query answer: {e:?}")
+                    }
+                },
+                Ok(None) => tracing::debug!(overlay_id = %self.id, %peer_id, "query timeout"),
+                Err(e) => tracing::debug!(overlay_id = %self.id, %peer_id, "query failed: {e:?}"),
+            }
+        }
+
+        Ok(None)
     }
 
     /// Distributes provided message to the neighbours subset.
@@ -456,6 +725,10 @@ impl Overlay {
     }
 
     /// Returns raw signed overlay node
+    ///
+    /// # Panics
+    ///
+    /// Panics if the overlay key uses an external signer, see [`Overlay::sign_local_node_async`]
     pub fn sign_local_node(&self) -> proto::overlay::NodeOwned {
         let key = self.overlay_key();
         let version = now();
@@ -467,12 +740,22 @@ impl Overlay {
         };
         let signature = key.sign(node_to_sign);
 
-        proto::overlay::NodeOwned {
-            id: key.full_id().as_tl().as_equivalent_owned(),
-            overlay: *self.id().as_slice(),
+        make_overlay_node(key, &self.id, version, &signature)
+    }
+
+    /// Returns raw signed overlay node. Supports keys with an external signer.
+    pub async fn sign_local_node_async(&self) -> Result<proto::overlay::NodeOwned> {
+        let key = self.overlay_key();
+        let version = now();
+
+        let node_to_sign = &proto::overlay::NodeToSign {
+            id: key.id().as_slice(),
+            overlay: self.id().as_slice(),
             version,
-            signature: signature.to_vec().into(),
-        }
+        };
+        let signature = key.sign_async(node_to_sign).await?;
+
+        Ok(make_overlay_node(key, &self.id, version, &signature))
     }
 
     /// Exchanges random peers with the specified peer. Returns `Ok(None)` in case of timeout.
@@ -505,7 +788,7 @@ impl Overlay {
         existing_peers: &dyn ExistingPeersFilter,
     ) -> Result<Option<Vec<adnl::NodeIdShort>>> {
         let query = proto::rpc::OverlayGetRandomPeersOwned {
-            peers: self.prepare_random_peers(),
+            peers: self.prepare_random_peers().await,
         };
         let answer = match self.adnl_query(adnl, peer_id, query, timeout).await? {
             Some(answer) => answer,
@@ -518,6 +801,9 @@ impl Overlay {
         let answer = tl_proto::deserialize_as_boxed(&answer)?;
         tracing::trace!(overlay_id = %self.id, %peer_id, "got random peers");
         let proto::overlay::Nodes { nodes } = self.filter_nodes(answer);
+        if self.members.is_none() {
+            self.remember_received_peers(&nodes);
+        }
 
         let nodes = nodes
             .into_iter()
@@ -549,24 +835,31 @@ impl Overlay {
         raw_data: &[u8],
     ) -> Result<()> {
         if self.is_broadcast_outdated(broadcast.date) {
+            OverlayStats::inc(&self.stats.outdated_broadcasts);
             return Ok(());
         }
 
         let node_id = adnl::NodeIdFull::try_from(broadcast.src)?;
         let node_peer_id = node_id.compute_short_id();
+        self.check_broadcast_rights(&node_peer_id, &broadcast.certificate, broadcast.data.len())?;
+
         let source = match broadcast.flags {
             flags if flags & BROADCAST_FLAG_ANY_SENDER == 0 => Some(node_peer_id),
             _ => None,
         };
 
-        let broadcast_data = match compression::decompress(broadcast.data) {
+        let broadcast_data = match self.decompress_broadcast(broadcast.data)? {
             Some(decompressed) => {
                 let broadcast_to_sign =
                     make_broadcast_to_sign(&decompressed, broadcast.date, source.as_ref());
                 match node_id.verify(&broadcast_to_sign, broadcast.signature) {
                     Ok(()) => {
                         let broadcast_id = broadcast_to_sign.compute_broadcast_id();
-                        if !self.create_broadcast(broadcast_id) {
+                        if !self.create_incoming_broadcast(
+                            &node_peer_id,
+                            broadcast_id,
+                            decompressed.len(),
+                        )? {
                             return Ok(());
                         }
                         Some((broadcast_id, decompressed))
@@ -582,10 +875,17 @@ impl Overlay {
             None => {
                 let broadcast_to_sign =
                     make_broadcast_to_sign(broadcast.data, broadcast.date, source.as_ref());
-                node_id.verify(&broadcast_to_sign, broadcast.signature)?;
+                if let Err(e) = node_id.verify(&broadcast_to_sign, broadcast.signature) {
+                    OverlayStats::inc(&self.stats.invalid_broadcasts);
+                    return Err(e.into());
+                }
 
                 let broadcast_id = broadcast_to_sign.compute_broadcast_id();
-                if !self.create_broadcast(broadcast_id) {
+                if !self.create_incoming_broadcast(
+                    &node_peer_id,
+                    broadcast_id,
+                    broadcast.data.len(),
+                )? {
                     return Ok(());
                 }
                 (broadcast_id, broadcast.data.to_vec())
@@ -597,11 +897,16 @@ impl Overlay {
             data,
             from: node_peer_id,
         });
-
-        let neighbours = self
-            .neighbours
-            .get_random_peers(self.options.secondary_broadcast_target_count, Some(peer_id));
-        self.distribute_broadcast(adnl, local_id, &neighbours, raw_data);
+        OverlayStats::inc(&self.stats.received_broadcasts);
+
+        self.relay_broadcast(
+            adnl,
+            local_id,
+            peer_id,
+            self.options.secondary_broadcast_target_count,
+            broadcast.date,
+            raw_data,
+        );
         self.spawn_broadcast_gc_task(broadcast_id);
 
         Ok(())
@@ -619,6 +924,7 @@ impl Overlay {
         use dashmap::mapref::entry::Entry;
 
         if self.is_broadcast_outdated(broadcast.date) {
+            OverlayStats::inc(&self.stats.outdated_broadcasts);
             return Ok(());
         }
 
@@ -631,9 +937,33 @@ impl Overlay {
             _ => return Err(OverlayError::UnsupportedSignature.into()),
         };
 
+        let part = BroadcastFec {
+            node_id,
+            data_hash: broadcast_id,
+            data_size: broadcast.data_size,
+            flags: broadcast.flags,
+            data: broadcast.data.to_vec(),
+            seqno: broadcast.seqno,
+            fec_type: broadcast.fec,
+            date: broadcast.date,
+            signature,
+        };
+
+        let mut verified = false;
         let transfer = match self.owned_broadcasts.entry(broadcast_id) {
             // First packet of the broadcast
             Entry::Vacant(entry) => {
+                self.check_broadcast_rights(
+                    &source,
+                    &broadcast.certificate,
+                    broadcast.data_size as usize,
+                )?;
+
+                // NOTE: the source must be verified before its limits are spent
+                self.verify_fec_part(&part)?;
+                verified = true;
+                self.check_broadcast_limits(&source, broadcast.data_size as usize)?;
+
                 self.spawn_fec_transfer_receiver(broadcast.fec, broadcast_id, source, entry)?
             }
             // Broadcast was already started
@@ -654,6 +984,22 @@ impl Overlay {
             return Ok(());
         }
 
+        // Don't spread parts of the broadcast which failed to decode
+        if transfer.failed.load(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        // Skip known parts without checking their signature. The history is not
+        // updated here, so that unverified parts can't be marked as delivered
+        if transfer.history.is_delivered(broadcast.seqno as u64) {
+            return Ok(());
+        }
+
+        // Verify part signature before it is marked as delivered and redistributed
+        if !verified {
+            self.verify_fec_part(&part)?;
+        }
+
         // Ignore duplicate packets
         if !transfer.history.deliver_packet(broadcast.seqno as u64) {
             return Ok(());
@@ -661,42 +1007,48 @@ impl Overlay {
 
         // Send broadcast to the processing queue
         if !transfer.completed.load(Ordering::Acquire) {
-            transfer.broadcast_tx.send(BroadcastFec {
-                node_id,
-                data_hash: broadcast_id,
-                data_size: broadcast.data_size,
-                flags: broadcast.flags,
-                data: broadcast.data.to_vec(),
-                seqno: broadcast.seqno,
-                fec_type: broadcast.fec,
-                date: broadcast.date,
-                signature,
-            })?;
+            transfer.broadcast_tx.send(part)?;
         }
 
         // Redistribute broadcast
-        let neighbours = self.neighbours.get_random_peers(
+        self.relay_broadcast(
+            adnl,
+            local_id,
+            peer_id,
             self.options.secondary_fec_broadcast_target_count,
-            Some(peer_id),
+            broadcast.date,
+            raw_data,
         );
-        self.distribute_broadcast(adnl, local_id, &neighbours, raw_data);
 
         Ok(())
     }
 
     /// Process random peers request
-    pub(super) fn process_get_random_peers(
+    pub(super) async fn process_get_random_peers(
         &self,
         query: proto::rpc::OverlayGetRandomPeers<'_>,
     ) -> proto::overlay::NodesOwned {
-        use std::collections::hash_map::Entry;
-
         // Update received peers
-        let peers = self.filter_nodes(query.peers).nodes;
+        let mut peers = self.filter_nodes(query.peers).nodes;
+        if self.members.is_some() {
+            peers.retain(|node| match adnl::NodeIdFull::try_from(node.id) {
+                Ok(full_id) => self.is_member(&full_id.compute_short_id()),
+                Err(_) => false,
+            });
+        }
+
+        self.remember_received_peers(&peers);
+
+        // Return random peers from our side
+        self.prepare_random_peers().await
+    }
+
+    /// Inserts received peers into the map, see [`Overlay::take_new_peers`]
+    fn remember_received_peers(&self, nodes: &[proto::overlay::Node<'_>]) {
+        use std::collections::hash_map::Entry;
 
-        // Insert received peers
         let mut received_peers = self.received_peers.lock();
-        for node in peers {
+        for node in nodes {
             match received_peers.entry(HashWrapper(node.id.as_equivalent_owned())) {
                 Entry::Occupied(mut entry) => {
                     if entry.get().version < node.version {
@@ -708,20 +1060,14 @@ impl Overlay {
                 }
             }
         }
-
-        // NOTE: reduce lock scope
-        drop(received_peers);
-
-        // Return random peers from our side
-        self.prepare_random_peers()
     }
 
     /// Send ordinary broadcast
     fn send_broadcast(
         self: &Arc<Self>,
-        adnl: &adnl::Node,
+        adnl: &Arc<adnl::Node>,
         local_id: &adnl::NodeIdShort,
-        mut data: Vec<u8>,
+        data: Vec<u8>,
         key: &Arc<adnl::Key>,
         target: BroadcastTarget,
     ) -> OutgoingBroadcastInfo {
@@ -736,25 +1082,93 @@ impl Overlay {
             );
             return Default::default();
         }
-        let signature = key.sign(broadcast_to_sign);
 
+        let neighbours = match target {
+            BroadcastTarget::RandomNeighbours => OwnedBroadcastTarget::Neighbours(
+                self.select_broadcast_targets(self.options.broadcast_target_count, None),
+            ),
+            BroadcastTarget::Explicit(neighbours) => OwnedBroadcastTarget::Explicit(neighbours),
+        };
+
+        let info = OutgoingBroadcastInfo {
+            packets: 1,
+            recipient_count: neighbours.as_ref().len(),
+        };
+
+        if key.is_external() {
+            // NOTE: keys with an external signer can't sign broadcasts synchronously
+            let overlay = self.clone();
+            let adnl = adnl.clone();
+            let local_id = *local_id;
+            let key = key.clone();
+            tokio::spawn(async move {
+                match key.sign_async(broadcast_to_sign).await {
+                    Ok(signature) => overlay.finish_broadcast(
+                        &adnl,
+                        &local_id,
+                        &broadcast_id,
+                        data,
+                        &key,
+                        date,
+                        &signature,
+                        neighbours.as_ref(),
+                    ),
+                    Err(e) => tracing::warn!(
+                        overlay_id = %overlay.id,
+                        broadcast_id = %DisplayBroadcastId(&broadcast_id),
+                        "failed to sign overlay broadcast: {e:?}"
+                    ),
+                }
+            });
+        } else {
+            let signature = key.sign(broadcast_to_sign);
+            self.finish_broadcast(
+                adnl,
+                local_id,
+                &broadcast_id,
+                data,
+                key,
+                date,
+                &signature,
+                neighbours.as_ref(),
+            );
+        }
+
+        self.spawn_broadcast_gc_task(broadcast_id);
+
+        info
+    }
+
+    /// Encodes signed ordinary broadcast and sends it to the neighbours
+    fn finish_broadcast(
+        &self,
+        adnl: &adnl::Node,
+        local_id: &adnl::NodeIdShort,
+        broadcast_id: &BroadcastId,
+        mut data: Vec<u8>,
+        key: &adnl::Key,
+        date: u32,
+        signature: &[u8; 64],
+        neighbours: &[adnl::NodeIdShort],
+    ) {
         if self.options.force_compression {
             if let Err(e) = compression::compress(&mut data) {
                 tracing::warn!(
                     overlay_id = %self.id,
-                    broadcast_id = %DisplayBroadcastId(&broadcast_id),
+                    broadcast_id = %DisplayBroadcastId(broadcast_id),
                     "failed to compress overlay broadcast: {e:?}"
                 );
             }
         }
 
+        let certificate = self.certificate_for(key.id());
         let broadcast = proto::overlay::Broadcast::Broadcast(proto::overlay::OverlayBroadcast {
             src: key.full_id().as_tl(),
-            certificate: proto::overlay::Certificate::EmptyCertificate,
+            certificate: certificate.as_equivalent_ref(),
             flags: BROADCAST_FLAG_ANY_SENDER,
             data: &data,
             date,
-            signature: &signature,
+            signature,
         });
 
         let mut buffer = Vec::with_capacity(self.message_prefix.len() + broadcast.max_size_hint());
@@ -762,21 +1176,7 @@ impl Overlay {
         broadcast.write_to(&mut buffer);
         drop(data);
 
-        let neighbours = match target {
-            BroadcastTarget::RandomNeighbours => OwnedBroadcastTarget::Neighbours(
-                self.neighbours
-                    .get_random_peers(self.options.broadcast_target_count, None),
-            ),
-            BroadcastTarget::Explicit(neighbours) => OwnedBroadcastTarget::Explicit(neighbours),
-        };
-
-        self.distribute_broadcast(adnl, local_id, neighbours.as_ref(), &buffer);
-        self.spawn_broadcast_gc_task(broadcast_id);
-
-        OutgoingBroadcastInfo {
-            packets: 1,
-            recipient_count: neighbours.as_ref().len(),
-        }
+        self.distribute_broadcast(adnl, local_id, neighbours, &buffer);
     }
 
     /// Send FEC broadcast
@@ -820,8 +1220,7 @@ impl Overlay {
 
         let neighbours = match target {
             BroadcastTarget::RandomNeighbours => OwnedBroadcastTarget::Neighbours(
-                self.neighbours
-                    .get_random_peers(self.options.broadcast_target_count, None),
+                self.select_broadcast_targets(self.options.broadcast_target_count, None),
             ),
             BroadcastTarget::Explicit(neighbours) => OwnedBroadcastTarget::Explicit(neighbours),
         };
@@ -842,7 +1241,10 @@ impl Overlay {
             // Send broadcast in waves
             'outer: while outgoing_transfer.seqno <= info.packets {
                 for _ in 0..wave_len {
-                    let data = match overlay.prepare_fec_broadcast(&mut outgoing_transfer, &key) {
+                    let data = match overlay
+                        .prepare_fec_broadcast(&mut outgoing_transfer, &key)
+                        .await
+                    {
                         Ok(data) => data,
                         // Rare case, it is easier to just ignore it
                         Err(e) => {
@@ -896,11 +1298,14 @@ impl Overlay {
     }
 
     /// Creates nodes list
-    fn prepare_random_peers(&self) -> proto::overlay::NodesOwned {
+    async fn prepare_random_peers(&self) -> proto::overlay::NodesOwned {
         const MAX_PEERS_IN_RESPONSE: u32 = 4;
 
         let mut nodes = SmallVec::with_capacity(MAX_PEERS_IN_RESPONSE as usize + 1);
-        nodes.push(self.sign_local_node());
+        match self.sign_local_node_async().await {
+            Ok(node) => nodes.push(node),
+            Err(e) => tracing::warn!(overlay_id = %self.id, "failed to sign local node: {e:?}"),
+        }
 
         let peers = adnl::PeersSet::with_capacity(MAX_PEERS_IN_RESPONSE);
         peers.randomly_fill_from(&self.neighbours, MAX_PEERS_IN_RESPONSE, None);
@@ -969,7 +1374,8 @@ impl Overlay {
         let entry = entry
             .insert(Arc::new(OwnedBroadcast::Incoming(IncomingFecTransfer {
                 completed: AtomicBool::new(false),
-                history: PacketsHistory::for_recv(),
+                failed: AtomicBool::new(false),
+                history: PacketsHistory::for_recv_with_config(Default::default()),
                 broadcast_tx,
                 source: peer_id,
                 updated_at: Default::default(),
@@ -983,11 +1389,12 @@ impl Overlay {
 
             // For each fec broadcast packet
             let mut packets = 0;
+            let mut failed = false;
             while let Some(broadcast) = broadcast_rx.recv().await {
                 packets += 1;
 
                 // Add new data to the encoder
-                match process_fec_broadcast(&mut decoder, broadcast) {
+                match process_fec_broadcast(&overlay, &mut decoder, broadcast) {
                     // Broadcast complete and successfully decoded
                     Ok(Some(data)) => {
                         let data = IncomingBroadcastInfo {
@@ -996,6 +1403,8 @@ impl Overlay {
                             from: peer_id,
                         };
                         overlay.received_broadcasts.push(data);
+                        OverlayStats::inc(&overlay.stats.received_broadcasts);
+                        OverlayStats::inc(&overlay.stats.completed_fec_broadcasts);
                         break;
                     }
                     // Broadcast is not complete yet
@@ -1007,6 +1416,8 @@ impl Overlay {
                             broadcast_id = %DisplayBroadcastId(&broadcast_id),
                             "error when receiving overlay broadcast: {e}"
                         );
+                        OverlayStats::inc(&overlay.stats.failed_fec_broadcasts);
+                        failed = true;
                         break;
                     }
                 }
@@ -1016,6 +1427,7 @@ impl Overlay {
             if let Some(broadcast) = overlay.owned_broadcasts.get(&broadcast_id) {
                 match broadcast.value().as_ref() {
                     OwnedBroadcast::Incoming(transfer) => {
+                        transfer.failed.store(failed, Ordering::Release);
                         transfer.completed.store(true, Ordering::Release);
                     }
                     _ => {
@@ -1067,7 +1479,7 @@ impl Overlay {
     }
 
     /// Encodes next chunk of FEC broadcast
-    fn prepare_fec_broadcast(
+    async fn prepare_fec_broadcast(
         &self,
         transfer: &mut OutgoingFecTransfer,
         key: &Arc<adnl::Key>,
@@ -1085,12 +1497,13 @@ impl Overlay {
             transfer.seqno,
             None,
         );
-        let signature = key.sign(broadcast_to_sign);
+        let signature = key.sign_async(broadcast_to_sign).await?;
 
+        let certificate = self.certificate_for(key.id());
         let broadcast =
             proto::overlay::Broadcast::BroadcastFec(proto::overlay::OverlayBroadcastFec {
                 src: key.full_id().as_tl(),
-                certificate: proto::overlay::Certificate::EmptyCertificate,
+                certificate: certificate.as_equivalent_ref(),
                 data_hash: &transfer.broadcast_id,
                 data_size: transfer.encoder.params().total_len,
                 flags: BROADCAST_FLAG_ANY_SENDER,
@@ -1129,14 +1542,233 @@ impl Overlay {
         }
     }
 
+    /// Redistributes the received broadcast message to the random neighbours
+    /// except the one it was received from
+    fn relay_broadcast(
+        &self,
+        adnl: &adnl::Node,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        target_count: u32,
+        date: u32,
+        data: &[u8],
+    ) {
+        if !self.is_relayed_broadcast(date) {
+            return;
+        }
+
+        let neighbours = self.select_broadcast_targets(target_count, Some(peer_id));
+        self.distribute_broadcast(adnl, local_id, &neighbours, data);
+        self.stats
+            .relayed_broadcast_messages
+            .fetch_add(neighbours.len() as u64, Ordering::Relaxed);
+    }
+
+    /// Selects all pinned neighbours and `amount` random neighbours
+    fn select_broadcast_targets(
+        &self,
+        amount: u32,
+        except: Option<&adnl::NodeIdShort>,
+    ) -> Vec<adnl::NodeIdShort> {
+        let mut targets = self
+            .pinned_neighbours
+            .iter()
+            .map(|item| *item.key())
+            .filter(|peer_id| Some(peer_id) != except)
+            .collect::<Vec<_>>();
+
+        targets.extend(
+            self.neighbours
+                .get_random_peers(amount, except)
+                .into_iter()
+                .filter(|peer_id| !self.pinned_neighbours.contains(peer_id)),
+        );
+        targets
+    }
+
+    #[inline(always)]
+    pub(super) fn stats(&self) -> &OverlayStats {
+        &self.stats
+    }
+
+    #[inline(always)]
+    pub(super) fn neighbours_set(&self) -> &adnl::PeersSet {
+        &self.neighbours
+    }
+
+    #[inline(always)]
+    pub(super) fn pinned_neighbours_set(&self) -> &FastDashSet<adnl::NodeIdShort> {
+        &self.pinned_neighbours
+    }
+
+    #[inline(always)]
+    pub(super) fn known_peers(&self) -> &adnl::PeersSet {
+        &self.known_peers
+    }
+
+    #[inline(always)]
+    pub(super) fn peer_stats(&self) -> &FastDashMap<adnl::NodeIdShort, NeighbourStats> {
+        &self.peer_stats
+    }
+
+    pub(super) fn is_ignored_peer(&self, peer_id: &adnl::NodeIdShort) -> bool {
+        self.ignored_peers.contains(peer_id)
+    }
+
+    fn certificate_for(&self, source_id: &adnl::NodeIdShort) -> proto::overlay::CertificateOwned {
+        match self.certificates.get(source_id) {
+            Some(certificate) => certificate.clone(),
+            None => proto::overlay::CertificateOwned::EmptyCertificate,
+        }
+    }
+
+    /// Checks whether the source is allowed to broadcast into the private overlay
+    fn check_broadcast_rights(
+        &self,
+        source_id: &adnl::NodeIdShort,
+        certificate: &proto::overlay::Certificate<'_>,
+        data_size: usize,
+    ) -> Result<()> {
+        let result = self.check_broadcast_certificate(source_id, certificate, data_size);
+        if result.is_err() {
+            OverlayStats::inc(&self.stats.unauthorized_broadcasts);
+        }
+        result
+    }
+
+    fn check_broadcast_certificate(
+        &self,
+        source_id: &adnl::NodeIdShort,
+        certificate: &proto::overlay::Certificate<'_>,
+        data_size: usize,
+    ) -> Result<()> {
+        if !self.is_member(source_id) {
+            return Err(OverlayError::NotAMember.into());
+        }
+        if self.members.is_none()
+            || self.certificate_issuers.is_empty()
+            || self.certificate_issuers.contains(source_id)
+        {
+            return Ok(());
+        }
+
+        let (issued_by, expire_at, max_size, signature) = match *certificate {
+            proto::overlay::Certificate::Certificate {
+                issued_by,
+                expire_at,
+                max_size,
+                signature,
+            } => (issued_by, expire_at, max_size, signature),
+            proto::overlay::Certificate::EmptyCertificate => {
+                return Err(OverlayError::CertificateRequired.into())
+            }
+        };
+
+        let issuer = adnl::NodeIdFull::try_from(issued_by)?;
+        if !self
+            .certificate_issuers
+            .contains(&issuer.compute_short_id())
+        {
+            return Err(OverlayError::UnknownCertificateIssuer.into());
+        }
+        if expire_at < now() {
+            return Err(OverlayError::CertificateExpired.into());
+        }
+        if data_size > max_size as usize {
+            return Err(OverlayError::BroadcastTooBig.into());
+        }
+
+        issuer.verify(
+            proto::overlay::CertificateId {
+                overlay_id: self.id.as_slice(),
+                node: source_id.as_slice(),
+                expire_at,
+                max_size,
+            },
+            signature,
+        )?;
+        Ok(())
+    }
+
+    /// Registers a new verified incoming broadcast if it fits the source limits.
+    /// Returns `false` if the broadcast was already received
+    fn create_incoming_broadcast(
+        &self,
+        source_id: &adnl::NodeIdShort,
+        broadcast_id: BroadcastId,
+        data_size: usize,
+    ) -> Result<bool> {
+        if self.owned_broadcasts.contains_key(&broadcast_id) {
+            return Ok(false);
+        }
+        self.check_broadcast_limits(source_id, data_size)?;
+        Ok(self.create_broadcast(broadcast_id))
+    }
+
+    fn check_broadcast_limits(
+        &self,
+        source_id: &adnl::NodeIdShort,
+        data_size: usize,
+    ) -> Result<()> {
+        match self.broadcast_limiter.check(source_id, data_size) {
+            Ok(()) => Ok(()),
+            Err(QueryLimitsError::TooBig) => {
+                OverlayStats::inc(&self.stats.oversized_broadcasts);
+                Err(OverlayError::BroadcastSizeLimitExceeded.into())
+            }
+            Err(QueryLimitsError::Throttled) => {
+                OverlayStats::inc(&self.stats.throttled_broadcasts);
+                Err(OverlayError::TooManyBroadcasts.into())
+            }
+        }
+    }
+
+    /// Decompresses the broadcast data if it is compressed.
+    /// Broadcasts which exceed the size limit after decompression are dropped
+    fn decompress_broadcast(&self, data: &[u8]) -> Result<Option<Vec<u8>>> {
+        let max_len = self
+            .options
+            .max_broadcast_size
+            .unwrap_or(MAX_DECOMPRESSED_BROADCAST_SIZE);
+        match compression::decompress(data, max_len) {
+            Ok(data) => Ok(data),
+            Err(_) => {
+                OverlayStats::inc(&self.stats.oversized_broadcasts);
+                Err(OverlayError::BroadcastSizeLimitExceeded.into())
+            }
+        }
+    }
+
+    /// Whether the received broadcast can be redistributed to the neighbours
+    fn is_relayed_broadcast(&self, date: u32) -> bool {
+        match self.options.max_broadcast_relay_age_sec {
+            Some(max_age) => date as u64 + max_age >= now() as u64,
+            None => true,
+        }
+    }
+
+    fn verify_fec_part(&self, part: &BroadcastFec) -> Result<()> {
+        let result = verify_fec_part_signature(part);
+        if result.is_err() {
+            OverlayStats::inc(&self.stats.invalid_broadcasts);
+        }
+        result
+    }
+
     fn is_broadcast_outdated(&self, date: u32) -> bool {
-        date + (self.options.broadcast_timeout_sec as u32) < now()
+        let now = now() as u64;
+        let date = date as u64;
+        date + self.options.broadcast_timeout_sec < now
+            || date > now + self.options.max_broadcast_date_skew_sec
     }
 
     fn spawn_broadcast_gc_task(self: &Arc<Self>, broadcast_id: BroadcastId) {
         let overlay = self.clone();
         tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(overlay.options.broadcast_timeout_sec)).await;
+            // NOTE: keep the broadcast id until its date is outdated
+            let timeout =
+                overlay.options.broadcast_timeout_sec + overlay.options.max_broadcast_date_skew_sec;
+            tokio::time::sleep(Duration::from_secs(timeout)).await;
             overlay
                 .finished_broadcast_count
                 .fetch_add(1, Ordering::Release);
@@ -1195,6 +1827,20 @@ where
     }
 }
 
+fn make_overlay_node(
+    key: &adnl::Key,
+    overlay_id: &IdShort,
+    version: u32,
+    signature: &[u8; 64],
+) -> proto::overlay::NodeOwned {
+    proto::overlay::NodeOwned {
+        id: key.full_id().as_tl().as_equivalent_owned(),
+        overlay: *overlay_id.as_slice(),
+        version,
+        signature: signature.to_vec().into(),
+    }
+}
+
 enum OwnedBroadcastTarget {
     Neighbours(Vec<adnl::NodeIdShort>),
     Explicit(Arc<Vec<adnl::NodeIdShort>>),
@@ -1214,42 +1860,76 @@ impl AsRef<[adnl::NodeIdShort]> for OwnedBroadcastTarget {
 pub struct OverlayMetrics {
     pub owned_broadcasts_len: usize,
     pub finished_broadcasts_len: u32,
+    /// Number of incoming FEC broadcasts which are still being received
+    pub incomplete_fec_broadcasts: usize,
+    /// Total number of delivered incoming broadcasts
+    pub received_broadcasts: u64,
+    /// Total number of successfully decoded incoming FEC broadcasts
+    pub completed_fec_broadcasts: u64,
+    /// Total number of incoming FEC broadcasts which failed to decode
+    pub failed_fec_broadcasts: u64,
+    /// Total number of broadcast messages redistributed to the neighbours
+    pub relayed_broadcast_messages: u64,
+    /// Total number of dropped broadcast messages with an outdated date
+    pub outdated_broadcasts: u64,
+    /// Total number of dropped broadcasts from non-members or without a valid certificate
+    pub unauthorized_broadcasts: u64,
+    /// Total number of dropped broadcast messages with an invalid signature
+    pub invalid_broadcasts: u64,
+    /// Total number of dropped broadcasts exceeding [`OverlayOptions::max_broadcast_size`]
+    pub oversized_broadcasts: u64,
+    /// Total number of dropped broadcasts exceeding [`OverlayOptions::broadcast_rate_limit`]
+    pub throttled_broadcasts: u64,
+    /// Total number of outgoing ADNL and RLDP queries
+    pub sent_queries: u64,
+    /// Total number of failed or timed out outgoing queries
+    pub failed_queries: u64,
+    /// Total number of incoming queries
+    pub received_queries: u64,
     pub node_count: usize,
     pub known_peers: usize,
     pub neighbours: usize,
+    /// Number of neighbours pinned with [`Overlay::pin_neighbour`]
+    pub pinned_neighbours: usize,
     pub received_broadcasts_data_len: usize,
     pub received_broadcasts_barrier_count: usize,
 }
 
+#[derive(Default)]
+pub(super) struct OverlayStats {
+    received_broadcasts: AtomicU64,
+    completed_fec_broadcasts: AtomicU64,
+    failed_fec_broadcasts: AtomicU64,
+    relayed_broadcast_messages: AtomicU64,
+    outdated_broadcasts: AtomicU64,
+    pub(super) unauthorized_broadcasts: AtomicU64,
+    invalid_broadcasts: AtomicU64,
+    oversized_broadcasts: AtomicU64,
+    throttled_broadcasts: AtomicU64,
+    pub(super) sent_queries: AtomicU64,
+    pub(super) failed_queries: AtomicU64,
+    pub(super) received_queries: AtomicU64,
+}
+
+impl OverlayStats {
+    #[inline(always)]
+    pub(super) fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
 fn process_fec_broadcast(
+    overlay: &Overlay,
     decoder: &mut RaptorQDecoder,
     broadcast: BroadcastFec,
 ) -> Result<Option<Vec<u8>>> {
     let broadcast_id = &broadcast.data_hash;
 
-    let broadcast_to_sign = &make_fec_part_to_sign(
-        broadcast_id,
-        broadcast.data_size,
-        broadcast.date,
-        broadcast.flags,
-        &broadcast.fec_type,
-        &broadcast.data,
-        broadcast.seqno,
-        if broadcast.flags & BROADCAST_FLAG_ANY_SENDER == 0 {
-            Some(broadcast.node_id.compute_short_id())
-        } else {
-            None
-        },
-    );
-    broadcast
-        .node_id
-        .verify(broadcast_to_sign, &broadcast.signature)?;
-
     match decoder.decode(broadcast.seqno, broadcast.data) {
         Some(result) if result.len() != broadcast.data_size as usize => {
             Err(OverlayError::DataSizeMismatch.into())
         }
-        Some(result) => match compression::decompress(&result) {
+        Some(result) => match overlay.decompress_broadcast(&result)? {
             Some(decompressed)
                 if sha2::Sha256::digest(&decompressed).as_slice() == broadcast_id =>
             {
@@ -1268,6 +1948,27 @@ fn process_fec_broadcast(
     }
 }
 
+fn verify_fec_part_signature(broadcast: &BroadcastFec) -> Result<()> {
+    let broadcast_to_sign = &make_fec_part_to_sign(
+        &broadcast.data_hash,
+        broadcast.data_size,
+        broadcast.date,
+        broadcast.flags,
+        &broadcast.fec_type,
+        &broadcast.data,
+        broadcast.seqno,
+        if broadcast.flags & BROADCAST_FLAG_ANY_SENDER == 0 {
+            Some(broadcast.node_id.compute_short_id())
+        } else {
+            None
+        },
+    );
+    broadcast
+        .node_id
+        .verify(broadcast_to_sign, &broadcast.signature)?;
+    Ok(())
+}
+
 #[derive(TlWrite)]
 #[tl(boxed, id = "overlay.broadcast.toSign", scheme = "scheme.tl")]
 struct OverlayBroadcastToSign {
@@ -1358,6 +2059,8 @@ pub struct OutgoingBroadcastInfo {
 
 struct IncomingFecTransfer {
     completed: AtomicBool,
+    /// Broadcast was received but failed to decode
+    failed: AtomicBool,
     history: PacketsHistory,
     broadcast_tx: BroadcastFecTx,
     source: adnl::NodeIdShort,
@@ -1421,6 +2124,176 @@ enum OverlayError {
     DataSizeMismatch,
     #[error("Data hash mismatch")]
     DataHashMismatch,
+    #[error("Not a member of the private overlay")]
+    NotAMember,
+    #[error("Broadcast certificate required")]
+    CertificateRequired,
+    #[error("Unknown certificate issuer")]
+    UnknownCertificateIssuer,
+    #[error("Certificate expired")]
+    CertificateExpired,
+    #[error("Broadcast is too big for the certificate")]
+    BroadcastTooBig,
+    #[error("Broadcast size limit exceeded")]
+    BroadcastSizeLimitExceeded,
+    #[error("Too many broadcasts from the source")]
+    TooManyBroadcasts,
 }
 
 const BROADCAST_FLAG_ANY_SENDER: u32 = 1; // Any sender
+
+const MAX_DECOMPRESSED_BROADCAST_SIZE: usize = 10 << 20;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_rights(
+        overlay: &Overlay,
+        source_id: &adnl::NodeIdShort,
+        certificate: &proto::overlay::CertificateOwned,
+        data_size: usize,
+    ) -> Result<(), String> {
+        overlay
+            .check_broadcast_rights(source_id, &certificate.as_equivalent_ref(), data_size)
+            .map_err(|e| match e.downcast_ref::<OverlayError>() {
+                Some(e) => e.to_string(),
+                None => "invalid signature".to_owned(),
+            })
+    }
+
+    #[tokio::test]
+    async fn private_overlay_broadcast_rights() {
+        let node_key = Arc::new(adnl::Key::from_bytes(rand::random()));
+        let issuer = adnl::Key::from_bytes(rand::random());
+        let member = adnl::Key::from_bytes(rand::random());
+        let other_member = adnl::Key::from_bytes(rand::random());
+        let stranger = adnl::Key::from_bytes(rand::random());
+
+        let overlay = Overlay::new(
+            node_key,
+            IdShort::new(rand::random()),
+            &[*issuer.id(), *member.id(), *other_member.id()],
+            true,
+            Default::default(),
+        );
+        let empty = proto::overlay::CertificateOwned::EmptyCertificate;
+
+        // Without issuers any member can broadcast
+        assert_eq!(check_rights(&overlay, member.id(), &empty, 100), Ok(()));
+        assert_eq!(
+            check_rights(&overlay, stranger.id(), &empty, 100),
+            Err(OverlayError::NotAMember.to_string())
+        );
+
+        // With issuers members need a valid certificate
+        assert!(overlay.add_certificate_issuer(*issuer.id()));
+        assert_eq!(check_rights(&overlay, issuer.id(), &empty, 100), Ok(()));
+        assert_eq!(
+            check_rights(&overlay, member.id(), &empty, 100),
+            Err(OverlayError::CertificateRequired.to_string())
+        );
+
+        let expire_at = now() + 600;
+        let certificate = overlay
+            .issue_certificate(&issuer, member.id(), expire_at, 1000)
+            .unwrap();
+        assert_eq!(
+            check_rights(&overlay, member.id(), &certificate, 1000),
+            Ok(())
+        );
+        assert_eq!(
+            check_rights(&overlay, member.id(), &certificate, 1001),
+            Err(OverlayError::BroadcastTooBig.to_string())
+        );
+
+        // Certificate is bound to the node
+        assert_eq!(
+            check_rights(&overlay, other_member.id(), &certificate, 100),
+            Err("invalid signature".to_owned())
+        );
+
+        let expired = overlay
+            .issue_certificate(&issuer, member.id(), now() - 1, 1000)
+            .unwrap();
+        assert_eq!(
+            check_rights(&overlay, member.id(), &expired, 100),
+            Err(OverlayError::CertificateExpired.to_string())
+        );
+
+        let unknown_issuer = overlay
+            .issue_certificate(&other_member, member.id(), expire_at, 1000)
+            .unwrap();
+        assert_eq!(
+            check_rights(&overlay, member.id(), &unknown_issuer, 100),
+            Err(OverlayError::UnknownCertificateIssuer.to_string())
+        );
+
+        // Certificate of the removed member is no longer valid
+        assert!(overlay.remove_member(member.id()));
+        assert_eq!(
+            check_rights(&overlay, member.id(), &certificate, 100),
+            Err(OverlayError::NotAMember.to_string())
+        );
+
+        assert_eq!(overlay.metrics().unauthorized_broadcasts, 7);
+    }
+
+    #[tokio::test]
+    async fn public_overlay_broadcast_rights() {
+        let overlay = Overlay::new(
+            Arc::new(adnl::Key::from_bytes(rand::random())),
+            IdShort::new(rand::random()),
+            &[],
+            false,
+            Default::default(),
+        );
+        assert!(!overlay.is_private());
+
+        let source = adnl::Key::from_bytes(rand::random());
+        assert!(overlay.add_certificate_issuer(*source.id()));
+        assert_eq!(
+            check_rights(
+                &overlay,
+                source.id(),
+                &proto::overlay::CertificateOwned::EmptyCertificate,
+                100
+            ),
+            Ok(())
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_neighbours_are_retried() {
+        let overlay = Overlay::new(
+            Arc::new(adnl::Key::from_bytes(rand::random())),
+            IdShort::new(rand::random()),
+            &[],
+            false,
+            Default::default(),
+        );
+
+        let peer_id = adnl::NodeIdShort::new(rand::random());
+        for _ in 0..overlay.options().max_neighbour_failures {
+            assert!(overlay.is_responsive_neighbour(&peer_id));
+            overlay.record_query(&peer_id, None);
+        }
+        assert!(!overlay.is_responsive_neighbour(&peer_id));
+
+        // The peer gets a probe query after the cooldown
+        let retry_interval = overlay.options().neighbour_retry_interval_sec as u32;
+        overlay
+            .peer_stats()
+            .get_mut(&peer_id)
+            .unwrap()
+            .last_failure_at = Some(now() - retry_interval);
+        assert!(overlay.is_responsive_neighbour(&peer_id));
+
+        // Failed probe delays the peer for another cooldown
+        overlay.record_query(&peer_id, None);
+        assert!(!overlay.is_responsive_neighbour(&peer_id));
+
+        overlay.record_query(&peer_id, Some(Duration::from_millis(10)));
+        assert!(overlay.is_responsive_neighbour(&peer_id));
+    }
+}
@@ -1,13 +1,15 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use smallvec::SmallVec;
 use tl_proto::{Bare, Boxed, BoxedConstructor, TlError, TlPacket, TlRead, TlResult, TlWrite};
 
+#[cfg(feature = "serde")]
+use super::serde_helpers::*;
 use super::HashRef;
 
 #[derive(Clone)]
 pub struct OutgoingPacketContents<'tl> {
-    /// 7 or 3 random bytes
+    /// Random bytes (3 by default)
     pub rand1: &'tl [u8],
     pub from: Option<everscale_crypto::tl::PublicKey<'tl>>,
     pub messages: OutgoingMessages<'tl>,
@@ -16,7 +18,7 @@ pub struct OutgoingPacketContents<'tl> {
     pub confirm_seqno: u64,
     pub reinit_dates: Option<ReinitDates>,
     pub signature: Option<&'tl [u8]>,
-    /// 3 or 7 random bytes
+    /// Random bytes (7 by default)
     pub rand2: &'tl [u8],
 }
 
@@ -25,7 +27,7 @@ impl<'tl> TlWrite for OutgoingPacketContents<'tl> {
 
     fn max_size_hint(&self) -> usize {
         4 // constructor
-            + 8 // rand1 (1 byte length, 7 bytes data)
+            + self.rand1.max_size_hint()
             + 4 // flags
             + self.from.max_size_hint()
             + self.messages.max_size_hint()
@@ -34,7 +36,7 @@ impl<'tl> TlWrite for OutgoingPacketContents<'tl> {
             + 8 // confirm_seqno
             + self.reinit_dates.max_size_hint()
             + self.signature.max_size_hint()
-            + 4 // rand2 (1 byte length, 3 bytes data)
+            + self.rand2.max_size_hint()
     }
 
     fn write_to<P>(&self, packet: &mut P)
@@ -107,8 +109,11 @@ impl<'tl> TlWrite for OutgoingMessages<'tl> {
 }
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct IncomingPacketContents<'tl> {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "public_key::serialize_opt"))]
     pub from: Option<everscale_crypto::tl::PublicKey<'tl>>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "hex_bytes::serialize_opt"))]
     pub from_short: Option<HashRef<'tl>>,
 
     pub messages: SmallVec<[Message<'tl>; 2]>,
@@ -119,6 +124,7 @@ pub struct IncomingPacketContents<'tl> {
 
     pub reinit_dates: Option<ReinitDates>,
 
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub signature: Option<PacketContentsSignature>,
 }
 
@@ -282,6 +288,7 @@ impl PacketContentsSignature {
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
 #[tl(size_hint = 8)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReinitDates {
     pub local: u32,
     pub target: u32,
@@ -289,20 +296,29 @@ pub struct ReinitDates {
 
 #[derive(Debug, Copy, Clone, TlRead, TlWrite)]
 #[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Message<'tl> {
     #[tl(id = "adnl.message.answer")]
     Answer {
         #[tl(size_hint = 32)]
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         query_id: HashRef<'tl>,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         answer: &'tl [u8],
     },
 
     #[tl(id = "adnl.message.custom")]
-    Custom { data: &'tl [u8] },
+    Custom {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        data: &'tl [u8],
+    },
 
     #[tl(id = "adnl.message.confirmChannel", size_hint = 68)]
     ConfirmChannel {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         key: HashRef<'tl>,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         peer_key: HashRef<'tl>,
         date: u32,
     },
@@ -310,19 +326,27 @@ pub enum Message<'tl> {
     #[tl(id = "adnl.message.part")]
     Part {
         #[tl(size_hint = 32)]
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         hash: HashRef<'tl>,
         total_size: u32,
         offset: u32,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         data: &'tl [u8],
     },
 
     #[tl(id = "adnl.message.createChannel", size_hint = 36)]
-    CreateChannel { key: HashRef<'tl>, date: u32 },
+    CreateChannel {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        key: HashRef<'tl>,
+        date: u32,
+    },
 
     #[tl(id = "adnl.message.query")]
     Query {
         #[tl(size_hint = 32)]
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         query_id: HashRef<'tl>,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         query: &'tl [u8],
     },
 
@@ -334,11 +358,16 @@ pub enum Message<'tl> {
 }
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AddressList {
-    /// Single address instead of list, because only one is always passed
+    /// Single address instead of list, because only one is always passed.
+    ///
+    /// When reading, the first IPv4 address is preferred over IPv6 ones
     pub address: Option<Address>,
     pub version: u32,
     pub reinit_date: u32,
+    /// ADNL prefers addresses from the packet address lists with higher priority
+    pub priority: u32,
     pub expire_at: u32,
 }
 
@@ -367,7 +396,7 @@ impl TlWrite for AddressList {
         self.address.write_to(packet);
         self.version.write_to(packet);
         self.reinit_date.write_to(packet);
-        0u32.write_to(packet); // priority
+        self.priority.write_to(packet);
         self.expire_at.write_to(packet);
     }
 }
@@ -380,49 +409,140 @@ impl<'tl> TlRead<'tl> for AddressList {
         let mut address = None;
         for _ in 0..address_count {
             let item = ok!(Address::read_from(packet, offset));
-            if address.is_none() {
-                address = Some(item);
+            match address {
+                None => address = Some(item),
+                Some(Address::Udp6 { .. }) if item.is_ipv4() => address = Some(item),
+                Some(_) => {}
             }
         }
 
         let version = ok!(u32::read_from(packet, offset));
         let reinit_date = ok!(u32::read_from(packet, offset));
-        let _priority = ok!(u32::read_from(packet, offset));
+        let priority = ok!(u32::read_from(packet, offset));
         let expire_at = ok!(u32::read_from(packet, offset));
 
         Ok(Self {
             address,
             version,
             reinit_date,
+            priority,
             expire_at,
         })
     }
 }
 
-#[derive(Debug, Copy, Clone, TlRead, TlWrite)]
-#[tl(boxed, id = "adnl.address.udp", scheme = "scheme.tl", size_hint = 8)]
-pub struct Address {
-    pub ip: u32,
-    pub port: u32,
+/// Same as [`AddressList`], but with all addresses preserved
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddressListFull {
+    pub addresses: SmallVec<[Address; 4]>,
+    pub version: u32,
+    pub reinit_date: u32,
+    pub priority: u32,
+    pub expire_at: u32,
+}
+
+impl BoxedConstructor for AddressListFull {
+    const TL_ID: u32 = AddressList::TL_ID;
+}
+
+impl TlWrite for AddressListFull {
+    type Repr = Bare;
+
+    fn max_size_hint(&self) -> usize {
+        16 + self.addresses.max_size_hint()
+    }
+
+    fn write_to<P>(&self, packet: &mut P)
+    where
+        P: TlPacket,
+    {
+        self.addresses.write_to(packet);
+        self.version.write_to(packet);
+        self.reinit_date.write_to(packet);
+        self.priority.write_to(packet);
+        self.expire_at.write_to(packet);
+    }
+}
+
+impl<'tl> TlRead<'tl> for AddressListFull {
+    type Repr = Bare;
+
+    fn read_from(packet: &'tl [u8], offset: &mut usize) -> TlResult<Self> {
+        Ok(Self {
+            addresses: ok!(SmallVec::read_from(packet, offset)),
+            version: ok!(u32::read_from(packet, offset)),
+            reinit_date: ok!(u32::read_from(packet, offset)),
+            priority: ok!(u32::read_from(packet, offset)),
+            expire_at: ok!(u32::read_from(packet, offset)),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, TlRead, TlWrite)]
+#[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum Address {
+    #[tl(id = "adnl.address.udp", size_hint = 8)]
+    Udp { ip: u32, port: u32 },
+    #[tl(id = "adnl.address.udp6", size_hint = 20)]
+    Udp6 {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        ip: [u8; 16],
+        port: u32,
+    },
+}
+
+impl Address {
+    pub fn is_ipv4(&self) -> bool {
+        matches!(self, Self::Udp { .. })
+    }
 }
 
 impl From<&SocketAddrV4> for Address {
     fn from(addr: &SocketAddrV4) -> Self {
-        Self {
+        Self::Udp {
             ip: u32::from_be_bytes(addr.ip().octets()),
             port: addr.port() as u32,
         }
     }
 }
 
-impl From<Address> for SocketAddrV4 {
+impl From<&SocketAddrV6> for Address {
+    fn from(addr: &SocketAddrV6) -> Self {
+        Self::Udp6 {
+            ip: addr.ip().octets(),
+            port: addr.port() as u32,
+        }
+    }
+}
+
+impl From<&SocketAddr> for Address {
+    fn from(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(addr) => Self::from(addr),
+            SocketAddr::V6(addr) => Self::from(addr),
+        }
+    }
+}
+
+impl From<Address> for SocketAddr {
     fn from(addr: Address) -> Self {
-        Self::new(Ipv4Addr::from(addr.ip), addr.port as u16)
+        match addr {
+            Address::Udp { ip, port } => {
+                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port as u16))
+            }
+            Address::Udp6 { ip, port } => {
+                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port as u16, 0, 0))
+            }
+        }
     }
 }
 
 #[derive(Debug, Copy, Clone, TlRead, TlWrite)]
 #[tl(boxed, id = "adnl.pong", size_hint = 8, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pong {
     pub value: u64,
 }
@@ -436,10 +556,48 @@ mod tests {
         let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 123);
 
         let test = Address::from(&addr);
-        assert_eq!(test.ip, 0x7f000001);
-        assert_eq!(test.port, 123);
+        assert_eq!(
+            test,
+            Address::Udp {
+                ip: 0x7f000001,
+                port: 123
+            }
+        );
+
+        let test = SocketAddr::from(test);
+        assert_eq!(test, SocketAddr::V4(addr));
 
-        let test = SocketAddrV4::from(test);
-        assert_eq!(test, addr);
+        let addr = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 123, 0, 0);
+        let test = SocketAddr::from(Address::from(&addr));
+        assert_eq!(test, SocketAddr::V6(addr));
+    }
+
+    #[test]
+    fn address_list_prefers_ipv4() {
+        let udp6 = Address::from(&SocketAddrV6::new(Ipv6Addr::LOCALHOST, 123, 0, 0));
+        let udp = Address::from(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, 123));
+
+        let mut data = Vec::new();
+        2u32.write_to(&mut data);
+        udp6.write_to(&mut data);
+        udp.write_to(&mut data);
+        data.extend_from_slice(&[0; 16]);
+
+        let list = tl_proto::deserialize::<AddressList>(&data).unwrap();
+        assert_eq!(list.address, Some(udp));
+
+        let list = tl_proto::deserialize::<AddressListFull>(&data).unwrap();
+        assert_eq!(list.addresses.as_slice(), [udp6, udp]);
+        assert_eq!(tl_proto::serialize(&list), data);
+
+        let data = tl_proto::serialize(AddressList {
+            address: Some(udp6),
+            version: 0,
+            reinit_date: 0,
+            priority: 0,
+            expire_at: 0,
+        });
+        let list = tl_proto::deserialize::<AddressList>(&data).unwrap();
+        assert_eq!(list.address, Some(udp6));
     }
 }
@@ -0,0 +1,325 @@
+//! Protocol debugging helpers
+
+use std::fmt::{self, Write};
+use std::net::SocketAddr;
+
+use tl_proto::TlRead;
+
+use super::adnl;
+
+/// Returns the name of the known TL constructor
+pub fn constructor_name(id: u32) -> Option<&'static str> {
+    CONSTRUCTORS
+        .iter()
+        .find(|(constructor, _)| *constructor == id)
+        .map(|(_, name)| *name)
+}
+
+/// Human-readable representation of the decrypted ADNL packet.
+///
+/// Implements [`Display`] (single line) and `serde::Serialize` with the `serde` feature.
+///
+/// Received packets are logged with the `adnl::packets` target at trace level.
+///
+/// [`Display`]: std::fmt::Display
+#[derive(Copy, Clone)]
+pub struct PacketDump<'a, 'tl>(pub &'a adnl::IncomingPacketContents<'tl>);
+
+impl PacketDump<'_, '_> {
+    /// Returns descriptions of the packet messages
+    pub fn payloads(&self) -> Vec<String> {
+        self.0
+            .messages
+            .iter()
+            .map(|message| MessageDump(message).to_string())
+            .collect()
+    }
+}
+
+impl fmt::Display for PacketDump<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let packet = self.0;
+
+        f.write_str("adnl.packetContents")?;
+        match &packet.from {
+            Some(everscale_crypto::tl::PublicKey::Ed25519 { key }) => {
+                write!(f, " from={}", hex::encode(key))?
+            }
+            Some(key) => write!(f, " from={key:?}")?,
+            None => {}
+        }
+        if let Some(from_short) = packet.from_short {
+            write!(f, " from_short={}", hex::encode(from_short))?;
+        }
+        if let Some(seqno) = packet.seqno {
+            write!(f, " seqno={seqno}")?;
+        }
+        if let Some(confirm_seqno) = packet.confirm_seqno {
+            write!(f, " confirm_seqno={confirm_seqno}")?;
+        }
+        if let Some(dates) = packet.reinit_dates {
+            write!(f, " reinit_dates={}/{}", dates.local, dates.target)?;
+        }
+        if let Some(address) = &packet.address {
+            f.write_str(" address=")?;
+            match address.address {
+                Some(addr) => write!(f, "{}", SocketAddr::from(addr))?,
+                None => f.write_str("none")?,
+            }
+            write!(f, "@{}", address.version)?;
+        }
+        if packet.signature.is_some() {
+            f.write_str(" signed")?;
+        }
+
+        f.write_str(" messages=[")?;
+        for (i, message) in packet.messages.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", MessageDump(message))?;
+        }
+        f.write_char(']')
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for PacketDump<'_, '_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("PacketDump", 2)?;
+        s.serialize_field("packet", self.0)?;
+        s.serialize_field("payloads", &self.payloads())?;
+        s.end()
+    }
+}
+
+struct MessageDump<'a, 'tl>(&'a adnl::Message<'tl>);
+
+impl fmt::Display for MessageDump<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self.0 {
+            adnl::Message::Answer { query_id, answer } => write!(
+                f,
+                "answer(query_id={}, {})",
+                hex::encode(query_id),
+                PayloadDump(answer)
+            ),
+            adnl::Message::Custom { data } => write!(f, "custom({})", PayloadDump(data)),
+            adnl::Message::ConfirmChannel { date, .. } => write!(f, "confirmChannel(date={date})"),
+            adnl::Message::Part {
+                hash,
+                total_size,
+                offset,
+                data,
+            } => {
+                write!(
+                    f,
+                    "part(hash={}, offset={offset}, total_size={total_size}",
+                    hex::encode(hash)
+                )?;
+                // Only the first part starts with the message constructor
+                if offset == 0 {
+                    write!(f, ", {}", PayloadDump(data))?;
+                }
+                f.write_char(')')
+            }
+            adnl::Message::CreateChannel { date, .. } => write!(f, "createChannel(date={date})"),
+            adnl::Message::Query { query_id, query } => write!(
+                f,
+                "query(query_id={}, {})",
+                hex::encode(query_id),
+                PayloadDump(query)
+            ),
+            adnl::Message::Nop => f.write_str("nop"),
+            adnl::Message::Reinit { date } => write!(f, "reinit(date={date})"),
+        }
+    }
+}
+
+/// Payload constructors chain (e.g. `overlay.query>overlay.getRandomPeers`) and its size
+pub struct PayloadDump<'a>(pub &'a [u8]);
+
+impl fmt::Display for PayloadDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bytes)", ConstructorDump(self.0), self.0.len())
+    }
+}
+
+/// Payload constructors chain (e.g. `overlay.query>overlay.getRandomPeers`)
+pub struct ConstructorDump<'a>(pub &'a [u8]);
+
+impl fmt::Display for ConstructorDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Constructor id and overlay id
+        const OVERLAY_PREFIX_LEN: usize = 4 + 32;
+
+        let mut offset = 0;
+        while let Ok(id) = u32::read_from(self.0, &mut std::convert::identity(offset)) {
+            if offset > 0 {
+                f.write_char('>')?;
+            }
+            match constructor_name(id) {
+                Some(name) => f.write_str(name)?,
+                None => write!(f, "#{id:08x}")?,
+            }
+
+            // Prefixed queries and messages
+            if id == OVERLAY_QUERY_ID || id == OVERLAY_MESSAGE_ID {
+                offset += OVERLAY_PREFIX_LEN;
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Payload constructors chain of the ADNL message (e.g. `dht.findValue`)
+/// or the message kind if it has no payload (e.g. `nop`)
+pub struct MessageConstructorDump<'a, 'tl>(pub &'a adnl::Message<'tl>);
+
+impl fmt::Display for MessageConstructorDump<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self.0 {
+            adnl::Message::Answer { answer: data, .. }
+            | adnl::Message::Custom { data }
+            | adnl::Message::Query { query: data, .. }
+            | adnl::Message::Part {
+                offset: 0, data, ..
+            } => ConstructorDump(data).fmt(f),
+            adnl::Message::ConfirmChannel { .. } => f.write_str("confirmChannel"),
+            adnl::Message::CreateChannel { .. } => f.write_str("createChannel"),
+            adnl::Message::Part { .. } => f.write_str("part"),
+            adnl::Message::Nop => f.write_str("nop"),
+            adnl::Message::Reinit { .. } => f.write_str("reinit"),
+        }
+    }
+}
+
+const OVERLAY_QUERY_ID: u32 = tl_proto::id!("overlay.query", scheme = "scheme.tl");
+const OVERLAY_MESSAGE_ID: u32 = tl_proto::id!("overlay.message", scheme = "scheme.tl");
+
+macro_rules! constructors {
+    ($($name:literal),*$(,)?) => {
+        &[$((tl_proto::id!($name, scheme = "scheme.tl"), $name)),*]
+    };
+}
+
+static CONSTRUCTORS: &[(u32, &str)] = constructors![
+    "adnl.packetContents",
+    "adnl.address.udp",
+    "adnl.address.udp6",
+    "adnl.addressList",
+    "adnl.id.short",
+    "adnl.message.answer",
+    "adnl.message.confirmChannel",
+    "adnl.message.createChannel",
+    "adnl.message.custom",
+    "adnl.message.nop",
+    "adnl.message.part",
+    "adnl.message.query",
+    "adnl.message.reinit",
+    "adnl.node",
+    "adnl.nodes",
+    "adnl.ping",
+    "adnl.pong",
+    "dht.findNode",
+    "dht.findValue",
+    "dht.getSignedAddressList",
+    "dht.key",
+    "dht.keyDescription",
+    "dht.message",
+    "dht.node",
+    "dht.nodes",
+    "dht.ping",
+    "dht.pong",
+    "dht.query",
+    "dht.store",
+    "dht.stored",
+    "dht.updateRule.anybody",
+    "dht.updateRule.overlayNodes",
+    "dht.updateRule.signature",
+    "dht.value",
+    "dht.valueFound",
+    "dht.valueNotFound",
+    "fec.raptorQ",
+    "overlay.broadcast",
+    "overlay.broadcastFec",
+    "overlay.broadcastFecShort",
+    "overlay.broadcastNotFound",
+    "overlay.certificate",
+    "overlay.emptyCertificate",
+    "overlay.fec.completed",
+    "overlay.fec.received",
+    "overlay.getRandomPeers",
+    "overlay.message",
+    "overlay.node",
+    "overlay.nodes",
+    "overlay.query",
+    "overlay.unicast",
+    "pub.aes",
+    "pub.ed25519",
+    "pub.overlay",
+    "rldp.answer",
+    "rldp.complete",
+    "rldp.confirm",
+    "rldp.message",
+    "rldp.messagePart",
+    "rldp.query",
+];
+
+#[cfg(test)]
+mod tests {
+    use smallvec::smallvec;
+    use tl_proto::BoxedConstructor;
+
+    use super::*;
+    use crate::proto;
+
+    #[test]
+    fn known_constructors() {
+        assert_eq!(
+            constructor_name(proto::dht::Nodes::TL_ID),
+            Some("dht.nodes")
+        );
+        assert_eq!(constructor_name(0), None);
+    }
+
+    #[test]
+    fn packet_dump() {
+        let mut query = tl_proto::serialize(proto::rpc::OverlayQuery { overlay: &[1; 32] });
+        query.extend_from_slice(&tl_proto::serialize(proto::rpc::AdnlPing { value: 1 }));
+
+        let packet = adnl::IncomingPacketContents {
+            from: None,
+            from_short: None,
+            messages: smallvec![
+                adnl::Message::Query {
+                    query_id: &[2; 32],
+                    query: &query,
+                },
+                adnl::Message::Nop,
+            ],
+            address: None,
+            seqno: Some(10),
+            confirm_seqno: Some(5),
+            reinit_dates: None,
+            signature: None,
+        };
+
+        assert_eq!(
+            PacketDump(&packet).to_string(),
+            format!(
+                "adnl.packetContents seqno=10 confirm_seqno=5 messages=[query(query_id={}, \
+                overlay.query>adnl.ping (48 bytes)), nop]",
+                hex::encode([2; 32])
+            )
+        );
+    }
+}
@@ -2,27 +2,38 @@ use bytes::Bytes;
 use smallvec::SmallVec;
 use tl_proto::{BoxedConstructor, BoxedWrapper, TlRead, TlWrite};
 
+#[cfg(feature = "serde")]
+use super::serde_helpers::*;
 use super::{adnl, HashRef};
 
 #[derive(TlRead)]
 #[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum ValueResult<'tl> {
     #[tl(id = "dht.valueFound")]
-    ValueFound(BoxedWrapper<Value<'tl>>),
+    ValueFound(
+        #[cfg_attr(feature = "serde", serde(with = "boxed_wrapper"))] BoxedWrapper<Value<'tl>>,
+    ),
     #[tl(id = "dht.valueNotFound")]
     ValueNotFound(NodesOwned),
 }
 
 #[derive(TlWrite)]
 #[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum ValueResultOwned {
     #[tl(id = "dht.valueFound")]
-    ValueFound(BoxedWrapper<ValueOwned>),
+    ValueFound(
+        #[cfg_attr(feature = "serde", serde(with = "boxed_wrapper"))] BoxedWrapper<ValueOwned>,
+    ),
     #[tl(id = "dht.valueNotFound")]
     ValueNotFound(NodesOwned),
 }
 
 #[derive(TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Nodes<'tl> {
     pub nodes: SmallVec<[Node<'tl>; 5]>,
 }
@@ -32,6 +43,7 @@ impl BoxedConstructor for Nodes<'_> {
 }
 
 #[derive(TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodesOwned {
     pub nodes: Vec<NodeOwned>,
 }
@@ -41,10 +53,13 @@ impl BoxedConstructor for NodesOwned {
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Node<'tl> {
+    #[cfg_attr(feature = "serde", serde(with = "public_key"))]
     pub id: everscale_crypto::tl::PublicKey<'tl>,
     pub addr_list: adnl::AddressList,
     pub version: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: &'tl [u8],
 }
 
@@ -64,10 +79,13 @@ impl Node<'_> {
 }
 
 #[derive(Debug, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeOwned {
+    #[cfg_attr(feature = "serde", serde(with = "public_key_owned"))]
     pub id: everscale_crypto::tl::PublicKeyOwned,
     pub addr_list: adnl::AddressList,
     pub version: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: Bytes,
 }
 
@@ -87,10 +105,13 @@ impl NodeOwned {
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Value<'tl> {
     pub key: KeyDescription<'tl>,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub value: &'tl [u8],
     pub ttl: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: &'tl [u8],
 }
 
@@ -110,10 +131,13 @@ impl Value<'_> {
 }
 
 #[derive(Debug, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ValueOwned {
     pub key: KeyDescriptionOwned,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub value: Bytes,
     pub ttl: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: Bytes,
 }
 
@@ -133,10 +157,13 @@ impl ValueOwned {
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct KeyDescription<'tl> {
     pub key: Key<'tl>,
+    #[cfg_attr(feature = "serde", serde(with = "public_key"))]
     pub id: everscale_crypto::tl::PublicKey<'tl>,
     pub update_rule: UpdateRule,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: &'tl [u8],
 }
 
@@ -156,10 +183,13 @@ impl KeyDescription<'_> {
 }
 
 #[derive(Debug, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeyDescriptionOwned {
     pub key: KeyOwned,
+    #[cfg_attr(feature = "serde", serde(with = "public_key_owned"))]
     pub id: everscale_crypto::tl::PublicKeyOwned,
     pub update_rule: UpdateRule,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: Bytes,
 }
 
@@ -179,9 +209,12 @@ impl KeyDescriptionOwned {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Key<'tl> {
     #[tl(size_hint = 32)]
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub id: HashRef<'tl>,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub name: &'tl [u8],
     pub idx: u32,
 }
@@ -201,9 +234,12 @@ impl Key<'_> {
 }
 
 #[derive(Debug, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct KeyOwned {
     #[tl(size_hint = 32)]
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub id: [u8; 32],
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub name: Bytes,
     pub idx: u32,
 }
@@ -224,6 +260,8 @@ impl KeyOwned {
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TlWrite, TlRead)]
 #[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum UpdateRule {
     #[tl(id = "dht.updateRule.anybody", size_hint = 0)]
     Anybody,
@@ -235,10 +273,12 @@ pub enum UpdateRule {
 
 #[derive(Copy, Clone, TlWrite, TlRead)]
 #[tl(boxed, id = "dht.pong", size_hint = 8, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pong {
     pub random_id: u64,
 }
 
 #[derive(Copy, Clone, TlWrite, TlRead)]
 #[tl(boxed, id = "dht.stored", size_hint = 0, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Stored;
@@ -3,9 +3,12 @@
 #![allow(clippy::enum_variant_names)]
 
 pub mod adnl;
+pub mod debug;
 pub mod dht;
 pub mod overlay;
 pub mod rldp;
 pub mod rpc;
+#[cfg(feature = "serde")]
+mod serde_helpers;
 
 pub type HashRef<'a> = &'a [u8; 32];
@@ -2,9 +2,12 @@ use bytes::Bytes;
 use smallvec::SmallVec;
 use tl_proto::{BoxedConstructor, TlRead, TlWrite};
 
+#[cfg(feature = "serde")]
+use super::serde_helpers::*;
 use super::{rldp, HashRef};
 
 #[derive(TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Nodes<'tl> {
     pub nodes: SmallVec<[Node<'tl>; 5]>,
 }
@@ -14,6 +17,7 @@ impl BoxedConstructor for Nodes<'_> {
 }
 
 #[derive(Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodesOwned {
     pub nodes: SmallVec<[NodeOwned; 5]>,
 }
@@ -23,12 +27,16 @@ impl BoxedConstructor for NodesOwned {
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Node<'tl> {
+    #[cfg_attr(feature = "serde", serde(with = "public_key"))]
     pub id: everscale_crypto::tl::PublicKey<'tl>,
     #[tl(size_hint = 32)]
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub overlay: HashRef<'tl>,
     #[tl(size_hint = 4)]
     pub version: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: &'tl [u8],
 }
 
@@ -44,10 +52,14 @@ impl Node<'_> {
 }
 
 #[derive(Debug, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NodeOwned {
+    #[cfg_attr(feature = "serde", serde(with = "public_key_owned"))]
     pub id: everscale_crypto::tl::PublicKeyOwned,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub overlay: [u8; 32],
     pub version: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: Bytes,
 }
 
@@ -106,12 +118,16 @@ where
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
 #[tl(boxed, id = "overlay.message", scheme = "scheme.tl", size_hint = 32)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Message<'tl> {
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub overlay: HashRef<'tl>,
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
 #[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Broadcast<'tl> {
     #[tl(id = "overlay.broadcast")]
     Broadcast(OverlayBroadcast<'tl>),
@@ -119,60 +135,156 @@ pub enum Broadcast<'tl> {
     BroadcastFec(OverlayBroadcastFec<'tl>),
     #[tl(id = "overlay.broadcastFecShort")]
     BroadcastFecShort {
+        #[cfg_attr(feature = "serde", serde(with = "public_key"))]
         src: everscale_crypto::tl::PublicKey<'tl>,
         certificate: Certificate<'tl>,
         #[tl(size_hint = 32)]
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         broadcast_hash: HashRef<'tl>,
         #[tl(size_hint = 32)]
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         part_data_hash: HashRef<'tl>,
         seqno: u32,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         signature: &'tl [u8],
     },
     #[tl(id = "overlay.broadcastNotFound", size_hint = 0)]
     BroadcastNotFound,
     #[tl(id = "overlay.fec.completed", size_hint = 32)]
-    FecCompleted { hash: HashRef<'tl> },
+    FecCompleted {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        hash: HashRef<'tl>,
+    },
     #[tl(id = "overlay.fec.received", size_hint = 32)]
-    FecReceived { hash: HashRef<'tl> },
+    FecReceived {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        hash: HashRef<'tl>,
+    },
     #[tl(id = "overlay.unicast")]
-    Unicast { data: &'tl [u8] },
+    Unicast {
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        data: &'tl [u8],
+    },
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct OverlayBroadcast<'tl> {
+    #[cfg_attr(feature = "serde", serde(with = "public_key"))]
     pub src: everscale_crypto::tl::PublicKey<'tl>,
     pub certificate: Certificate<'tl>,
     pub flags: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub data: &'tl [u8],
     pub date: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: &'tl [u8],
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct OverlayBroadcastFec<'tl> {
+    #[cfg_attr(feature = "serde", serde(with = "public_key"))]
     pub src: everscale_crypto::tl::PublicKey<'tl>,
     pub certificate: Certificate<'tl>,
     #[tl(size_hint = 32)]
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub data_hash: HashRef<'tl>,
     pub data_size: u32,
     pub flags: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub data: &'tl [u8],
     pub seqno: u32,
     pub fec: rldp::RaptorQFecType,
     pub date: u32,
+    #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
     pub signature: &'tl [u8],
 }
 
 #[derive(Debug, Copy, Clone, TlWrite, TlRead)]
 #[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Certificate<'tl> {
     #[tl(id = "overlay.certificate")]
     Certificate {
+        #[cfg_attr(feature = "serde", serde(with = "public_key"))]
         issued_by: everscale_crypto::tl::PublicKey<'tl>,
         expire_at: u32,
         max_size: u32,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
         signature: &'tl [u8],
     },
     #[tl(id = "overlay.emptyCertificate", size_hint = 0)]
     EmptyCertificate,
 }
+
+impl Certificate<'_> {
+    pub fn as_equivalent_owned(&self) -> CertificateOwned {
+        match *self {
+            Self::Certificate {
+                issued_by,
+                expire_at,
+                max_size,
+                signature,
+            } => CertificateOwned::Certificate {
+                issued_by: issued_by.as_equivalent_owned(),
+                expire_at,
+                max_size,
+                signature: signature.to_vec().into(),
+            },
+            Self::EmptyCertificate => CertificateOwned::EmptyCertificate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, TlWrite, TlRead)]
+#[tl(boxed, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CertificateOwned {
+    #[tl(id = "overlay.certificate")]
+    Certificate {
+        #[cfg_attr(feature = "serde", serde(with = "public_key_owned"))]
+        issued_by: everscale_crypto::tl::PublicKeyOwned,
+        expire_at: u32,
+        max_size: u32,
+        #[cfg_attr(feature = "serde", serde(with = "hex_bytes"))]
+        signature: Bytes,
+    },
+    #[tl(id = "overlay.emptyCertificate", size_hint = 0)]
+    EmptyCertificate,
+}
+
+impl CertificateOwned {
+    pub fn as_equivalent_ref(&self) -> Certificate<'_> {
+        match self {
+            Self::Certificate {
+                issued_by,
+                expire_at,
+                max_size,
+                signature,
+            } => Certificate::Certificate {
+                issued_by: issued_by.as_equivalent_ref(),
+                expire_at: *expire_at,
+                max_size: *max_size,
+                signature,
+            },
+            Self::EmptyCertificate => Certificate::EmptyCertificate,
+        }
+    }
+}
+
+#[derive(TlWrite)]
+#[tl(
+    boxed,
+    id = "overlay.certificateId",
+    scheme = "scheme.tl",
+    size_hint = 72
+)]
+pub struct CertificateId<'tl> {
+    pub overlay_id: HashRef<'tl>,
+    pub node: HashRef<'tl>,
+    pub expire_at: u32,
+    pub max_size: u32,
+}
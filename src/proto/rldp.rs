@@ -55,6 +55,7 @@ pub enum MessagePart<'tl> {
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, TlRead, TlWrite)]
 #[tl(boxed, id = "fec.raptorQ", size_hint = 12, scheme = "scheme.tl")]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RaptorQFecType {
     pub total_len: u32,
     pub packet_len: u32,
@@ -0,0 +1,203 @@
+//! Serde helpers for the TL models.
+//!
+//! Bytes are represented as hex strings, public keys as internally tagged objects.
+
+use std::borrow::Cow;
+
+use everscale_crypto::tl;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tl_proto::BoxedWrapper;
+
+pub mod hex_bytes {
+    use super::*;
+
+    pub fn serialize<T, S>(data: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]> + ?Sized,
+        S: Serializer,
+    {
+        serializer.serialize_str(&hex::encode(data.as_ref()))
+    }
+
+    pub fn serialize_opt<T, S>(data: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: Serializer,
+    {
+        match data {
+            Some(data) => serialize(data, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: TryFrom<Vec<u8>>,
+        D: Deserializer<'de>,
+    {
+        let data = String::deserialize(deserializer)?;
+        hex::decode(data)
+            .map_err(Error::custom)?
+            .try_into()
+            .map_err(|_| Error::custom("invalid bytes length"))
+    }
+}
+
+pub mod public_key {
+    use super::*;
+
+    pub fn serialize<S>(key: &tl::PublicKey<'_>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        PublicKeyRepr::from(key).serialize(serializer)
+    }
+
+    pub fn serialize_opt<S>(
+        key: &Option<tl::PublicKey<'_>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match key {
+            Some(key) => serialize(key, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+}
+
+pub mod public_key_owned {
+    use super::*;
+
+    pub fn serialize<S>(key: &tl::PublicKeyOwned, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        PublicKeyRepr::from(&key.as_equivalent_ref()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<tl::PublicKeyOwned, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        fn to_key<E: Error>(key: Cow<'_, [u8]>) -> Result<[u8; 32], E> {
+            key.as_ref()
+                .try_into()
+                .map_err(|_| Error::custom("invalid key length"))
+        }
+
+        Ok(match PublicKeyRepr::deserialize(deserializer)? {
+            PublicKeyRepr::Ed25519 { key } => tl::PublicKeyOwned::Ed25519 { key: to_key(key)? },
+            PublicKeyRepr::Overlay { name } => tl::PublicKeyOwned::Overlay {
+                name: name.into_owned(),
+            },
+            PublicKeyRepr::Aes { key } => tl::PublicKeyOwned::Aes { key: to_key(key)? },
+            PublicKeyRepr::Unencoded { data } => tl::PublicKeyOwned::Unencoded {
+                data: data.into_owned(),
+            },
+        })
+    }
+}
+
+pub mod boxed_wrapper {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &BoxedWrapper<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize,
+        S: Serializer,
+    {
+        value.0.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<BoxedWrapper<T>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        T::deserialize(deserializer).map(BoxedWrapper)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PublicKeyRepr<'a> {
+    Ed25519 {
+        #[serde(with = "hex_bytes")]
+        key: Cow<'a, [u8]>,
+    },
+    Overlay {
+        #[serde(with = "hex_bytes")]
+        name: Cow<'a, [u8]>,
+    },
+    Aes {
+        #[serde(with = "hex_bytes")]
+        key: Cow<'a, [u8]>,
+    },
+    Unencoded {
+        #[serde(with = "hex_bytes")]
+        data: Cow<'a, [u8]>,
+    },
+}
+
+impl<'a> From<&tl::PublicKey<'a>> for PublicKeyRepr<'a> {
+    fn from(key: &tl::PublicKey<'a>) -> Self {
+        match *key {
+            tl::PublicKey::Ed25519 { key } => Self::Ed25519 {
+                key: Cow::Borrowed(key),
+            },
+            tl::PublicKey::Overlay { name } => Self::Overlay {
+                name: Cow::Borrowed(name),
+            },
+            tl::PublicKey::Aes { key } => Self::Aes {
+                key: Cow::Borrowed(key),
+            },
+            tl::PublicKey::Unencoded { data } => Self::Unencoded {
+                data: Cow::Borrowed(data),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto;
+
+    #[test]
+    fn json_roundtrip() {
+        let value = proto::dht::ValueOwned {
+            key: proto::dht::KeyDescriptionOwned {
+                key: proto::dht::KeyOwned {
+                    id: [1; 32],
+                    name: b"address".to_vec().into(),
+                    idx: 0,
+                },
+                id: everscale_crypto::tl::PublicKeyOwned::Ed25519 { key: [2; 32] },
+                update_rule: proto::dht::UpdateRule::Signature,
+                signature: vec![3; 64].into(),
+            },
+            value: vec![4; 10].into(),
+            ttl: 123,
+            signature: vec![5; 64].into(),
+        };
+
+        let json = serde_json::to_value(&value).unwrap();
+        assert_eq!(json["key"]["id"]["type"], "ed25519");
+        assert_eq!(json["key"]["key"]["name"], hex::encode(b"address"));
+        assert_eq!(json["key"]["update_rule"], "signature");
+
+        // Borrowed and owned models have the same representation
+        assert_eq!(
+            serde_json::to_value(value.as_equivalent_ref()).unwrap(),
+            json
+        );
+
+        let parsed: proto::dht::ValueOwned = serde_json::from_value(json).unwrap();
+        assert_eq!(
+            tl_proto::serialize(parsed.as_equivalent_ref()),
+            tl_proto::serialize(value.as_equivalent_ref())
+        );
+    }
+}
@@ -1,8 +1,17 @@
 pub fn compress(data: &mut Vec<u8>) -> std::io::Result<()> {
-    let uncompressed = data.len();
-    if uncompressed <= COMPRESSION_THRESHOLD {
+    if data.len() <= COMPRESSION_THRESHOLD {
         return Ok(());
     }
+    compress_always(data)
+}
+
+/// Compresses data with zstd regardless of its size.
+///
+/// Used for RLDP queries when `force_compression` is enabled. The trailing
+/// tag byte tells the remote peer that the query was compressed, so it
+/// compresses the answer as well.
+pub fn compress_always(data: &mut Vec<u8>) -> std::io::Result<()> {
+    let uncompressed = data.len();
 
     let mut result = Vec::with_capacity(data.len() + 1);
     ok!(zstd::stream::copy_encode(
@@ -21,30 +30,43 @@ pub fn compress(data: &mut Vec<u8>) -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+/// Decompresses data if it is marked as compressed.
+///
+/// Returns `None` if the data is not compressed (or is invalid). Decompression
+/// stops as soon as the output exceeds `max_len`, and an error is returned
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Option<Vec<u8>>, CompressionError> {
+    use std::io::Read;
+
     if data.last() != Some(&TAG_COMPRESSED) {
-        return None;
+        return Ok(None);
+    }
+
+    let decoder = match zstd::stream::read::Decoder::with_buffer(&data[..data.len() - 1]) {
+        Ok(decoder) => decoder,
+        Err(_) => return Ok(None),
+    };
+
+    // NOTE: decompressed data is followed by its 4-byte length
+    let limit = max_len.saturating_add(4);
+    let mut data = Vec::new();
+    match decoder.take(limit as u64 + 1).read_to_end(&mut data) {
+        Ok(_) if data.len() > limit => return Err(CompressionError::TooBig),
+        Ok(_) if data.len() >= 4 => {}
+        _ => return Ok(None),
     }
 
     let len = data.len();
-    match zstd::stream::decode_all(&mut &data[..len - 1]) {
-        Ok(mut data) if data.len() >= 4 => {
-            let len = data.len();
-
-            let src_len = ((data[len - 4] as usize) << 24)
-                | ((data[len - 3] as usize) << 16)
-                | ((data[len - 2] as usize) << 8)
-                | (data[len - 1] as usize);
-
-            if src_len != len - 4 {
-                return None;
-            }
-
-            data.truncate(src_len);
-            Some(data)
-        }
-        _ => None,
+    let src_len = ((data[len - 4] as usize) << 24)
+        | ((data[len - 3] as usize) << 16)
+        | ((data[len - 2] as usize) << 8)
+        | (data[len - 1] as usize);
+
+    if src_len != len - 4 {
+        return Ok(None);
     }
+
+    data.truncate(src_len);
+    Ok(Some(data))
 }
 
 const COMPRESSION_THRESHOLD: usize = 256;
@@ -52,6 +74,12 @@ const COMPRESSION_LEVEL: i32 = 3;
 
 const TAG_COMPRESSED: u8 = 0x80;
 
+#[derive(thiserror::Error, Debug)]
+pub enum CompressionError {
+    #[error("Decompressed data is too big")]
+    TooBig,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,7 +94,38 @@ mod tests {
         let mut compressed = data.clone();
         compress(&mut compressed).unwrap();
 
-        let decompressed = decompress(&compressed).unwrap();
+        let decompressed = decompress(&compressed, data.len()).unwrap().unwrap();
         assert_eq!(decompressed, data);
     }
+
+    #[test]
+    fn bounded_decompression() {
+        let data = vec![0u8; 1 << 20];
+
+        let mut compressed = data.clone();
+        compress(&mut compressed).unwrap();
+        assert!(compressed.len() < 1024);
+
+        assert_eq!(decompress(&compressed, data.len()).unwrap().unwrap(), data);
+        assert!(matches!(
+            decompress(&compressed, data.len() - 1),
+            Err(CompressionError::TooBig)
+        ));
+
+        // Uncompressed data is returned as is
+        assert!(decompress(&[1, 2, 3], 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn small_data_compression() {
+        let data = vec![1, 2, 3, 4];
+
+        let mut compressed = data.clone();
+        compress(&mut compressed).unwrap();
+        assert_eq!(compressed, data);
+
+        compress_always(&mut compressed).unwrap();
+        assert_ne!(compressed, data);
+        assert_eq!(decompress(&compressed, data.len()).unwrap().unwrap(), data);
+    }
 }
@@ -10,7 +10,7 @@ use crate::proto;
 pub struct IncomingTransfer {
     buffer: Vec<u8>,
     transfer_id: TransferId,
-    max_answer_size: u32,
+    max_size: u32,
     confirm_count: usize,
     data: Vec<u8>,
     decoder: Option<RaptorQDecoder>,
@@ -20,11 +20,11 @@ pub struct IncomingTransfer {
 }
 
 impl IncomingTransfer {
-    pub fn new(transfer_id: TransferId, max_answer_size: u32) -> Self {
+    pub fn new(transfer_id: TransferId, max_size: u32) -> Self {
         Self {
             buffer: Vec::new(),
             transfer_id,
-            max_answer_size,
+            max_size,
             confirm_count: 0,
             data: Vec::new(),
             decoder: None,
@@ -62,11 +62,12 @@ impl IncomingTransfer {
             Some(total_size) => total_size,
             None => {
                 let total_size = message.total_size as usize;
-                if total_size > self.max_answer_size as usize {
+                if total_size > self.max_size as usize {
                     return Err(IncomingTransferError::TooBigTransferSize.into());
                 }
+                // NOTE: memory is not reserved for the declared size,
+                // data buffer grows as the parts are decoded
                 self.total_size = Some(total_size);
-                self.data.reserve_exact(total_size);
                 total_size
             }
         };
@@ -14,7 +14,7 @@ use frunk_core::indices::{Here, There};
 
 pub(crate) use decoder::RaptorQDecoder;
 pub(crate) use encoder::RaptorQEncoder;
-pub use node::{Node, NodeMetrics, NodeOptions};
+pub use node::{Node, NodeMetrics, NodeOptions, QueryOptions};
 
 use crate::adnl;
 use crate::subscriber::QuerySubscriber;
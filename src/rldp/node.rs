@@ -1,8 +1,10 @@
 use std::sync::Arc;
+use std::time::Instant;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 
 use super::compression;
 use super::transfers_cache::*;
@@ -21,6 +23,12 @@ pub struct NodeOptions {
     /// Default: `10485760` (10 MB)
     pub max_answer_size: u32,
 
+    /// Max allowed size of the incoming RLDP query in bytes.
+    /// Incoming transfer will be rejected if the query is bigger.
+    ///
+    /// Default: `10485760` (10 MB)
+    pub max_query_size: u32,
+
     /// Max parallel RLDP queries per peer.
     ///
     /// Default: `16`
@@ -46,7 +54,33 @@ pub struct NodeOptions {
     /// Default: `10` ms
     pub query_wave_interval_ms: u64,
 
-    /// Whether requests will be compressed.
+    /// Max number of sent but not yet confirmed packets of the transfer.
+    /// Sending is paused until the peer confirms the received packets.
+    ///
+    /// Default: `1000`
+    pub max_unconfirmed_packets: u32,
+
+    /// Max number of concurrent incoming transfers (queries from other nodes).
+    /// Excess transfers are rejected on the first received part.
+    ///
+    /// Default: `1024`
+    pub max_incoming_transfers: usize,
+
+    /// Max number of concurrent incoming transfers from the same peer.
+    ///
+    /// Default: `16`
+    pub max_peer_incoming_transfers: usize,
+
+    /// Max total size of all concurrent incoming transfers.
+    ///
+    /// Default: `256` MiB
+    pub incoming_transfers_memory_limit: usize,
+
+    /// Whether requests and answers will be compressed.
+    ///
+    /// When enabled, outgoing queries are compressed regardless of their size,
+    /// so the remote peer compresses the answer even if it doesn't force
+    /// compression itself.
     ///
     /// Default: `false`
     pub force_compression: bool,
@@ -56,16 +90,51 @@ impl Default for NodeOptions {
     fn default() -> Self {
         Self {
             max_answer_size: 10 * 1024 * 1024,
+            max_query_size: 10 * 1024 * 1024,
             max_peer_queries: 16,
             query_min_timeout_ms: 500,
             query_max_timeout_ms: 10000,
             query_wave_len: 10,
             query_wave_interval_ms: 10,
+            max_unconfirmed_packets: 1000,
+            max_incoming_transfers: 1024,
+            max_peer_incoming_transfers: 16,
+            incoming_transfers_memory_limit: 256 << 20,
             force_compression: false,
         }
     }
 }
 
+/// RLDP query settings
+///
+/// See [`Node::query_with_options`]
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptions {
+    /// Measured roundtrip of the peer, used to compute the query timeout.
+    ///
+    /// Default: None
+    pub roundtrip: Option<u64>,
+
+    /// Max allowed answer size in bytes.
+    /// [`NodeOptions::max_answer_size`] is used if not specified.
+    ///
+    /// Default: None
+    pub max_answer_size: Option<u32>,
+
+    /// Absolute time after which the query is considered failed.
+    ///
+    /// Default: None
+    pub deadline: Option<Instant>,
+
+    /// Token to cancel the query. Both transfers are stopped and the peer
+    /// receives `complete` for the remaining answer parts.
+    ///
+    /// NOTE: Dropping the query future has the same effect.
+    ///
+    /// Default: None
+    pub cancellation_token: Option<CancellationToken>,
+}
+
 /// Reliable UDP transport layer
 pub struct Node {
     /// Underlying ADNL node
@@ -112,6 +181,9 @@ impl Node {
         NodeMetrics {
             peer_count: self.semaphores.len(),
             transfers_cache_len: self.transfers.len(),
+            incoming_transfers: self.transfers.incoming_transfers(),
+            incoming_transfers_memory: self.transfers.incoming_transfers_memory(),
+            rejected_incoming_transfers: self.transfers.rejected_incoming_transfers(),
         }
     }
 
@@ -122,7 +194,10 @@ impl Node {
             .retain(|_, semaphore| semaphore.available_permits() < max_permits);
     }
 
-    #[tracing::instrument(level = "debug", name = "rldp_query", skip_all, fields(%local_id, %peer_id, ?roundtrip))]
+    /// Sends RLDP query to the remote peer and waits for the answer.
+    /// Returns the answer (`None` in case of timeout) and the updated roundtrip.
+    ///
+    /// See [`NodeOptions::max_answer_size`]
     pub async fn query(
         &self,
         local_id: &adnl::NodeIdShort,
@@ -130,7 +205,59 @@ impl Node {
         data: Vec<u8>,
         roundtrip: Option<u64>,
     ) -> Result<(Option<Vec<u8>>, u64)> {
-        let (query_id, query) = self.make_query(data);
+        let options = QueryOptions {
+            roundtrip,
+            ..Default::default()
+        };
+        self.query_with_options(local_id, peer_id, data, options)
+            .await
+    }
+
+    /// Sends RLDP query to the remote peer with the explicit max answer size.
+    /// Bigger answers are rejected.
+    ///
+    /// See [`Node::query`]
+    pub async fn query_with_max_answer_size(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        data: Vec<u8>,
+        roundtrip: Option<u64>,
+        max_answer_size: u32,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let options = QueryOptions {
+            roundtrip,
+            max_answer_size: Some(max_answer_size),
+            ..Default::default()
+        };
+        self.query_with_options(local_id, peer_id, data, options)
+            .await
+    }
+
+    /// Sends RLDP query to the remote peer with the explicit settings.
+    ///
+    /// Returns `Ok(None)` answer if the deadline is reached
+    /// and an error if the query was cancelled.
+    ///
+    /// See [`Node::query`]
+    #[tracing::instrument(
+        level = "debug",
+        name = "rldp_query",
+        skip_all,
+        fields(%local_id, %peer_id, roundtrip = ?options.roundtrip)
+    )]
+    pub async fn query_with_options(
+        &self,
+        local_id: &adnl::NodeIdShort,
+        peer_id: &adnl::NodeIdShort,
+        data: Vec<u8>,
+        options: QueryOptions,
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let roundtrip = options.roundtrip;
+        let max_answer_size = options
+            .max_answer_size
+            .unwrap_or(self.options.max_answer_size);
+        let (query_id, query) = self.make_query(data, max_answer_size);
 
         let peer = self
             .semaphores
@@ -139,22 +266,52 @@ impl Node {
             .value()
             .clone();
 
-        let result = {
+        let query = async {
             let _permit = peer.acquire().await.ok();
             self.transfers
-                .query(&self.adnl, local_id, peer_id, query, roundtrip)
+                .query(
+                    &self.adnl,
+                    local_id,
+                    peer_id,
+                    query,
+                    roundtrip,
+                    max_answer_size,
+                )
                 .await
         };
 
+        let deadline = async {
+            match options.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                None => futures_util::future::pending().await,
+            }
+        };
+        let cancelled = async {
+            match &options.cancellation_token {
+                Some(token) => token.cancelled().await,
+                None => futures_util::future::pending().await,
+            }
+        };
+
+        // NOTE: transfers are cleared when the query future is dropped
+        let result = tokio::select! {
+            result = query => result,
+            _ = deadline => return Ok((None, roundtrip.unwrap_or_default())),
+            _ = cancelled => return Err(NodeError::QueryCancelled.into()),
+        };
+
         match result? {
             (Some(answer), roundtrip) => match tl_proto::deserialize(&answer) {
                 Ok(proto::rldp::Message::Answer {
                     query_id: answer_id,
                     data,
-                }) if answer_id == &query_id => Ok((
-                    Some(compression::decompress(data).unwrap_or_else(|| data.to_vec())),
-                    roundtrip,
-                )),
+                }) if answer_id == &query_id => {
+                    let answer = match compression::decompress(data, max_answer_size as usize)? {
+                        Some(decompressed) => decompressed,
+                        None => data.to_vec(),
+                    };
+                    Ok((Some(answer), roundtrip))
+                }
                 Ok(proto::rldp::Message::Answer { .. }) => Err(NodeError::QueryIdMismatch.into()),
                 Ok(proto::rldp::Message::Message { .. }) => {
                     Err(NodeError::UnexpectedAnswer("RldpMessageView::Message").into())
@@ -168,9 +325,9 @@ impl Node {
         }
     }
 
-    fn make_query(&self, mut data: Vec<u8>) -> ([u8; 32], Vec<u8>) {
+    fn make_query(&self, mut data: Vec<u8>, max_answer_size: u32) -> ([u8; 32], Vec<u8>) {
         if self.options.force_compression {
-            if let Err(e) = compression::compress(&mut data) {
+            if let Err(e) = compression::compress_always(&mut data) {
                 tracing::warn!("failed to compress RLDP query: {e:?}");
             }
         }
@@ -178,7 +335,7 @@ impl Node {
         let query_id = gen_fast_bytes();
         let data = proto::rldp::Message::Query {
             query_id: &query_id,
-            max_answer_size: self.options.max_answer_size as u64,
+            max_answer_size: max_answer_size as u64,
             timeout: now() + self.options.query_max_timeout_ms as u32 / 1000,
             data: &data,
         };
@@ -214,6 +371,12 @@ impl MessageSubscriber for TransfersCache {
 pub struct NodeMetrics {
     pub peer_count: usize,
     pub transfers_cache_len: usize,
+    /// Number of transfers which are being received
+    pub incoming_transfers: usize,
+    /// Memory reserved for the transfers which are being received
+    pub incoming_transfers_memory: usize,
+    /// Number of incoming transfers rejected due to the limits
+    pub rejected_incoming_transfers: u64,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -224,4 +387,6 @@ enum NodeError {
     InvalidPacketContent(tl_proto::TlError),
     #[error("Unknown query id")]
     QueryIdMismatch,
+    #[error("Query cancelled")]
+    QueryCancelled,
 }
@@ -6,27 +6,40 @@ use anyhow::Result;
 use super::encoder::*;
 use super::transfers_cache::TransferId;
 use crate::proto;
+use crate::subscriber::AnswerStream;
 use crate::util::*;
 
 pub struct OutgoingTransfer {
     buffer: Vec<u8>,
     transfer_id: TransferId,
-    data: Vec<u8>,
+    data: OutgoingData,
+    total_size: usize,
     current_message_part: u32,
     encoder: Option<RaptorQEncoder>,
+    /// Max number of sent but not confirmed packets
+    window: u32,
+    /// Max size of the message part
+    slice: usize,
     state: Arc<OutgoingTransferState>,
 }
 
 impl OutgoingTransfer {
-    pub fn new(data: Vec<u8>, transfer_id: Option<TransferId>) -> Self {
+    pub fn new<T>(data: T, transfer_id: Option<TransferId>, window: u32) -> Self
+    where
+        T: Into<OutgoingData>,
+    {
         let transfer_id = transfer_id.unwrap_or_else(gen_fast_bytes);
+        let data = data.into();
 
         Self {
             buffer: Vec::new(),
             transfer_id,
+            total_size: data.len(),
             data,
             current_message_part: 0,
             encoder: None,
+            window: std::cmp::max(window, 1),
+            slice: SLICE,
             state: Default::default(),
         }
     }
@@ -37,24 +50,27 @@ impl OutgoingTransfer {
     }
 
     /// Encodes next part of the message. Returns packet count which is required to be sent.
-    pub fn start_next_part(&mut self) -> Result<Option<u32>> {
+    pub async fn start_next_part(&mut self) -> Result<Option<u32>> {
         if self.is_finished() {
             return Ok(None);
         }
 
-        let total = self.data.len();
+        let total = self.total_size;
         let part = self.state.part() as usize;
-        let processed = part * SLICE;
+        let processed = part * self.slice;
         if processed >= total {
             return Ok(None);
         }
 
+        // NOTE: encoding symbol ids start from zero for each part
+        if self.encoder.is_some() {
+            self.state.reset_seqno();
+        }
         self.current_message_part = part as u32;
 
-        let chunk_size = std::cmp::min(total - processed, SLICE);
-        let encoder = self.encoder.insert(RaptorQEncoder::with_data(
-            &self.data[processed..processed + chunk_size],
-        ));
+        let chunk_size = std::cmp::min(total - processed, self.slice);
+        let chunk = ok!(self.data.read(processed, chunk_size).await);
+        let encoder = self.encoder.insert(RaptorQEncoder::with_data(chunk));
 
         let packet_count = encoder.params().packet_count;
         Ok(if packet_count > 0 {
@@ -78,7 +94,7 @@ impl OutgoingTransfer {
         let seqno_in = self.state.seqno_in();
 
         let mut next_seqno_out = seqno_out;
-        if seqno_out - seqno_in <= WINDOW {
+        if seqno_out - seqno_in <= self.window {
             if previous_seqno_out == seqno_out {
                 next_seqno_out += 1;
             }
@@ -90,7 +106,7 @@ impl OutgoingTransfer {
                 transfer_id: &self.transfer_id,
                 fec_type: *encoder.params(),
                 part: self.current_message_part,
-                total_size: self.data.len() as u64,
+                total_size: self.total_size as u64,
                 seqno: seqno_out,
                 data: &data,
             },
@@ -99,8 +115,14 @@ impl OutgoingTransfer {
         Ok(&self.buffer)
     }
 
+    /// Returns the number of packets which can be sent before the peer confirms the received ones
+    pub fn available_window(&self) -> u32 {
+        let unconfirmed = self.state.seqno_out().saturating_sub(self.state.seqno_in());
+        self.window.saturating_sub(unconfirmed)
+    }
+
     pub fn is_finished(&self) -> bool {
-        self.state.has_reply() && ((self.state.part() as usize + 1) * SLICE >= self.data.len())
+        self.state.has_reply() && ((self.state.part() as usize + 1) * self.slice >= self.total_size)
     }
 
     pub fn is_finished_or_next_part(&self, part: u32) -> Result<bool> {
@@ -120,6 +142,110 @@ impl OutgoingTransfer {
     }
 }
 
+/// Outgoing transfer data
+pub enum OutgoingData {
+    Full(Vec<u8>),
+    /// Data which is produced by chunks. Only the current part is kept in memory
+    Stream {
+        /// Serialized data before the stream
+        prefix: Vec<u8>,
+        stream: AnswerStream,
+        /// Zero bytes after the stream
+        padding: usize,
+        /// Current part of the data
+        part: Vec<u8>,
+    },
+}
+
+impl OutgoingData {
+    /// Frames the answer stream as a serialized `rldp.answer` message
+    pub fn answer_stream(query_id: &[u8; 32], stream: AnswerStream) -> Self {
+        let len = stream.total_len();
+
+        // NOTE: `rldp.answer` with empty data ends with 4 bytes of the empty `bytes`
+        let mut prefix = tl_proto::serialize(proto::rldp::Message::Answer {
+            query_id,
+            data: &[],
+        });
+        prefix.truncate(prefix.len() - 4);
+
+        let header_len = if len < 254 {
+            prefix.push(len as u8);
+            1
+        } else {
+            prefix.extend_from_slice(&[254, len as u8, (len >> 8) as u8, (len >> 16) as u8]);
+            4
+        };
+
+        Self::Stream {
+            prefix,
+            stream,
+            padding: (4 - (header_len + len) % 4) % 4,
+            part: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Full(data) => data.len(),
+            Self::Stream {
+                prefix,
+                stream,
+                padding,
+                ..
+            } => prefix.len() + stream.total_len() + padding,
+        }
+    }
+
+    /// Returns the next `len` bytes at the specified offset.
+    ///
+    /// NOTE: stream parts must be read sequentially
+    async fn read(&mut self, offset: usize, len: usize) -> Result<&[u8]> {
+        match self {
+            Self::Full(data) => Ok(&data[offset..offset + len]),
+            Self::Stream {
+                prefix,
+                stream,
+                padding,
+                part,
+            } => {
+                part.clear();
+                part.reserve_exact(len);
+
+                let mut remaining = len;
+
+                let prefix_len = std::cmp::min(remaining, prefix.len());
+                part.extend(prefix.drain(..prefix_len));
+                remaining -= prefix_len;
+
+                if stream.remaining() > 0 {
+                    let stream_len = std::cmp::min(remaining, stream.remaining());
+                    ok!(stream.read_into(part, stream_len).await);
+                    remaining -= stream_len;
+
+                    if stream.remaining() == 0 {
+                        ok!(stream.finish().await);
+                    }
+                }
+
+                if remaining > *padding {
+                    return Err(OutgoingTransferError::PartMismatch.into());
+                }
+                *padding -= remaining;
+                part.resize(len, 0);
+
+                Ok(part)
+            }
+        }
+    }
+}
+
+impl From<Vec<u8>> for OutgoingData {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Full(data)
+    }
+}
+
 #[derive(Default)]
 pub struct OutgoingTransferState {
     part: AtomicU32,
@@ -155,6 +281,12 @@ impl OutgoingTransferState {
         self.seqno_out.fetch_max(seqno, Ordering::Release);
     }
 
+    /// Resets the sent and confirmed packets of the previous part
+    pub fn reset_seqno(&self) {
+        self.seqno_in.store(0, Ordering::Release);
+        self.seqno_out.store(0, Ordering::Release);
+    }
+
     pub fn seqno_in(&self) -> u32 {
         self.seqno_in.load(Ordering::Acquire)
     }
@@ -167,7 +299,6 @@ impl OutgoingTransferState {
     }
 }
 
-const WINDOW: u32 = 1000;
 const SLICE: usize = 2000000;
 
 #[derive(thiserror::Error, Debug)]
@@ -177,3 +308,61 @@ enum OutgoingTransferError {
     #[error("Part mismatch")]
     PartMismatch,
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn window_is_reset_for_each_part() {
+        let mut transfer = OutgoingTransfer::new(vec![0xaa; 30000], None, 8);
+        transfer.slice = 10000;
+
+        for part in 0..3 {
+            assert!(transfer.start_next_part().await.unwrap().is_some());
+            assert_eq!(transfer.available_window(), 8);
+
+            // Peer doesn't confirm packets until the part is complete
+            while transfer.available_window() > 0 {
+                transfer.prepare_chunk().unwrap();
+            }
+
+            transfer.state().set_part(part + 1);
+        }
+        transfer.state().set_reply();
+        assert!(transfer.is_finished());
+    }
+
+    #[tokio::test]
+    async fn answer_stream_framing() {
+        let query_id = [0x11; 32];
+
+        for len in [0, 1, 3, 253, 254, 255, 1000, 100003] {
+            let answer = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let expected = tl_proto::serialize(proto::rldp::Message::Answer {
+                query_id: &query_id,
+                data: &answer,
+            });
+
+            let chunks = answer
+                .chunks(77)
+                .map(|chunk| anyhow::Ok(Bytes::copy_from_slice(chunk)))
+                .collect::<Vec<_>>();
+            let stream = AnswerStream::new(len, futures_util::stream::iter(chunks));
+
+            let mut data = OutgoingData::answer_stream(&query_id, stream);
+            assert_eq!(data.len(), expected.len());
+
+            let mut framed = Vec::new();
+            let mut offset = 0;
+            while offset < expected.len() {
+                let part_len = std::cmp::min(expected.len() - offset, 999);
+                framed.extend_from_slice(data.read(offset, part_len).await.unwrap());
+                offset += part_len;
+            }
+            assert_eq!(framed, expected);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -20,8 +21,10 @@ pub struct TransfersCache {
     transfers: Arc<FastDashMap<TransferId, RldpTransfer>>,
     subscribers: Arc<Vec<Arc<dyn QuerySubscriber>>>,
     query_options: QueryOptions,
-    max_answer_size: u32,
+    max_query_size: u32,
     force_compression: bool,
+    transfers_budget: Arc<adnl::TransfersBudget>,
+    rejected_transfers: AtomicU64,
 }
 
 impl TransfersCache {
@@ -32,11 +35,21 @@ impl TransfersCache {
             query_options: QueryOptions {
                 query_wave_len: options.query_wave_len,
                 query_wave_interval_ms: options.query_wave_interval_ms,
+                max_unconfirmed_packets: options.max_unconfirmed_packets,
                 query_min_timeout_ms: options.query_min_timeout_ms,
                 query_max_timeout_ms: options.query_max_timeout_ms,
             },
-            max_answer_size: options.max_answer_size,
+            max_query_size: options.max_query_size,
             force_compression: options.force_compression,
+            transfers_budget: Arc::new(
+                adnl::TransfersBudget::new(
+                    options.max_query_size as usize,
+                    options.max_peer_incoming_transfers,
+                    options.incoming_transfers_memory_limit,
+                )
+                .with_max_transfers(options.max_incoming_transfers),
+            ),
+            rejected_transfers: Default::default(),
         }
     }
 
@@ -48,9 +61,11 @@ impl TransfersCache {
         peer_id: &adnl::NodeIdShort,
         data: Vec<u8>,
         roundtrip: Option<u64>,
+        max_answer_size: u32,
     ) -> Result<(Option<Vec<u8>>, u64)> {
         // Initiate outgoing transfer with new id
-        let outgoing_transfer = OutgoingTransfer::new(data, None);
+        let outgoing_transfer =
+            OutgoingTransfer::new(data, None, self.query_options.max_unconfirmed_packets);
         let outgoing_transfer_id = *outgoing_transfer.transfer_id();
         let outgoing_transfer_state = outgoing_transfer.state().clone();
         self.transfers.insert(
@@ -60,12 +75,20 @@ impl TransfersCache {
 
         // Initiate incoming transfer with derived id
         let incoming_transfer_id = negate_id(outgoing_transfer_id);
-        let incoming_transfer = IncomingTransfer::new(incoming_transfer_id, self.max_answer_size);
+        let incoming_transfer = IncomingTransfer::new(incoming_transfer_id, max_answer_size);
         let incoming_transfer_state = incoming_transfer.state().clone();
         let (parts_tx, parts_rx) = mpsc::unbounded_channel();
         self.transfers
             .insert(incoming_transfer_id, RldpTransfer::Incoming(parts_tx));
 
+        // Stop and clear both transfers when the query is finished or dropped
+        let _guard = QueryTransfersGuard {
+            transfers: self.transfers.clone(),
+            outgoing_transfer_id,
+            incoming_transfer_id,
+            completion_interval: self.query_options.completion_interval(),
+        };
+
         // Prepare contexts
         let outgoing_context = OutgoingContext {
             adnl: adnl.clone(),
@@ -81,6 +104,7 @@ impl TransfersCache {
             parts_rx,
             transfer: incoming_transfer,
             transfer_id: outgoing_transfer_id,
+            permit: None,
         };
 
         // Start query transfer loop
@@ -98,14 +122,14 @@ impl TransfersCache {
         });
 
         // Send data and wait until something is received
-        let result = outgoing_context.send(self.query_options, roundtrip).await;
-        if result.is_ok() {
-            self.transfers
-                .insert(outgoing_transfer_id, RldpTransfer::Done);
-        }
+        let result = outgoing_context
+            .send(self.query_options, roundtrip, None)
+            .await?;
+        self.transfers
+            .insert(outgoing_transfer_id, RldpTransfer::Done);
 
-        let result = match result {
-            Ok((true, mut roundtrip)) => {
+        match result {
+            (true, mut roundtrip) => {
                 let mut start = Instant::now();
                 let mut updates = incoming_transfer_state.updates();
                 let mut timeout = self.query_options.compute_timeout(Some(roundtrip));
@@ -132,35 +156,24 @@ impl TransfersCache {
                     }
                 }
             }
-            Ok((false, roundtrip)) => Ok((None, roundtrip)),
-            Err(e) => {
-                // Reset transfer entries
-                self.transfers
-                    .insert(outgoing_transfer_id, RldpTransfer::Done);
-                Err(e)
-            }
-        };
+            (false, roundtrip) => Ok((None, roundtrip)),
+        }
+    }
 
-        self.transfers
-            .insert(incoming_transfer_id, RldpTransfer::Done);
+    pub fn len(&self) -> usize {
+        self.transfers.len()
+    }
 
-        // Clear transfers in background
-        tokio::spawn({
-            let transfers = self.transfers.clone();
-            let interval = self.query_options.completion_interval();
-            async move {
-                tokio::time::sleep(interval).await;
-                transfers.remove(&outgoing_transfer_id);
-                transfers.remove(&incoming_transfer_id);
-            }
-        });
+    pub fn incoming_transfers(&self) -> usize {
+        self.transfers_budget.transfers()
+    }
 
-        // Done
-        result
+    pub fn incoming_transfers_memory(&self) -> usize {
+        self.transfers_budget.memory_used()
     }
 
-    pub fn len(&self) -> usize {
-        self.transfers.len()
+    pub fn rejected_incoming_transfers(&self) -> u64 {
+        self.rejected_transfers.load(Ordering::Relaxed)
     }
 
     /// Handles incoming message
@@ -221,7 +234,7 @@ impl TransfersCache {
                     },
                     // If transfer doesn't exist (it is a query from other node)
                     None => match self
-                        .create_answer_handler(adnl, local_id, peer_id, *transfer_id)
+                        .create_answer_handler(adnl, local_id, peer_id, *transfer_id, total_size)
                         .await?
                     {
                         // Forward message part on `incoming` state (for newly created transfer)
@@ -273,15 +286,38 @@ impl TransfersCache {
         local_id: &adnl::NodeIdShort,
         peer_id: &adnl::NodeIdShort,
         transfer_id: TransferId,
+        total_size: u64,
     ) -> Result<Option<MessagePartsTx>> {
         use dashmap::mapref::entry::Entry;
 
-        let (parts_tx, parts_rx) = match self.transfers.entry(transfer_id) {
+        let started_at = Instant::now();
+        let (parts_tx, parts_rx, permit) = match self.transfers.entry(transfer_id) {
             // Create new transfer
             Entry::Vacant(entry) => {
+                let permit = match self
+                    .transfers_budget
+                    .try_acquire_lazy(peer_id, total_size.try_into().unwrap_or(usize::MAX))
+                {
+                    Ok(permit) => permit,
+                    Err(e) => {
+                        // Reject transfer. All its parts will be answered with `complete`
+                        entry.insert(RldpTransfer::Done);
+                        self.rejected_transfers.fetch_add(1, Ordering::Relaxed);
+                        tracing::debug!(%peer_id, total_size, "rejected incoming RLDP transfer: {e}");
+
+                        let transfers = self.transfers.clone();
+                        let interval = self.query_options.completion_interval();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(interval).await;
+                            transfers.remove(&transfer_id);
+                        });
+                        return Ok(None);
+                    }
+                };
+
                 let (parts_tx, parts_rx) = mpsc::unbounded_channel();
                 entry.insert(RldpTransfer::Incoming(parts_tx.clone()));
-                (parts_tx, parts_rx)
+                (parts_tx, parts_rx, permit)
             }
             // Or do nothing if it already exists
             Entry::Occupied(_) => return Ok(None),
@@ -293,14 +329,16 @@ impl TransfersCache {
             local_id: *local_id,
             peer_id: *peer_id,
             parts_rx,
-            transfer: IncomingTransfer::new(transfer_id, self.max_answer_size),
+            transfer: IncomingTransfer::new(transfer_id, self.max_query_size),
             transfer_id,
+            permit: Some(permit),
         };
 
         // Spawn processing task
         let subscribers = self.subscribers.clone();
         let transfers = self.transfers.clone();
         let query_options = self.query_options;
+        let max_query_size = self.max_query_size as usize;
         let force_compression = self.force_compression;
         tokio::spawn(async move {
             // Wait until incoming query is received
@@ -313,7 +351,9 @@ impl TransfersCache {
                     transfers.clone(),
                     subscribers,
                     query_options,
+                    max_query_size,
                     force_compression,
+                    started_at,
                 )
                 .await
                 .unwrap_or_default();
@@ -339,6 +379,42 @@ impl TransfersCache {
     }
 }
 
+/// Marks query transfers as done and removes them after the completion interval.
+///
+/// Incoming parts of the done transfers are answered with `complete`, so the peer
+/// stops sending the rest of the answer.
+struct QueryTransfersGuard {
+    transfers: Arc<FastDashMap<TransferId, RldpTransfer>>,
+    outgoing_transfer_id: TransferId,
+    incoming_transfer_id: TransferId,
+    completion_interval: Duration,
+}
+
+impl Drop for QueryTransfersGuard {
+    fn drop(&mut self) {
+        // NOTE: dropping the incoming parts sender stops the receiver task
+        self.transfers
+            .insert(self.outgoing_transfer_id, RldpTransfer::Done);
+        self.transfers
+            .insert(self.incoming_transfer_id, RldpTransfer::Done);
+
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => return,
+        };
+
+        let transfers = self.transfers.clone();
+        let ids = [self.outgoing_transfer_id, self.incoming_transfer_id];
+        let interval = self.completion_interval;
+        handle.spawn(async move {
+            tokio::time::sleep(interval).await;
+            for id in &ids {
+                transfers.remove(id);
+            }
+        });
+    }
+}
+
 enum RldpTransfer {
     Incoming(MessagePartsTx),
     Outgoing(Arc<OutgoingTransferState>),
@@ -352,6 +428,8 @@ struct IncomingContext {
     parts_rx: MessagePartsRx,
     transfer: IncomingTransfer,
     transfer_id: TransferId,
+    /// Memory reserved for the incoming query
+    permit: Option<adnl::TransferPermit>,
 }
 
 impl IncomingContext {
@@ -359,6 +437,8 @@ impl IncomingContext {
     async fn receive(&mut self, mut outgoing_transfer_state: Option<Arc<OutgoingTransferState>>) {
         // For each incoming message part
         while let Some(message) = self.parts_rx.recv().await {
+            let received = self.transfer.data().len();
+
             // Trying to process its data
             match self.transfer.process_chunk(message) {
                 // If some data was successfully processed
@@ -375,6 +455,15 @@ impl IncomingContext {
                 _ => {}
             }
 
+            // Reserve memory for the decoded data
+            if let Some(permit) = &mut self.permit {
+                if let Err(e) = permit.try_grow(self.transfer.data().len() - received) {
+                    tracing::debug!(peer_id = %self.peer_id, "RLDP transfer aborted: {e}");
+                    self.transfer.take_data();
+                    break;
+                }
+            }
+
             // Increase `updates` counter
             self.transfer.state().increase_updates();
 
@@ -406,7 +495,9 @@ impl IncomingContext {
         transfers: Arc<FastDashMap<TransferId, RldpTransfer>>,
         subscribers: Arc<Vec<Arc<dyn QuerySubscriber>>>,
         query_options: QueryOptions,
+        max_query_size: usize,
         force_compression: bool,
+        started_at: Instant,
     ) -> Result<Option<TransferId>> {
         // Deserialize incoming query
         let query = match OwnedRldpMessageQuery::from_data(self.transfer.take_data()) {
@@ -414,23 +505,46 @@ impl IncomingContext {
             None => return Err(TransfersCacheError::UnexpectedMessage.into()),
         };
 
+        // NOTE: the answer is sent at least for the max query timeout to tolerate clock skew
+        let deadline = Instant::now()
+            + std::cmp::max(
+                Duration::from_secs(query.timeout.saturating_sub(now()) as u64),
+                Duration::from_millis(query_options.query_max_timeout_ms),
+            );
+
         // Process query
         let ctx = SubscriberContext {
             adnl: &self.adnl,
             local_id: &self.local_id,
             peer_id: &self.peer_id,
         };
-        let answer = match process_rldp_query(ctx, &subscribers, query, force_compression).await? {
+        let answer = match process_rldp_query(
+            ctx,
+            &subscribers,
+            query,
+            max_query_size,
+            force_compression,
+            started_at,
+        )
+        .await?
+        {
             QueryProcessingResult::Processed(Some(answer)) => answer,
             QueryProcessingResult::Processed(None) => return Ok(None),
             QueryProcessingResult::Rejected => {
                 return Err(TransfersCacheError::NoSubscribers.into())
             }
         };
+        if Instant::now() >= deadline {
+            return Err(TransfersCacheError::QueryTimeoutExceeded.into());
+        }
 
         // Create outgoing transfer
         let outgoing_transfer_id = negate_id(self.transfer_id);
-        let outgoing_transfer = OutgoingTransfer::new(answer, Some(outgoing_transfer_id));
+        let outgoing_transfer = OutgoingTransfer::new(
+            answer,
+            Some(outgoing_transfer_id),
+            query_options.max_unconfirmed_packets,
+        );
         transfers.insert(
             outgoing_transfer_id,
             RldpTransfer::Outgoing(outgoing_transfer.state().clone()),
@@ -445,7 +559,9 @@ impl IncomingContext {
         };
 
         // Send answer
-        outgoing_context.send(query_options, None).await?;
+        outgoing_context
+            .send(query_options, None, Some(deadline))
+            .await?;
 
         // Done
         Ok(Some(outgoing_transfer_id))
@@ -465,6 +581,7 @@ impl OutgoingContext {
         mut self,
         query_options: QueryOptions,
         roundtrip: Option<u64>,
+        deadline: Option<Instant>,
     ) -> Result<(bool, u64)> {
         // Prepare timeout
         let mut timeout = query_options.compute_timeout(roundtrip);
@@ -473,7 +590,7 @@ impl OutgoingContext {
         let waves_interval = Duration::from_millis(query_options.query_wave_interval_ms);
 
         // For each outgoing message part
-        while let Some(packet_count) = ok!(self.transfer.start_next_part()) {
+        while let Some(packet_count) = ok!(self.transfer.start_next_part().await) {
             let wave_len = std::cmp::min(packet_count, query_options.query_wave_len);
 
             let part = self.transfer.state().part();
@@ -482,7 +599,8 @@ impl OutgoingContext {
 
             let mut incoming_seqno = 0;
             'part: loop {
-                // Send parts in waves
+                // Send parts in waves, pausing until the peer confirms enough packets
+                let wave_len = std::cmp::min(wave_len, self.transfer.available_window());
                 for _ in 0..wave_len {
                     ok!(self.adnl.send_custom_message(
                         &self.local_id,
@@ -499,6 +617,9 @@ impl OutgoingContext {
                 if ok!(self.transfer.is_finished_or_next_part(part)) {
                     break 'part;
                 }
+                if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                    return Ok((false, roundtrip));
+                }
 
                 // Update timeout on incoming packets
                 let new_incoming_seqno = self.transfer.state().seqno_in();
@@ -524,6 +645,7 @@ impl OutgoingContext {
 struct QueryOptions {
     query_wave_len: u32,
     query_wave_interval_ms: u64,
+    max_unconfirmed_packets: u32,
     query_min_timeout_ms: u64,
     query_max_timeout_ms: u64,
 }
@@ -562,9 +684,11 @@ async fn process_rldp_query(
     ctx: SubscriberContext<'_>,
     subscribers: &[Arc<dyn QuerySubscriber>],
     mut query: OwnedRldpMessageQuery,
+    max_query_size: usize,
     force_compression: bool,
-) -> Result<QueryProcessingResult<Vec<u8>>> {
-    let answer_compression = match compression::decompress(&query.data) {
+    received_at: Instant,
+) -> Result<QueryProcessingResult<OutgoingData>> {
+    let answer_compression = match compression::decompress(&query.data, max_query_size)? {
         Some(decompressed) => {
             query.data = decompressed;
             true
@@ -572,34 +696,48 @@ async fn process_rldp_query(
         None => force_compression,
     };
 
-    match process_query(ctx, subscribers, Cow::Owned(query.data)).await? {
-        QueryProcessingResult::Processed(answer) => Ok(match answer {
-            Some(mut answer) => {
-                if answer_compression {
-                    if let Err(e) = compression::compress(&mut answer) {
-                        tracing::warn!("failed to compress RLDP answer: {e:?}");
-                    }
-                }
-                if answer.len() > query.max_answer_size as usize {
-                    return Err(TransfersCacheError::AnswerSizeExceeded.into());
-                }
+    let answer = match process_query(ctx, subscribers, Cow::Owned(query.data), received_at).await? {
+        QueryProcessingResult::Processed(Some(answer)) => answer,
+        QueryProcessingResult::Processed(None) => {
+            return Ok(QueryProcessingResult::Processed(None))
+        }
+        QueryProcessingResult::Rejected => return Ok(QueryProcessingResult::Rejected),
+    };
 
-                QueryProcessingResult::Processed(Some(tl_proto::serialize(
-                    proto::rldp::Message::Answer {
-                        query_id: &query.query_id,
-                        data: &answer,
-                    },
-                )))
+    let mut answer = match answer {
+        // NOTE: compression requires the whole answer
+        QueryAnswer::Stream(answer) if !answer_compression => {
+            if answer.total_len() > query.max_answer_size as usize {
+                return Err(TransfersCacheError::AnswerSizeExceeded.into());
             }
-            None => QueryProcessingResult::Processed(None),
-        }),
-        _ => Ok(QueryProcessingResult::Rejected),
+            return Ok(QueryProcessingResult::Processed(Some(
+                OutgoingData::answer_stream(&query.query_id, answer),
+            )));
+        }
+        answer => answer.collect().await?,
+    };
+
+    if answer_compression {
+        if let Err(e) = compression::compress(&mut answer) {
+            tracing::warn!("failed to compress RLDP answer: {e:?}");
+        }
     }
+    if answer.len() > query.max_answer_size as usize {
+        return Err(TransfersCacheError::AnswerSizeExceeded.into());
+    }
+
+    Ok(QueryProcessingResult::Processed(Some(OutgoingData::Full(
+        tl_proto::serialize(proto::rldp::Message::Answer {
+            query_id: &query.query_id,
+            data: &answer,
+        }),
+    ))))
 }
 
 struct OwnedRldpMessageQuery {
     query_id: [u8; 32],
     max_answer_size: u64,
+    timeout: u32,
     data: Vec<u8>,
 }
 
@@ -639,6 +777,7 @@ impl OwnedRldpMessageQuery {
         Some(Self {
             query_id: params.query_id,
             max_answer_size: params.max_answer_size,
+            timeout: params.timeout,
             data,
         })
     }
@@ -667,4 +806,147 @@ enum TransfersCacheError {
     NoSubscribers,
     #[error("Answer size exceeded")]
     AnswerSizeExceeded,
+    #[error("Query timeout exceeded")]
+    QueryTimeoutExceeded,
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use bytes::Bytes;
+    use everscale_crypto::ed25519;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    struct StreamingSubscriber {
+        answer: Arc<Vec<u8>>,
+    }
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for StreamingSubscriber {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            _: u32,
+            _: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            let answer = self.answer.clone();
+            let chunks = futures_util::stream::iter(
+                (0..answer.len())
+                    .step_by(65536)
+                    .map(move |offset| {
+                        let end = std::cmp::min(offset + 65536, answer.len());
+                        Ok(Bytes::copy_from_slice(&answer[offset..end]))
+                    })
+                    .collect::<Vec<_>>(),
+            );
+            Ok(QueryConsumingResult::ConsumedStream(AnswerStream::new(
+                self.answer.len(),
+                chunks,
+            )))
+        }
+    }
+
+    fn make_node(
+        network: &MemoryNetwork,
+        subscriber: Arc<dyn QuerySubscriber>,
+        options: adnl::NodeOptions,
+    ) -> (Arc<adnl::Node>, Arc<crate::rldp::Node>, adnl::NodeIdShort) {
+        let key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let keystore = adnl::Keystore::builder()
+            .with_tagged_keys([(key.to_bytes(), 0)])
+            .unwrap()
+            .build();
+        let adnl = network
+            .create_node(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                keystore,
+                options,
+                None,
+            )
+            .unwrap();
+        adnl.add_query_subscriber(subscriber.clone()).unwrap();
+        let rldp =
+            crate::rldp::Node::new(adnl.clone(), vec![subscriber], Default::default()).unwrap();
+        let local_id = *adnl.key_by_tag(0).unwrap().id();
+        (adnl, rldp, local_id)
+    }
+
+    #[tokio::test]
+    async fn streamed_answers() {
+        let answer = Arc::new((0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+        let subscriber = Arc::new(StreamingSubscriber {
+            answer: answer.clone(),
+        });
+
+        let network = MemoryNetwork::new();
+        let (left_adnl, left, left_id) =
+            make_node(&network, subscriber.clone(), Default::default());
+        let (right_adnl, _right, right_id) = make_node(&network, subscriber, Default::default());
+        connect_nodes(&left_adnl, &left_id, &right_adnl, &right_id).unwrap();
+        left_adnl.start().unwrap();
+        right_adnl.start().unwrap();
+
+        let (received, _) = left
+            .query(&left_id, &right_id, vec![0; 4], None)
+            .await
+            .unwrap();
+        assert_eq!(received.as_deref(), Some(answer.as_slice()));
+    }
+
+    #[tokio::test]
+    async fn streamed_adnl_answer() {
+        // NOTE: answer is sent as a split ADNL message
+        let answer = Arc::new((0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+        let subscriber = Arc::new(StreamingSubscriber {
+            answer: answer.clone(),
+        });
+
+        let network = MemoryNetwork::new();
+        let (left, _, left_id) = make_node(&network, subscriber.clone(), Default::default());
+        let (right, _, right_id) = make_node(&network, subscriber, Default::default());
+        connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let received = left
+            .query_raw(&left_id, &right_id, Bytes::from_static(&[0; 4]), Some(1000))
+            .await
+            .unwrap();
+        assert_eq!(received.as_deref(), Some(answer.as_slice()));
+    }
+    #[tokio::test]
+    async fn big_answers_via_rldp() {
+        let answer = Arc::new(vec![0xaa; 5000]);
+        let subscriber = Arc::new(StreamingSubscriber {
+            answer: answer.clone(),
+        });
+        let options = adnl::NodeOptions {
+            max_answer_size: 1000,
+            ..Default::default()
+        };
+
+        let network = MemoryNetwork::new();
+        let (left_adnl, left, left_id) = make_node(&network, subscriber.clone(), options);
+        let (right_adnl, _right, right_id) = make_node(&network, subscriber, options);
+        connect_nodes(&left_adnl, &left_id, &right_adnl, &right_id).unwrap();
+        left_adnl.start().unwrap();
+        right_adnl.start().unwrap();
+
+        // ADNL answer is dropped
+        let received = left_adnl
+            .query_raw(&left_id, &right_id, Bytes::from_static(&[0; 4]), Some(500))
+            .await
+            .unwrap();
+        assert!(received.is_none());
+
+        // Same subscriber answers via RLDP
+        let (received, _) = left
+            .query(&left_id, &right_id, vec![0; 4], None)
+            .await
+            .unwrap();
+        assert_eq!(received.as_deref(), Some(answer.as_slice()));
+    }
 }
@@ -0,0 +1,107 @@
+use std::time::Instant;
+
+use crate::adnl::NodeIdShort;
+use crate::util::FastDashMap;
+
+/// Incoming queries limits which are checked before the subscribers dispatch
+pub(crate) struct QueryLimiter {
+    max_size: Option<usize>,
+    rate_limit: Option<u32>,
+    buckets: FastDashMap<NodeIdShort, TokenBucket>,
+}
+
+impl QueryLimiter {
+    pub fn new(max_size: Option<usize>, rate_limit: Option<u32>) -> Self {
+        Self {
+            max_size,
+            rate_limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Checks whether the query from the peer can be processed
+    pub fn check(&self, peer_id: &NodeIdShort, query_len: usize) -> Result<(), QueryLimitsError> {
+        if matches!(self.max_size, Some(max_size) if query_len > max_size) {
+            return Err(QueryLimitsError::TooBig);
+        }
+
+        let rate_limit = match self.rate_limit {
+            Some(rate_limit) => rate_limit as f64,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(*peer_id).or_insert(TokenBucket {
+            tokens: rate_limit,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate_limit).min(rate_limit);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(QueryLimitsError::Throttled)
+        }
+    }
+
+    /// Removes buckets which are already full. Returns the number of removed buckets
+    pub fn remove_idle(&self) -> usize {
+        let rate_limit = match self.rate_limit {
+            Some(rate_limit) => rate_limit as f64,
+            None => return 0,
+        };
+
+        let now = Instant::now();
+        let len = self.buckets.len();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens + elapsed * rate_limit < rate_limit
+        });
+        len.saturating_sub(self.buckets.len())
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum QueryLimitsError {
+    #[error("Query is too big")]
+    TooBig,
+    #[error("Too many queries from peer")]
+    Throttled,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        let limiter = QueryLimiter::new(Some(10), Some(3));
+        let peer_id = NodeIdShort::new([1; 32]);
+
+        assert!(matches!(
+            limiter.check(&peer_id, 11),
+            Err(QueryLimitsError::TooBig)
+        ));
+
+        for _ in 0..3 {
+            limiter.check(&peer_id, 10).unwrap();
+        }
+        assert!(matches!(
+            limiter.check(&peer_id, 10),
+            Err(QueryLimitsError::Throttled)
+        ));
+
+        // Other peers are not affected
+        limiter.check(&NodeIdShort::new([2; 32]), 10).unwrap();
+        assert_eq!(limiter.remove_idle(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::util::FastDashMap;
+
+/// Handled queries statistics
+#[derive(Default)]
+pub(crate) struct SubscriberStats {
+    entries: FastDashMap<QueryStatsKey, QueryStatsEntry>,
+}
+
+impl SubscriberStats {
+    /// Records a query which was consumed by the subscriber (or rejected by all if `None`)
+    pub fn record(
+        &self,
+        constructor: u32,
+        subscriber: Option<&'static str>,
+        timings: QueryTimings,
+    ) {
+        self.record_impl(constructor, subscriber, timings, false);
+    }
+
+    /// Records a query which failed to be processed by the subscriber
+    pub fn record_error(&self, constructor: u32, subscriber: &'static str, timings: QueryTimings) {
+        self.record_impl(constructor, Some(subscriber), timings, true);
+    }
+
+    fn record_impl(
+        &self,
+        constructor: u32,
+        subscriber: Option<&'static str>,
+        timings: QueryTimings,
+        error: bool,
+    ) {
+        let elapsed_us = as_micros(timings.processing_time);
+        let queue_time_us = as_micros(timings.queue_time);
+
+        // NOTE: constructors are controlled by the peers, so unknown rejected queries
+        // and all new entries after the limit are folded into a single entry
+        let mut key = QueryStatsKey {
+            constructor: Some(constructor),
+            subscriber,
+        };
+        if subscriber.is_none() && crate::proto::debug::constructor_name(constructor).is_none() {
+            key.constructor = None;
+        }
+
+        let entry = match self.entries.get(&key) {
+            Some(entry) => entry,
+            None => {
+                if self.entries.len() >= MAX_ENTRIES {
+                    key = QueryStatsKey {
+                        constructor: None,
+                        subscriber: None,
+                    };
+                }
+                self.entries.entry(key).or_default().downgrade()
+            }
+        };
+
+        entry.count.fetch_add(1, Ordering::Relaxed);
+        if error {
+            entry.error_count.fetch_add(1, Ordering::Relaxed);
+        }
+        entry.total_time_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        entry.max_time_us.fetch_max(elapsed_us, Ordering::Relaxed);
+        entry
+            .total_queue_time_us
+            .fetch_add(queue_time_us, Ordering::Relaxed);
+        entry
+            .max_queue_time_us
+            .fetch_max(queue_time_us, Ordering::Relaxed);
+        if timings.slow {
+            entry.slow_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn metrics(&self) -> SubscriberMetrics {
+        let mut queries = self
+            .entries
+            .iter()
+            .map(|entry| QueryStats {
+                constructor: entry.key().constructor,
+                subscriber: entry.key().subscriber,
+                count: entry.count.load(Ordering::Relaxed),
+                error_count: entry.error_count.load(Ordering::Relaxed),
+                total_time_us: entry.total_time_us.load(Ordering::Relaxed),
+                max_time_us: entry.max_time_us.load(Ordering::Relaxed),
+                total_queue_time_us: entry.total_queue_time_us.load(Ordering::Relaxed),
+                max_queue_time_us: entry.max_queue_time_us.load(Ordering::Relaxed),
+                slow_count: entry.slow_count.load(Ordering::Relaxed),
+            })
+            .collect::<Vec<_>>();
+        queries.sort_unstable_by_key(|stats| std::cmp::Reverse(stats.count));
+
+        SubscriberMetrics { queries }
+    }
+}
+
+/// Timings of the processed query
+#[derive(Copy, Clone)]
+pub(crate) struct QueryTimings {
+    /// Time since the query was received until the processing started
+    pub queue_time: Duration,
+    /// Processing time by all subscribers
+    pub processing_time: Duration,
+    /// Whether the processing time exceeded the slow subscriber threshold
+    pub slow: bool,
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_micros().min(u64::MAX as u128) as u64
+}
+
+/// Max number of distinct (constructor, subscriber) entries
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+struct QueryStatsKey {
+    constructor: Option<u32>,
+    subscriber: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct QueryStatsEntry {
+    count: AtomicU64,
+    error_count: AtomicU64,
+    total_time_us: AtomicU64,
+    max_time_us: AtomicU64,
+    total_queue_time_us: AtomicU64,
+    max_queue_time_us: AtomicU64,
+    slow_count: AtomicU64,
+}
+
+/// Handled queries statistics, sorted by the number of queries (descending)
+#[derive(Debug, Clone)]
+pub struct SubscriberMetrics {
+    pub queries: Vec<QueryStats>,
+}
+
+/// Statistics for the queries with the same constructor handled by the same subscriber
+#[derive(Debug, Copy, Clone)]
+pub struct QueryStats {
+    /// TL constructor id of the query.
+    /// `None` for the other queries (rejected queries with unknown constructors
+    /// and all queries which didn't fit into the stats)
+    pub constructor: Option<u32>,
+    /// Name of the subscriber which consumed the query.
+    /// `None` if the query was rejected by all subscribers
+    pub subscriber: Option<&'static str>,
+    /// Total number of queries
+    pub count: u64,
+    /// Number of queries which were consumed by the subscriber with an error
+    pub error_count: u64,
+    /// Total processing time in microseconds
+    pub total_time_us: u64,
+    /// Max processing time in microseconds
+    pub max_time_us: u64,
+    /// Total time in microseconds the queries waited before processing
+    pub total_queue_time_us: u64,
+    /// Max time in microseconds the query waited before processing
+    pub max_queue_time_us: u64,
+    /// Number of queries which were processed longer than the slow subscriber threshold.
+    ///
+    /// See [`NodeOptions::slow_subscriber_threshold_ms`]
+    ///
+    /// [`NodeOptions::slow_subscriber_threshold_ms`]: crate::adnl::NodeOptions::slow_subscriber_threshold_ms
+    pub slow_count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slow_queries_stats() {
+        let stats = SubscriberStats::default();
+        for (queue_time_ms, processing_time_ms, slow) in [(1, 10, false), (5, 2000, true)] {
+            stats.record(
+                123,
+                Some("test"),
+                QueryTimings {
+                    queue_time: Duration::from_millis(queue_time_ms),
+                    processing_time: Duration::from_millis(processing_time_ms),
+                    slow,
+                },
+            );
+        }
+
+        let metrics = stats.metrics();
+        assert_eq!(metrics.queries.len(), 1);
+
+        let query = metrics.queries[0];
+        assert_eq!(query.count, 2);
+        assert_eq!(query.slow_count, 1);
+        assert_eq!(query.total_queue_time_us, 6000);
+        assert_eq!(query.max_queue_time_us, 5000);
+        assert_eq!(query.max_time_us, 2000000);
+    }
+    fn timings() -> QueryTimings {
+        QueryTimings {
+            queue_time: Duration::from_millis(1),
+            processing_time: Duration::from_millis(1),
+            slow: false,
+        }
+    }
+
+    #[test]
+    fn unknown_queries_are_folded() {
+        let stats = SubscriberStats::default();
+        for constructor in 0..1000 {
+            stats.record(constructor, None, timings());
+        }
+        for constructor in 0..1000 {
+            stats.record(constructor, Some("test"), timings());
+        }
+
+        let metrics = stats.metrics();
+        assert_eq!(metrics.queries.len(), MAX_ENTRIES);
+
+        let other = metrics
+            .queries
+            .iter()
+            .find(|query| query.constructor.is_none())
+            .unwrap();
+        assert_eq!(other.subscriber, None);
+        assert_eq!(other.count, 1000 + 1000 - (MAX_ENTRIES as u64 - 1));
+    }
+
+    #[test]
+    fn errors_stats() {
+        let stats = SubscriberStats::default();
+        stats.record(123, Some("test"), timings());
+        stats.record_error(123, "test", timings());
+
+        let query = stats.metrics().queries[0];
+        assert_eq!(query.constructor, Some(123));
+        assert_eq!(query.count, 2);
+        assert_eq!(query.error_count, 1);
+    }
+}
@@ -1,10 +1,26 @@
 use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use bytes::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use smallvec::SmallVec;
 use tl_proto::TlRead;
 
+pub(crate) use self::limits::QueryLimiter;
+pub use self::metrics::{QueryStats, SubscriberMetrics};
+pub(crate) use self::metrics::{QueryTimings, SubscriberStats};
+pub use self::prefix::PrefixQuerySubscriber;
+pub use self::typed::{QueryHandler, TypedQuery, TypedQuerySubscriber};
+
 use crate::adnl;
+use crate::proto;
+
+pub(crate) mod limits;
+mod metrics;
+mod prefix;
+mod typed;
 
 /// ADNL custom messages subscriber
 #[async_trait::async_trait]
@@ -15,9 +31,21 @@ pub trait MessageSubscriber: Send + Sync {
         constructor: u32,
         data: &'a [u8],
     ) -> Result<bool>;
+
+    /// Subscriber name used in the slow subscribers log
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
-/// ADNL, RLDP or overlay queries subscriber
+/// ADNL, RLDP or overlay queries subscriber.
+///
+/// The same subscriber can handle queries from any transport, the answer is sent back
+/// the way the query arrived. Answers to RLDP queries are only limited by the max answer
+/// size from the query, while answers to ADNL queries bigger than
+/// [`NodeOptions::max_answer_size`] are dropped.
+///
+/// [`NodeOptions::max_answer_size`]: crate::adnl::NodeOptions::max_answer_size
 #[async_trait::async_trait]
 pub trait QuerySubscriber: Send + Sync {
     async fn try_consume_query<'a>(
@@ -26,6 +54,24 @@ pub trait QuerySubscriber: Send + Sync {
         constructor: u32,
         query: Cow<'a, [u8]>,
     ) -> Result<QueryConsumingResult<'a>>;
+
+    /// Subscriber name used in the queries statistics (see [`SubscriberMetrics`])
+    /// and in the slow subscribers log
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Periodic background work of the protocol built on top of ADNL
+/// (e.g. DHT republish or overlay gossip).
+///
+/// See [`Node::add_background_task`]
+///
+/// [`Node::add_background_task`]: crate::adnl::Node::add_background_task
+#[async_trait::async_trait]
+pub trait BackgroundTask: Send + Sync {
+    /// Called on each tick with the current unix timestamp in seconds
+    async fn poll(&self, now: u32) -> Result<()>;
 }
 
 /// Message or query context.
@@ -42,6 +88,8 @@ pub struct SubscriberContext<'a> {
 pub enum QueryConsumingResult<'a> {
     /// Query is accepted and processed
     Consumed(Option<Vec<u8>>),
+    /// Query is accepted and the answer will be produced by chunks
+    ConsumedStream(AnswerStream),
     /// Query rejected and will be processed by the next subscriber
     Rejected(Cow<'a, [u8]>),
 }
@@ -55,29 +103,278 @@ impl QueryConsumingResult<'_> {
     }
 }
 
+/// Query answer which is produced by chunks
+///
+/// RLDP answers are framed into the transfer by chunks, so only the current
+/// transfer part is kept in memory. Compressed RLDP answers and ADNL answers are
+/// still collected into a single buffer, because each part of the split ADNL message
+/// contains the hash of the whole message.
+pub struct AnswerStream {
+    total_len: usize,
+    received: usize,
+    pending: Bytes,
+    chunks: BoxStream<'static, Result<Bytes>>,
+}
+
+impl AnswerStream {
+    /// Creates answer from the stream of chunks with the known total length
+    pub fn new<S>(total_len: usize, chunks: S) -> Self
+    where
+        S: futures_util::Stream<Item = Result<Bytes>> + Send + 'static,
+    {
+        Self {
+            total_len,
+            received: 0,
+            pending: Bytes::new(),
+            chunks: chunks.boxed(),
+        }
+    }
+
+    /// Total answer length in bytes
+    pub fn total_len(&self) -> usize {
+        self.total_len
+    }
+
+    /// Collects all chunks into a single buffer
+    pub async fn collect(mut self) -> Result<Vec<u8>> {
+        let mut answer = Vec::with_capacity(self.total_len);
+        self.read_into(&mut answer, self.total_len).await?;
+        self.finish().await?;
+        Ok(answer)
+    }
+
+    /// Number of bytes which were not read yet
+    #[cfg(feature = "rldp")]
+    pub(crate) fn remaining(&self) -> usize {
+        self.total_len - self.received + self.pending.len()
+    }
+
+    /// Appends exactly `len` next bytes of the answer to the buffer
+    pub(crate) async fn read_into(&mut self, buffer: &mut Vec<u8>, mut len: usize) -> Result<()> {
+        while len > 0 {
+            if self.pending.is_empty() {
+                self.pending = match self.chunks.next().await {
+                    Some(chunk) => chunk?,
+                    None => return Err(AnswerStreamError::TooShort.into()),
+                };
+                self.received += self.pending.len();
+                if self.received > self.total_len {
+                    return Err(AnswerStreamError::TooLong.into());
+                }
+            }
+
+            let chunk = self
+                .pending
+                .split_to(std::cmp::min(len, self.pending.len()));
+            buffer.extend_from_slice(&chunk);
+            len -= chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Checks that there are no chunks left
+    pub(crate) async fn finish(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            return Err(AnswerStreamError::TooLong.into());
+        }
+        while let Some(chunk) = self.chunks.next().await {
+            if !chunk?.is_empty() {
+                return Err(AnswerStreamError::TooLong.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Processed query answer
+pub(crate) enum QueryAnswer {
+    Full(Vec<u8>),
+    Stream(AnswerStream),
+}
+
+impl QueryAnswer {
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Full(answer) => answer.len(),
+            Self::Stream(answer) => answer.total_len(),
+        }
+    }
+
+    pub async fn collect(self) -> Result<Vec<u8>> {
+        match self {
+            Self::Full(answer) => Ok(answer),
+            Self::Stream(answer) => answer.collect().await,
+        }
+    }
+}
+
 pub(crate) async fn process_query<'a>(
     ctx: SubscriberContext<'a>,
     subscribers: &[Arc<dyn QuerySubscriber>],
     mut query: Cow<'_, [u8]>,
-) -> Result<QueryProcessingResult<Vec<u8>>> {
+    received_at: Instant,
+) -> Result<QueryProcessingResult<QueryAnswer>> {
+    ctx.adnl.query_limiter().check(ctx.peer_id, query.len())?;
+
     let constructor = u32::read_from(&query, &mut 0)?;
+    let started_at = Instant::now();
+    let slow_log = SlowSubscriberLog::new(ctx, "query", &query, received_at);
+    let stats = ctx.adnl.subscriber_stats();
 
     for subscriber in subscribers {
-        query = match subscriber
-            .try_consume_query(ctx, constructor, query)
-            .await?
-        {
-            QueryConsumingResult::Consumed(answer) => {
-                return Ok(QueryProcessingResult::Processed(answer))
+        let subscriber_started_at = Instant::now();
+        let answer = match subscriber.try_consume_query(ctx, constructor, query).await {
+            Ok(QueryConsumingResult::Consumed(answer)) => Ok(answer.map(QueryAnswer::Full)),
+            Ok(QueryConsumingResult::ConsumedStream(answer)) => {
+                Ok(Some(QueryAnswer::Stream(answer)))
+            }
+            Ok(QueryConsumingResult::Rejected(rejected)) => {
+                slow_log.check(subscriber.name(), subscriber_started_at.elapsed());
+                query = rejected;
+                continue;
             }
-            QueryConsumingResult::Rejected(query) => query,
+            Err(e) => Err(e),
         };
+        slow_log.check(subscriber.name(), subscriber_started_at.elapsed());
+        let answer = match answer {
+            Ok(answer) => answer,
+            Err(e) => {
+                let timings = slow_log.timings(started_at.elapsed());
+                stats.record_error(constructor, subscriber.name(), timings);
+                return Err(e);
+            }
+        };
+
+        stats.record(
+            constructor,
+            Some(subscriber.name()),
+            slow_log.timings(started_at.elapsed()),
+        );
+        return Ok(QueryProcessingResult::Processed(answer));
     }
 
+    stats.record(constructor, None, slow_log.timings(started_at.elapsed()));
     Ok(QueryProcessingResult::Rejected)
 }
 
+/// Logs subscribers which process a query or a message longer than
+/// [`NodeOptions::slow_subscriber_threshold_ms`]
+///
+/// [`NodeOptions::slow_subscriber_threshold_ms`]: crate::adnl::NodeOptions::slow_subscriber_threshold_ms
+pub(crate) struct SlowSubscriberLog<'a> {
+    ctx: SubscriberContext<'a>,
+    kind: &'static str,
+    /// Enough bytes to display the constructors chain of the prefixed query
+    head: SmallVec<[u8; LOG_HEAD_LEN]>,
+    queue_time: Duration,
+    threshold: Option<Duration>,
+}
+
+impl<'a> SlowSubscriberLog<'a> {
+    pub fn new(
+        ctx: SubscriberContext<'a>,
+        kind: &'static str,
+        data: &[u8],
+        received_at: Instant,
+    ) -> Self {
+        Self {
+            ctx,
+            kind,
+            head: SmallVec::from_slice(&data[..std::cmp::min(data.len(), LOG_HEAD_LEN)]),
+            queue_time: received_at.elapsed(),
+            threshold: ctx
+                .adnl
+                .options()
+                .slow_subscriber_threshold_ms
+                .map(Duration::from_millis),
+        }
+    }
+
+    /// Logs the subscriber if it is slow. Returns whether the subscriber is slow
+    pub fn check(&self, subscriber: &str, processing_time: Duration) -> bool {
+        if !self.is_slow(processing_time) {
+            return false;
+        }
+
+        tracing::warn!(
+            local_id = %self.ctx.local_id,
+            peer_id = %self.ctx.peer_id,
+            subscriber,
+            constructor = %proto::debug::ConstructorDump(&self.head),
+            queue_time_ms = self.queue_time.as_millis() as u64,
+            processing_time_ms = processing_time.as_millis() as u64,
+            "slow {} subscriber",
+            self.kind,
+        );
+        true
+    }
+
+    /// Timings of the processed query
+    pub fn timings(&self, processing_time: Duration) -> QueryTimings {
+        QueryTimings {
+            queue_time: self.queue_time,
+            processing_time,
+            slow: self.is_slow(processing_time),
+        }
+    }
+
+    fn is_slow(&self, processing_time: Duration) -> bool {
+        matches!(self.threshold, Some(threshold) if processing_time >= threshold)
+    }
+}
+
+/// Overlay query prefix with the inner constructor
+const LOG_HEAD_LEN: usize = 4 + 32 + 4;
+
 pub(crate) enum QueryProcessingResult<T> {
     Processed(Option<T>),
     Rejected,
 }
+
+#[derive(thiserror::Error, Debug)]
+enum AnswerStreamError {
+    #[error("Answer stream is longer than expected")]
+    TooLong,
+    #[error("Answer stream is shorter than expected")]
+    TooShort,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_stream(total_len: usize, chunks: &[&'static [u8]]) -> AnswerStream {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect::<Vec<_>>();
+        AnswerStream::new(total_len, futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn answer_stream_collect() {
+        let answer = make_stream(6, &[b"ab", b"", b"cde", b"f"]);
+        assert_eq!(answer.collect().await.unwrap(), b"abcdef");
+
+        let answer = make_stream(7, &[b"ab", b"cde", b"f"]);
+        assert!(answer.collect().await.is_err());
+
+        let answer = make_stream(5, &[b"ab", b"cde", b"f"]);
+        assert!(answer.collect().await.is_err());
+
+        let answer = make_stream(4, &[b"ab", b"cde"]);
+        assert!(answer.collect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answer_stream_read_by_parts() {
+        let mut answer = make_stream(6, &[b"abc", b"def"]);
+
+        let mut buffer = Vec::new();
+        for len in [2, 2, 2] {
+            answer.read_into(&mut buffer, len).await.unwrap();
+        }
+        answer.finish().await.unwrap();
+        assert_eq!(buffer, b"abcdef");
+    }
+}
@@ -0,0 +1,220 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tl_proto::{TlRead, TlWrite};
+
+use super::{QueryConsumingResult, QuerySubscriber, SubscriberContext};
+use crate::util::FastHashMap;
+
+/// Query subscriber which strips registered prefixes and routes
+/// the remaining query to the subscriber bound to that prefix
+///
+/// See [`Node::query_with_prefix`]
+///
+/// [`Node::query_with_prefix`]: crate::adnl::Node::query_with_prefix
+#[derive(Default)]
+pub struct PrefixQuerySubscriber {
+    /// Routes grouped by the prefix constructor
+    routes: FastHashMap<u32, Vec<PrefixRoute>>,
+}
+
+impl PrefixQuerySubscriber {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds subscriber to the TL-serialized prefix (e.g. `rpc::OverlayQuery`).
+    /// Replaces the previous subscriber for the same prefix.
+    pub fn register<P>(&mut self, prefix: P, subscriber: Arc<dyn QuerySubscriber>) -> &mut Self
+    where
+        P: TlWrite<Repr = tl_proto::Boxed>,
+    {
+        self.register_raw(tl_proto::serialize(prefix), subscriber)
+            .expect("Boxed prefix always starts with a constructor")
+    }
+
+    /// Binds subscriber to the raw prefix.
+    /// Replaces the previous subscriber for the same prefix.
+    ///
+    /// NOTE: prefix must contain at least a constructor
+    pub fn register_raw(
+        &mut self,
+        prefix: Vec<u8>,
+        subscriber: Arc<dyn QuerySubscriber>,
+    ) -> Result<&mut Self> {
+        let constructor = match u32::read_from(&prefix, &mut 0) {
+            Ok(constructor) => constructor,
+            Err(_) => return Err(PrefixQuerySubscriberError::PrefixTooShort.into()),
+        };
+
+        let routes = self.routes.entry(constructor).or_default();
+        match routes.iter_mut().find(|route| route.prefix == prefix) {
+            Some(route) => route.subscriber = subscriber,
+            None => {
+                routes.push(PrefixRoute { prefix, subscriber });
+                // Prefer longer prefixes
+                routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[async_trait::async_trait]
+impl QuerySubscriber for PrefixQuerySubscriber {
+    async fn try_consume_query<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>> {
+        // NOTE: routed query must contain at least the inner constructor after the prefix
+        let (route, inner_constructor) = match self.routes.get(&constructor).and_then(|routes| {
+            routes.iter().find_map(|route| {
+                if !query.starts_with(&route.prefix) {
+                    return None;
+                }
+                let inner_constructor = u32::read_from(&query, &mut route.prefix.len()).ok()?;
+                Some((route, inner_constructor))
+            })
+        }) {
+            Some(route) => route,
+            None => return Ok(QueryConsumingResult::Rejected(query)),
+        };
+
+        let offset = route.prefix.len();
+
+        let (result, query) = match query {
+            Cow::Borrowed(query) => {
+                let result = route
+                    .subscriber
+                    .try_consume_query(ctx, inner_constructor, Cow::Borrowed(&query[offset..]))
+                    .await?;
+                (result, Cow::Borrowed(query))
+            }
+            Cow::Owned(query) => {
+                let result = route
+                    .subscriber
+                    .try_consume_query(ctx, inner_constructor, Cow::Owned(query[offset..].to_vec()))
+                    .await?;
+                (result, Cow::Owned(query))
+            }
+        };
+
+        Ok(match result {
+            QueryConsumingResult::Consumed(answer) => QueryConsumingResult::Consumed(answer),
+            QueryConsumingResult::ConsumedStream(answer) => {
+                QueryConsumingResult::ConsumedStream(answer)
+            }
+            QueryConsumingResult::Rejected(_) => QueryConsumingResult::Rejected(query),
+        })
+    }
+}
+
+struct PrefixRoute {
+    prefix: Vec<u8>,
+    subscriber: Arc<dyn QuerySubscriber>,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum PrefixQuerySubscriberError {
+    #[error("Prefix must contain at least a constructor")]
+    PrefixTooShort,
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::adnl;
+    use crate::test_utils::MemoryNetwork;
+
+    /// Answers with the inner constructor and the query itself
+    struct EchoSubscriber(u8);
+
+    #[async_trait::async_trait]
+    impl QuerySubscriber for EchoSubscriber {
+        async fn try_consume_query<'a>(
+            &self,
+            _: SubscriberContext<'a>,
+            constructor: u32,
+            query: Cow<'a, [u8]>,
+        ) -> Result<QueryConsumingResult<'a>> {
+            let mut answer = vec![self.0];
+            answer.extend_from_slice(&constructor.to_le_bytes());
+            answer.extend_from_slice(&query);
+            Ok(QueryConsumingResult::Consumed(Some(answer)))
+        }
+    }
+
+    #[tokio::test]
+    async fn prefix_routes() {
+        let network = MemoryNetwork::new();
+        let keystore = adnl::Keystore::builder()
+            .with_tagged_keys([(rand::random(), 0)])
+            .unwrap()
+            .build();
+        let node = network
+            .create_node(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                keystore,
+                Default::default(),
+                None,
+            )
+            .unwrap();
+        let local_id = *node.key_by_tag(0).unwrap().id();
+        let ctx = SubscriberContext {
+            adnl: &node,
+            local_id: &local_id,
+            peer_id: &local_id,
+        };
+
+        let mut subscriber = PrefixQuerySubscriber::new();
+        assert!(subscriber
+            .register_raw(vec![1, 2, 3], Arc::new(EchoSubscriber(0)))
+            .is_err());
+        subscriber
+            .register_raw(vec![1, 0, 0, 0], Arc::new(EchoSubscriber(1)))
+            .unwrap()
+            .register_raw(vec![1, 0, 0, 0, 5, 5], Arc::new(EchoSubscriber(2)))
+            .unwrap();
+
+        let consume = |query: &'static [u8]| {
+            let subscriber = &subscriber;
+            async move {
+                let constructor = u32::read_from(query, &mut 0).unwrap();
+                match subscriber
+                    .try_consume_query(ctx, constructor, Cow::Borrowed(query))
+                    .await
+                    .unwrap()
+                {
+                    QueryConsumingResult::Consumed(answer) => answer,
+                    QueryConsumingResult::Rejected(rejected) => {
+                        assert_eq!(rejected.as_ref(), query);
+                        None
+                    }
+                    QueryConsumingResult::ConsumedStream(_) => unreachable!(),
+                }
+            }
+        };
+
+        // Longer prefix is preferred
+        assert_eq!(
+            consume(&[1, 0, 0, 0, 5, 5, 7, 0, 0, 0, 9]).await,
+            Some(vec![2, 7, 0, 0, 0, 7, 0, 0, 0, 9])
+        );
+        assert_eq!(
+            consume(&[1, 0, 0, 0, 6, 0, 0, 0]).await,
+            Some(vec![1, 6, 0, 0, 0, 6, 0, 0, 0])
+        );
+
+        // Queries without the inner constructor are rejected
+        assert_eq!(consume(&[1, 0, 0, 0, 5, 5]).await, None);
+        assert_eq!(consume(&[1, 0, 0, 0, 6]).await, None);
+
+        // Unknown prefixes are rejected
+        assert_eq!(consume(&[2, 0, 0, 0, 6, 0, 0, 0]).await, None);
+    }
+}
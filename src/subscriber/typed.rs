@@ -0,0 +1,226 @@
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use anyhow::Result;
+use tl_proto::{TlRead, TlWrite};
+
+use super::{QueryConsumingResult, QuerySubscriber, SubscriberContext};
+use crate::proto;
+use crate::util::FastHashMap;
+
+/// Boxed TL query with the known constructor id
+///
+/// For the derived types it can be implemented with their `TL_ID`:
+///
+/// ```
+/// # use everscale_network::TypedQuery;
+/// #[derive(tl_proto::TlRead)]
+/// #[tl(boxed, id = 0x12345678)]
+/// struct CustomQuery {
+///     value: u32,
+/// }
+///
+/// impl TypedQuery for CustomQuery {
+///     const TL_ID: u32 = Self::TL_ID;
+/// }
+/// ```
+pub trait TypedQuery: for<'a> TlRead<'a, Repr = tl_proto::Boxed> + Send + 'static {
+    const TL_ID: u32;
+}
+
+macro_rules! impl_typed_query {
+    ($($ty:ty),*$(,)?) => {
+        $(impl TypedQuery for $ty {
+            const TL_ID: u32 = <$ty>::TL_ID;
+        })*
+    };
+}
+
+impl_typed_query!(
+    proto::rpc::AdnlPing,
+    proto::rpc::DhtPing,
+    proto::rpc::DhtGetSignedAddressList,
+    proto::rpc::OverlayGetRandomPeersOwned,
+);
+
+/// Typed query handler
+///
+/// See [`TypedQuerySubscriber::register`]
+#[async_trait::async_trait]
+pub trait QueryHandler<Q, A>: Send + Sync + 'static {
+    async fn handle(&self, ctx: SubscriberContext<'_>, query: Q) -> Result<Option<A>>;
+}
+
+/// Query subscriber which dispatches queries to the typed handlers by TL constructor
+///
+/// ```
+/// # use anyhow::Result;
+/// # use everscale_network::{proto, QueryHandler, SubscriberContext, TypedQuerySubscriber};
+/// struct PingHandler;
+///
+/// #[async_trait::async_trait]
+/// impl QueryHandler<proto::rpc::AdnlPing, proto::adnl::Pong> for PingHandler {
+///     async fn handle(
+///         &self,
+///         _: SubscriberContext<'_>,
+///         query: proto::rpc::AdnlPing,
+///     ) -> Result<Option<proto::adnl::Pong>> {
+///         Ok(Some(proto::adnl::Pong { value: query.value }))
+///     }
+/// }
+///
+/// let mut subscriber = TypedQuerySubscriber::new();
+/// subscriber.register(PingHandler);
+/// ```
+#[derive(Default)]
+pub struct TypedQuerySubscriber {
+    handlers: FastHashMap<u32, Arc<dyn RawQueryHandler>>,
+}
+
+impl TypedQuerySubscriber {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers handler for the query type. Queries are matched by `Q::TL_ID`.
+    /// Replaces the previous handler for the same constructor.
+    pub fn register<Q, A, H>(&mut self, handler: H) -> &mut Self
+    where
+        Q: TypedQuery,
+        A: TlWrite<Repr = tl_proto::Boxed> + Send + 'static,
+        H: QueryHandler<Q, A>,
+    {
+        self.handlers.insert(
+            <Q as TypedQuery>::TL_ID,
+            Arc::new(TypedHandler {
+                handler,
+                _marker: PhantomData::<fn(Q) -> A>,
+            }),
+        );
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl QuerySubscriber for TypedQuerySubscriber {
+    async fn try_consume_query<'a>(
+        &self,
+        ctx: SubscriberContext<'a>,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>> {
+        match self.handlers.get(&constructor) {
+            Some(handler) => Ok(QueryConsumingResult::Consumed(
+                handler.handle(ctx, &query).await?,
+            )),
+            None => Ok(QueryConsumingResult::Rejected(query)),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+trait RawQueryHandler: Send + Sync {
+    async fn handle(&self, ctx: SubscriberContext<'_>, query: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+struct TypedHandler<Q, A, H> {
+    handler: H,
+    _marker: PhantomData<fn(Q) -> A>,
+}
+
+#[async_trait::async_trait]
+impl<Q, A, H> RawQueryHandler for TypedHandler<Q, A, H>
+where
+    Q: for<'a> TlRead<'a, Repr = tl_proto::Boxed> + Send + 'static,
+    A: TlWrite<Repr = tl_proto::Boxed> + Send + 'static,
+    H: QueryHandler<Q, A>,
+{
+    async fn handle(&self, ctx: SubscriberContext<'_>, query: &[u8]) -> Result<Option<Vec<u8>>> {
+        let query = tl_proto::deserialize::<Q>(query)?;
+        let answer = self.handler.handle(ctx, query).await?;
+        Ok(answer.map(tl_proto::serialize))
+    }
+}
+
+#[cfg(all(test, feature = "test-utils"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+    use crate::adnl;
+    use crate::test_utils::{connect_nodes, MemoryNetwork};
+
+    struct DhtPingHandler;
+
+    #[async_trait::async_trait]
+    impl QueryHandler<proto::rpc::DhtPing, proto::dht::Pong> for DhtPingHandler {
+        async fn handle(
+            &self,
+            _: SubscriberContext<'_>,
+            query: proto::rpc::DhtPing,
+        ) -> Result<Option<proto::dht::Pong>> {
+            Ok(Some(proto::dht::Pong {
+                random_id: query.random_id,
+            }))
+        }
+    }
+
+    fn make_node(network: &MemoryNetwork) -> (Arc<adnl::Node>, adnl::NodeIdShort) {
+        let keystore = adnl::Keystore::builder()
+            .with_tagged_keys([(rand::random(), 0)])
+            .unwrap()
+            .build();
+        let node = network
+            .create_node(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                keystore,
+                Default::default(),
+                None,
+            )
+            .unwrap();
+        let local_id = *node.key_by_tag(0).unwrap().id();
+        (node, local_id)
+    }
+
+    #[tokio::test]
+    async fn typed_handlers() {
+        let network = MemoryNetwork::new();
+        let (left, left_id) = make_node(&network);
+        let (right, right_id) = make_node(&network);
+        connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+
+        let mut subscriber = TypedQuerySubscriber::new();
+        subscriber.register(DhtPingHandler);
+        assert!(subscriber
+            .handlers
+            .contains_key(&proto::rpc::DhtPing::TL_ID));
+
+        right.add_query_subscriber(Arc::new(subscriber)).unwrap();
+        left.start().unwrap();
+        right.start().unwrap();
+
+        let pong = left
+            .query::<_, proto::dht::Pong>(
+                &left_id,
+                &right_id,
+                proto::rpc::DhtPing { random_id: 123 },
+                Some(1000),
+            )
+            .await
+            .unwrap();
+        assert_eq!(pong.map(|pong| pong.random_id), Some(123));
+
+        // Queries without handlers are not answered
+        let answer = left
+            .query::<_, proto::dht::Pong>(
+                &left_id,
+                &right_id,
+                proto::rpc::DhtGetSignedAddressList,
+                Some(100),
+            )
+            .await
+            .unwrap();
+        assert!(answer.is_none());
+    }
+}
@@ -0,0 +1,442 @@
+//! Utilities for the deterministic integration tests of the code built on top of ADNL.
+//!
+//! - [`MemoryNetwork`] connects multiple ADNL nodes of the same process without
+//!   UDP sockets and simulates packet loss, latency and reordering.
+//! - [`MockClock`] controls the unix time used by the protocol logic.
+//!
+//! Random decisions of the network are made by the seeded generator, so tests are reproducible
+//! when running on the current thread runtime (default for `#[tokio::test]`). Latency is
+//! implemented with tokio timers, so it can be skipped with the paused time (`tokio::time::pause`).
+
+use std::cell::Cell;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::mpsc;
+
+use crate::adnl;
+use crate::adnl::NodeSocket;
+use crate::util::FastHashMap;
+
+/// In-memory network between ADNL nodes of the same process.
+///
+/// Each node is bound to the unique address, packets to the unknown addresses are lost
+/// (like with UDP). Link conditions can be changed at any time.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    state: Arc<NetworkState>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryNetwork {
+    /// Creates an empty network with the default seed
+    pub fn new() -> Self {
+        Self::with_seed(0)
+    }
+
+    /// Creates an empty network with the seed of the random link conditions
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            state: Arc::new(NetworkState {
+                endpoints: Default::default(),
+                conditions: Default::default(),
+                link_conditions: Default::default(),
+                rng: Mutex::new(SmallRng::seed_from_u64(seed)),
+                next_port: Mutex::new(FIRST_AUTO_PORT),
+                sent: Default::default(),
+                delivered: Default::default(),
+                lost: Default::default(),
+            }),
+        }
+    }
+
+    /// Sets conditions of all links without explicit conditions
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        *self.state.conditions.lock() = conditions;
+    }
+
+    /// Sets conditions of the link in one direction. Removes explicit conditions if `None`
+    pub fn set_link_conditions(
+        &self,
+        from: SocketAddrV4,
+        to: SocketAddrV4,
+        conditions: Option<LinkConditions>,
+    ) {
+        let mut link_conditions = self.state.link_conditions.lock();
+        match conditions {
+            Some(conditions) => link_conditions.insert((from, to), conditions),
+            None => link_conditions.remove(&(from, to)),
+        };
+    }
+
+    /// Binds a new socket to the address. Assigns a free port if it is zero
+    pub fn bind(&self, mut addr: SocketAddrV4) -> Result<MemorySocket, MemoryNetworkError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let mut endpoints = self.state.endpoints.lock();
+        if addr.port() == 0 {
+            let mut next_port = self.state.next_port.lock();
+            loop {
+                let port = *next_port;
+                *next_port = next_port.checked_add(1).unwrap_or(FIRST_AUTO_PORT);
+                addr.set_port(port);
+                if !endpoints.contains_key(&addr) {
+                    break;
+                }
+            }
+        } else if endpoints.contains_key(&addr) {
+            return Err(MemoryNetworkError::AddressInUse(addr));
+        }
+        endpoints.insert(addr, tx);
+
+        Ok(MemorySocket {
+            state: self.state.clone(),
+            addr,
+            rx: tokio::sync::Mutex::new(rx),
+        })
+    }
+
+    /// Creates an ADNL node which is bound to the address in this network.
+    ///
+    /// See [`Node::new`]
+    ///
+    /// [`Node::new`]: crate::adnl::Node::new
+    pub fn create_node(
+        &self,
+        addr: SocketAddrV4,
+        keystore: adnl::Keystore,
+        options: adnl::NodeOptions,
+        peer_filter: Option<Arc<dyn adnl::PeerFilter>>,
+    ) -> Result<Arc<adnl::Node>> {
+        let socket = self.bind(addr)?;
+        let addr = socket.local_addr();
+        adnl::Node::with_socket(
+            addr,
+            NodeSocket::Memory(socket),
+            keystore,
+            options,
+            peer_filter,
+        )
+    }
+
+    /// Packets statistics
+    pub fn stats(&self) -> MemoryNetworkStats {
+        MemoryNetworkStats {
+            sent: self.state.sent.load(Ordering::Relaxed),
+            delivered: self.state.delivered.load(Ordering::Relaxed),
+            lost: self.state.lost.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Adds both nodes as peers of each other
+pub fn connect_nodes(
+    left: &adnl::Node,
+    left_id: &adnl::NodeIdShort,
+    right: &adnl::Node,
+    right_id: &adnl::NodeIdShort,
+) -> Result<()> {
+    let left_id_full = *left.key_by_id(left_id)?.full_id();
+    let right_id_full = *right.key_by_id(right_id)?.full_id();
+
+    left.add_peer(
+        adnl::NewPeerContext::AdnlPacket,
+        left_id,
+        right_id,
+        right.socket_addr(),
+        right_id_full,
+    )?;
+    right.add_peer(
+        adnl::NewPeerContext::AdnlPacket,
+        right_id,
+        left_id,
+        left.socket_addr(),
+        left_id_full,
+    )?;
+    Ok(())
+}
+
+/// Simulated conditions of the link between two addresses
+#[derive(Debug, Copy, Clone, Default)]
+pub struct LinkConditions {
+    /// Probability of the packet loss (`0.0..=1.0`).
+    ///
+    /// Default: `0.0`
+    pub loss: f64,
+    /// Delivery delay of each packet.
+    ///
+    /// Default: zero
+    pub latency: Duration,
+    /// Max random delay which is added to the latency.
+    ///
+    /// Default: zero
+    pub jitter: Duration,
+    /// Probability of the packet being delayed for an additional `latency + jitter`
+    /// (at least 1 ms), so it arrives after the next packets (`0.0..=1.0`).
+    ///
+    /// Default: `0.0`
+    pub reorder: f64,
+}
+
+/// Packets statistics of the [`MemoryNetwork`]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MemoryNetworkStats {
+    /// Total number of sent packets
+    pub sent: u64,
+    /// Number of packets which were passed to the bound sockets
+    pub delivered: u64,
+    /// Number of packets which were lost due to the link conditions or unknown destination
+    pub lost: u64,
+}
+
+/// Socket bound to the address in the [`MemoryNetwork`]
+pub struct MemorySocket {
+    state: Arc<NetworkState>,
+    addr: SocketAddrV4,
+    rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl MemorySocket {
+    /// Bound address
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.addr
+    }
+
+    /// Sends the packet with the link conditions between the addresses
+    pub fn send_to(&self, data: &[u8], addr: SocketAddrV4) {
+        let state = &self.state;
+        state.sent.fetch_add(1, Ordering::Relaxed);
+
+        let conditions = match state.link_conditions.lock().get(&(self.addr, addr)) {
+            Some(conditions) => *conditions,
+            None => *state.conditions.lock(),
+        };
+
+        let delay = {
+            let mut rng = state.rng.lock();
+            if conditions.loss > 0.0 && rng.gen_bool(conditions.loss.min(1.0)) {
+                state.lost.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            let mut delay = conditions.latency;
+            if !conditions.jitter.is_zero() {
+                delay += rng.gen_range(Duration::ZERO..=conditions.jitter);
+            }
+            if conditions.reorder > 0.0 && rng.gen_bool(conditions.reorder.min(1.0)) {
+                delay += std::cmp::max(
+                    conditions.latency + conditions.jitter,
+                    Duration::from_millis(1),
+                );
+            }
+            delay
+        };
+
+        let datagram = Datagram {
+            data: data.to_vec(),
+            from: self.addr,
+        };
+        if delay.is_zero() {
+            state.deliver(addr, datagram);
+        } else {
+            let state = state.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                state.deliver(addr, datagram);
+            });
+        }
+    }
+
+    /// Waits for the next packet. Truncates the packet if it doesn't fit into the buffer
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        match self.rx.lock().await.recv().await {
+            Some(datagram) => {
+                let len = std::cmp::min(datagram.data.len(), buffer.len());
+                buffer[..len].copy_from_slice(&datagram.data[..len]);
+                Ok((len, SocketAddr::V4(datagram.from)))
+            }
+            None => Err(std::io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.state.endpoints.lock().remove(&self.addr);
+    }
+}
+
+struct NetworkState {
+    endpoints: Mutex<FastHashMap<SocketAddrV4, mpsc::UnboundedSender<Datagram>>>,
+    conditions: Mutex<LinkConditions>,
+    link_conditions: Mutex<FastHashMap<(SocketAddrV4, SocketAddrV4), LinkConditions>>,
+    rng: Mutex<SmallRng>,
+    next_port: Mutex<u16>,
+    sent: AtomicU64,
+    delivered: AtomicU64,
+    lost: AtomicU64,
+}
+
+impl NetworkState {
+    fn deliver(&self, addr: SocketAddrV4, datagram: Datagram) {
+        let delivered = match self.endpoints.lock().get(&addr) {
+            Some(tx) => tx.send(datagram).is_ok(),
+            None => false,
+        };
+        if delivered {
+            self.delivered.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.lost.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+struct Datagram {
+    data: Vec<u8>,
+    from: SocketAddrV4,
+}
+
+/// Mock of the unix time used by the protocol logic (e.g. reinit dates,
+/// address lists and channel timeouts).
+///
+/// The mock is set for the current thread, so tests which run in parallel don't affect
+/// each other. Nodes must be used on the current thread runtime (default for `#[tokio::test]`).
+///
+/// NOTE: monotonic timers (timeouts and intervals) are not affected,
+/// use `tokio::time::pause` to control them.
+pub struct MockClock;
+
+impl MockClock {
+    /// Freezes the time at the specified unix timestamp in seconds
+    pub fn set(now: u32) {
+        MOCK_NOW.with(|mock| mock.set(Some(now)));
+    }
+
+    /// Moves the frozen time forward. Freezes the current system time first if it is not mocked
+    pub fn advance(secs: u32) {
+        let now = Self::now().unwrap_or_else(system_now);
+        Self::set(now.saturating_add(secs));
+    }
+
+    /// Switches back to the system time
+    pub fn reset() {
+        MOCK_NOW.with(|mock| mock.set(None));
+    }
+
+    /// Returns the mocked time if it is set
+    pub fn now() -> Option<u32> {
+        MOCK_NOW.with(Cell::get)
+    }
+}
+
+thread_local! {
+    static MOCK_NOW: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+fn system_now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum MemoryNetworkError {
+    #[error("Address is already in use: {0}")]
+    AddressInUse(SocketAddrV4),
+}
+
+/// First port which is assigned to the sockets bound to port zero
+const FIRST_AUTO_PORT: u16 = 30000;
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use everscale_crypto::ed25519;
+
+    use super::*;
+
+    fn make_node(network: &MemoryNetwork) -> (Arc<adnl::Node>, adnl::NodeIdShort) {
+        let key = ed25519::SecretKey::generate(&mut rand::thread_rng());
+        let node = network
+            .create_node(
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+                adnl::Keystore::builder()
+                    .with_tagged_keys([(key.to_bytes(), 0)])
+                    .unwrap()
+                    .build(),
+                Default::default(),
+                None,
+            )
+            .unwrap();
+        let id = *node.key_by_tag(0).unwrap().id();
+        (node, id)
+    }
+
+    #[tokio::test]
+    async fn memory_network() {
+        let network = MemoryNetwork::with_seed(123);
+        let (left, left_id) = make_node(&network);
+        let (right, right_id) = make_node(&network);
+        assert_ne!(left.socket_addr(), right.socket_addr());
+        assert!(network.bind(left.socket_addr()).is_err());
+
+        connect_nodes(&left, &left_id, &right, &right_id).unwrap();
+        left.start().unwrap();
+        right.start().unwrap();
+
+        network.set_conditions(LinkConditions {
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(5),
+            reorder: 0.2,
+            ..Default::default()
+        });
+        assert!(left
+            .establish_channel(&left_id, &right_id, Some(1000))
+            .await
+            .unwrap());
+        assert!(network.stats().delivered > 0);
+
+        // All packets from the right node are lost
+        network.set_link_conditions(
+            right.socket_addr(),
+            left.socket_addr(),
+            Some(LinkConditions {
+                loss: 1.0,
+                ..Default::default()
+            }),
+        );
+        let query = left.query_raw(
+            &left_id,
+            &right_id,
+            tl_proto::serialize(crate::proto::rpc::AdnlPing { value: 1 }).into(),
+            Some(100),
+        );
+        assert!(query.await.unwrap().is_none());
+        assert!(network.stats().lost > 0);
+    }
+
+    #[test]
+    fn mock_clock() {
+        MockClock::set(1000);
+        assert_eq!(crate::util::now(), 1000);
+
+        MockClock::advance(10);
+        assert_eq!(crate::util::now(), 1010);
+
+        MockClock::reset();
+        assert!(crate::util::now() > 1010);
+    }
+}
@@ -1,9 +1,13 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{SocketAddr, SocketAddrV4};
+
+use smallvec::SmallVec;
 
 use super::now;
 use crate::proto;
 
-/// Validates address list and extracts socket address from it
+/// Validates address list and extracts socket address from it.
+///
+/// Lists with only IPv6 addresses are rejected because ADNL sockets are IPv4-only
 pub fn parse_address_list(
     list: &proto::adnl::AddressList,
     clock_tolerance: u32,
@@ -19,10 +23,66 @@ pub fn parse_address_list(
         return Err(AdnlAddressListError::Expired);
     }
 
-    Ok(SocketAddrV4::new(
-        Ipv4Addr::from(address.ip),
-        address.port as u16,
-    ))
+    match SocketAddr::from(address) {
+        SocketAddr::V4(addr) => Ok(addr),
+        SocketAddr::V6(addr) => Err(AdnlAddressListError::UnsupportedAddress(addr.into())),
+    }
+}
+
+/// Validates address list and extracts all socket addresses from it.
+///
+/// Unlike [`parse_address_list`], expired lists are not rejected
+/// but marked with [`ParsedAddressList::expired`]
+pub fn parse_address_list_full(
+    list: &proto::adnl::AddressListFull,
+    clock_tolerance: u32,
+) -> Result<ParsedAddressList, AdnlAddressListError> {
+    if list.addresses.is_empty() {
+        return Err(AdnlAddressListError::ListIsEmpty);
+    }
+
+    let version = now();
+    if list.reinit_date > version + clock_tolerance {
+        return Err(AdnlAddressListError::TooNewVersion);
+    }
+
+    Ok(ParsedAddressList {
+        addresses: list
+            .addresses
+            .iter()
+            .map(|&address| address.into())
+            .collect(),
+        version: list.version,
+        priority: list.priority,
+        reinit_date: list.reinit_date,
+        expire_at: list.expire_at,
+        expired: list.expire_at != 0 && list.expire_at < version,
+    })
+}
+
+/// Address list contents with validity info
+#[derive(Debug, Clone)]
+pub struct ParsedAddressList {
+    /// All addresses in the list order
+    pub addresses: SmallVec<[SocketAddr; 4]>,
+    pub version: u32,
+    /// Priority of the list, shared by all of its addresses
+    pub priority: u32,
+    pub reinit_date: u32,
+    /// Unix timestamp after which the list is no longer valid, `0` if it never expires
+    pub expire_at: u32,
+    /// Whether the list has already expired
+    pub expired: bool,
+}
+
+impl ParsedAddressList {
+    /// Returns the first IPv4 address of the list
+    pub fn best_ipv4(&self) -> Option<SocketAddrV4> {
+        self.addresses.iter().find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(*addr),
+            SocketAddr::V6(_) => None,
+        })
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -33,11 +93,15 @@ pub enum AdnlAddressListError {
     TooNewVersion,
     #[error("Address list is expired")]
     Expired,
+    #[error("Unsupported address: {0}")]
+    UnsupportedAddress(SocketAddr),
 }
 
 #[cfg(test)]
 mod tests {
-    use std::net::SocketAddrV4;
+    use std::net::{Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    use super::*;
 
     #[test]
     fn correct_port_update() {
@@ -47,4 +111,31 @@ mod tests {
         ip.set_port(4560);
         assert_eq!(ip.port(), 4560);
     }
+
+    #[test]
+    fn full_address_list() {
+        let udp6 = SocketAddrV6::new(Ipv6Addr::LOCALHOST, 123, 0, 0);
+        let udp = SocketAddrV4::new(0x12345678.into(), 123);
+
+        let mut list = proto::adnl::AddressListFull {
+            addresses: [(&udp6).into(), (&udp).into()].into_iter().collect(),
+            version: now(),
+            reinit_date: 0,
+            priority: 10,
+            expire_at: 1,
+        };
+
+        let parsed = parse_address_list_full(&list, 0).unwrap();
+        assert!(parsed.expired);
+        assert_eq!(parsed.addresses.len(), 2);
+        assert_eq!(parsed.addresses[0], SocketAddr::V6(udp6));
+        assert_eq!(parsed.priority, 10);
+        assert_eq!(parsed.best_ipv4(), Some(udp));
+
+        list.reinit_date = now() + 100;
+        assert!(matches!(
+            parse_address_list_full(&list, 0),
+            Err(AdnlAddressListError::TooNewVersion)
+        ));
+    }
 }
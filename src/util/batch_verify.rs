@@ -0,0 +1,183 @@
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::{IsIdentity, VartimeMultiscalarMul};
+use everscale_crypto::ed25519;
+use rand::Rng;
+use sha2::{Digest, Sha512};
+
+/// Signature to verify in a batch
+pub(crate) struct SignatureItem<'a> {
+    pub public_key: &'a [u8; 32],
+    pub message: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+/// Verifies ed25519 signatures. Returns validity of each signature.
+///
+/// All signatures are checked at once using the random linear combination
+/// of the cofactorless verification equations, the same ones as in
+/// [`ed25519::PublicKey::verify_raw`]. Signatures are checked one by one
+/// only if the whole batch is invalid.
+///
+/// NOTE: random coefficients are odd, so a single signature which is only valid
+/// under the cofactored equation (i.e. differs by a small-order point) always fails the batch.
+pub(crate) fn verify_signatures(items: &[SignatureItem<'_>]) -> Vec<bool> {
+    if items.len() > 1 && verify_batch(items) {
+        return vec![true; items.len()];
+    }
+    items.iter().map(verify_single).collect()
+}
+
+fn verify_single(item: &SignatureItem<'_>) -> bool {
+    let signature = match <&[u8; 64]>::try_from(item.signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    match ed25519::PublicKey::from_bytes(*item.public_key) {
+        Some(public_key) => public_key.verify_raw(item.message, signature),
+        None => false,
+    }
+}
+
+fn verify_batch(items: &[SignatureItem<'_>]) -> bool {
+    let mut rng = rand::thread_rng();
+
+    let mut base_scalar = Scalar::ZERO;
+    let mut scalars = Vec::with_capacity(items.len() * 2);
+    let mut points = Vec::with_capacity(items.len() * 2);
+
+    for item in items {
+        if item.signature.len() != 64 {
+            return false;
+        }
+        let (r_bytes, s_bytes) = item.signature.split_at(32);
+
+        let s =
+            match Option::<Scalar>::from(Scalar::from_canonical_bytes(s_bytes.try_into().unwrap()))
+            {
+                Some(s) => s,
+                None => return false,
+            };
+
+        let r_compressed = CompressedEdwardsY(r_bytes.try_into().unwrap());
+        let (r, a) = match (
+            r_compressed.decompress(),
+            CompressedEdwardsY(*item.public_key).decompress(),
+        ) {
+            // NOTE: non-canonical R encodings are rejected by the single verification
+            (Some(r), Some(a)) if r.compress() == r_compressed => (r, a),
+            _ => return false,
+        };
+
+        let mut h = Sha512::new();
+        h.update(r_bytes);
+        h.update(item.public_key);
+        h.update(item.message);
+        let k = Scalar::from_bytes_mod_order_wide(&h.finalize().into());
+
+        let z = Scalar::from(rng.gen::<u128>() | 1);
+
+        // [z * s]B == [z]R + [z * k]A
+        base_scalar -= z * s;
+        scalars.push(z);
+        points.push(r);
+        scalars.push(z * k);
+        points.push(a);
+    }
+
+    scalars.push(base_scalar);
+    points.push(ED25519_BASEPOINT_POINT);
+
+    EdwardsPoint::vartime_multiscalar_mul(scalars, points).is_identity()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_verification() {
+        let keys = (0..8u8)
+            .map(|i| ed25519::KeyPair::from(&ed25519::SecretKey::from_bytes([i + 1; 32])))
+            .collect::<Vec<_>>();
+        let messages = (0..8u8).map(|i| vec![i; 100]).collect::<Vec<_>>();
+        let mut signatures = keys
+            .iter()
+            .zip(&messages)
+            .map(|(key, message)| key.sign_raw(message))
+            .collect::<Vec<_>>();
+
+        let make_items = |signatures: &[[u8; 64]]| -> Vec<bool> {
+            let items = keys
+                .iter()
+                .zip(&messages)
+                .zip(signatures)
+                .map(|((key, message), signature)| SignatureItem {
+                    public_key: key.public_key.as_bytes(),
+                    message,
+                    signature,
+                })
+                .collect::<Vec<_>>();
+            verify_signatures(&items)
+        };
+
+        assert!(make_items(&signatures).into_iter().all(|valid| valid));
+
+        signatures[3][40] ^= 1;
+        let valid = make_items(&signatures);
+        assert_eq!(valid.iter().filter(|valid| !**valid).count(), 1);
+        assert!(!valid[3]);
+
+        // Mixed valid and invalid signatures
+        signatures[5][10] ^= 1;
+        let valid = make_items(&signatures);
+        for (i, valid) in valid.into_iter().enumerate() {
+            assert_eq!(valid, i != 3 && i != 5);
+        }
+    }
+
+    #[test]
+    fn cofactored_only_signature() {
+        use curve25519_dalek::constants::EIGHT_TORSION;
+
+        let mut rng = rand::thread_rng();
+
+        let key = ed25519::KeyPair::from(&ed25519::SecretKey::from_bytes([1; 32]));
+        let message = b"hello world";
+        let valid_signature = key.sign_raw(message);
+
+        // Signature with a small-order component in R, which is valid only for the cofactored check
+        let secret = Scalar::from_bytes_mod_order(rng.gen());
+        let public_key = (ED25519_BASEPOINT_POINT * secret).compress().to_bytes();
+        let nonce = Scalar::from_bytes_mod_order(rng.gen());
+        let r = (ED25519_BASEPOINT_POINT * nonce + EIGHT_TORSION[1])
+            .compress()
+            .to_bytes();
+
+        let mut h = Sha512::new();
+        h.update(r);
+        h.update(public_key);
+        h.update(message);
+        let k = Scalar::from_bytes_mod_order_wide(&h.finalize().into());
+
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&r);
+        signature[32..].copy_from_slice((nonce + k * secret).as_bytes());
+
+        let items = [
+            SignatureItem {
+                public_key: key.public_key.as_bytes(),
+                message,
+                signature: &valid_signature,
+            },
+            SignatureItem {
+                public_key: &public_key,
+                message,
+                signature: &signature,
+            },
+        ];
+        assert!(!verify_single(&items[1]));
+        assert_eq!(verify_signatures(&items), [true, false]);
+    }
+}
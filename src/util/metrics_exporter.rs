@@ -0,0 +1,267 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::Result;
+use metrics::{counter, gauge};
+
+use crate::adnl;
+#[cfg(feature = "dht")]
+use crate::dht;
+#[cfg(feature = "overlay")]
+use crate::overlay;
+#[cfg(feature = "rldp")]
+use crate::rldp;
+use crate::subscriber::BackgroundTask;
+use crate::util::FastHashSet;
+
+/// Records node metrics with the [`metrics`] facade.
+///
+/// Instant values are recorded as gauges and totals as counters. Per-peer metrics
+/// (see [`MetricsExporter::with_peer_metrics`]) have `local_id` and `peer_id` labels
+/// (roundtrip times only `peer_id`), overlay metrics have the `overlay_id` label.
+///
+/// Metrics are recorded either on each [`BackgroundTask`] tick (see [`Node::add_background_task`])
+/// or by the separate loop (see [`MetricsExporter::spawn`]).
+///
+/// [`Node::add_background_task`]: crate::adnl::Node::add_background_task
+pub struct MetricsExporter {
+    adnl: Weak<adnl::Node>,
+    #[cfg(feature = "dht")]
+    dht: Option<Weak<dht::Node>>,
+    #[cfg(feature = "overlay")]
+    overlay: Option<Weak<overlay::Node>>,
+    #[cfg(feature = "rldp")]
+    rldp: Option<Weak<rldp::Node>>,
+    peer_metrics: bool,
+}
+
+impl MetricsExporter {
+    /// Creates an exporter of the ADNL node metrics
+    pub fn new(adnl: &Arc<adnl::Node>) -> Self {
+        Self {
+            adnl: Arc::downgrade(adnl),
+            #[cfg(feature = "dht")]
+            dht: None,
+            #[cfg(feature = "overlay")]
+            overlay: None,
+            #[cfg(feature = "rldp")]
+            rldp: None,
+            peer_metrics: false,
+        }
+    }
+
+    /// Also records DHT node metrics
+    #[cfg(feature = "dht")]
+    pub fn with_dht(mut self, dht: &Arc<dht::Node>) -> Self {
+        self.dht = Some(Arc::downgrade(dht));
+        self
+    }
+
+    /// Also records metrics of all overlays
+    #[cfg(feature = "overlay")]
+    pub fn with_overlay(mut self, overlay: &Arc<overlay::Node>) -> Self {
+        self.overlay = Some(Arc::downgrade(overlay));
+        self
+    }
+
+    /// Also records RLDP node metrics
+    #[cfg(feature = "rldp")]
+    pub fn with_rldp(mut self, rldp: &Arc<rldp::Node>) -> Self {
+        self.rldp = Some(Arc::downgrade(rldp));
+        self
+    }
+
+    /// Whether to record metrics for each remote peer. Disabled by default.
+    ///
+    /// NOTE: the [`metrics`] facade has no way to remove label values, so metrics
+    /// of the removed peers are just no longer updated. Configure the recorder
+    /// to expire idle metrics (e.g. `PrometheusBuilder::idle_timeout`)
+    /// to keep the number of label values bounded.
+    pub fn with_peer_metrics(mut self, enabled: bool) -> Self {
+        self.peer_metrics = enabled;
+        self
+    }
+
+    /// Records metrics of all nodes once.
+    /// Returns `false` if the ADNL node was dropped
+    pub fn record(&self) -> bool {
+        let adnl = match self.adnl.upgrade() {
+            Some(adnl) => adnl,
+            None => return false,
+        };
+
+        record_adnl(&adnl, self.peer_metrics);
+        #[cfg(feature = "dht")]
+        if let Some(dht) = self.dht.as_ref().and_then(Weak::upgrade) {
+            record_dht(&dht);
+        }
+        #[cfg(feature = "overlay")]
+        if let Some(overlay) = self.overlay.as_ref().and_then(Weak::upgrade) {
+            record_overlay(&overlay);
+        }
+        #[cfg(feature = "rldp")]
+        if let Some(rldp) = self.rldp.as_ref().and_then(Weak::upgrade) {
+            record_rldp(&rldp);
+        }
+        true
+    }
+
+    /// Records metrics with the specified interval until the ADNL node is dropped
+    pub fn spawn(self, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if !self.record() {
+                    break;
+                }
+            }
+            tracing::debug!("metrics exporter loop finished");
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundTask for MetricsExporter {
+    async fn poll(&self, _: u32) -> Result<()> {
+        self.record();
+        Ok(())
+    }
+}
+
+fn record_adnl(adnl: &adnl::Node, peer_metrics: bool) {
+    let metrics = adnl.metrics();
+    gauge!("adnl_peers").set(metrics.peer_count as f64);
+    gauge!("adnl_channels").set(metrics.channels_by_peers_len as f64);
+    gauge!("adnl_incoming_transfers").set(metrics.incoming_transfers_len as f64);
+    gauge!("adnl_incoming_transfers_memory_bytes").set(metrics.incoming_transfers_memory as f64);
+    gauge!("adnl_pending_queries").set(metrics.query_count as f64);
+    counter!("adnl_expired_queries_total").absolute(metrics.queries_expired);
+    counter!("adnl_stale_queries_total").absolute(metrics.queries_stale);
+    counter!("adnl_rejected_queries_total").absolute(metrics.queries_rejected);
+    for (reason, count) in adnl.packet_drop_metrics().iter() {
+        counter!("adnl_dropped_packets_total", "reason" => reason.as_str()).absolute(count);
+    }
+
+    if !peer_metrics {
+        return;
+    }
+
+    let mut peer_ids = FastHashSet::default();
+    for peer in adnl.peer_metrics() {
+        let labels = [
+            ("local_id", peer.local_id.to_string()),
+            ("peer_id", peer.peer_id.to_string()),
+        ];
+        counter!("adnl_peer_packets_in_total", &labels).absolute(peer.packets_in);
+        counter!("adnl_peer_bytes_in_total", &labels).absolute(peer.bytes_in);
+        counter!("adnl_peer_packets_out_total", &labels).absolute(peer.packets_out);
+        counter!("adnl_peer_bytes_out_total", &labels).absolute(peer.bytes_out);
+        counter!("adnl_peer_decrypt_failures_total", &labels).absolute(peer.decrypt_failures);
+        counter!("adnl_peer_duplicate_packets_total", &labels).absolute(peer.duplicate_packets);
+        gauge!("adnl_peer_last_activity", &labels).set(peer.last_activity as f64);
+        if let Some(age) = peer.channel_age_sec {
+            gauge!("adnl_peer_channel_age_seconds", &labels).set(age as f64);
+        }
+        peer_ids.insert(peer.peer_id);
+    }
+
+    // NOTE: roundtrip times are tracked for each remote peer regardless of the local id
+    for peer_id in peer_ids {
+        if let Some(rtt) = adnl.peer_rtt(&peer_id) {
+            let labels = [("peer_id", peer_id.to_string())];
+            gauge!("adnl_peer_rtt_ms", &labels).set(rtt.smoothed_ms as f64);
+            gauge!("adnl_peer_rtt_p90_ms", &labels).set(rtt.p90_ms as f64);
+        }
+    }
+}
+
+#[cfg(feature = "dht")]
+fn record_dht(dht: &dht::Node) {
+    let metrics = dht.metrics();
+    gauge!("dht_known_peers").set(metrics.known_peers_len as f64);
+    gauge!("dht_bad_peers").set(metrics.bad_peers_len as f64);
+    gauge!("dht_bucket_peers").set(metrics.bucket_peer_count as f64);
+    gauge!("dht_storage_values").set(metrics.storage_len as f64);
+    gauge!("dht_storage_bytes").set(metrics.storage_total_size as f64);
+    gauge!("dht_lookup_avg_time_ms").set(metrics.lookup_avg_time_ms as f64);
+    counter!("dht_lookups_total").absolute(metrics.lookups);
+    counter!("dht_failed_queries_total").absolute(metrics.failed_queries);
+    for (query, count) in [
+        ("ping", metrics.ping_queries),
+        ("find_node", metrics.find_node_queries),
+        ("find_value", metrics.find_value_queries),
+        (
+            "get_signed_address_list",
+            metrics.get_signed_address_list_queries,
+        ),
+        ("store", metrics.store_queries),
+    ] {
+        counter!("dht_incoming_queries_total", "query" => query).absolute(count);
+    }
+}
+
+#[cfg(feature = "overlay")]
+fn record_overlay(overlay: &overlay::Node) {
+    for (overlay_id, metrics) in overlay.metrics() {
+        let labels = [("overlay_id", overlay_id.to_string())];
+        gauge!("overlay_nodes", &labels).set(metrics.node_count as f64);
+        gauge!("overlay_known_peers", &labels).set(metrics.known_peers as f64);
+        gauge!("overlay_neighbours", &labels).set(metrics.neighbours as f64);
+        gauge!("overlay_incomplete_fec_broadcasts", &labels)
+            .set(metrics.incomplete_fec_broadcasts as f64);
+        for (name, value) in [
+            (
+                "overlay_received_broadcasts_total",
+                metrics.received_broadcasts,
+            ),
+            (
+                "overlay_completed_fec_broadcasts_total",
+                metrics.completed_fec_broadcasts,
+            ),
+            (
+                "overlay_failed_fec_broadcasts_total",
+                metrics.failed_fec_broadcasts,
+            ),
+            (
+                "overlay_relayed_broadcast_messages_total",
+                metrics.relayed_broadcast_messages,
+            ),
+            (
+                "overlay_outdated_broadcasts_total",
+                metrics.outdated_broadcasts,
+            ),
+            (
+                "overlay_unauthorized_broadcasts_total",
+                metrics.unauthorized_broadcasts,
+            ),
+            (
+                "overlay_invalid_broadcasts_total",
+                metrics.invalid_broadcasts,
+            ),
+            (
+                "overlay_oversized_broadcasts_total",
+                metrics.oversized_broadcasts,
+            ),
+            (
+                "overlay_throttled_broadcasts_total",
+                metrics.throttled_broadcasts,
+            ),
+            ("overlay_sent_queries_total", metrics.sent_queries),
+            ("overlay_failed_queries_total", metrics.failed_queries),
+            ("overlay_received_queries_total", metrics.received_queries),
+        ] {
+            counter!(name, &labels).absolute(value);
+        }
+    }
+}
+
+#[cfg(feature = "rldp")]
+fn record_rldp(rldp: &rldp::Node) {
+    let metrics = rldp.metrics();
+    gauge!("rldp_peers").set(metrics.peer_count as f64);
+    gauge!("rldp_incoming_transfers").set(metrics.incoming_transfers as f64);
+    gauge!("rldp_incoming_transfers_memory_bytes").set(metrics.incoming_transfers_memory as f64);
+    counter!("rldp_rejected_incoming_transfers_total")
+        .absolute(metrics.rejected_incoming_transfers);
+}
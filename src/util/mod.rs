@@ -2,17 +2,27 @@
 
 use std::collections::{HashMap, HashSet};
 
+pub use self::address_list::{parse_address_list_full, AdnlAddressListError, ParsedAddressList};
+#[cfg(feature = "metrics")]
+pub use self::metrics_exporter::MetricsExporter;
 pub use self::network_builder::{
     DeferredInitialization, DeferredInitializationList, NetworkBuilder,
 };
+pub use self::packets_history::{PacketsHistoryConfig, PacketsHistoryMode};
 
 pub(crate) use self::address_list::*;
+#[cfg(feature = "dht")]
+pub(crate) use self::batch_verify::*;
 pub(crate) use self::fast_rand::*;
 pub(crate) use self::packets_history::*;
 pub(crate) use self::updated_at::*;
 
 mod address_list;
+#[cfg(feature = "dht")]
+mod batch_verify;
 mod fast_rand;
+#[cfg(feature = "metrics")]
+mod metrics_exporter;
 mod network_builder;
 mod packets_history;
 mod updated_at;
@@ -24,6 +34,11 @@ pub(crate) type FastDashMap<K, V> = dashmap::DashMap<K, V, FastHasherState>;
 pub(crate) type FastHasherState = ahash::RandomState;
 
 pub(crate) fn now() -> u32 {
+    #[cfg(feature = "test-utils")]
+    if let Some(now) = crate::test_utils::MockClock::now() {
+        return now;
+    }
+
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default()
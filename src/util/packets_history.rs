@@ -1,25 +1,49 @@
 use std::sync::atomic::{AtomicU64, Ordering};
 
+use serde::{Deserialize, Serialize};
+
 pub struct PacketsHistory {
     mask: Option<HistoryBits>,
+    mode: PacketsHistoryMode,
     seqno: AtomicU64,
+    duplicate_packets: AtomicU64,
+    outdated_packets: AtomicU64,
 }
 
 impl PacketsHistory {
     pub fn for_send() -> Self {
         Self {
             mask: None,
+            mode: PacketsHistoryMode::Window,
             seqno: Default::default(),
+            duplicate_packets: Default::default(),
+            outdated_packets: Default::default(),
         }
     }
 
-    pub fn for_recv() -> Self {
+    pub fn for_recv_with_config(config: PacketsHistoryConfig) -> Self {
         Self {
-            mask: Some(Default::default()),
+            mask: match config.mode {
+                PacketsHistoryMode::Window => Some(HistoryBits::with_window(config.window)),
+                PacketsHistoryMode::Monotonic => None,
+            },
+            mode: config.mode,
             seqno: Default::default(),
+            duplicate_packets: Default::default(),
+            outdated_packets: Default::default(),
         }
     }
 
+    /// Number of packets which were dropped as already received
+    pub fn duplicate_packets(&self) -> u64 {
+        self.duplicate_packets.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets which were dropped as too old
+    pub fn outdated_packets(&self) -> u64 {
+        self.outdated_packets.load(Ordering::Relaxed)
+    }
+
     pub fn reset(&self) {
         if let Some(mask) = &self.mask {
             loop {
@@ -39,9 +63,10 @@ impl PacketsHistory {
                 break;
             }
 
-            for i in 0..HISTORY_SIZE {
-                let value = u64::from(i == HISTORY_SIZE / 2);
-                mask.bits[i].store(value, Ordering::Release);
+            let history_size = mask.bits.len();
+            for (i, bits) in mask.bits.iter().enumerate() {
+                let value = u64::from(i == history_size / 2);
+                bits.store(value, Ordering::Release);
             }
         }
 
@@ -57,15 +82,77 @@ impl PacketsHistory {
         self.seqno.load(Ordering::Acquire)
     }
 
+    /// Updates the last known seqno without the deduplication
+    pub fn update_seqno(&self, seqno: u64) {
+        self.seqno.fetch_max(seqno, Ordering::AcqRel);
+    }
+
     pub fn bump_seqno(&self) -> u64 {
         self.seqno.fetch_add(1, Ordering::AcqRel) + 1
     }
 
+    /// Whether the packet was already delivered or is too old to be delivered.
+    /// Unlike [`PacketsHistory::deliver_packet`], the history is not updated
+    #[cfg(any(test, feature = "overlay"))]
+    pub fn is_delivered(&self, seqno: u64) -> bool {
+        let mask = match &self.mask {
+            Some(mask) => mask,
+            None => {
+                return self.mode == PacketsHistoryMode::Monotonic
+                    && self.seqno.load(Ordering::Acquire) >= seqno
+            }
+        };
+
+        let index_mask = mask.index_mask();
+        let history_size = mask.bits.len();
+
+        let seqno_masked = seqno & index_mask;
+        let seqno_normalized = seqno & !index_mask;
+
+        loop {
+            let index = mask.index.load(Ordering::Acquire);
+            if index == IN_TRANSIT {
+                continue;
+            }
+
+            let index_normalized = index & !index_mask;
+            if index_normalized > seqno_normalized + index_mask + 1 {
+                return true;
+            }
+
+            let mask_offset = match index_normalized.cmp(&seqno_normalized) {
+                std::cmp::Ordering::Greater => 0,
+                std::cmp::Ordering::Equal => history_size / 2,
+                std::cmp::Ordering::Less => return false,
+            };
+
+            let mask_bit = 1 << (seqno_masked % 64);
+            let delivered = mask.bits[mask_offset + seqno_masked as usize / 64]
+                .load(Ordering::Acquire)
+                & mask_bit;
+            if mask.index.load(Ordering::Acquire) != index {
+                continue;
+            }
+
+            return delivered != 0;
+        }
+    }
+
     pub fn deliver_packet(&self, seqno: u64) -> bool {
         let mask = match &self.mask {
             Some(mask) => mask,
             None => loop {
                 let last_seqno = self.seqno.load(Ordering::Acquire);
+                if self.mode == PacketsHistoryMode::Monotonic && last_seqno >= seqno {
+                    let counter = if last_seqno == seqno {
+                        &self.duplicate_packets
+                    } else {
+                        &self.outdated_packets
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+
                 if last_seqno < seqno
                     && self
                         .seqno
@@ -78,8 +165,11 @@ impl PacketsHistory {
             },
         };
 
-        let seqno_masked = seqno & INDEX_MASK;
-        let seqno_normalized = seqno & !INDEX_MASK;
+        let index_mask = mask.index_mask();
+        let history_size = mask.bits.len();
+
+        let seqno_masked = seqno & index_mask;
+        let seqno_normalized = seqno & !index_mask;
 
         loop {
             let index = mask.index.load(Ordering::Acquire);
@@ -88,18 +178,19 @@ impl PacketsHistory {
                 continue;
             }
 
-            let index_masked = index & INDEX_MASK;
-            let index_normalized = index & !INDEX_MASK;
+            let index_masked = index & index_mask;
+            let index_normalized = index & !index_mask;
 
-            if index_normalized > seqno_normalized + INDEX_MASK + 1 {
+            if index_normalized > seqno_normalized + index_mask + 1 {
                 tracing::debug!(seqno, index_normalized, "peer packet is too old");
+                self.outdated_packets.fetch_add(1, Ordering::Relaxed);
                 return false;
             }
 
             let mask_bit = 1 << (seqno_masked % 64);
             let mask_offset = match index_normalized.cmp(&seqno_normalized) {
                 std::cmp::Ordering::Greater => Some(0),
-                std::cmp::Ordering::Equal => Some(HISTORY_SIZE / 2),
+                std::cmp::Ordering::Equal => Some(history_size / 2),
                 std::cmp::Ordering::Less => None,
             };
 
@@ -114,6 +205,7 @@ impl PacketsHistory {
 
                     if already_delivered != 0 {
                         tracing::trace!(seqno, "peer packet was already received");
+                        self.duplicate_packets.fetch_add(1, Ordering::Relaxed);
                         return false;
                     }
 
@@ -138,24 +230,30 @@ impl PacketsHistory {
                         continue;
                     }
 
-                    if index_normalized + INDEX_MASK + 1 == seqno_normalized {
-                        for i in 0..HISTORY_SIZE / 2 {
+                    if index_normalized + index_mask + 1 == seqno_normalized {
+                        for i in 0..history_size / 2 {
                             mask.bits[i].store(
-                                mask.bits[i + HISTORY_SIZE / 2].load(Ordering::Acquire),
+                                mask.bits[i + history_size / 2].load(Ordering::Acquire),
                                 Ordering::Release,
                             )
                         }
 
-                        for bits in &mask.bits[HISTORY_SIZE / 2..HISTORY_SIZE] {
+                        for bits in &mask.bits[history_size / 2..history_size] {
                             bits.store(0, Ordering::Relaxed)
                         }
                     } else {
-                        for bits in &mask.bits {
+                        for bits in mask.bits.iter() {
                             bits.store(0, Ordering::Release)
                         }
                     }
 
-                    index_normalized
+                    // Mark the packet which moved the window as delivered
+                    mask.bits[history_size / 2 + seqno_masked as usize / 64]
+                        .fetch_or(mask_bit, Ordering::Release);
+
+                    // NOTE: the window is moved to the new seqno, otherwise
+                    // each next packet would reset the whole history
+                    seqno_normalized
                 }
             };
 
@@ -164,7 +262,7 @@ impl PacketsHistory {
                 self.seqno.store(seqno, Ordering::Release);
             }
 
-            let index_masked = (index_masked + 1) & !INDEX_MASK;
+            let index_masked = (index_masked + 1) & !index_mask;
             let _ = mask.index.compare_exchange(
                 IN_TRANSIT,
                 next_index | index_masked,
@@ -179,14 +277,141 @@ impl PacketsHistory {
     }
 }
 
-#[derive(Default)]
+/// Received packets deduplication settings
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PacketsHistoryConfig {
+    /// Deduplication strategy
+    ///
+    /// Default: [`PacketsHistoryMode::Window`]
+    pub mode: PacketsHistoryMode,
+
+    /// Number of the recent seqno tracked by the [`PacketsHistoryMode::Window`] strategy.
+    /// Will be rounded up to the power of two (minimal value is `128`).
+    ///
+    /// Default: `512`
+    pub window: u32,
+}
+
+impl Default for PacketsHistoryConfig {
+    fn default() -> Self {
+        Self {
+            mode: PacketsHistoryMode::Window,
+            window: DEFAULT_HISTORY_BITS as u32,
+        }
+    }
+}
+
+/// Received packets deduplication strategy
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketsHistoryMode {
+    /// Tracks each seqno within the sliding window. Allows reordered packets.
+    Window,
+    /// Accepts only packets with seqno greater than the last received one.
+    /// Drops all reordered packets.
+    Monotonic,
+}
+
 struct HistoryBits {
     index: AtomicU64,
-    bits: [AtomicU64; HISTORY_SIZE],
+    bits: Box<[AtomicU64]>,
+}
+
+impl HistoryBits {
+    fn with_window(window: u32) -> Self {
+        let window = (window as usize).next_power_of_two().max(MIN_HISTORY_BITS);
+        Self {
+            index: Default::default(),
+            bits: (0..window / 64).map(|_| AtomicU64::default()).collect(),
+        }
+    }
+
+    #[inline(always)]
+    fn index_mask(&self) -> u64 {
+        (self.bits.len() * 64) as u64 / 2 - 1
+    }
 }
 
-const INDEX_MASK: u64 = HISTORY_BITS as u64 / 2 - 1;
 const IN_TRANSIT: u64 = 0xFFFFFFFFFFFFFFFF;
 
-const HISTORY_BITS: usize = 512;
-const HISTORY_SIZE: usize = HISTORY_BITS / 64;
+const DEFAULT_HISTORY_BITS: usize = 512;
+const MIN_HISTORY_BITS: usize = 128;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_history() {
+        for window in [128, 512, 1000] {
+            let history = PacketsHistory::for_recv_with_config(PacketsHistoryConfig {
+                mode: PacketsHistoryMode::Window,
+                window,
+            });
+
+            assert!(history.deliver_packet(1));
+            assert!(history.deliver_packet(3));
+            assert!(!history.is_delivered(2));
+            assert!(history.deliver_packet(2));
+            assert!(history.is_delivered(2));
+            assert!(!history.deliver_packet(2));
+            assert_eq!(history.duplicate_packets(), 1);
+
+            assert!(!history.is_delivered(10000));
+            assert!(history.deliver_packet(10000));
+            assert!(history.is_delivered(4));
+            assert!(!history.deliver_packet(4));
+            assert_eq!(history.outdated_packets(), 1);
+            assert_eq!(history.seqno(), 10000);
+        }
+    }
+
+    #[test]
+    fn window_history_replay() {
+        let history = PacketsHistory::for_recv_with_config(PacketsHistoryConfig {
+            mode: PacketsHistoryMode::Window,
+            window: 128,
+        });
+
+        // Packets which move the window are remembered
+        assert!(history.deliver_packet(301));
+        assert!(!history.deliver_packet(301));
+        assert!(history.deliver_packet(351));
+        assert!(!history.deliver_packet(351));
+        assert!(history.deliver_packet(580));
+        assert!(!history.deliver_packet(580));
+        assert_eq!(history.duplicate_packets(), 3);
+
+        // Packets below the window are rejected
+        assert!(!history.deliver_packet(351));
+        assert!(!history.deliver_packet(500));
+        assert_eq!(history.outdated_packets(), 2);
+
+        // Window slides without resetting the history
+        for seqno in 581..1000 {
+            assert!(history.deliver_packet(seqno));
+        }
+        for seqno in 950..1000 {
+            assert!(!history.deliver_packet(seqno));
+        }
+        assert_eq!(history.duplicate_packets(), 53);
+    }
+
+    #[test]
+    fn monotonic_history() {
+        let history = PacketsHistory::for_recv_with_config(PacketsHistoryConfig {
+            mode: PacketsHistoryMode::Monotonic,
+            window: 0,
+        });
+
+        assert!(history.deliver_packet(1));
+        assert!(!history.is_delivered(3));
+        assert!(history.deliver_packet(3));
+        assert!(history.is_delivered(2));
+        assert!(!history.deliver_packet(2));
+        assert!(!history.deliver_packet(3));
+        assert_eq!(history.duplicate_packets(), 1);
+        assert_eq!(history.outdated_packets(), 1);
+    }
+}